@@ -1,7 +1,23 @@
 use clap::Clap;
+use mongo_sync::blocking::mongo_syncer::restore::parse_restore_point;
+use mongo_sync::cancel_on_signals;
+use mongo_sync::ApplyFailurePolicy;
+use mongo_sync::ApplyRetry;
+use mongo_sync::BatchLimits;
+use mongo_sync::CancelToken;
+use mongo_sync::DbSelector;
 use mongo_sync::DbSyncConf;
+use mongo_sync::FullSyncWritePolicy;
 use mongo_sync::MongoSyncer;
-use std::path::Path;
+use mongo_sync::NamespaceMapping;
+use mongo_sync::OplogGapPolicy;
+use mongo_sync::RemovedCollPolicy;
+use mongo_sync::RetryPolicy;
+use mongo_sync::SamplingConfig;
+use mongo_sync::SyncError;
+use mongo_sync::{RestoreBase, RestoreOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use tracing::info;
 
@@ -14,15 +30,27 @@ struct Opts {
     /// target mongodb uri.
     #[clap(short, long)]
     target_uri: String,
-    /// mongodb uri which save oplogs, it's saved by `oplog_syncer` binary.
+    /// mongodb uri which save oplogs, it's saved by `oplog_syncer` binary, required unless `--once` is used.
     #[clap(short, long)]
-    oplog_storage_uri: String,
-    /// database to sync.
+    oplog_storage_uri: Option<String>,
+    /// databases to sync, `*` can be used as wildcard, e.g: `app_*`.
     #[clap(short, long)]
-    db: String,
-    /// collections to sync, default sync all collections inside a database.
+    db: Option<Vec<String>>,
+    /// databases not to sync, `*` can be used as wildcard.
+    #[clap(long)]
+    exclude_db: Option<Vec<String>>,
+    /// sync all user databases.
+    #[clap(long)]
+    all_dbs: bool,
+    /// collections to sync by exact name, default sync all collections inside a database.
     #[clap(short, long)]
     colls: Option<Vec<String>>,
+    /// collections to sync, `*` can be used as wildcard, or a regex wrapped in `/`, e.g: `/^log_\d+$/`.
+    #[clap(long)]
+    include_coll: Option<Vec<String>>,
+    /// collections not to sync, same format as `--include-coll`, exclusion wins.
+    #[clap(long)]
+    exclude_coll: Option<Vec<String>>,
     /// how many threads to sync a database.
     #[clap(long)]
     collection_concurrent: Option<usize>,
@@ -32,6 +60,200 @@ struct Opts {
     /// log file path, if no specified, all log information will be output to stdout.
     #[clap(long)]
     log_path: Option<String>,
+    /// how many times sync is run again after transient errors one after another, e.g: network errors.
+    #[clap(long, default_value = "10")]
+    max_retries: u32,
+    /// run sync again after transient errors forever, `--max-retries` is ignored.
+    #[clap(long)]
+    retry_forever: bool,
+    /// max seconds to wait before running sync again, the wait begins from 1 second and doubles.
+    #[clap(long, default_value = "300")]
+    max_backoff_secs: u64,
+    /// make a one-shot copy and exit, no oplog storage is needed.
+    #[clap(long)]
+    once: bool,
+    /// read full sync documents at one cluster time on mongodb 5.0+ source, the copy must finish within
+    /// `minSnapshotHistoryWindowInSeconds` of source.
+    #[clap(long)]
+    snapshot_read: bool,
+    /// verify target against source and exit, differences are checked again after incremental sync passes
+    /// them if oplog storage is given.
+    #[clap(long)]
+    verify: bool,
+    /// repair documents which differ after `--verify`, they are copied from source again.
+    #[clap(long)]
+    repair: bool,
+    /// how to write into target collections during full sync: drop, merge or fail-if-non-empty.  The default
+    /// refuses non-empty target collections, use merge (or drop with `--confirm-drop`) to sync into them.
+    #[clap(long, default_value = "fail-if-non-empty")]
+    write_policy: FullSyncWritePolicy,
+    /// what to do with collections synced before but not selected any more: keep, drop or refuse.
+    #[clap(long, default_value = "keep")]
+    removed_coll_policy: RemovedCollPolicy,
+    /// what to do when check point is older than the earliest stored oplog: fail, resync (copies all
+    /// collections) or resync-affected (copies collections which follow the check point), both follow
+    /// `--write-policy`.
+    #[clap(long, default_value = "fail")]
+    oplog_gap_policy: OplogGapPolicy,
+    /// apply oplogs this many seconds behind source, makes target a delayed replica.
+    #[clap(long)]
+    apply_delay_secs: Option<u64>,
+    /// apply updates with current full documents fetched from source, instead of replaying update operators.
+    #[clap(long)]
+    full_document_updates: bool,
+    /// how many times a batch of oplogs which fails to apply is retried, with doubled backoff from 1 second.
+    #[clap(long, default_value = "3")]
+    apply_retries: u32,
+    /// what to do with an oplog which still fails to apply after retries: fail or dead-letter.
+    #[clap(long, default_value = "fail")]
+    apply_failure_policy: ApplyFailurePolicy,
+    /// sample random documents every this many seconds in incremental sync, and compare them with source,
+    /// it can't be used with `--apply-delay-secs`.
+    #[clap(long)]
+    sample_interval_secs: Option<u64>,
+    /// how many documents are sampled from each collection in a round, default is 100.
+    #[clap(long)]
+    sample_docs: Option<usize>,
+    /// log an error when mismatched documents of a round crosses the rate, default is 0 (any mismatch).
+    #[clap(long)]
+    sample_alert_rate: Option<f64>,
+    /// restore target to the point and exit, in `time:increment`, seconds since epoch, or RFC 3339 time.
+    #[clap(long)]
+    restore_to: Option<String>,
+    /// restore from mongodump directory instead of synced target, used with `--restore-to`.
+    #[clap(long)]
+    restore_dump: Option<String>,
+    /// when the dump starts, required if the dump is not made with `--oplog`, same format as `--restore-to`.
+    #[clap(long)]
+    restore_dump_start: Option<String>,
+    /// stop restore before the oplog at the timestamp, e.g: a bad operation, in `time:increment` format.
+    #[clap(long)]
+    restore_stop_before: Option<String>,
+    /// confirm that target collections can be dropped, required by `--write-policy drop`.
+    #[clap(long)]
+    confirm_drop: bool,
+    /// max documents (or oplogs) in one batch, default is 10000.
+    #[clap(long, parse(try_from_str = parse_non_zero))]
+    batch_docs: Option<usize>,
+    /// max encoded bson bytes in one batch, default is 8MB.
+    #[clap(long, parse(try_from_str = parse_non_zero))]
+    batch_bytes: Option<usize>,
+    /// max bytes used by all buffers, default is 512MB.
+    #[clap(long, parse(try_from_str = parse_non_zero))]
+    memory_budget: Option<usize>,
+    /// map source database to target database, in `from=to` format, `*` can be used as wildcard.
+    #[clap(long)]
+    map_db: Option<Vec<String>>,
+    /// map source collection to target collection, in `db.coll=db.coll` format, `*` can be used as wildcard in
+    /// collection name.
+    #[clap(long)]
+    map_coll: Option<Vec<String>>,
+}
+
+/// build retry policy from `--max-retries`, `--retry-forever` and `--max-backoff-secs`.
+fn retry_policy(max_retries: u32, retry_forever: bool, max_backoff_secs: u64) -> RetryPolicy {
+    let max_retries = if retry_forever {
+        None
+    } else {
+        Some(max_retries)
+    };
+    RetryPolicy::new(max_retries).with_backoff(
+        Duration::from_secs(1),
+        Duration::from_secs(max_backoff_secs),
+    )
+}
+
+/// build namespace mapping from `--map-db` and `--map-coll` rules.
+fn namespace_mapping(
+    map_db: Option<Vec<String>>,
+    map_coll: Option<Vec<String>>,
+) -> Result<NamespaceMapping, String> {
+    let split_rule = |rule: &str| {
+        rule.split_once('=')
+            .ok_or_else(|| format!("invalid mapping rule {:?}, expect `from=to`", rule))
+    };
+    let mut mapping = NamespaceMapping::new();
+    for rule in map_db.unwrap_or_default() {
+        let (from, to) = split_rule(&rule)?;
+        mapping = mapping.with_db(from, to);
+    }
+    for rule in map_coll.unwrap_or_default() {
+        let (from, to) = split_rule(&rule)?;
+        mapping = mapping.with_coll(from, to);
+    }
+    Ok(mapping)
+}
+
+/// build database selector from `--db`, `--exclude-db` and `--all-dbs`.
+///
+/// Returns the database name and None when only one database is given, it's synced as before.
+fn db_selector(
+    dbs: Option<Vec<String>>,
+    exclude_dbs: Option<Vec<String>>,
+    all_dbs: bool,
+) -> Result<(String, Option<DbSelector>), String> {
+    let dbs = dbs.unwrap_or_default();
+    let exclude_dbs = exclude_dbs.unwrap_or_default();
+    if dbs.is_empty() && !all_dbs {
+        return Err("--db or --all-dbs is required".to_string());
+    }
+    if dbs.len() == 1 && !dbs[0].contains('*') && exclude_dbs.is_empty() && !all_dbs {
+        return Ok((dbs[0].clone(), None));
+    }
+    let mut selector = if all_dbs {
+        DbSelector::all()
+    } else {
+        DbSelector::new()
+    };
+    for db in dbs.iter() {
+        selector = selector.with_include(db);
+    }
+    for db in exclude_dbs.iter() {
+        selector = selector.with_exclude(db);
+    }
+    Ok((String::new(), Some(selector)))
+}
+
+/// parse a buffer limit from command line, it must be larger than 0.
+fn parse_non_zero(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("it must be larger than 0".to_string()),
+        Ok(val) => Ok(val),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// apply batch and memory options from command line to `conf`.
+fn with_buffer_opts(
+    mut conf: DbSyncConf,
+    batch_docs: Option<usize>,
+    batch_bytes: Option<usize>,
+    memory_budget: Option<usize>,
+) -> DbSyncConf {
+    let default_limits = BatchLimits::default();
+    conf = conf.with_batch_limits(BatchLimits {
+        max_count: batch_docs.unwrap_or(default_limits.max_count),
+        max_bytes: batch_bytes.unwrap_or(default_limits.max_bytes),
+    });
+    if let Some(memory_budget) = memory_budget {
+        conf = conf.with_memory_budget(memory_budget);
+    }
+    conf
+}
+
+/// apply collection patterns from `--include-coll` and `--exclude-coll` to `conf`.
+fn with_coll_patterns(
+    mut conf: DbSyncConf,
+    include_colls: Option<Vec<String>>,
+    exclude_colls: Option<Vec<String>>,
+) -> DbSyncConf {
+    for pattern in include_colls.unwrap_or_default() {
+        conf = conf.with_coll_include(&pattern);
+    }
+    for pattern in exclude_colls.unwrap_or_default() {
+        conf = conf.with_coll_exclude(&pattern);
+    }
+    conf
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -49,19 +271,159 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     collector.with_writer(non_blocking).init();
 
+    let mapping = namespace_mapping(opts.map_db, opts.map_coll)?;
+    let (db, dbs) = db_selector(opts.db, opts.exclude_db, opts.all_dbs)?;
+
+    if opts.verify {
+        let conf = match opts.oplog_storage_uri {
+            Some(oplog_storage_uri) => DbSyncConf::new(
+                opts.src_uri,
+                opts.target_uri,
+                oplog_storage_uri,
+                db,
+                opts.colls,
+                opts.collection_concurrent,
+                opts.doc_concurrent,
+            ),
+            None => DbSyncConf::new_oneshot(
+                opts.src_uri,
+                opts.target_uri,
+                db,
+                opts.colls,
+                opts.collection_concurrent,
+                opts.doc_concurrent,
+            ),
+        }
+        .with_namespace_mapping(mapping);
+        let mut conf = with_coll_patterns(conf, opts.include_coll, opts.exclude_coll);
+        if let Some(dbs) = dbs {
+            conf = conf.with_dbs(dbs);
+        }
+        info!("Use the following config to verify database: {:?}", conf);
+
+        let report = MongoSyncer::new(&conf).verify()?;
+        println!("{}", report);
+        let mut consistent = report.is_consistent();
+        if opts.repair && !consistent {
+            let docs = report.repair_docs();
+            let result = MongoSyncer::new(&conf).repair(&docs)?;
+            println!("Repair {} documents: {}", docs.len(), result);
+            // differences of indexes and options, and `_id`s which are not listed are not repaired.
+            consistent = report
+                .collections
+                .iter()
+                .all(|c| c.error.is_none() && c.meta_diffs.is_empty() && !c.id_diffs.truncated);
+        }
+        drop(_guard);
+        std::process::exit(if consistent { 0 } else { 1 });
+    }
+
+    if opts.once {
+        let conf: DbSyncConf = DbSyncConf::new_oneshot(
+            opts.src_uri,
+            opts.target_uri,
+            db,
+            opts.colls,
+            opts.collection_concurrent,
+            opts.doc_concurrent,
+        )
+        .with_write_policy(opts.write_policy)
+        .with_confirm_drop(opts.confirm_drop)
+        .with_snapshot_read(opts.snapshot_read)
+        .with_namespace_mapping(mapping);
+        let conf = with_buffer_opts(conf, opts.batch_docs, opts.batch_bytes, opts.memory_budget);
+        let mut conf = with_coll_patterns(conf, opts.include_coll, opts.exclude_coll);
+        if let Some(dbs) = dbs {
+            conf = conf.with_dbs(dbs);
+        }
+        info!("Use the following config to copy database: {:?}", conf);
+
+        let syncer = MongoSyncer::new(&conf);
+        info!("Begin to copy database.");
+        let report = syncer.sync_once()?;
+        println!("{}", report);
+        // `process::exit` doesn't run destructors, so flush log before exit.
+        drop(_guard);
+        std::process::exit(if report.is_success() { 0 } else { 1 });
+    }
+
+    let oplog_storage_uri = opts
+        .oplog_storage_uri
+        .ok_or("--oplog-storage-uri is required unless --once is used")?;
     let conf: DbSyncConf = DbSyncConf::new(
         opts.src_uri,
         opts.target_uri,
-        opts.oplog_storage_uri,
-        opts.db,
+        oplog_storage_uri,
+        db,
         opts.colls,
         opts.collection_concurrent,
         opts.doc_concurrent,
-    );
+    )
+    .with_write_policy(opts.write_policy)
+    .with_confirm_drop(opts.confirm_drop)
+    .with_snapshot_read(opts.snapshot_read)
+    .with_removed_coll_policy(opts.removed_coll_policy)
+    .with_oplog_gap_policy(opts.oplog_gap_policy)
+    .with_full_document_updates(opts.full_document_updates)
+    .with_apply_retry(ApplyRetry::new(opts.apply_retries, Duration::from_secs(1)))
+    .with_apply_failure_policy(opts.apply_failure_policy)
+    .with_namespace_mapping(mapping);
+    let conf = with_buffer_opts(conf, opts.batch_docs, opts.batch_bytes, opts.memory_budget);
+    let mut conf = with_coll_patterns(conf, opts.include_coll, opts.exclude_coll);
+    if let Some(dbs) = dbs {
+        conf = conf.with_dbs(dbs);
+    }
+    if let Some(secs) = opts.apply_delay_secs {
+        conf = conf.with_apply_delay(Duration::from_secs(secs));
+    }
+    if let Some(secs) = opts.sample_interval_secs {
+        let docs_per_coll = opts
+            .sample_docs
+            .unwrap_or(SamplingConfig::default().docs_per_coll);
+        let mut sampling = SamplingConfig::new(Duration::from_secs(secs), docs_per_coll);
+        if let Some(alert_rate) = opts.sample_alert_rate {
+            sampling = sampling.with_alert_rate(alert_rate);
+        }
+        conf = conf.with_sampling(sampling);
+    }
+    if let Some(until) = opts.restore_to {
+        let base = match opts.restore_dump {
+            Some(dir) => RestoreBase::Dump {
+                dir: PathBuf::from(dir),
+                start: opts
+                    .restore_dump_start
+                    .map(|start| parse_restore_point(&start))
+                    .transpose()?,
+            },
+            None => RestoreBase::Target,
+        };
+        let mut options = RestoreOptions::new(base, parse_restore_point(&until)?);
+        if let Some(stop_before) = opts.restore_stop_before {
+            options = options.with_stop_before(parse_restore_point(&stop_before)?);
+        }
+        info!(
+            ?options,
+            "Use the following config to restore database: {:?}", conf
+        );
+
+        let syncer = MongoSyncer::new(&conf);
+        let report = syncer.restore(&options)?;
+        println!("{}", report);
+        return Ok(());
+    }
     info!("Use the following config to sync database: {:?}", conf);
 
-    let syncer = MongoSyncer::new(&conf);
-    info!("Begin to sync database.");
-    syncer.sync().unwrap();
-    Ok(())
+    // SIGINT and SIGTERM stop syncing after the in-flight batch, so next sync resumes from check point.
+    let cancel = CancelToken::new();
+    cancel_on_signals(cancel.clone())?;
+    let policy = retry_policy(opts.max_retries, opts.retry_forever, opts.max_backoff_secs);
+    let syncer = MongoSyncer::new(&conf).with_cancel_token(cancel);
+    info!(?policy, "Begin to sync database.");
+    match syncer.sync_supervised(policy) {
+        Err(SyncError::Cancelled) => {
+            info!("Sync database is stopped gracefully, check point is saved. ");
+            Ok(())
+        }
+        result => Ok(result?),
+    }
 }
@@ -1,5 +1,7 @@
 use clap::Clap;
-use mongo_sync::{OplogCleaner, OplogSyncer};
+use mongo_sync::{
+    cancel_on_signals, CancelToken, OplogCleaner, OplogSyncer, RetryPolicy, Supervisor, SyncError,
+};
 use std::path::Path;
 use std::time::Duration;
 use tracing::{error, info};
@@ -16,6 +18,15 @@ struct Opts {
     /// log file path, if not specified, all log information will be output to stdout.
     #[clap(long)]
     log_path: Option<String>,
+    /// how many times oplog sync is run again after transient errors one after another, e.g: network errors.
+    #[clap(long, default_value = "10")]
+    max_retries: u32,
+    /// run oplog sync again after transient errors forever, `--max-retries` is ignored.
+    #[clap(long)]
+    retry_forever: bool,
+    /// max seconds to wait before running oplog sync again, the wait begins from 1 second and doubles.
+    #[clap(long, default_value = "300")]
+    max_backoff_secs: u64,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -33,10 +44,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     collector.with_writer(non_blocking).init();
 
-    let sleep_time = 10;
-    let max_retry_times = 10;
-    let mut retry_times = 0;
-
     // start up cleaner.
     info!("Starting oplog cleaner...");
     let storage_uri = opts.oplog_storage_uri.clone();
@@ -54,19 +61,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         })?;
     info!("Starting oplog cleaner complete...");
 
-    loop {
-        let oplog_syncer: OplogSyncer = OplogSyncer::new(&opts.src_uri, &opts.oplog_storage_uri)?;
-        let res = oplog_syncer.sync_forever();
-        if let Err(e) = res {
-            error!(?e, "Sync oplog error occurred. ");
-        }
-        info!(sleep_time, "Wait for a while.....");
-        std::thread::sleep(std::time::Duration::from_secs(sleep_time));
-        if retry_times >= max_retry_times {
-            info!(%retry_times, "No, retried several times, the server is still down, I'm leaving now.");
-            break Ok(());
-        }
-        retry_times += 1;
+    let max_retries = if opts.retry_forever {
+        None
+    } else {
+        Some(opts.max_retries)
+    };
+    let policy = RetryPolicy::new(max_retries).with_backoff(
+        Duration::from_secs(1),
+        Duration::from_secs(opts.max_backoff_secs),
+    );
+    // SIGINT and SIGTERM stop syncing after batched oplogs are written.
+    let cancel = CancelToken::new();
+    cancel_on_signals(cancel.clone())?;
+    let supervisor = Supervisor::new(policy).with_cancel_token(cancel.clone());
+    // each run reconnects, and resumes from the latest stored oplog.
+    let result = supervisor.run("oplog_syncer", || {
+        OplogSyncer::new(&opts.src_uri, &opts.oplog_storage_uri)?
+            .with_cancel_token(cancel.clone())
+            .sync_forever()
+    });
+    if let Err(SyncError::Cancelled) = result {
+        info!("Sync oplog is stopped gracefully, truncate after point is saved. ");
+        return Ok(());
+    }
+    if let Err(e) = result {
+        let retries = supervisor.stats().snapshot();
+        error!(?e, ?retries, "Sync oplog error occurred, I'm leaving now. ");
+        return Err(e.into());
     }
-    // iterate through syncer configuration, and start new mongo_sync via command line.
+    Ok(())
 }
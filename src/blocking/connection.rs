@@ -1,8 +1,15 @@
+use super::mongo_syncer::checkpoint::CHECKPOINT_COLL;
+use super::mongo_syncer::control::CONTROL_COLL;
+use super::mongo_syncer::dead_letter::DEAD_LETTER_COLL;
+use super::mongo_syncer::repair::REPAIR_FENCE_COLL;
+use super::mongo_syncer::sampler::SAMPLING_STATS_COLL;
 use crate::error::{Result, SyncError};
 use crate::DbSyncConf;
-use crate::{ADMIN_DB_NAME, LOG_STORAGE_COLL, LOG_STORAGE_DB};
-use bson::Document;
+use crate::{ADMIN_DB_NAME, LOG_STORAGE_COLL, LOG_STORAGE_DB, SYNC_META_DB};
+use bson::{doc, Bson, Document};
+use mongodb::options::ClientOptions;
 use mongodb::sync::{Client, Collection, Database};
+use std::collections::HashSet;
 
 #[derive(Clone)]
 /// A simple abstraction for mongodb syncer connection.
@@ -15,49 +22,162 @@ impl<'a> Connection<'a> {
     pub fn new(config: &DbSyncConf) -> Result<Connection> {
         let source_conn = Client::with_uri_str(config.get_src_uri())?;
         let target_conn = Client::with_uri_str(config.get_dst_uri())?;
-        let oplog_storage_conn = Client::with_uri_str(config.get_oplog_storage_uri())?;
+        let oplog_storage_conn = match config.get_oplog_storage_uri() {
+            Some(uri) => Some(Client::with_uri_str(uri)?),
+            None => None,
+        };
         Ok(Connection {
             inner: ConnectionInner {
                 source_conn,
                 target_conn,
                 oplog_storage_conn,
                 config,
+                db: config.get_db().to_string(),
             },
         })
     }
 
+    /// create a connection which syncs database `db`, clients are shared with this connection.
+    pub fn with_db(&self, db: &str) -> Connection<'a> {
+        let mut inner = self.inner.clone();
+        inner.db = db.to_string();
+        Connection { inner }
+    }
+
+    /// get database to sync.
+    pub fn get_db(&self) -> &str {
+        &self.inner.db
+    }
+
     /// Check if we have enough permissions to run sync progress.
     pub fn check_permissions(&self) -> Result<()> {
         self.inner.check_permissions()
     }
 
+    /// Check that we never sync a database onto itself.
+    ///
+    /// Servers are collected from connection string and `isMaster` result, returns
+    /// [SyncError::SameSourceAndTarget] if source and target share any server, unless the database is mapped
+    /// to a differently named target database.
+    pub fn check_source_target_differ(&self) -> Result<()> {
+        let config = self.inner.config;
+        let common = self.common_servers()?;
+        let db = self.get_db();
+        if common.is_empty() || config.get_namespace_mapping().target_db(db) != db {
+            Ok(())
+        } else {
+            Err(SyncError::SameSourceAndTarget {
+                servers: common,
+                db: db.to_string(),
+            })
+        }
+    }
+
+    /// Check if source and target are the same cluster.
+    pub fn is_same_cluster(&self) -> Result<bool> {
+        Ok(!self.common_servers()?.is_empty())
+    }
+
+    /// get sorted servers which are shared by source and target.
+    fn common_servers(&self) -> Result<Vec<String>> {
+        let inner = &self.inner;
+        let src_servers = server_addresses(&inner.source_conn, inner.config.get_src_uri())?;
+        let target_servers = server_addresses(&inner.target_conn, inner.config.get_dst_uri())?;
+        let mut common: Vec<String> = src_servers.intersection(&target_servers).cloned().collect();
+        common.sort();
+        Ok(common)
+    }
+
     /// get database to sync.
     pub fn get_src_db(&self) -> Database {
-        self.inner.source_conn.database(self.inner.config.get_db())
+        self.inner.source_conn.database(&self.inner.db)
     }
 
-    /// get target database to save.
+    /// get target database to save, it's mapped from source database by namespace mapping.
     pub fn get_target_db(&self) -> Database {
-        self.inner.target_conn.database(self.inner.config.get_db())
+        let config = self.inner.config;
+        self.inner
+            .target_conn
+            .database(&config.get_namespace_mapping().target_db(&self.inner.db))
+    }
+
+    /// get target collection of source collection `coll`, it's mapped by namespace mapping.
+    pub fn get_target_coll(&self, coll: &str) -> Collection<Document> {
+        let config = self.inner.config;
+        let (db, coll) = config
+            .get_namespace_mapping()
+            .target_ns(&self.inner.db, coll);
+        self.inner.target_conn.database(&db).collection(&coll)
     }
 
     /// get sync time record collection.
+    ///
+    /// It's saved in target database, or in sync meta database when multiple databases are synced, so all
+    /// databases share one record.
     pub fn time_record_coll(&self) -> Collection<Document> {
-        self.get_target_db()
+        self.sync_state_db()
             .collection(self.inner.config.get_record_collection())
     }
 
+    /// get collection which saves checkpoints of collections, it's saved next to sync time record.
+    pub fn coll_checkpoint_coll(&self) -> Collection<Document> {
+        self.sync_state_db().collection(CHECKPOINT_COLL)
+    }
+
+    /// get collection which receives control commands, it's saved next to sync time record.
+    pub fn control_coll(&self) -> Collection<Document> {
+        self.sync_state_db().collection(CONTROL_COLL)
+    }
+
+    /// get collection which saves oplogs which fail to apply, it's saved next to sync time record.
+    pub fn dead_letter_coll(&self) -> Collection<Document> {
+        self.sync_state_db().collection(DEAD_LETTER_COLL)
+    }
+
+    /// get collection which saves fences of repaired documents, it's saved next to sync time record.
+    pub fn repair_fence_coll(&self) -> Collection<Document> {
+        self.sync_state_db().collection(REPAIR_FENCE_COLL)
+    }
+
+    /// get collection which saves drift statistics of consistency sampling, it's saved next to sync time
+    /// record.
+    pub fn sampling_stats_coll(&self) -> Collection<Document> {
+        self.sync_state_db().collection(SAMPLING_STATS_COLL)
+    }
+
+    /// get database which saves sync state, it's target database, or sync meta database when multiple
+    /// databases are synced.
+    fn sync_state_db(&self) -> Database {
+        match self.inner.config.get_dbs() {
+            Some(_) => self.get_sync_meta_db(),
+            None => self.get_target_db(),
+        }
+    }
+
+    /// get target database which saves sync state of multiple databases.
+    pub fn get_sync_meta_db(&self) -> Database {
+        self.inner.target_conn.database(SYNC_META_DB)
+    }
+
+    /// get source mongodb client.
+    pub fn get_src_client(&self) -> Client {
+        self.inner.source_conn.clone()
+    }
+
     /// get target mongodb client.
     pub fn get_target_client(&self) -> Client {
         self.inner.target_conn.clone()
     }
 
     /// return collection which saves oplog.
-    pub fn oplog_coll(&self) -> Collection<Document> {
+    ///
+    /// Returns [SyncError::OplogStorageMissing] if oplog storage is not configured.
+    pub fn oplog_coll(&self) -> Result<Collection<Document>> {
         self.inner
             .oplog_storage_conn
-            .database(LOG_STORAGE_DB)
-            .collection(LOG_STORAGE_COLL)
+            .as_ref()
+            .map(|conn| conn.database(LOG_STORAGE_DB).collection(LOG_STORAGE_COLL))
+            .ok_or(SyncError::OplogStorageMissing)
     }
 
     /// return colleciton which saves extra admin infor.
@@ -71,18 +191,48 @@ impl<'a> Connection<'a> {
     }
 }
 
+/// get addresses of servers behind `client`, which is connected through `uri`.
+///
+/// It contains hosts in `uri`, and replica set members reported by `isMaster`, addresses are lowercased
+/// `host:port`.
+fn server_addresses(client: &Client, uri: &str) -> Result<HashSet<String>> {
+    let mut servers: HashSet<String> = ClientOptions::parse(uri)?
+        .hosts
+        .iter()
+        .map(|h| h.to_string().to_lowercase())
+        .collect();
+    let is_master = client
+        .database(ADMIN_DB_NAME)
+        .run_command(doc! {"isMaster": 1}, None)?;
+    for key in ["hosts", "passives", "arbiters"] {
+        if let Ok(members) = is_master.get_array(key) {
+            servers.extend(
+                members
+                    .iter()
+                    .filter_map(Bson::as_str)
+                    .map(|h| h.to_lowercase()),
+            );
+        }
+    }
+    if let Ok(me) = is_master.get_str("me") {
+        servers.insert(me.to_lowercase());
+    }
+    Ok(servers)
+}
+
 #[derive(Clone)]
 struct ConnectionInner<'a> {
     source_conn: Client,
     target_conn: Client,
-    oplog_storage_conn: Client,
+    oplog_storage_conn: Option<Client>,
     config: &'a DbSyncConf,
+    db: String,
 }
 
 impl<'a> ConnectionInner<'a> {
     pub fn check_permissions(&self) -> Result<()> {
         // TODO: add admin access check, to ensure that we can execute applylog command.
-        let db_name = self.config.get_db();
+        let db_name = &self.db;
         let source_db = self.source_conn.database(db_name);
         if let Err(e) = source_db.list_collection_names(None) {
             return Err(SyncError::PermissionError {
@@ -92,7 +242,9 @@ impl<'a> ConnectionInner<'a> {
             });
         }
 
-        let target_db = self.target_conn.database(db_name);
+        let target_db = self
+            .target_conn
+            .database(&self.config.get_namespace_mapping().target_db(db_name));
         if let Err(e) = target_db.list_collection_names(None) {
             return Err(SyncError::PermissionError {
                 uri: self.config.get_dst_uri().to_string(),
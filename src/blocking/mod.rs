@@ -4,4 +4,9 @@ mod connection;
 pub mod mongo_syncer;
 
 pub use connection::Connection;
-pub use mongo_syncer::{MongoSyncer, OplogSyncer, OplogCleaner};
+pub use mongo_syncer::{
+    cancel_on_signals, CancelToken, CollProgress, CollSyncReport, CollVerifyReport,
+    FullSyncProgress, MongoSyncer, OplogCleaner, OplogSyncer, ProgressLogger, ProgressSnapshot,
+    RangeProgress, RepairDoc, RestoreBase, RestoreOptions, RestoreReport, RetryCounts, RetryStats,
+    Supervisor, SyncReport, VerifyReport,
+};
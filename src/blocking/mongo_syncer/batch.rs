@@ -0,0 +1,176 @@
+//! Provide byte-size aware batching and process wide memory budget for buffers.
+
+use super::bson_helper::encoded_size;
+use crate::BatchLimits;
+use bson::Document;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// max bytes of a command sent to mongodb, it's 16MB bson limit minus some space for command envelope.
+pub const MAX_COMMAND_BYTES: usize = 16 * 1024 * 1024 - 64 * 1024;
+/// max statements in one write command, which is `maxWriteBatchSize` of mongodb.
+pub const MAX_WRITE_BATCH_COUNT: usize = 100_000;
+/// bytes used by array index key and element type when a document is embedded into a command array.
+const ARRAY_ELEMENT_OVERHEAD: usize = 16;
+
+/// Limits to build a write command from `limits`, which never exceeds mongodb command limits.
+pub fn command_limits(limits: &BatchLimits) -> BatchLimits {
+    BatchLimits {
+        max_count: limits.max_count.min(MAX_WRITE_BATCH_COUNT),
+        max_bytes: limits.max_bytes.min(MAX_COMMAND_BYTES),
+    }
+}
+
+/// Split `docs` into batches, each batch doesn't exceed `limits` unless it contains only one document.
+///
+/// The order of documents is kept.
+pub fn split_by_limits(docs: Vec<Document>, limits: &BatchLimits) -> Vec<Vec<Document>> {
+    let mut batches = vec![];
+    let mut current = vec![];
+    let mut current_bytes = 0;
+    for doc in docs {
+        let size = encoded_size(&doc) + ARRAY_ELEMENT_OVERHEAD;
+        if !current.is_empty()
+            && (current.len() >= limits.max_count || current_bytes + size > limits.max_bytes)
+        {
+            batches.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+        current_bytes += size;
+        current.push(doc);
+    }
+    if !current.is_empty() {
+        batches.push(current);
+    }
+    batches
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    total: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Memory budget shared by all buffers in a process, cloned budgets share the same quota.
+///
+/// Before filling a buffer, workers [acquire](MemoryBudget::acquire) the bytes they will use, and wait if
+/// the budget is exhausted.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+impl MemoryBudget {
+    /// create a budget with `total` bytes.
+    pub fn new(total: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(BudgetInner {
+                total,
+                used: Mutex::new(0),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// acquire `bytes` from the budget, blocks until there is enough quota.
+    ///
+    /// A request larger than the whole budget is shrunk to the whole budget, so it can always be satisfied
+    /// when no one else holds the quota.  The quota is released when the returned permit is dropped.
+    pub fn acquire(&self, bytes: usize) -> MemoryPermit {
+        let bytes = bytes.min(self.inner.total);
+        let mut used = self.inner.used.lock().expect("memory budget lock poisoned");
+        while *used + bytes > self.inner.total {
+            used = self
+                .inner
+                .released
+                .wait(used)
+                .expect("memory budget lock poisoned");
+        }
+        *used += bytes;
+        MemoryPermit {
+            budget: self.clone(),
+            bytes,
+        }
+    }
+
+    /// bytes which are acquired now.
+    pub fn used(&self) -> usize {
+        *self.inner.used.lock().expect("memory budget lock poisoned")
+    }
+}
+
+/// Quota acquired from [MemoryBudget], it's released when dropped.
+#[derive(Debug)]
+pub struct MemoryPermit {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Drop for MemoryPermit {
+    fn drop(&mut self) {
+        let inner = &self.budget.inner;
+        let mut used = inner.used.lock().expect("memory budget lock poisoned");
+        *used -= self.bytes;
+        inner.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bson::doc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_split_by_limits() {
+        let docs: Vec<Document> = (0..5).map(|i| doc! {"a": i}).collect();
+        // split by count.
+        let batches = split_by_limits(docs.clone(), &BatchLimits::new(2, usize::MAX).unwrap());
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        // split by bytes, each document takes 12 + 16 bytes.
+        let batches = split_by_limits(docs.clone(), &BatchLimits::new(100, 60).unwrap());
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        // a document larger than limit is in its own batch.
+        let batches = split_by_limits(docs.clone(), &BatchLimits::new(100, 1).unwrap());
+        assert_eq!(batches.len(), 5);
+        assert_eq!(batches.concat(), docs);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+        let permit = budget.acquire(60);
+        assert_eq!(budget.used(), 60);
+        let (started_tx, started) = mpsc::channel();
+        let (acquired_tx, acquired) = mpsc::channel();
+        let big_permit = {
+            let budget = budget.clone();
+            // blocks until the first permit is released, and it's shrunk to whole budget.
+            std::thread::spawn(move || {
+                started_tx.send(()).unwrap();
+                let permit = budget.acquire(1000);
+                acquired_tx.send(()).unwrap();
+                permit
+            })
+        };
+        started.recv().unwrap();
+        assert_eq!(
+            acquired.recv_timeout(Duration::from_millis(200)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        assert_eq!(budget.used(), 60);
+        drop(permit);
+        acquired.recv().unwrap();
+        let big_permit = big_permit.join().unwrap();
+        assert_eq!(budget.used(), 100);
+        drop(big_permit);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -20,6 +20,18 @@ pub fn get_uuid(doc: &Document, key: &str) -> ValueAccessResult<Uuid> {
     }
 }
 
+/// Get encoded bson size of given `doc`.
+pub fn encoded_size(doc: &Document) -> usize {
+    // serialize a `Document` to bytes never fails.
+    bson::to_vec(doc).map(|v| v.len()).unwrap_or(0)
+}
+
+/// Get a key of `_id` value `id` which can be hashed, it's the encoded bytes of `{"_id": id}`.
+pub fn id_key(id: &Bson) -> Vec<u8> {
+    // serialize a `Document` to bytes never fails.
+    bson::to_vec(&bson::doc! {"_id": id}).unwrap_or_default()
+}
+
 /// Create a new bson::Binary from given `uuid`.
 pub fn new_bson_binary(uuid: Uuid) -> Binary {
     Binary {
@@ -49,6 +61,19 @@ mod test {
         assert!(get_uuid(&doc! {"a": new_bson_binary(test_id)}, "b").is_err());
     }
 
+    #[test]
+    fn test_encoded_size() {
+        // 4 bytes length + 1 byte type + "a\0" + 4 bytes i32 + 1 byte terminator.
+        assert_eq!(encoded_size(&doc! {"a": 1}), 12);
+        assert_eq!(encoded_size(&doc! {}), 5);
+    }
+
+    #[test]
+    fn test_id_key() {
+        assert_eq!(id_key(&Bson::Int32(1)), id_key(&Bson::Int32(1)));
+        assert_ne!(id_key(&Bson::Int32(1)), id_key(&Bson::Int64(1)));
+    }
+
     #[test]
     fn test_get_uuid_when_key_is_not_valid_type() {
         assert!(get_uuid(&doc! {"a": "bbbb"}, "a").is_err());
@@ -0,0 +1,116 @@
+//! Provide cancellation handle, which stops a running syncer gracefully.
+//!
+//! Syncers check the handle between batches: the in-flight batch is finished, check point (or oplog truncate
+//! point) is written, then they return [SyncError::Cancelled](crate::SyncError::Cancelled).  Binaries cancel the
+//! handle on SIGINT and SIGTERM by [cancel_on_signals], a second signal exits the process at once.
+
+use crate::{Result, SyncError};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// how often sleeping syncers check if they are cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle to stop a running syncer, clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// create a handle which is not cancelled.
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// ask syncers which hold the handle to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// return true if the handle is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// return [SyncError::Cancelled] if the handle is cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(SyncError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// sleep `duration`, wake up early and return [SyncError::Cancelled] if the handle is cancelled.
+    pub fn sleep(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // only async-signal-safe functions can be called here.
+    if SIGNALLED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// cancel `token` when the process receives SIGINT or SIGTERM, a second signal exits the process at once.
+pub fn cancel_on_signals(token: CancelToken) -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    std::thread::Builder::new()
+        .name("signal watcher".to_string())
+        .spawn(move || {
+            while !SIGNALLED.load(Ordering::SeqCst) {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            warn!("Received stop signal, stop after the in-flight batch, send it again to exit at once. ");
+            token.cancel();
+            info!("Cancellation is requested. ");
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancel_token() {
+        let token = CancelToken::new();
+        assert!(token.check().is_ok());
+        assert!(token.sleep(Duration::from_millis(10)).is_ok());
+
+        let cloned = token.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cloned.cancel();
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            token.sleep(Duration::from_secs(60)),
+            Err(SyncError::Cancelled)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(token.is_cancelled());
+        canceller.join().unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! Provide per-collection checkpoints, which are persisted in target database.
+//!
+//! Collections follow the global checkpoint in `oplog_records` by default.  A collection which is added to
+//! sync set later has its own checkpoint in `coll_checkpoints` collection, while it's copied in background
+//! and catches up with the global checkpoint:
+//!
+//! ```text
+//! {"_id": "db.coll", "state": "copying", "ts": Timestamp}
+//! {"_id": "db.coll", "state": "catching_up", "ts": Timestamp}
+//! ```
+//!
+//! `ts` is the oplog timestamp which the collection is synced to.  Oplogs of the collection are not applied
+//! by the global oplog stream until it catches up, then the checkpoint is removed.
+
+use crate::Result;
+use bson::{doc, Document, Timestamp};
+use mongodb::options::ReplaceOptions;
+use mongodb::sync::Collection;
+
+/// collection name which saves checkpoints of collections.
+pub const CHECKPOINT_COLL: &str = "coll_checkpoints";
+
+/// Sync state of a collection which has own checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointState {
+    /// documents are being copied, `ts` is the oplog timestamp when copy starts.
+    Copying,
+    /// documents are copied, oplogs after `ts` need to be applied.
+    CatchingUp,
+}
+
+impl CheckpointState {
+    /// get name of the state.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckpointState::Copying => "copying",
+            CheckpointState::CatchingUp => "catching_up",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "copying" => Some(CheckpointState::Copying),
+            "catching_up" => Some(CheckpointState::CatchingUp),
+            _ => None,
+        }
+    }
+}
+
+/// Checkpoint of a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollCheckpoint {
+    /// collection namespace, `db.coll`.
+    pub ns: String,
+    /// sync state of the collection.
+    pub state: CheckpointState,
+    /// oplog timestamp which the collection is synced to.
+    pub ts: Timestamp,
+}
+
+impl CollCheckpoint {
+    /// create checkpoint of collection `coll` in database `db`.
+    pub fn new(db: &str, coll: &str, state: CheckpointState, ts: Timestamp) -> Self {
+        CollCheckpoint {
+            ns: format!("{}.{}", db, coll),
+            state,
+            ts,
+        }
+    }
+
+    /// get database and collection name of the checkpoint.
+    pub fn split_ns(&self) -> (&str, &str) {
+        self.ns
+            .split_once('.')
+            .expect("namespace of checkpoint should be split by '.'")
+    }
+
+    fn to_document(&self) -> Document {
+        doc! {"_id": &self.ns, "state": self.state.as_str(), "ts": self.ts}
+    }
+
+    fn from_document(d: &Document) -> Result<Option<Self>> {
+        let state = match CheckpointState::parse(d.get_str("state")?) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        Ok(Some(CollCheckpoint {
+            ns: d.get_str("_id")?.to_string(),
+            state,
+            ts: d.get_timestamp("ts")?,
+        }))
+    }
+}
+
+/// Checkpoints of collections, which are persisted in target database.
+#[derive(Debug, Clone)]
+pub struct CollCheckpoints {
+    coll: Collection<Document>,
+}
+
+impl CollCheckpoints {
+    /// create checkpoints handler, they are saved in `coll`.
+    pub fn new(coll: Collection<Document>) -> Self {
+        CollCheckpoints { coll }
+    }
+
+    /// get all checkpoints, checkpoints with unknown state are ignored.
+    pub fn list(&self) -> Result<Vec<CollCheckpoint>> {
+        let mut result = vec![];
+        for d in self.coll.find(None, None)? {
+            if let Some(checkpoint) = CollCheckpoint::from_document(&d?)? {
+                result.push(checkpoint);
+            }
+        }
+        Ok(result)
+    }
+
+    /// save `checkpoint`, old checkpoint of the same collection is replaced.
+    pub fn save(&self, checkpoint: &CollCheckpoint) -> Result<()> {
+        self.coll.replace_one(
+            doc! {"_id": &checkpoint.ns},
+            checkpoint.to_document(),
+            ReplaceOptions::builder().upsert(true).build(),
+        )?;
+        Ok(())
+    }
+
+    /// remove checkpoint of collection namespace `ns`, the collection follows the global checkpoint then.
+    pub fn remove(&self, ns: &str) -> Result<()> {
+        self.coll.delete_one(doc! {"_id": ns}, None)?;
+        Ok(())
+    }
+
+    /// remove all checkpoints, it's invoked after full sync complete.
+    pub fn remove_all(&self) -> Result<()> {
+        self.coll.drop(None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_document_round_trip() {
+        let ts = Timestamp {
+            time: 10,
+            increment: 1,
+        };
+        let checkpoint = CollCheckpoint::new("a", "b.c", CheckpointState::CatchingUp, ts);
+        assert_eq!(checkpoint.split_ns(), ("a", "b.c"));
+        assert_eq!(
+            checkpoint.to_document(),
+            doc! {"_id": "a.b.c", "state": "catching_up", "ts": ts}
+        );
+        assert_eq!(
+            CollCheckpoint::from_document(&checkpoint.to_document()).unwrap(),
+            Some(checkpoint)
+        );
+        assert_eq!(
+            CollCheckpoint::from_document(&doc! {"_id": "a.b", "state": "paused", "ts": ts})
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Provide runtime control channel, which is a collection next to sync time record in target database.
+//!
+//! Commands are inserted into `mongo_sync_control` collection, incremental sync polls them between oplog
+//! batches, and handles them in insertion order:
+//!
+//! ```text
+//! {"command": "pause"}
+//! {"command": "resume"}
+//! {"command": "resync", "coll": "users", "db": "app", "confirm": true}
+//! {"command": "add_coll", "coll": "users"}
+//! {"command": "remove_coll", "coll": "users"}
+//! {"command": "fast_forward", "to": Timestamp}
+//! {"command": "repair", "docs": [{"ns": "app.users", "_id": id}]}
+//! {"command": "redrive_dead_letters"}
+//! {"command": "status"}
+//! ```
+//!
+//! `db` is optional, default to all synced databases.  `confirm` is required to drop the target collection
+//! when write policy is `fail-if-non-empty`.  `to` is optional, default to now.  After a command is
+//! handled, it's acknowledged in the same document: `state` is set to `done` with `result`, or `failed` with
+//! `error`, and `acked_at` is set.
+
+use super::incr::UnmatchedStats;
+use super::repair::{FenceSet, RepairDoc};
+use crate::{Result, SyncError};
+use bson::{doc, Bson, DateTime, Document, Timestamp};
+use mongodb::options::FindOptions;
+use mongodb::sync::Collection;
+use std::time::{Duration, Instant};
+
+/// collection name which receives control commands.
+pub const CONTROL_COLL: &str = "mongo_sync_control";
+
+/// Command to control a running syncer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    /// stop applying oplogs until resumed.
+    Pause,
+    /// continue applying oplogs.
+    Resume,
+    /// copy collection `coll` again, of database `db` or all synced databases, `confirm` allows to drop
+    /// target collection under [FullSyncWritePolicy::FailIfNonEmpty](crate::FullSyncWritePolicy).
+    Resync {
+        db: Option<String>,
+        coll: String,
+        confirm: bool,
+    },
+    /// add collection `coll` into sync set.
+    AddColl { coll: String },
+    /// remove collection `coll` from sync set.
+    RemoveColl { coll: String },
+    /// apply oplogs until `to` (or now) regardless of apply delay.
+    FastForward { to: Option<Timestamp> },
+    /// copy documents `docs` from source again.
+    Repair { docs: Vec<RepairDoc> },
+    /// apply dead-lettered oplogs again.
+    RedriveDeadLetters,
+    /// report sync status.
+    Status,
+}
+
+impl ControlCommand {
+    /// parse command from control document `d`.
+    pub fn from_document(d: &Document) -> Result<Self> {
+        let invalid = |detail: String| SyncError::InvalidControlCommand { detail };
+        let coll = || {
+            d.get_str("coll")
+                .map(|c| c.to_string())
+                .map_err(|_| invalid("`coll` field is required".to_string()))
+        };
+        let command = d
+            .get_str("command")
+            .map_err(|_| invalid("`command` field is required".to_string()))?;
+        match command {
+            "pause" => Ok(ControlCommand::Pause),
+            "resume" => Ok(ControlCommand::Resume),
+            "resync" => Ok(ControlCommand::Resync {
+                db: d.get_str("db").ok().map(|db| db.to_string()),
+                coll: coll()?,
+                confirm: d.get_bool("confirm").unwrap_or(false),
+            }),
+            "add_coll" => Ok(ControlCommand::AddColl { coll: coll()? }),
+            "remove_coll" => Ok(ControlCommand::RemoveColl { coll: coll()? }),
+            "fast_forward" => Ok(ControlCommand::FastForward {
+                to: d.get_timestamp("to").ok(),
+            }),
+            "repair" => {
+                let docs = d
+                    .get_array("docs")
+                    .map_err(|_| invalid("`docs` field is required".to_string()))?;
+                let docs = docs
+                    .iter()
+                    .map(|one| match one {
+                        Bson::Document(one) => RepairDoc::from_document(one),
+                        _ => Err(invalid(format!("{} is not a document to repair", one))),
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(ControlCommand::Repair { docs })
+            }
+            "redrive_dead_letters" => Ok(ControlCommand::RedriveDeadLetters),
+            "status" => Ok(ControlCommand::Status),
+            _ => Err(invalid(format!("unknown command {:?}", command))),
+        }
+    }
+}
+
+/// State of incremental sync which is changed by control commands.
+#[derive(Debug, Clone, Default)]
+pub struct ControlState {
+    /// stop applying oplogs until resumed.
+    pub paused: bool,
+    /// apply oplogs until the timestamp regardless of apply delay, it's saved next to check point.
+    pub fast_forward_to: Option<Timestamp>,
+    /// fences of repaired documents, which are not released yet.
+    pub fences: FenceSet,
+    /// how often operator updates match no target document.
+    pub unmatched: UnmatchedStats,
+    /// how many oplogs are saved into dead letters since incremental sync starts.
+    pub dead_lettered: u64,
+}
+
+/// Control channel, which is persisted in target database.
+#[derive(Debug, Clone)]
+pub struct ControlChannel {
+    coll: Collection<Document>,
+}
+
+impl ControlChannel {
+    /// create control channel, commands are received from `coll`.
+    pub fn new(coll: Collection<Document>) -> Self {
+        ControlChannel { coll }
+    }
+
+    /// get command documents which are not acknowledged yet, in insertion order.
+    pub fn pending(&self) -> Result<Vec<Document>> {
+        let mut result = vec![];
+        for d in self.coll.find(
+            doc! {"state": {"$exists": false}},
+            FindOptions::builder().sort(doc! {"_id": 1}).build(),
+        )? {
+            result.push(d?);
+        }
+        Ok(result)
+    }
+
+    /// submit `command` document, returns its `_id`.
+    pub fn submit(&self, command: Document) -> Result<Bson> {
+        Ok(self.coll.insert_one(command, None)?.inserted_id)
+    }
+
+    /// wait until command `id` is acknowledged, returns the acknowledged document, or None if it's not
+    /// acknowledged in `timeout`.
+    pub fn wait_ack(&self, id: &Bson, timeout: Duration) -> Result<Option<Document>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let acked = self
+                .coll
+                .find_one(doc! {"_id": id, "state": {"$exists": true}}, None)?;
+            if acked.is_some() || Instant::now() >= deadline {
+                return Ok(acked);
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// acknowledge command document `command` with handled `result`.
+    pub fn ack(&self, command: &Document, result: &Result<Document>) -> Result<()> {
+        let ack = match result {
+            Ok(result) => doc! {"state": "done", "result": result, "acked_at": DateTime::now()},
+            Err(e) => doc! {"state": "failed", "error": e.to_string(), "acked_at": DateTime::now()},
+        };
+        self.coll
+            .update_one(doc! {"_id": command.get("_id")}, doc! {"$set": ack}, None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_control_command() {
+        assert_eq!(
+            ControlCommand::from_document(&doc! {"command": "pause"}).unwrap(),
+            ControlCommand::Pause
+        );
+        assert_eq!(
+            ControlCommand::from_document(&doc! {"command": "resync", "coll": "b"}).unwrap(),
+            ControlCommand::Resync {
+                db: None,
+                coll: "b".to_string(),
+                confirm: false
+            }
+        );
+        assert_eq!(
+            ControlCommand::from_document(
+                &doc! {"command": "resync", "db": "a", "coll": "b", "confirm": true}
+            )
+            .unwrap(),
+            ControlCommand::Resync {
+                db: Some("a".to_string()),
+                coll: "b".to_string(),
+                confirm: true
+            }
+        );
+        assert_eq!(
+            ControlCommand::from_document(&doc! {"command": "add_coll", "coll": "b"}).unwrap(),
+            ControlCommand::AddColl {
+                coll: "b".to_string()
+            }
+        );
+        let to = Timestamp {
+            time: 10,
+            increment: 0,
+        };
+        assert_eq!(
+            ControlCommand::from_document(&doc! {"command": "fast_forward", "to": to}).unwrap(),
+            ControlCommand::FastForward { to: Some(to) }
+        );
+        assert_eq!(
+            ControlCommand::from_document(&doc! {"command": "fast_forward"}).unwrap(),
+            ControlCommand::FastForward { to: None }
+        );
+        assert_eq!(
+            ControlCommand::from_document(
+                &doc! {"command": "repair", "docs": [{"ns": "a.b", "_id": 1}]}
+            )
+            .unwrap(),
+            ControlCommand::Repair {
+                docs: vec![RepairDoc::new("a.b".to_string(), Bson::Int32(1))]
+            }
+        );
+        assert!(ControlCommand::from_document(&doc! {"command": "repair", "docs": [1]}).is_err());
+        assert_eq!(
+            ControlCommand::from_document(&doc! {"command": "redrive_dead_letters"}).unwrap(),
+            ControlCommand::RedriveDeadLetters
+        );
+        assert!(ControlCommand::from_document(&doc! {"command": "remove_coll"}).is_err());
+        assert!(ControlCommand::from_document(&doc! {"command": "restart"}).is_err());
+        assert!(ControlCommand::from_document(&doc! {"coll": "b"}).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Provide dead-letter handling of oplogs which still fail to apply after retries.
+//!
+//! When a batch keeps failing, its oplogs are applied one by one, and an oplog which fails with write errors
+//! is saved in `dead_letters` collection next to check point, other oplogs are applied as usual:
+//!
+//! ```text
+//! {"ts": Timestamp, "ns": "db.coll", "op": "i", "oplog": {...}, "error": "...", "write_errors": [...],
+//!  "attempts": 1, "created_at": DateTime}
+//! ```
+//!
+//! `oplog` is saved as it's applied, after collection filters and full document rewrite, namespace is not
+//! mapped yet.  Entries are applied again by `redrive_dead_letters` control command in oplog order, and removed
+//! after they are applied.
+
+use crate::{Result, SyncError, NAMESPACE_KEY, OP_KEY, TIMESTAMP_KEY};
+use bson::{doc, Bson, DateTime, Document};
+use mongodb::options::FindOptions;
+use mongodb::sync::Collection;
+
+/// collection name which saves oplogs which fail to apply.
+pub const DEAD_LETTER_COLL: &str = "dead_letters";
+
+/// Dead-lettered oplogs, which are persisted in target database.
+#[derive(Debug, Clone)]
+pub struct DeadLetters {
+    coll: Collection<Document>,
+}
+
+impl DeadLetters {
+    /// create dead-letter handler, oplogs are saved in `coll`.
+    pub fn new(coll: Collection<Document>) -> Self {
+        DeadLetters { coll }
+    }
+
+    /// save `oplog` which fails to apply with `error`.
+    pub fn save(&self, oplog: &Document, error: &SyncError) -> Result<()> {
+        let mut entry = doc! {
+            TIMESTAMP_KEY: oplog.get(TIMESTAMP_KEY),
+            NAMESPACE_KEY: oplog.get(NAMESPACE_KEY),
+            OP_KEY: oplog.get(OP_KEY),
+            "oplog": oplog,
+            "attempts": 1,
+            "created_at": DateTime::now(),
+        };
+        entry.extend(error_fields(error));
+        self.coll.insert_one(entry, None)?;
+        Ok(())
+    }
+
+    /// get dead-lettered entries in oplog order.
+    pub fn list(&self) -> Result<Vec<Document>> {
+        let mut result = vec![];
+        for d in self.coll.find(
+            None,
+            FindOptions::builder()
+                .sort(doc! {TIMESTAMP_KEY: 1, "_id": 1})
+                .build(),
+        )? {
+            result.push(d?);
+        }
+        Ok(result)
+    }
+
+    /// remove entry `id` which is applied.
+    pub fn remove(&self, id: &Bson) -> Result<()> {
+        self.coll.delete_one(doc! {"_id": id}, None)?;
+        Ok(())
+    }
+
+    /// record that entry `id` fails to apply again with `error`.
+    pub fn record_failure(&self, id: &Bson, error: &SyncError) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": id},
+            doc! {"$set": error_fields(error), "$inc": {"attempts": 1}},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// get how many entries are not applied yet.
+    pub fn count(&self) -> Result<u64> {
+        Ok(self.coll.count_documents(None, None)?)
+    }
+}
+
+// get fields which describe `error`, write errors are kept because they tell which statement fails.
+fn error_fields(error: &SyncError) -> Document {
+    let write_errors = match error {
+        SyncError::ApplyOplogError(result) => result.get("writeErrors").cloned(),
+        _ => None,
+    };
+    doc! {"error": error.to_string(), "write_errors": write_errors}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_fields() {
+        let write_errors = bson::bson!([{"index": 0, "code": 11000, "errmsg": "duplicate key"}]);
+        let error = SyncError::ApplyOplogError(doc! {"n": 0, "writeErrors": write_errors.clone()});
+        let fields = error_fields(&error);
+        assert_eq!(fields.get("write_errors"), Some(&write_errors));
+
+        let fields = error_fields(&SyncError::EmptyDocError);
+        assert_eq!(fields.get("write_errors"), Some(&Bson::Null));
+        assert_eq!(
+            fields.get_str("error").unwrap(),
+            SyncError::EmptyDocError.to_string()
+        );
+    }
+}
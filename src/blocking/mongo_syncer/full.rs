@@ -1,179 +1,474 @@
+use super::batch::MemoryBudget;
+use super::bson_helper::encoded_size;
+use super::cancel::CancelToken;
+use super::plan::{FullSyncPlan, RangeState};
+use super::progress::FullSyncProgress;
+use super::snapshot::{SnapshotCursor, SnapshotRead};
+use super::splitter::{
+    compare_ids, get_id_bounds, later_id_types, same_type_class, RangeScheduler, RangeTask,
+};
 use crate::error::{Result, SyncError};
-use bson::doc;
-use bson::oid::ObjectId;
-use bson::Document;
+use crate::{BatchLimits, CollFilter};
+use bson::{doc, Bson, Document};
 use crossbeam::channel;
-use mongodb::options::{FindOneOptions, FindOptions};
-use mongodb::sync::Collection;
+use mongodb::error::{BulkWriteFailure, ErrorKind};
+use mongodb::options::{FindOptions, InsertManyOptions, ReplaceOptions};
+use mongodb::sync::{Collection, Database};
 use rayon::ThreadPool;
+use std::cmp::Ordering;
+use std::ops::AddAssign;
 use std::sync::Arc;
 use tracing::info;
 
 /// Internal message which is used by [sync_one_concurrent].
 pub enum SyncTableStatus {
-    /// sync part of table success.
-    Done,
+    /// sync part of table success, along with how many data is copied.
+    Done(CopyStats),
     /// sync part of table failed, along with error message.
     Failed(SyncError),
 }
 
+/// How many documents and bytes are copied during full sync.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CopyStats {
+    /// documents copied.
+    pub docs: u64,
+    /// encoded bson bytes copied.
+    pub bytes: u64,
+}
+
+impl AddAssign for CopyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.docs += other.docs;
+        self.bytes += other.bytes;
+    }
+}
+
+/// mongodb error code when the namespace already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+/// Create collection `target_coll_name` in `target_db` with the same options of `coll_name` in `source_db`.
+///
+/// It does nothing when the collection doesn't have any options, because target collection will be created
+/// automatically during insertion.
+pub fn create_coll_with_options(
+    source_db: &Database,
+    coll_name: &str,
+    target_db: &Database,
+    target_coll_name: &str,
+) -> Result<()> {
+    let result = source_db.run_command(
+        doc! {"listCollections": 1, "filter": {"name": coll_name}},
+        None,
+    )?;
+    let colls = result.get_document("cursor")?.get_array("firstBatch")?;
+    let options = match colls.first().and_then(|c| c.as_document()) {
+        Some(coll_info) => coll_info.get_document("options")?.clone(),
+        None => return Ok(()),
+    };
+    if options.is_empty() {
+        return Ok(());
+    }
+
+    let mut create_cmd = doc! {"create": target_coll_name};
+    create_cmd.extend(options);
+    match target_db.run_command(create_cmd, None) {
+        Ok(_) => Ok(()),
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::Command(err) if err.code == NAMESPACE_EXISTS => Ok(()),
+            _ => Err(SyncError::from(e)),
+        },
+    }
+}
+
+/// Options to copy a collection in full sync.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// read documents at the snapshot cluster time, None means read live data.
+    pub snapshot: Option<SnapshotRead>,
+    /// save copy progress into the plan, so the copy can be resumed after restart.
+    pub plan: Option<FullSyncPlan>,
+    /// replace documents which already exist in target collection, instead of inserting them.
+    pub upsert: bool,
+    /// record copied documents and bytes into the tracker.
+    pub progress: Option<FullSyncProgress>,
+    /// documents and bytes limits of one batch.
+    pub limits: BatchLimits,
+    /// acquire buffer memory from the budget before reading a batch.
+    pub budget: Option<MemoryBudget>,
+    /// only copy documents and fields selected by the collection filter.
+    pub coll_filter: Option<CollFilter>,
+    /// stop copying after the in-flight batch is saved when it's cancelled.
+    pub cancel: Option<CancelToken>,
+}
+
+/// mongodb error code: DuplicateKey.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// how many documents are fetched from server in one round trip.
+const FETCH_SIZE: usize = 10000;
+/// how many `_id` samples are taken for each range when splitting collection.
+const SAMPLES_PER_RANGE: usize = 20;
+
+/// Find documents which match `filter` in `coll`, documents are sorted by `_id`, and fetched `batch_size`
+/// documents in one round trip.
+///
+/// If `coll_filter` is given, only documents which match both filters are returned, and they are projected.
+/// If `snapshot` is given, documents are read at the snapshot cluster time.
+fn find_docs(
+    coll: &Collection<Document>,
+    filter: Option<Document>,
+    coll_filter: &Option<CollFilter>,
+    snapshot: &Option<SnapshotRead>,
+    batch_size: usize,
+) -> Result<Box<dyn Iterator<Item = Result<Document>>>> {
+    let (filter, projection) = match coll_filter {
+        Some(f) => {
+            let filter = match filter {
+                Some(filter) => doc! {"$and": [filter, f.filter.clone()]},
+                None => f.filter.clone(),
+            };
+            (Some(filter), f.projection.clone())
+        }
+        None => (filter, None),
+    };
+    match snapshot {
+        Some(snapshot) => Ok(Box::new(SnapshotCursor::new(
+            &snapshot.client,
+            coll,
+            filter.unwrap_or_default(),
+            projection,
+            snapshot.at,
+            batch_size.min(FETCH_SIZE) as i32,
+            None,
+        )?)),
+        None => {
+            let cursor = coll.find(
+                filter,
+                FindOptions::builder()
+                    .sort(doc! {"_id": 1})
+                    .projection(projection)
+                    .batch_size(batch_size.min(FETCH_SIZE) as u32)
+                    .build(),
+            )?;
+            Ok(Box::new(cursor.map(|d| d.map_err(SyncError::from))))
+        }
+    }
+}
+
+/// Write `docs` into `target_coll`.
+///
+/// When `upsert` is true, documents with the same `_id` in `target_coll` will be replaced, so it's safe to
+/// write the same documents again.  Documents are inserted first, and only those which already exist are
+/// replaced one by one, so a failed write never removes target documents.
+pub fn write_docs(
+    target_coll: &Collection<Document>,
+    docs: Vec<Document>,
+    upsert: bool,
+) -> Result<()> {
+    if !upsert {
+        target_coll.insert_many(docs, None)?;
+        return Ok(());
+    }
+    let options = InsertManyOptions::builder().ordered(false).build();
+    let existing: Vec<usize> = match target_coll.insert_many(&docs, options) {
+        Ok(_) => return Ok(()),
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::BulkWrite(BulkWriteFailure {
+                write_errors: Some(errors),
+                write_concern_error: None,
+                ..
+            }) if errors.iter().all(|e| e.code == DUPLICATE_KEY) => {
+                errors.iter().map(|e| e.index).collect()
+            }
+            _ => return Err(e.into()),
+        },
+    };
+    // duplicate key on a unique index other than `_id` fails again here, and is returned.
+    for idx in existing {
+        let doc = &docs[idx];
+        target_coll.replace_one(
+            doc! {"_id": doc.get("_id")},
+            doc,
+            ReplaceOptions::builder().upsert(true).build(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Copy documents of the range `task` from `source_coll` to `target_coll`.
+///
+/// Documents are read by one `_id` sorted cursor batch by batch, the range can be split by [RangeScheduler]
+/// during copying, documents beyond the shrunk bound are left to the new range.  A batch is limited by
+/// `options.limits`, and its memory is acquired from `options.budget`.  The progress will be saved to the plan in
+/// `options`, so a restarted copy continues after the `last_id` of the range.
+fn copy_range(
+    source_coll: &Collection<Document>,
+    target_coll: &Collection<Document>,
+    task: &RangeTask,
+    options: &CopyOptions,
+) -> Result<CopyStats> {
+    let mut stats = CopyStats::default();
+    let coll_name = source_coll.name();
+    // progress is tracked by namespace, because collections of multiple databases may be copied together.
+    let progress_name = source_coll.namespace().to_string();
+
+    let limits = &options.limits;
+    let state = task.lock().clone();
+    let mut cursor = find_docs(
+        source_coll,
+        remaining_filter(source_coll, &state)?,
+        &options.coll_filter,
+        &options.snapshot,
+        limits.max_count,
+    )?;
+    loop {
+        let _permit = options.budget.as_ref().map(|b| b.acquire(limits.max_bytes));
+        let mut docs = Vec::new();
+        let mut sizes = Vec::new();
+        let mut batch_bytes = 0;
+        let mut exhausted = false;
+        while docs.len() < limits.max_count && batch_bytes < limits.max_bytes {
+            let doc = match cursor.next() {
+                Some(doc) => doc?,
+                None => {
+                    exhausted = true;
+                    break;
+                }
+            };
+            let size = encoded_size(&doc);
+            batch_bytes += size;
+            docs.push(doc);
+            sizes.push(size);
+        }
+
+        // hold the lock until progress is saved, so the range can't be split during writing.
+        let mut range = task.lock();
+        // the range may be shrunk by split after reading, documents beyond the new bound belong to another range.
+        let mut truncated = false;
+        if let Some(max) = &range.max {
+            let beyond_max = docs.iter().position(|d| {
+                d.get("_id")
+                    .and_then(|id| compare_ids(id, max))
+                    .map(|o| o != Ordering::Less)
+                    .unwrap_or(false)
+            });
+            if let Some(pos) = beyond_max {
+                docs.truncate(pos);
+                sizes.truncate(pos);
+                truncated = true;
+            }
+        }
+
+        if let Some(last_id) = docs.last().and_then(|d| d.get("_id")).cloned() {
+            let batch_stats = CopyStats {
+                docs: docs.len() as u64,
+                bytes: sizes.iter().map(|s| *s as u64).sum(),
+            };
+            write_docs(target_coll, docs, options.upsert)?;
+            if let Some(plan) = &options.plan {
+                plan.save_progress(coll_name, task.idx, &last_id)?;
+            }
+            range.last_id = Some(last_id);
+            stats += batch_stats;
+            if let Some(progress) = &options.progress {
+                progress.add_copied(&progress_name, task.idx, batch_stats);
+            }
+        }
+        if truncated || exhausted {
+            break;
+        }
+        if let Some(cancel) = &options.cancel {
+            cancel.check()?;
+        }
+    }
+
+    if let Some(plan) = &options.plan {
+        plan.finish_range(coll_name, task.idx)?;
+    }
+    if let Some(progress) = &options.progress {
+        progress.finish_range(&progress_name, task.idx);
+    }
+    Ok(stats)
+}
+
+/// Build filter of documents in range `state` which are not copied yet.
+///
+/// Range query only matches `_id`s in the type bracket of its bound.  When the collection contains `_id`s of
+/// other types than `last_id`, documents whose `_id` types sort after `last_id` are selected by type as well.
+fn remaining_filter(
+    source_coll: &Collection<Document>,
+    state: &RangeState,
+) -> Result<Option<Document>> {
+    // bounded ranges are only planned for collections with one `_id` type.
+    let last_id = match (&state.last_id, &state.max) {
+        (Some(last_id), None) => last_id,
+        _ => return Ok(state.remaining_filter()),
+    };
+    if let Some((min_id, _)) = get_id_bounds(source_coll)? {
+        if same_type_class(&min_id, last_id) {
+            return Ok(state.remaining_filter());
+        }
+    }
+    match later_id_types(last_id) {
+        Some(types) => Ok(Some(doc! {
+            "$or": [{"_id": {"$gt": last_id}}, {"_id": {"$type": types}}]
+        })),
+        // the type of `last_id` is unknown, copy the range again, documents are upserted when resuming.
+        None => Ok(RangeState::new(state.min.clone(), None).remaining_filter()),
+    }
+}
+
+/// Get ranges of collection `coll_name` from the plan in `options`, if the plan doesn't contains ranges of
+/// the collection, ranges are created by `make_ranges` and saved into the plan.
+fn load_or_plan_ranges(
+    coll_name: &str,
+    options: &CopyOptions,
+    make_ranges: impl FnOnce() -> Result<Vec<RangeState>>,
+) -> Result<Vec<RangeState>> {
+    if let Some(plan) = &options.plan {
+        if let Some(state) = plan.coll_state(coll_name)? {
+            if !state.ranges.is_empty() {
+                info!(collection_name=%coll_name, "Full state: resume collection from plan. ");
+                return Ok(state.ranges);
+            }
+        }
+    }
+    let ranges = make_ranges()?;
+    if let Some(plan) = &options.plan {
+        plan.save_ranges(coll_name, &ranges)?;
+    }
+    Ok(ranges)
+}
+
 /// Sync one collection from `source_coll` to `target_coll` concurrently.
 ///
 /// During sync progress, new threads will be allocated by `pool`, and there will be max to `doc_concurrent` threads.
+/// When a thread finishes its range, it helps the lagging ones by splitting their ranges.
 pub fn sync_one_concurrent(
     source_coll: Collection<Document>,
     target_coll: Collection<Document>,
     doc_concurrent: usize,
     pool: Arc<ThreadPool>,
-) -> Result<()> {
+    options: CopyOptions,
+) -> Result<CopyStats> {
     info!(collection_name=%source_coll.name(), "Full state: Begin to sync collection concurrently. ");
-    // get ObjectId range for each threads.
-    let id_ranges: Vec<(ObjectId, ObjectId)> = split_ids(&source_coll, doc_concurrent)?;
-    let buf_size = 10000;
+    let ranges = load_or_plan_ranges(source_coll.name(), &options, || {
+        split_ids(&source_coll, doc_concurrent)
+    })?;
+    let scheduler = Arc::new(RangeScheduler::new(
+        source_coll.clone(),
+        options.plan.clone(),
+        ranges,
+    )?);
     let (sender, receiver) = channel::bounded(doc_concurrent);
 
-    for (id_min, id_max) in id_ranges {
+    for _ in 0..doc_concurrent {
         let source_coll = source_coll.clone();
         let target_coll = target_coll.clone();
         let sender = sender.clone();
+        let options = options.clone();
+        let scheduler = scheduler.clone();
         pool.spawn(move || {
-            let mut buffer = Vec::with_capacity(buf_size);
-            let res = source_coll
-                .find(
-                    Some(doc! {"_id": {"$gte": id_min, "$lte": id_max}}),
-                    FindOptions::builder().batch_size(10000).build(),
-                )
-                .and_then(|cursor| {
-                    for doc in cursor {
-                        buffer.push(doc.unwrap());
-                        if buffer.len() == buf_size {
-                            let mut data_to_write = Vec::with_capacity(buf_size);
-                            data_to_write.append(&mut buffer);
-                            target_coll.insert_many(data_to_write, None)?;
-                        }
-                    }
-                    if !buffer.is_empty() {
-                        target_coll.insert_many(buffer, None)?;
-                    }
-
-                    Ok(())
-                });
-
-            match res {
-                Err(e) => {
-                    let _ = sender.send(SyncTableStatus::Failed(SyncError::MongoError {
-                        source: e,
-                        backtrace: std::backtrace::Backtrace::capture(),
-                    }));
-                }
-                Ok(_) => {
-                    let _ = sender.send(SyncTableStatus::Done);
+            let mut stats = CopyStats::default();
+            let status = loop {
+                let task = match scheduler.next_task() {
+                    Ok(Some(task)) => task,
+                    Ok(None) => break SyncTableStatus::Done(stats),
+                    Err(e) => break SyncTableStatus::Failed(e),
+                };
+                let result = copy_range(&source_coll, &target_coll, &task, &options);
+                scheduler.finish_task(&task);
+                match result {
+                    Ok(range_stats) => stats += range_stats,
+                    Err(e) => break SyncTableStatus::Failed(e),
                 }
             };
+            let _ = sender.send(status);
         })
     }
 
     let mut count = 0;
-    while let Ok(event) = receiver.recv() {
-        match event {
+    let mut total_stats = CopyStats::default();
+    while count < doc_concurrent {
+        match receiver.recv()? {
             SyncTableStatus::Failed(e) => return Err(e),
-            SyncTableStatus::Done => {
+            SyncTableStatus::Done(stats) => {
+                total_stats += stats;
                 count += 1;
-                if count == doc_concurrent {
-                    break;
-                }
             }
         }
     }
-    info!(collection_name=%source_coll.name(), "Full state: Finish sync collection concurrently. ");
-    Ok(())
+    info!(collection_name=%source_coll.name(), docs=total_stats.docs, bytes=total_stats.bytes, "Full state: Finish sync collection concurrently. ");
+    Ok(total_stats)
 }
 
-/// split collection into a list of ObjectId range pair.
-pub fn split_ids(
-    coll: &Collection<Document>,
-    doc_concurrent: usize,
-) -> Result<Vec<(ObjectId, ObjectId)>> {
-    let count = coll.count_documents(None, None)? as usize;
-
-    let mut id_ranges: Vec<(ObjectId, ObjectId)> = Vec::with_capacity(doc_concurrent);
-    let docs_per_worker = count / doc_concurrent;
-    let null_id_bytes = [0; 12];
-    for i in 0..doc_concurrent - 1 {
-        // We can assume that the doc is always exists.
-        let mut min_id = coll
-            .find_one(
-                None,
-                FindOneOptions::builder()
-                    .sort(doc! {"_id": 1})
-                    .skip(Some((i * docs_per_worker) as u64))
-                    .build(),
-            )?
-            .unwrap();
-        let mut max_id = coll
-            .find_one(
-                None,
-                FindOneOptions::builder()
-                    .sort(doc! {"_id": 1})
-                    .skip(Some(((i + 1) * docs_per_worker) as u64 - 1))
-                    .build(),
-            )?
-            .unwrap();
-
-        let min_id = min_id.get_object_id_mut("_id").unwrap();
-        let min_id = std::mem::replace(min_id, ObjectId::from_bytes(null_id_bytes));
-        let max_id = max_id.get_object_id_mut("_id").unwrap();
-        let max_id = std::mem::replace(max_id, ObjectId::from_bytes(null_id_bytes));
-        id_ranges.push((min_id, max_id));
+/// split collection into at most `doc_concurrent` `_id` ranges.
+///
+/// Range bounds are the quantiles of `_id` samples taken by `$sample`, so it's fast even for large
+/// collections.  The first range and the last range are unbounded, so documents which are inserted after
+/// splitting are not missed.
+pub fn split_ids(coll: &Collection<Document>, doc_concurrent: usize) -> Result<Vec<RangeState>> {
+    let unbounded = vec![RangeState::new(None, None)];
+    if doc_concurrent <= 1 {
+        return Ok(unbounded);
+    }
+    // range query only matches values of the same type, so collection with mixed `_id` types can't be split.
+    if get_id_bounds(coll)?.is_none() {
+        info!(collection_name=%coll.name(), "Full state: collection is empty or has mixed _id types, don't split it. ");
+        return Ok(unbounded);
     }
 
-    // last worker get remain ids.
-    let mut last_min_id = coll
-        .find_one(
+    let count = coll.estimated_document_count(None)? as usize;
+    let sample_size = count.min(doc_concurrent * SAMPLES_PER_RANGE).max(1);
+    let samples: Vec<Bson> = coll
+        .aggregate(
+            vec![
+                doc! {"$sample": {"size": sample_size as i64}},
+                doc! {"$project": {"_id": 1}},
+                doc! {"$sort": {"_id": 1}},
+            ],
             None,
-            FindOneOptions::builder()
-                .sort(doc! {"_id": 1})
-                .skip(Some(((doc_concurrent - 1) * docs_per_worker) as u64))
-                .build(),
         )?
-        .unwrap();
-    let last_min_id = last_min_id.get_object_id_mut("_id").unwrap();
-    let last_min_id = std::mem::replace(last_min_id, ObjectId::from_bytes(null_id_bytes));
-    let mut last_max_id = coll
-        .find_one(
-            None,
-            FindOneOptions::builder().sort(doc! {"_id": -1}).build(),
-        )?
-        .unwrap();
-    let last_max_id = last_max_id.get_object_id_mut("_id").unwrap();
-    let last_max_id = std::mem::replace(last_max_id, ObjectId::from_bytes(null_id_bytes));
-    id_ranges.push((last_min_id, last_max_id));
-    Ok(id_ranges)
+        .filter_map(|d| d.ok().and_then(|mut d| d.remove("_id")))
+        .collect();
+
+    let mut bounds: Vec<Bson> = Vec::with_capacity(doc_concurrent);
+    for i in 1..doc_concurrent {
+        if let Some(bound) = samples.get(i * samples.len() / doc_concurrent) {
+            if bounds.last() != Some(bound) {
+                bounds.push(bound.clone());
+            }
+        }
+    }
+
+    let mut ranges = Vec::with_capacity(bounds.len() + 1);
+    let mut min = None;
+    for bound in bounds {
+        ranges.push(RangeState::new(min, Some(bound.clone())));
+        min = Some(bound);
+    }
+    ranges.push(RangeState::new(min, None));
+    Ok(ranges)
 }
 
 /// Synchronize mongodb collection from `source_coll` to `target_coll` serial.
 pub fn sync_one_serial(
     source_coll: Collection<Document>,
     target_coll: Collection<Document>,
-) -> Result<()> {
-    info!(collection_name=%source_coll.name(), "Full state: Finish sync collection serial. ");
-    let buf_size = 10000;
-    let mut buffer = Vec::with_capacity(buf_size);
-    let cursor = source_coll
-        .find(None, FindOptions::builder().batch_size(10000).build())
-        .unwrap();
-    for doc in cursor {
-        buffer.push(doc.unwrap());
-        if buffer.len() == buf_size {
-            let mut data_to_write = Vec::with_capacity(buf_size);
-            data_to_write.append(&mut buffer);
-            target_coll.insert_many(data_to_write, None)?;
+    options: CopyOptions,
+) -> Result<CopyStats> {
+    info!(collection_name=%source_coll.name(), "Full state: Begin to sync collection serial. ");
+    let ranges = load_or_plan_ranges(source_coll.name(), &options, || {
+        Ok(vec![RangeState::new(None, None)])
+    })?;
+    let mut stats = CopyStats::default();
+    for (idx, range) in ranges.into_iter().enumerate() {
+        if !range.done {
+            let task = RangeTask::new(idx, range);
+            stats += copy_range(&source_coll, &target_coll, &task, &options)?;
         }
     }
-
-    if !buffer.is_empty() {
-        target_coll.insert_many(buffer, None)?;
-    }
-    info!(collection_name=%source_coll.name(), "Full state: Finish sync collection serial. ");
-    Ok(())
+    info!(collection_name=%source_coll.name(), docs=stats.docs, bytes=stats.bytes, "Full state: Finish sync collection serial. ");
+    Ok(stats)
 }
@@ -1,23 +1,37 @@
 //! Provide IncrDumper in incremental sync process.
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
 
-use bson::{doc, Document, Timestamp};
+use bson::{doc, Bson, Document, Timestamp};
 use mongodb::sync::Client as MongoClient;
-use tracing::info;
+use tracing::{error, info, warn};
 
-use crate::{Result, SyncError, COMMAND_OP, OP_KEY, TIMESTAMP_KEY};
+use crate::{
+    ApplyRetry, BatchLimits, NamespaceMapping, Result, SyncError, COMMAND_OP, NAMESPACE_KEY,
+    OP_KEY, TIMESTAMP_KEY,
+};
 
-use super::oplog_bulk::execute_normal_oplogs;
+use super::bson_helper::id_key;
+use super::dead_letter::DeadLetters;
+use super::oplog_bulk::execute_normal_oplogs_with_mapping;
+use super::oplog_filter::OplogFilter;
+use super::repair::RepairDoc;
 use crate::cmd_oplog::CmdOplog;
 
 const BATCH_SIZE: usize = 10000;
+/// max `_id`s in one `find` command when fetching documents from source.
+const FETCH_BATCH_SIZE: usize = 1000;
 
-fn apply_command_log(cmd_log: Document, mongo_conn: &MongoClient) -> Result<()> {
+fn apply_command_log(
+    cmd_log: Document,
+    mongo_conn: &MongoClient,
+    mapping: &NamespaceMapping,
+) -> Result<()> {
     let cmd_oplog = CmdOplog::from_oplog_doc(&cmd_log)?;
     if let Some(l) = cmd_oplog {
         info!(?l, "begin to apply command oplog...");
-        l.apply(mongo_conn)?;
+        l.apply_with_mapping(mongo_conn, mapping)?;
     }
     Ok(())
 }
@@ -34,6 +48,106 @@ fn _execute_apply_ops_cmd(oplogs: &mut Vec<Document>, mongo_conn: &MongoClient)
     Ok(())
 }
 
+// fetch current documents of `ids_by_ns` from `source`, returns documents which still exist with their
+// namespaces.
+fn fetch_source_docs(
+    source: &MongoClient,
+    ids_by_ns: HashMap<String, Vec<Bson>>,
+) -> Result<Vec<(String, Document)>> {
+    let mut result = vec![];
+    for (ns, ids) in ids_by_ns {
+        let (db, coll) = match ns.split_once('.') {
+            Some(names) => names,
+            None => continue,
+        };
+        let coll = source.database(db).collection::<Document>(coll);
+        for ids in ids.chunks(FETCH_BATCH_SIZE) {
+            for doc in coll.find(doc! {"_id": {"$in": ids}}, None)? {
+                result.push((ns.clone(), doc?));
+            }
+        }
+    }
+    Ok(result)
+}
+
+// fetch current documents `docs` from `source`, returns insert oplogs of documents which still exist.
+fn fetch_insert_oplogs(source: &MongoClient, docs: Vec<RepairDoc>) -> Result<Vec<Document>> {
+    let mut ids_by_ns: HashMap<String, Vec<Bson>> = HashMap::new();
+    for doc in docs {
+        ids_by_ns.entry(doc.ns).or_default().push(doc.id);
+    }
+    Ok(fetch_source_docs(source, ids_by_ns)?
+        .into_iter()
+        .map(|(ns, doc)| doc! {OP_KEY: "i", NAMESPACE_KEY: ns, "o": doc})
+        .collect())
+}
+
+// convert update oplogs in `oplogs` to replacements with current source documents, or deletes if they don't
+// exist in source any more.  Each document is fetched once.
+fn to_full_document_oplogs(source: &MongoClient, oplogs: Vec<Document>) -> Result<Vec<Document>> {
+    let mut ids_by_ns: HashMap<String, Vec<Bson>> = HashMap::new();
+    let mut seen = HashSet::new();
+    for one_log in oplogs.iter() {
+        if one_log.get_str(OP_KEY)? != "u" {
+            continue;
+        }
+        let ns = one_log.get_str(NAMESPACE_KEY)?;
+        if let Some(id) = one_log.get_document("o2")?.get("_id") {
+            if seen.insert((ns.to_string(), id_key(id))) {
+                ids_by_ns
+                    .entry(ns.to_string())
+                    .or_default()
+                    .push(id.clone());
+            }
+        }
+    }
+    if ids_by_ns.is_empty() {
+        return Ok(oplogs);
+    }
+    let docs: HashMap<(String, Vec<u8>), Document> = fetch_source_docs(source, ids_by_ns)?
+        .into_iter()
+        .filter_map(|(ns, doc)| {
+            let key = id_key(doc.get("_id")?);
+            Some(((ns, key), doc))
+        })
+        .collect();
+    info!(
+        updates = seen.len(),
+        fetched = docs.len(),
+        "Fetch full documents of updates from source. "
+    );
+
+    let mut result = Vec::with_capacity(oplogs.len());
+    for mut one_log in oplogs {
+        if one_log.get_str(OP_KEY)? == "u" {
+            let ns = one_log.get_str(NAMESPACE_KEY)?.to_string();
+            if let Some(id) = one_log.get_document("o2")?.get("_id").cloned() {
+                match docs.get(&(ns, id_key(&id))) {
+                    Some(doc) => {
+                        one_log.insert("o", doc.clone());
+                    }
+                    None => {
+                        one_log.insert(OP_KEY, "d");
+                        one_log.insert("o", doc! {"_id": id});
+                        one_log.remove("o2");
+                    }
+                }
+            }
+        }
+        result.push(one_log);
+    }
+    Ok(result)
+}
+
+/// How often operator updates match no target document, and the documents are fetched from source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnmatchedStats {
+    /// operator updates which match no target document.
+    pub unmatched: u64,
+    /// documents which are fetched from source and upserted into target.
+    pub refetched: u64,
+}
+
 /// A mongodb data incremental dumper to dump data to mongodb.
 ///
 /// Basically, when we receive oplogs record from server, we need to use [push_oplogs](IncrDumper::push_oplogs)
@@ -43,6 +157,15 @@ fn _execute_apply_ops_cmd(oplogs: &mut Vec<Document>, mongo_conn: &MongoClient)
 pub struct IncrDumper {
     oplog_batch: VecDeque<Document>,
     mongo_conn: MongoClient,
+    limits: BatchLimits,
+    oplog_filter: Option<OplogFilter>,
+    mapping: NamespaceMapping,
+    source: Option<MongoClient>,
+    full_document_source: Option<MongoClient>,
+    unmatched_stats: UnmatchedStats,
+    retry: ApplyRetry,
+    dead_letters: Option<DeadLetters>,
+    dead_lettered: u64,
 }
 
 impl IncrDumper {
@@ -51,7 +174,228 @@ impl IncrDumper {
         IncrDumper {
             oplog_batch: VecDeque::with_capacity(BATCH_SIZE),
             mongo_conn,
+            limits: BatchLimits::default(),
+            oplog_filter: None,
+            mapping: NamespaceMapping::default(),
+            source: None,
+            full_document_source: None,
+            unmatched_stats: UnmatchedStats::default(),
+            // failed batches are not retried by default.
+            retry: ApplyRetry::new(0, Duration::ZERO),
+            dead_letters: None,
+            dead_lettered: 0,
+        }
+    }
+
+    /// set `limits` of write commands which are used to apply oplogs.
+    pub fn with_batch_limits(mut self, limits: BatchLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// rewrite CRUD oplogs by `oplog_filter` before they are applied.
+    pub fn with_oplog_filter(mut self, oplog_filter: OplogFilter) -> Self {
+        self.oplog_filter = Some(oplog_filter);
+        self
+    }
+
+    /// apply oplogs into target namespaces which are mapped by `mapping`.
+    pub fn with_namespace_mapping(mut self, mapping: NamespaceMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// fetch documents from `source` client when their operator updates match no target document, e.g: the
+    /// insert is filtered out or failed before, and upsert them in full.
+    pub fn with_source_fallback(mut self, source: MongoClient) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// apply update oplogs with current full documents which are fetched from `source` client, instead of
+    /// replaying update operators.  Updates of documents which don't exist in source any more become deletes.
+    pub fn with_full_document_updates(mut self, source: MongoClient) -> Self {
+        self.full_document_source = Some(source);
+        self
+    }
+
+    /// retry batches of CRUD oplogs which fail to apply by `retry`.
+    pub fn with_retry(mut self, retry: ApplyRetry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// save oplogs which still fail to apply after retries into `dead_letters`, and continue with other
+    /// oplogs.  Without it, the error is returned.
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// get how often operator updates match no target document.
+    pub fn unmatched_stats(&self) -> UnmatchedStats {
+        self.unmatched_stats
+    }
+
+    /// get how many oplogs are saved into dead letters.
+    pub fn dead_lettered(&self) -> u64 {
+        self.dead_lettered
+    }
+
+    /// apply entries of `dead_letters` again in oplog order, entries which are applied are removed, returns
+    /// how many are applied and how many still fail.
+    pub fn redrive_dead_letters(&mut self, dead_letters: &DeadLetters) -> Result<Document> {
+        let (mut applied, mut failed) = (0, 0);
+        for entry in dead_letters.list()? {
+            let id = entry.get("_id").cloned().unwrap_or(Bson::Null);
+            let mut oplogs = vec![entry.get_document("oplog")?.clone()];
+            self.rewrite_oplogs(&mut oplogs)?;
+            let result = if oplogs.is_empty() {
+                Ok(vec![])
+            } else {
+                execute_normal_oplogs_with_mapping(
+                    &mut oplogs,
+                    &self.mongo_conn,
+                    &self.limits,
+                    &self.mapping,
+                )
+            };
+            match result {
+                Ok(unmatched) => {
+                    self.upsert_unmatched(unmatched)?;
+                    dead_letters.remove(&id)?;
+                    applied += 1;
+                }
+                Err(e @ SyncError::ApplyOplogError(_)) => {
+                    warn!(?entry, ?e, "Dead-lettered oplog fails to apply again. ");
+                    dead_letters.record_failure(&id, &e)?;
+                    failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        info!(applied, failed, "Redrive dead-lettered oplogs complete. ");
+        Ok(doc! {"applied": applied, "failed": failed})
+    }
+
+    /// apply CRUD `oplogs`, after the function is invoked, oplogs will be empty.
+    fn execute_normal_oplogs(&mut self, oplogs: &mut Vec<Document>) -> Result<()> {
+        self.rewrite_oplogs(oplogs)?;
+        if oplogs.is_empty() {
+            return Ok(());
+        }
+        let unmatched = self.write_oplogs(oplogs)?;
+        self.upsert_unmatched(unmatched)
+    }
+
+    // rewrite updates into full documents, and rewrite oplogs by collection filters.
+    fn rewrite_oplogs(&self, oplogs: &mut Vec<Document>) -> Result<()> {
+        if let Some(source) = &self.full_document_source {
+            *oplogs = to_full_document_oplogs(source, std::mem::take(oplogs))?;
+        }
+        if let Some(oplog_filter) = &self.oplog_filter {
+            *oplogs = oplog_filter.rewrite(std::mem::take(oplogs))?;
+        }
+        Ok(())
+    }
+
+    // write `oplogs` into target, a failed batch is retried with backoff.  If it still fails, oplogs are written
+    // one by one, and oplogs which fail with write errors are saved into dead letters if it's configured.
+    fn write_oplogs(&mut self, oplogs: &mut Vec<Document>) -> Result<Vec<RepairDoc>> {
+        if self.retry.retries == 0 && self.dead_letters.is_none() {
+            return execute_normal_oplogs_with_mapping(
+                oplogs,
+                &self.mongo_conn,
+                &self.limits,
+                &self.mapping,
+            );
+        }
+        let mut attempt = 0;
+        let error = loop {
+            // statements before a write error are applied, so they are applied again on retry, like a restart.
+            let mut batch = oplogs.clone();
+            match execute_normal_oplogs_with_mapping(
+                &mut batch,
+                &self.mongo_conn,
+                &self.limits,
+                &self.mapping,
+            ) {
+                Ok(unmatched) => {
+                    oplogs.clear();
+                    return Ok(unmatched);
+                }
+                Err(e) if attempt < self.retry.retries => {
+                    let backoff = self.retry.backoff_of(attempt);
+                    warn!(?e, attempt, ?backoff, "Apply oplogs failed, retry later. ");
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                }
+                Err(e) => break e,
+            }
+        };
+        let dead_letters = match &self.dead_letters {
+            Some(dead_letters) => dead_letters,
+            None => return Err(error),
+        };
+        warn!(
+            ?error,
+            oplogs = oplogs.len(),
+            "Apply oplogs failed after retries, apply them one by one. "
+        );
+        let mut unmatched = vec![];
+        for one_log in oplogs.drain(..) {
+            let mut single = vec![one_log.clone()];
+            match execute_normal_oplogs_with_mapping(
+                &mut single,
+                &self.mongo_conn,
+                &self.limits,
+                &self.mapping,
+            ) {
+                Ok(one_unmatched) => unmatched.extend(one_unmatched),
+                Err(e @ SyncError::ApplyOplogError(_)) => {
+                    dead_letters.save(&one_log, &e)?;
+                    self.dead_lettered += 1;
+                    error!(
+                        ?one_log,
+                        ?e,
+                        dead_lettered = self.dead_lettered,
+                        "Apply oplog failed, save it into dead letters. "
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(unmatched)
+    }
+
+    // fetch documents whose operator updates match nothing from source, and upsert them.
+    fn upsert_unmatched(&mut self, unmatched: Vec<RepairDoc>) -> Result<()> {
+        if unmatched.is_empty() {
+            return Ok(());
+        }
+        self.unmatched_stats.unmatched += unmatched.len() as u64;
+        let source = match &self.source {
+            Some(source) => source,
+            None => {
+                warn!(?unmatched, "Updates match no target document. ");
+                return Ok(());
+            }
+        };
+        // current source documents are newer than the updates, later oplogs are replayed on them safely.
+        let mut refetched = fetch_insert_oplogs(source, unmatched)?;
+        if let Some(oplog_filter) = &self.oplog_filter {
+            refetched = oplog_filter.rewrite(refetched)?;
+        }
+        self.unmatched_stats.refetched += refetched.len() as u64;
+        warn!(
+            refetched = refetched.len(),
+            stats = ?self.unmatched_stats,
+            "Updates match no target document, upsert them from source. "
+        );
+        if !refetched.is_empty() {
+            self.write_oplogs(&mut refetched)?;
         }
+        Ok(())
     }
 
     /// Push given `oplogs`.
@@ -101,11 +445,11 @@ impl IncrDumper {
                 COMMAND_OP => {
                     if !normal_oplogs.is_empty() {
                         info!(?latest_ts, "Begin to apply oplogs... ");
-                        execute_normal_oplogs(&mut normal_oplogs, &self.mongo_conn)?;
+                        self.execute_normal_oplogs(&mut normal_oplogs)?;
                         info!(?latest_ts, "Apply oplogs complete... ");
                     }
 
-                    apply_command_log(one_log, &self.mongo_conn)?;
+                    apply_command_log(one_log, &self.mongo_conn, &self.mapping)?;
                     return Ok((!self.oplog_batch.is_empty(), latest_ts));
                 }
                 _ => {
@@ -126,7 +470,7 @@ impl IncrDumper {
         if !normal_oplogs.is_empty() {
             // finally, check normal_oplogs, and apply.
             info!(?latest_ts, "Begin to apply oplogs... ");
-            execute_normal_oplogs(&mut normal_oplogs, &self.mongo_conn)?;
+            self.execute_normal_oplogs(&mut normal_oplogs)?;
             info!(?latest_ts, "Apply oplogs complete... ");
         }
         Ok((!self.oplog_batch.is_empty(), latest_ts))
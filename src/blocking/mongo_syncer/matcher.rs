@@ -0,0 +1,499 @@
+//! Provide an embedded document matcher and projection, which are used to filter oplogs in incremental sync.
+//!
+//! Only a subset of mongodb query language is supported:
+//!
+//! - implicit equality, `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`, `$exists`, `$not`.
+//! - logical `$and`, `$or`, `$nor`.
+//! - dotted paths, and arrays are matched if any element matches.
+//!
+//! Projection supports inclusion or exclusion of (dotted) fields, `_id` is always kept.
+
+use crate::{Result, SyncError};
+use bson::{Bson, Document};
+use std::cmp::Ordering;
+
+const FIELD_OPERATORS: [&str; 10] = [
+    "$eq", "$ne", "$gt", "$gte", "$lt", "$lte", "$in", "$nin", "$exists", "$not",
+];
+const LOGICAL_OPERATORS: [&str; 3] = ["$and", "$or", "$nor"];
+
+fn invalid_filter(detail: String) -> SyncError {
+    SyncError::InvalidCollFilter { detail }
+}
+
+/// Check that `filter` only uses supported operators.
+pub fn validate_filter(filter: &Document) -> Result<()> {
+    for (key, value) in filter.iter() {
+        if LOGICAL_OPERATORS.contains(&key.as_str()) {
+            for sub in logical_clauses(key, value)? {
+                validate_filter(sub)?;
+            }
+        } else if key.starts_with('$') {
+            return Err(invalid_filter(format!("unsupported operator {}", key)));
+        } else if let Bson::Document(ops) = value {
+            if is_operator_doc(ops) {
+                validate_field_ops(ops)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_field_ops(ops: &Document) -> Result<()> {
+    for (op, arg) in ops.iter() {
+        if !FIELD_OPERATORS.contains(&op.as_str()) {
+            return Err(invalid_filter(format!("unsupported operator {}", op)));
+        }
+        match (op.as_str(), arg) {
+            ("$in" | "$nin", Bson::Array(_)) => {}
+            ("$in" | "$nin", _) => {
+                return Err(invalid_filter(format!("{} needs an array", op)));
+            }
+            ("$not", Bson::Document(sub)) if is_operator_doc(sub) => validate_field_ops(sub)?,
+            ("$not", _) => {
+                return Err(invalid_filter(
+                    "$not needs an operator document".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn logical_clauses<'a>(key: &str, value: &'a Bson) -> Result<Vec<&'a Document>> {
+    match value {
+        Bson::Array(clauses) if !clauses.is_empty() => clauses
+            .iter()
+            .map(|c| {
+                c.as_document()
+                    .ok_or_else(|| invalid_filter(format!("{} needs an array of documents", key)))
+            })
+            .collect(),
+        _ => Err(invalid_filter(format!(
+            "{} needs a nonempty array of documents",
+            key
+        ))),
+    }
+}
+
+fn is_operator_doc(d: &Document) -> bool {
+    d.keys().next().map(|k| k.starts_with('$')).unwrap_or(false)
+}
+
+/// Returns true if `doc` matches `filter`.
+///
+/// `filter` should be checked by [validate_filter] first, unsupported operators are treated as error.
+pub fn matches(filter: &Document, doc: &Document) -> Result<bool> {
+    for (key, value) in filter.iter() {
+        let matched = match key.as_str() {
+            "$and" => {
+                let mut all = true;
+                for sub in logical_clauses(key, value)? {
+                    if !matches(sub, doc)? {
+                        all = false;
+                        break;
+                    }
+                }
+                all
+            }
+            "$or" | "$nor" => {
+                let mut any = false;
+                for sub in logical_clauses(key, value)? {
+                    if matches(sub, doc)? {
+                        any = true;
+                        break;
+                    }
+                }
+                if key == "$or" {
+                    any
+                } else {
+                    !any
+                }
+            }
+            op if op.starts_with('$') => {
+                return Err(invalid_filter(format!("unsupported operator {}", op)));
+            }
+            path => {
+                let values = resolve_path(doc, path);
+                match value {
+                    Bson::Document(ops) if is_operator_doc(ops) => match_ops(ops, &values)?,
+                    _ => match_eq(value, &values),
+                }
+            }
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn match_ops(ops: &Document, values: &[&Bson]) -> Result<bool> {
+    for (op, arg) in ops.iter() {
+        let matched = match op.as_str() {
+            "$eq" => match_eq(arg, values),
+            "$ne" => !match_eq(arg, values),
+            "$gt" => match_cmp(arg, values, |o| o == Ordering::Greater),
+            "$gte" => match_cmp(arg, values, |o| o != Ordering::Less),
+            "$lt" => match_cmp(arg, values, |o| o == Ordering::Less),
+            "$lte" => match_cmp(arg, values, |o| o != Ordering::Greater),
+            "$in" | "$nin" => {
+                let candidates = arg
+                    .as_array()
+                    .ok_or_else(|| invalid_filter(format!("{} needs an array", op)))?;
+                let found = candidates.iter().any(|c| match_eq(c, values));
+                if op == "$in" {
+                    found
+                } else {
+                    !found
+                }
+            }
+            "$exists" => {
+                let expected = match arg {
+                    Bson::Boolean(b) => *b,
+                    Bson::Int32(v) => *v != 0,
+                    Bson::Int64(v) => *v != 0,
+                    Bson::Double(v) => *v != 0.0,
+                    _ => true,
+                };
+                values.is_empty() != expected
+            }
+            "$not" => match arg {
+                Bson::Document(sub) => !match_ops(sub, values)?,
+                _ => {
+                    return Err(invalid_filter(
+                        "$not needs an operator document".to_string(),
+                    ))
+                }
+            },
+            _ => return Err(invalid_filter(format!("unsupported operator {}", op))),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Get values at dotted `path` of `doc`, arrays in the middle of path are traversed.
+fn resolve_path<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let mut result = vec![];
+    match path.split_once('.') {
+        None => {
+            if let Some(v) = doc.get(path) {
+                result.push(v);
+            }
+        }
+        Some((head, rest)) => match doc.get(head) {
+            Some(Bson::Document(sub)) => result.extend(resolve_path(sub, rest)),
+            Some(Bson::Array(arr)) => result.extend(resolve_in_array(arr, rest)),
+            _ => {}
+        },
+    }
+    result
+}
+
+fn resolve_in_array<'a>(arr: &'a [Bson], path: &str) -> Vec<&'a Bson> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    // numeric path component indexes into array.
+    if let Ok(idx) = head.parse::<usize>() {
+        return match (arr.get(idx), rest) {
+            (Some(v), None) => vec![v],
+            (Some(Bson::Document(sub)), Some(rest)) => resolve_path(sub, rest),
+            (Some(Bson::Array(sub)), Some(rest)) => resolve_in_array(sub, rest),
+            _ => vec![],
+        };
+    }
+    arr.iter()
+        .filter_map(|v| v.as_document())
+        .flat_map(|sub| resolve_path(sub, path))
+        .collect()
+}
+
+/// expand `values`, so an array value is matched by its elements as well.
+fn candidates<'a>(values: &[&'a Bson]) -> Vec<&'a Bson> {
+    let mut result = vec![];
+    for v in values {
+        result.push(*v);
+        if let Bson::Array(arr) = v {
+            result.extend(arr.iter());
+        }
+    }
+    result
+}
+
+fn match_eq(expected: &Bson, values: &[&Bson]) -> bool {
+    // `{field: null}` matches documents which don't contain the field.
+    if values.is_empty() {
+        return matches!(expected, Bson::Null);
+    }
+    candidates(values)
+        .into_iter()
+        .any(|v| compare_values(v, expected) == Some(Ordering::Equal))
+}
+
+fn match_cmp(expected: &Bson, values: &[&Bson], accept: impl Fn(Ordering) -> bool) -> bool {
+    candidates(values)
+        .into_iter()
+        .any(|v| compare_values(v, expected).map(&accept).unwrap_or(false))
+}
+
+fn as_f64(v: &Bson) -> Option<f64> {
+    match v {
+        Bson::Int32(v) => Some(*v as f64),
+        Bson::Int64(v) => Some(*v as f64),
+        Bson::Double(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Compare two bson values, returns None if they are not comparable (different types).
+pub fn compare_values(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::Int32(a), Bson::Int32(b)) => Some(a.cmp(b)),
+        (Bson::Int64(a), Bson::Int64(b)) => Some(a.cmp(b)),
+        (Bson::Int32(a), Bson::Int64(b)) => Some((*a as i64).cmp(b)),
+        (Bson::Int64(a), Bson::Int32(b)) => Some(a.cmp(&(*b as i64))),
+        _ if as_f64(a).is_some() && as_f64(b).is_some() => as_f64(a)?.partial_cmp(&as_f64(b)?),
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => {
+            Some((a.time, a.increment).cmp(&(b.time, b.increment)))
+        }
+        (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// Get field paths which are referred by `filter`.
+pub fn filter_paths(filter: &Document) -> Vec<String> {
+    let mut paths = vec![];
+    for (key, value) in filter.iter() {
+        if LOGICAL_OPERATORS.contains(&key.as_str()) {
+            if let Bson::Array(clauses) = value {
+                for sub in clauses.iter().filter_map(|c| c.as_document()) {
+                    paths.extend(filter_paths(sub));
+                }
+            }
+        } else if !key.starts_with('$') {
+            paths.push(key.clone());
+        }
+    }
+    paths
+}
+
+/// Returns true if one of path `a` and `b` is the same as, or the parent of the other one.
+pub fn paths_overlap(a: &str, b: &str) -> bool {
+    let is_prefix = |short: &str, long: &str| {
+        long == short || (long.starts_with(short) && long.as_bytes()[short.len()] == b'.')
+    };
+    if a.len() <= b.len() {
+        is_prefix(a, b)
+    } else {
+        is_prefix(b, a)
+    }
+}
+
+fn is_truthy(v: &Bson) -> bool {
+    match v {
+        Bson::Boolean(b) => *b,
+        Bson::Int32(v) => *v != 0,
+        Bson::Int64(v) => *v != 0,
+        Bson::Double(v) => *v != 0.0,
+        _ => true,
+    }
+}
+
+/// Projection of documents, which is built from mongodb projection document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projection {
+    /// true if the projection includes fields, or else it excludes fields.
+    inclusion: bool,
+    fields: Vec<String>,
+}
+
+/// How a field path is affected by a projection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathVisibility {
+    /// the whole field is kept.
+    Visible,
+    /// the whole field is removed.
+    Hidden,
+    /// some sub fields are kept and others are removed.
+    Partial,
+}
+
+impl Projection {
+    /// build projection from mongodb `projection` document, `_id` is ignored because it's always kept.
+    pub fn new(projection: &Document) -> Result<Projection> {
+        let mut inclusion = None;
+        let mut fields = vec![];
+        for (key, value) in projection.iter() {
+            if key == "_id" {
+                continue;
+            }
+            if key.starts_with('$') || matches!(value, Bson::Document(_)) {
+                return Err(invalid_filter(format!("unsupported projection on {}", key)));
+            }
+            let include = is_truthy(value);
+            if *inclusion.get_or_insert(include) != include {
+                return Err(invalid_filter(
+                    "projection can't mix inclusion and exclusion".to_string(),
+                ));
+            }
+            fields.push(key.clone());
+        }
+        Ok(Projection {
+            inclusion: inclusion.unwrap_or(false),
+            fields,
+        })
+    }
+
+    /// get how field `path` is affected by the projection.
+    pub fn visibility(&self, path: &str) -> PathVisibility {
+        if path == "_id" {
+            return PathVisibility::Visible;
+        }
+        let (selected, selected_child) = self.fields.iter().fold((false, false), |acc, f| {
+            let overlap = paths_overlap(f, path);
+            (
+                acc.0 || (overlap && f.len() <= path.len()),
+                acc.1 || (overlap && f.len() > path.len()),
+            )
+        });
+        match (self.inclusion, selected, selected_child) {
+            (true, true, _) | (false, false, false) => PathVisibility::Visible,
+            (true, false, false) | (false, true, _) => PathVisibility::Hidden,
+            (_, false, true) => PathVisibility::Partial,
+        }
+    }
+
+    /// apply projection to `doc`.
+    pub fn apply(&self, doc: Document) -> Document {
+        self.apply_with_prefix(doc, "")
+    }
+
+    fn apply_with_prefix(&self, doc: Document, prefix: &str) -> Document {
+        let mut result = Document::new();
+        for (key, value) in doc.into_iter() {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            match self.visibility(&path) {
+                PathVisibility::Visible => {
+                    result.insert(key, value);
+                }
+                PathVisibility::Hidden => {}
+                PathVisibility::Partial => match value {
+                    Bson::Document(sub) => {
+                        result.insert(key, self.apply_with_prefix(sub, &path));
+                    }
+                    Bson::Array(arr) => {
+                        let arr: Vec<Bson> = arr
+                            .into_iter()
+                            .filter_map(|v| match v {
+                                Bson::Document(sub) => {
+                                    Some(Bson::Document(self.apply_with_prefix(sub, &path)))
+                                }
+                                // scalar elements are kept only in exclusion mode.
+                                other if !self.inclusion => Some(other),
+                                _ => None,
+                            })
+                            .collect();
+                        result.insert(key, arr);
+                    }
+                    // scalar value doesn't contain sub fields.
+                    other => {
+                        if !self.inclusion {
+                            result.insert(key, other);
+                        }
+                    }
+                },
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn test_matches_equality_and_comparison() {
+        let d = doc! {"tenant": "acme", "n": 5, "tags": ["a", "b"], "sub": {"x": 1.0}};
+        assert!(matches(&doc! {"tenant": "acme"}, &d).unwrap());
+        assert!(!matches(&doc! {"tenant": "other"}, &d).unwrap());
+        assert!(matches(&doc! {"n": {"$gt": 3, "$lte": 5}}, &d).unwrap());
+        assert!(!matches(&doc! {"n": {"$lt": 5}}, &d).unwrap());
+        assert!(matches(&doc! {"tags": "b"}, &d).unwrap());
+        assert!(matches(&doc! {"sub.x": 1}, &d).unwrap());
+        assert!(matches(&doc! {"missing": null}, &d).unwrap());
+        assert!(matches(&doc! {"n": {"$in": [1, 5]}}, &d).unwrap());
+        assert!(matches(&doc! {"n": {"$nin": [1, 2]}}, &d).unwrap());
+        assert!(matches(&doc! {"n": {"$exists": true}, "m": {"$exists": false}}, &d).unwrap());
+        assert!(matches(&doc! {"n": {"$not": {"$gt": 10}}}, &d).unwrap());
+        assert!(matches(&doc! {"n": {"$ne": "5"}}, &d).unwrap());
+    }
+
+    #[test]
+    fn test_matches_logical_and_array_path() {
+        let d = doc! {"items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 7}]};
+        assert!(matches(&doc! {"items.qty": {"$gt": 5}}, &d).unwrap());
+        assert!(matches(&doc! {"items.1.sku": "b"}, &d).unwrap());
+        assert!(matches(&doc! {"$or": [{"items.sku": "z"}, {"items.sku": "a"}]}, &d).unwrap());
+        assert!(!matches(&doc! {"$nor": [{"items.sku": "a"}]}, &d).unwrap());
+        assert!(matches(&doc! {"$and": [{"items.sku": "a"}, {"items.sku": "b"}]}, &d).unwrap());
+    }
+
+    #[test]
+    fn test_validate_filter() {
+        assert!(validate_filter(&doc! {"a": 1, "$or": [{"b": {"$in": [1]}}]}).is_ok());
+        assert!(validate_filter(&doc! {"a": {"$regex": "^x"}}).is_err());
+        assert!(validate_filter(&doc! {"$where": "true"}).is_err());
+        assert!(validate_filter(&doc! {"$or": []}).is_err());
+        // a document without operators is an equality match.
+        assert!(validate_filter(&doc! {"a": {"b": 1}}).is_ok());
+    }
+
+    #[test]
+    fn test_filter_paths_and_overlap() {
+        assert_eq!(
+            filter_paths(&doc! {"a": 1, "$or": [{"b.c": 1}, {"d": {"$gt": 1}}]}),
+            vec!["a", "b.c", "d"]
+        );
+        assert!(paths_overlap("a", "a.b"));
+        assert!(paths_overlap("a.b", "a"));
+        assert!(paths_overlap("a", "a"));
+        assert!(!paths_overlap("a", "ab"));
+    }
+
+    #[test]
+    fn test_projection() {
+        let d = doc! {"_id": 1, "a": 1, "blob": "xx", "sub": {"x": 1, "y": 2}};
+        let exclude = Projection::new(&doc! {"blob": 0, "sub.y": 0}).unwrap();
+        assert_eq!(
+            exclude.apply(d.clone()),
+            doc! {"_id": 1, "a": 1, "sub": {"x": 1}}
+        );
+        assert_eq!(exclude.visibility("blob.z"), PathVisibility::Hidden);
+        assert_eq!(exclude.visibility("sub"), PathVisibility::Partial);
+        assert_eq!(exclude.visibility("a"), PathVisibility::Visible);
+
+        let include = Projection::new(&doc! {"_id": 0, "a": 1, "sub.x": 1}).unwrap();
+        assert_eq!(include.apply(d), doc! {"_id": 1, "a": 1, "sub": {"x": 1}});
+        assert_eq!(include.visibility("blob"), PathVisibility::Hidden);
+
+        assert!(Projection::new(&doc! {"a": 1, "b": 0}).is_err());
+    }
+}
@@ -1,16 +1,55 @@
 #[doc(hidden)]
+pub mod batch;
+#[doc(hidden)]
 pub mod bson_helper;
+mod cancel;
+#[doc(hidden)]
+pub mod checkpoint;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod dead_letter;
 #[doc(hidden)]
 pub mod full;
 #[doc(hidden)]
 pub mod incr;
 #[doc(hidden)]
+pub mod matcher;
+#[doc(hidden)]
+pub mod oplog_filter;
+#[doc(hidden)]
 pub mod oplog_helper;
 mod oplog_syncer;
 #[doc(hidden)]
 mod syncer;
 #[doc(hidden)]
 pub mod oplog_bulk;
+#[doc(hidden)]
+pub mod plan;
+mod progress;
+#[doc(hidden)]
+pub mod repair;
+mod report;
+#[doc(hidden)]
+pub mod restore;
+#[doc(hidden)]
+pub mod sampler;
+#[doc(hidden)]
+pub mod snapshot;
+#[doc(hidden)]
+pub mod splitter;
+mod supervisor;
+#[doc(hidden)]
+pub mod verify;
 
+pub use cancel::{cancel_on_signals, CancelToken};
 pub use oplog_syncer::{OplogSyncer, OplogCleaner};
+pub use progress::{
+    CollProgress, FullSyncProgress, ProgressLogger, ProgressSnapshot, RangeProgress,
+};
+pub use repair::RepairDoc;
+pub use report::{CollSyncReport, SyncReport};
+pub use restore::{RestoreBase, RestoreOptions, RestoreReport};
+pub use supervisor::{RetryCounts, RetryStats, Supervisor};
 pub use syncer::MongoSyncer;
+pub use verify::{CollVerifyReport, VerifyReport};
@@ -1,15 +1,48 @@
 //! Provide something similar to oplog `bulkWrite` feature.
 
-use crate::{Result, SyncError, NAMESPACE_KEY, OP_KEY};
-use bson::{doc, Document};
-use mongodb::sync::Client as MongoClient;
-use std::collections::HashMap;
+use super::batch::{command_limits, split_by_limits};
+use super::bson_helper::id_key;
+use super::repair::RepairDoc;
+use crate::{BatchLimits, NamespaceMapping, Result, SyncError, NAMESPACE_KEY, OP_KEY};
+use bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::sync::{Client as MongoClient, Collection};
+use std::collections::{HashMap, HashSet};
 use tracing::{info, warn};
 
 /// Execute normal CRUD `oplogs` against `mongo_conn` connection.
 ///
 /// Must make sure that given `oplogs` doesn't contains `c` operation.  Or it will be ignored.
-pub fn execute_normal_oplogs(oplogs: &mut Vec<Document>, mongo_conn: &MongoClient) -> Result<()> {
+///
+/// Returns documents whose operator updates match nothing in target, they're identified by source namespace.
+pub fn execute_normal_oplogs(
+    oplogs: &mut Vec<Document>,
+    mongo_conn: &MongoClient,
+) -> Result<Vec<RepairDoc>> {
+    execute_normal_oplogs_with_limits(oplogs, mongo_conn, &BatchLimits::default())
+}
+
+/// Execute normal CRUD `oplogs` against `mongo_conn` connection, each write command doesn't exceed `limits`.
+///
+/// Statements which exceed `limits` or mongodb command limits are split into several commands.
+pub fn execute_normal_oplogs_with_limits(
+    oplogs: &mut Vec<Document>,
+    mongo_conn: &MongoClient,
+    limits: &BatchLimits,
+) -> Result<Vec<RepairDoc>> {
+    execute_normal_oplogs_with_mapping(oplogs, mongo_conn, limits, &NamespaceMapping::default())
+}
+
+/// Execute normal CRUD `oplogs` against `mongo_conn` connection, namespaces of oplogs are mapped to target
+/// namespaces by `mapping`.
+///
+/// It's the same as [execute_normal_oplogs_with_limits] except the mapping.
+pub fn execute_normal_oplogs_with_mapping(
+    oplogs: &mut Vec<Document>,
+    mongo_conn: &MongoClient,
+    limits: &BatchLimits,
+    mapping: &NamespaceMapping,
+) -> Result<Vec<RepairDoc>> {
     // convert from oplog to relative operation is inspired from py-mongo-sync:
     // https://github.com/caosiyang/py-mongo-sync/blob/master/mongosync/multi_oplog_replayer.py
     //
@@ -19,15 +52,9 @@ pub fn execute_normal_oplogs(oplogs: &mut Vec<Document>, mongo_conn: &MongoClien
     let mut oplogs_to_write = vec![];
     oplogs_to_write.append(oplogs);
     let mut statement_docs = vec![];
+    let mut unmatched = vec![];
 
     let mut current_op = oplogs_to_write[0].get_str(OP_KEY).unwrap().to_string();
-    let db_name = oplogs_to_write[0]
-        .get_str(NAMESPACE_KEY)
-        .unwrap()
-        .split_once(".")
-        .unwrap()
-        .0
-        .to_string();
     for mut one_log in oplogs_to_write.into_iter() {
         let obj = one_log.remove("o");
         let mut obj = match obj {
@@ -36,13 +63,20 @@ pub fn execute_normal_oplogs(oplogs: &mut Vec<Document>, mongo_conn: &MongoClien
         };
 
         let op = one_log.get_str(OP_KEY)?;
-        // here we inject collection name to every statement document, then we
+        // here we inject target database and collection name to every statement document, then we
         // can easily tell mongodb apply statement to which colleciton.
-        let coll_name = one_log.get_str(NAMESPACE_KEY)?.split_once(".").unwrap().1;
+        let src_ns = one_log.get_str(NAMESPACE_KEY)?;
+        let (db_name, coll_name) = src_ns.split_once(".").unwrap();
+        let (db_name, coll_name) = mapping.target_ns(db_name, coll_name);
 
         // need to flush logs.
         if _need_to_flush(op, &current_op) {
-            _flush_oplogs(&current_op, &mut statement_docs, &db_name, mongo_conn)?;
+            unmatched.extend(_flush_oplogs(
+                &current_op,
+                &mut statement_docs,
+                mongo_conn,
+                limits,
+            )?);
             current_op = op.to_string();
         }
 
@@ -63,13 +97,13 @@ pub fn execute_normal_oplogs(oplogs: &mut Vec<Document>, mongo_conn: &MongoClien
                     // $v is only for mongodb internal usage, don't send this key to server.
                     obj.remove("$v");
                 };
-                // make compiler happy, assign `coll_name` again.
-                let coll_name = one_log.get_str(NAMESPACE_KEY)?.split_once(".").unwrap().1;
                 statement_docs.push(doc! {
                     "q": {"_id": one_log.get_document("o2")?.get_object_id("_id")?},
                     "u": obj,
                     "upsert": !is_update,
-                    "coll_name": coll_name
+                    "db_name": db_name,
+                    "coll_name": coll_name,
+                    "src_ns": src_ns
                 })
             }
             // insert operation, because we need the oplog replay idempotently.
@@ -78,12 +112,14 @@ pub fn execute_normal_oplogs(oplogs: &mut Vec<Document>, mongo_conn: &MongoClien
                 "q": {"_id": obj.get_object_id("_id")?},
                 "u": obj,
                 "upsert": true,
+                "db_name": db_name,
                 "coll_name": coll_name
             }),
             // delete operation.
             "d" => statement_docs.push(doc! {
                 "q": {"_id": obj.get_object_id("_id")?},
                 "limit": 1,
+                "db_name": db_name,
                 "coll_name": coll_name
             }),
             _ => {
@@ -93,9 +129,14 @@ pub fn execute_normal_oplogs(oplogs: &mut Vec<Document>, mongo_conn: &MongoClien
     }
 
     if !statement_docs.is_empty() {
-        _flush_oplogs(&current_op, &mut statement_docs, &db_name, mongo_conn)?;
+        unmatched.extend(_flush_oplogs(
+            &current_op,
+            &mut statement_docs,
+            mongo_conn,
+            limits,
+        )?);
     }
-    Ok(())
+    Ok(unmatched)
 }
 
 fn _need_to_flush(op: &str, current_op: &str) -> bool {
@@ -103,13 +144,13 @@ fn _need_to_flush(op: &str, current_op: &str) -> bool {
     update_ops.contains(&current_op) ^ update_ops.contains(&op)
 }
 
-// after flush, `oplogs` will be empty.
+// after flush, `oplogs` will be empty, returns documents whose updates match nothing.
 fn _flush_oplogs(
     op: &str,
     statement_docs: &mut Vec<Document>,
-    db_name: &str,
     mongo_conn: &MongoClient,
-) -> Result<()> {
+    limits: &BatchLimits,
+) -> Result<Vec<RepairDoc>> {
     let mut coll_ops = HashMap::new();
     let (command, update_doc_key) = if op == "d" {
         ("delete", "deletes")
@@ -120,35 +161,77 @@ fn _flush_oplogs(
     let mut oplogs_to_apply = vec![];
     oplogs_to_apply.append(statement_docs);
     for mut one_log in oplogs_to_apply {
-        let coll_name = one_log.remove("coll_name").unwrap();
-        let coll_name = match coll_name {
-            bson::Bson::String(c) => c,
-            _ => panic!("collection name must be a string"),
+        let (db_name, coll_name) = match (one_log.remove("db_name"), one_log.remove("coll_name")) {
+            (Some(bson::Bson::String(d)), Some(bson::Bson::String(c))) => (d, c),
+            _ => panic!("database and collection name must be a string"),
         };
 
-        if !coll_ops.contains_key(&coll_name) {
-            coll_ops.insert(coll_name.to_string(), vec![]);
+        coll_ops
+            .entry((db_name, coll_name))
+            .or_insert_with(Vec::new)
+            .push(one_log);
+    }
+
+    let limits = command_limits(limits);
+    let mut unmatched = vec![];
+    for ((db_name, coll_name), oplogs) in coll_ops.into_iter() {
+        let db = mongo_conn.database(&db_name);
+        // split statements, so a command never exceeds 16MB bson limit, and commands are executed in order.
+        for mut oplogs in split_by_limits(oplogs, &limits) {
+            // operator updates don't upsert, they match nothing when target document is missing.
+            let mut no_upserts = vec![];
+            for one_log in oplogs.iter_mut() {
+                if let Some(Bson::String(src_ns)) = one_log.remove("src_ns") {
+                    if !one_log.get_bool("upsert")? {
+                        let id = one_log.get_document("q")?.get("_id").cloned();
+                        no_upserts.push(RepairDoc::new(src_ns, id.unwrap_or(Bson::Null)));
+                    }
+                }
+            }
+            let statements = oplogs.len();
+            info!(%db_name, %coll_name, operation=%command, statements=oplogs.len(), "Flush oplogs for collection.");
+            // `ordered` key default to be true, so we don't need to tell mongodb explicitly.
+            let result = db.run_command(
+                doc! {
+                    command: &coll_name,
+                    update_doc_key: oplogs
+                },
+                None,
+            )?;
+
+            if result.contains_key("writeErrors") {
+                return Err(SyncError::ApplyOplogError(result));
+            }
+            // `n` is how many statements match or upsert a document.
+            let matched = match result.get("n") {
+                Some(Bson::Int32(n)) => *n as usize,
+                Some(Bson::Int64(n)) => *n as usize,
+                _ => statements,
+            };
+            if matched < statements && !no_upserts.is_empty() {
+                unmatched.extend(_missing_docs(&db.collection(&coll_name), no_upserts)?);
+            }
         }
-        coll_ops.get_mut(&coll_name).unwrap().push(one_log);
     }
+    Ok(unmatched)
+}
 
-    let db = mongo_conn.database(db_name);
-    for (coll_name, oplogs) in coll_ops.into_iter() {
-        info!(%coll_name, operation=%command, "Flush oplogs for collection.");
-        // `ordered` key default to be true, so we don't need to tell mongodb explicitly.
-        let result = db.run_command(
-            doc! {
-                command: coll_name,
-                update_doc_key: oplogs
-            },
-            None,
-        )?;
-
-        if result.contains_key("writeErrors") {
-            return Err(SyncError::ApplyOplogError(result));
+// get documents of `docs` which don't exist in target `coll`.
+fn _missing_docs(coll: &Collection<Document>, docs: Vec<RepairDoc>) -> Result<Vec<RepairDoc>> {
+    let ids: Vec<&Bson> = docs.iter().map(|d| &d.id).collect();
+    let mut existing = HashSet::new();
+    for d in coll.find(
+        doc! {"_id": {"$in": ids}},
+        FindOptions::builder().projection(doc! {"_id": 1}).build(),
+    )? {
+        if let Some(id) = d?.get("_id") {
+            existing.insert(id_key(id));
         }
     }
-    Ok(())
+    Ok(docs
+        .into_iter()
+        .filter(|d| !existing.contains(&id_key(&d.id)))
+        .collect())
 }
 
 #[cfg(test)]
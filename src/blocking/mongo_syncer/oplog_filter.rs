@@ -0,0 +1,496 @@
+//! Provide oplog rewriting for collections which have document filter and projection.
+//!
+//! Before oplogs are applied in incremental sync, oplogs of filtered collections are rewritten:
+//!
+//! - insert: applied with projected document if the document matches, or else skipped.
+//! - replacement update: replaced with projected document if it matches, or else converted to delete.
+//! - operator update which doesn't touch filter fields: projected, and skipped if nothing is left.
+//! - other updates (e.g: `$rename`, or updates which touch filter fields): the current source document
+//!   (post-image) is fetched, and the update is converted to a replacement or delete according to it.
+//! - delete: applied as it is.
+
+use super::matcher::{self, PathVisibility, Projection};
+use crate::{CollFilter, Result, SyncError, NAMESPACE_KEY, OP_KEY, TIMESTAMP_KEY};
+use bson::{doc, Bson, Document};
+use mongodb::sync::Client as MongoClient;
+use std::collections::HashMap;
+
+/// update operators which can be evaluated without the post-image.
+const UPDATE_OPERATORS: [&str; 14] = [
+    "$set",
+    "$unset",
+    "$inc",
+    "$mul",
+    "$min",
+    "$max",
+    "$push",
+    "$pull",
+    "$pullAll",
+    "$addToSet",
+    "$pop",
+    "$currentDate",
+    "$bit",
+    "$setOnInsert",
+];
+/// max `_id`s in one `find` command when fetching post-images.
+const FETCH_BATCH_SIZE: usize = 1000;
+
+/// Check that document filter and projection in `filters` are supported by incremental sync.
+pub fn validate_coll_filters(filters: &HashMap<String, CollFilter>) -> Result<()> {
+    for (coll, filter) in filters.iter() {
+        CompiledFilter::new(coll, filter)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct CompiledFilter {
+    filter: Document,
+    /// field paths referred by filter, numeric components are removed.
+    paths: Vec<String>,
+    projection: Option<Projection>,
+}
+
+/// remove array index components from dotted `path`, e.g: `a.1.b` becomes `a.b`.
+fn strip_indexes(path: &str) -> String {
+    path.split('.')
+        .filter(|c| c.parse::<usize>().is_err())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+impl CompiledFilter {
+    fn new(coll: &str, coll_filter: &CollFilter) -> Result<Self> {
+        let with_coll = |e: SyncError| match e {
+            SyncError::InvalidCollFilter { detail } => SyncError::InvalidCollFilter {
+                detail: format!("collection {}: {}", coll, detail),
+            },
+            e => e,
+        };
+        matcher::validate_filter(&coll_filter.filter).map_err(with_coll)?;
+        let projection = match &coll_filter.projection {
+            Some(p) => Some(Projection::new(p).map_err(with_coll)?),
+            None => None,
+        };
+        Ok(CompiledFilter {
+            filter: coll_filter.filter.clone(),
+            paths: matcher::filter_paths(&coll_filter.filter)
+                .iter()
+                .map(|p| strip_indexes(p))
+                .collect(),
+            projection,
+        })
+    }
+
+    fn project(&self, doc: Document) -> Document {
+        match &self.projection {
+            Some(p) => p.apply(doc),
+            None => doc,
+        }
+    }
+
+    /// rewrite operator `update` against the projection.
+    ///
+    /// Returns None if the update can't be decided without post-image, the returned update may contain
+    /// no operators, which means that it doesn't change target document.
+    fn rewrite_update(&self, update: &Document) -> Option<Document> {
+        let mut result = Document::new();
+        for (op, fields) in update.iter() {
+            if op == "$v" {
+                result.insert(op, fields.clone());
+                continue;
+            }
+            let fields = match fields {
+                Bson::Document(fields) if UPDATE_OPERATORS.contains(&op.as_str()) => fields,
+                _ => return None,
+            };
+            let mut kept = Document::new();
+            for (path, value) in fields.iter() {
+                let stripped = strip_indexes(path);
+                if self
+                    .paths
+                    .iter()
+                    .any(|p| matcher::paths_overlap(p, &stripped))
+                {
+                    return None;
+                }
+                let visibility = match &self.projection {
+                    Some(p) => p.visibility(&stripped),
+                    None => PathVisibility::Visible,
+                };
+                match visibility {
+                    PathVisibility::Visible => {
+                        kept.insert(path, value.clone());
+                    }
+                    PathVisibility::Hidden => {}
+                    PathVisibility::Partial => return None,
+                }
+            }
+            if !kept.is_empty() {
+                result.insert(op, kept);
+            }
+        }
+        Some(result)
+    }
+}
+
+/// How an oplog is rewritten.
+enum Decision {
+    /// apply the oplog.
+    Apply(Document),
+    /// the oplog doesn't change target, skip it.
+    Skip,
+    /// decide according to current source document with `_id`.
+    Fetch { oplog: Document, id: Bson },
+}
+
+/// Rewrite oplogs of collections which have document filter and projection.
+#[derive(Debug)]
+pub struct OplogFilter {
+    /// database which filters apply to, None means all databases.
+    db: Option<String>,
+    /// compiled filters keyed by collection name.
+    filters: HashMap<String, CompiledFilter>,
+    /// source client to fetch post-images.
+    source: MongoClient,
+}
+
+impl OplogFilter {
+    /// create oplog filter from `filters` of collections in database `db`, post-images are fetched through
+    /// `source` client.
+    pub fn new(
+        db: &str,
+        filters: &HashMap<String, CollFilter>,
+        source: MongoClient,
+    ) -> Result<OplogFilter> {
+        Self::with_db(Some(db.to_string()), filters, source)
+    }
+
+    /// create oplog filter from `filters` of collections in all databases, post-images are fetched through
+    /// `source` client.
+    pub fn for_all_dbs(
+        filters: &HashMap<String, CollFilter>,
+        source: MongoClient,
+    ) -> Result<OplogFilter> {
+        Self::with_db(None, filters, source)
+    }
+
+    fn with_db(
+        db: Option<String>,
+        filters: &HashMap<String, CollFilter>,
+        source: MongoClient,
+    ) -> Result<OplogFilter> {
+        let mut compiled = HashMap::with_capacity(filters.len());
+        for (coll, filter) in filters.iter() {
+            compiled.insert(coll.clone(), CompiledFilter::new(coll, filter)?);
+        }
+        Ok(OplogFilter {
+            db,
+            filters: compiled,
+            source,
+        })
+    }
+
+    /// get filter of namespace `ns`.
+    fn get_filter(&self, ns: &str) -> Option<&CompiledFilter> {
+        let (db, coll) = ns.split_once('.')?;
+        match &self.db {
+            Some(filter_db) if filter_db != db => None,
+            _ => self.filters.get(coll),
+        }
+    }
+
+    /// returns true if there is no collection filter.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// rewrite CRUD `oplogs`, oplogs of collections without filter are kept unchanged.
+    pub fn rewrite(&self, oplogs: Vec<Document>) -> Result<Vec<Document>> {
+        if self.is_empty() {
+            return Ok(oplogs);
+        }
+        let mut decisions = Vec::with_capacity(oplogs.len());
+        let mut to_fetch: HashMap<String, Vec<Bson>> = HashMap::new();
+        for oplog in oplogs {
+            let decision = match self.get_filter(oplog.get_str(NAMESPACE_KEY)?) {
+                Some(filter) => decide(filter, oplog)?,
+                None => Decision::Apply(oplog),
+            };
+            if let Decision::Fetch { oplog, id } = &decision {
+                to_fetch
+                    .entry(oplog.get_str(NAMESPACE_KEY)?.to_string())
+                    .or_default()
+                    .push(id.clone());
+            }
+            decisions.push(decision);
+        }
+
+        let post_images = self.fetch_post_images(to_fetch)?;
+        let mut result = Vec::with_capacity(decisions.len());
+        for decision in decisions {
+            match decision {
+                Decision::Apply(oplog) => result.push(oplog),
+                Decision::Skip => {}
+                Decision::Fetch { oplog, id } => {
+                    let ns = oplog.get_str(NAMESPACE_KEY)?;
+                    // filter exists because the decision is made by the filter.
+                    let filter = self.get_filter(ns).expect("filter of fetched namespace");
+                    let post_image = post_images.get(&(ns.to_string(), id_key(&id)?));
+                    result.push(match post_image {
+                        Some(doc) if matcher::matches(&filter.filter, doc)? => {
+                            replace_oplog(&oplog, id, filter.project(doc.clone()))?
+                        }
+                        _ => delete_oplog(&oplog, id)?,
+                    });
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// fetch current documents from source, returns documents keyed by namespace and `_id`.
+    fn fetch_post_images(
+        &self,
+        to_fetch: HashMap<String, Vec<Bson>>,
+    ) -> Result<HashMap<(String, Vec<u8>), Document>> {
+        let mut result = HashMap::new();
+        for (ns, ids) in to_fetch {
+            let (db, coll) = match ns.split_once('.') {
+                Some(names) => names,
+                None => continue,
+            };
+            let coll = self.source.database(db).collection::<Document>(coll);
+            for ids in ids.chunks(FETCH_BATCH_SIZE) {
+                for doc in coll.find(doc! {"_id": {"$in": ids}}, None)? {
+                    let doc = doc?;
+                    if let Some(id) = doc.get("_id") {
+                        result.insert((ns.clone(), id_key(id)?), doc);
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// hashable key of `_id` value, which is the bson encoding of it.
+fn id_key(id: &Bson) -> Result<Vec<u8>> {
+    bson::to_vec(&doc! {"_id": id}).map_err(|e| SyncError::BsonValueError {
+        key: "_id".to_string(),
+        val: e.to_string(),
+    })
+}
+
+fn decide(filter: &CompiledFilter, mut oplog: Document) -> Result<Decision> {
+    match oplog.get_str(OP_KEY)? {
+        "i" => {
+            let obj = oplog.get_document("o")?;
+            if matcher::matches(&filter.filter, obj)? {
+                let obj = filter.project(obj.clone());
+                oplog.insert("o", obj);
+                Ok(Decision::Apply(oplog))
+            } else {
+                Ok(Decision::Skip)
+            }
+        }
+        "u" => {
+            let id = oplog
+                .get_document("o2")?
+                .get("_id")
+                .cloned()
+                .ok_or(SyncError::EmptyDocError)?;
+            let obj = oplog.get_document("o")?;
+            let is_update = obj.keys().any(|k| k.starts_with('$'));
+            if !is_update {
+                return if matcher::matches(&filter.filter, obj)? {
+                    let obj = filter.project(obj.clone());
+                    Ok(Decision::Apply(replace_oplog(&oplog, id, obj)?))
+                } else {
+                    Ok(Decision::Apply(delete_oplog(&oplog, id)?))
+                };
+            }
+            match filter.rewrite_update(obj) {
+                Some(update) if update.keys().all(|k| k == "$v") => Ok(Decision::Skip),
+                Some(update) => {
+                    oplog.insert("o", update);
+                    Ok(Decision::Apply(oplog))
+                }
+                None => Ok(Decision::Fetch { oplog, id }),
+            }
+        }
+        _ => Ok(Decision::Apply(oplog)),
+    }
+}
+
+/// make an oplog which replaces the document `id` with `doc`, at the same time and namespace of `oplog`.
+fn replace_oplog(oplog: &Document, id: Bson, doc: Document) -> Result<Document> {
+    Ok(doc! {
+        TIMESTAMP_KEY: oplog.get_timestamp(TIMESTAMP_KEY)?,
+        OP_KEY: "u",
+        NAMESPACE_KEY: oplog.get_str(NAMESPACE_KEY)?,
+        "o2": {"_id": id},
+        "o": doc,
+    })
+}
+
+/// make an oplog which deletes the document `id`, at the same time and namespace of `oplog`.
+fn delete_oplog(oplog: &Document, id: Bson) -> Result<Document> {
+    Ok(doc! {
+        TIMESTAMP_KEY: oplog.get_timestamp(TIMESTAMP_KEY)?,
+        OP_KEY: "d",
+        NAMESPACE_KEY: oplog.get_str(NAMESPACE_KEY)?,
+        "o": {"_id": id},
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bson::Timestamp;
+
+    fn compiled(filter: Document, projection: Option<Document>) -> CompiledFilter {
+        CompiledFilter::new("c", &CollFilter { filter, projection }).unwrap()
+    }
+
+    fn oplog(op: &str, o: Document, o2: Option<Document>) -> Document {
+        let mut log = doc! {
+            TIMESTAMP_KEY: Timestamp { time: 1, increment: 1 },
+            OP_KEY: op,
+            NAMESPACE_KEY: "db.c",
+            "o": o,
+        };
+        if let Some(o2) = o2 {
+            log.insert("o2", o2);
+        }
+        log
+    }
+
+    #[test]
+    fn test_decide_insert_and_replacement() {
+        let filter = compiled(doc! {"tenant": "acme"}, Some(doc! {"blob": 0}));
+        match decide(
+            &filter,
+            oplog("i", doc! {"_id": 1, "tenant": "acme", "blob": "x"}, None),
+        )
+        .unwrap()
+        {
+            Decision::Apply(log) => assert_eq!(
+                log.get_document("o").unwrap(),
+                &doc! {"_id": 1, "tenant": "acme"}
+            ),
+            _ => panic!("matched insert should be applied"),
+        }
+        assert!(matches!(
+            decide(
+                &filter,
+                oplog("i", doc! {"_id": 1, "tenant": "other"}, None)
+            )
+            .unwrap(),
+            Decision::Skip
+        ));
+        // replacement which stops matching becomes a delete.
+        match decide(
+            &filter,
+            oplog(
+                "u",
+                doc! {"_id": 1, "tenant": "other"},
+                Some(doc! {"_id": 1}),
+            ),
+        )
+        .unwrap()
+        {
+            Decision::Apply(log) => {
+                assert_eq!(log.get_str(OP_KEY).unwrap(), "d");
+                assert_eq!(log.get_document("o").unwrap(), &doc! {"_id": 1});
+            }
+            _ => panic!("replacement should be converted to delete"),
+        }
+    }
+
+    #[test]
+    fn test_decide_operator_update() {
+        let filter = compiled(doc! {"tenant": "acme"}, Some(doc! {"blob": 0, "sub.y": 0}));
+        let id = Some(doc! {"_id": 1});
+        // hidden fields are removed from update.
+        match decide(
+            &filter,
+            oplog(
+                "u",
+                doc! {"$v": 1, "$set": {"a": 1, "blob": "x"}},
+                id.clone(),
+            ),
+        )
+        .unwrap()
+        {
+            Decision::Apply(log) => assert_eq!(
+                log.get_document("o").unwrap(),
+                &doc! {"$v": 1, "$set": {"a": 1}}
+            ),
+            _ => panic!("update should be applied"),
+        }
+        // update which only touches hidden fields is skipped.
+        assert!(matches!(
+            decide(
+                &filter,
+                oplog("u", doc! {"$v": 1, "$unset": {"blob.z": true}}, id.clone())
+            )
+            .unwrap(),
+            Decision::Skip
+        ));
+        // updates which touch filter fields, partially projected fields, or use unknown format need post-image.
+        for update in [
+            doc! {"$set": {"tenant": "other"}},
+            doc! {"$set": {"sub": {"x": 1, "y": 2}}},
+            doc! {"$rename": {"a": "b"}},
+            doc! {"$v": 2, "diff": {"u": {"a": 1}}},
+        ] {
+            assert!(matches!(
+                decide(&filter, oplog("u", update, id.clone())).unwrap(),
+                Decision::Fetch { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_coll_filters() {
+        let mut filters = HashMap::new();
+        filters.insert(
+            "a".to_string(),
+            CollFilter {
+                filter: doc! {"n": {"$gt": 1}},
+                projection: None,
+            },
+        );
+        assert!(validate_coll_filters(&filters).is_ok());
+        filters.insert(
+            "b".to_string(),
+            CollFilter {
+                filter: doc! {"n": {"$regex": "x"}},
+                projection: None,
+            },
+        );
+        assert!(validate_coll_filters(&filters).is_err());
+    }
+
+    #[test]
+    fn test_get_filter_of_dbs() {
+        let mut filters = HashMap::new();
+        filters.insert(
+            "a".to_string(),
+            CollFilter {
+                filter: doc! {"n": 1},
+                projection: None,
+            },
+        );
+        // client is created lazily, no connection is made.
+        let source = MongoClient::with_uri_str("mongodb://localhost:27017").unwrap();
+        let one_db = OplogFilter::new("x", &filters, source.clone()).unwrap();
+        assert!(one_db.get_filter("x.a").is_some());
+        assert!(one_db.get_filter("y.a").is_none());
+        assert!(one_db.get_filter("x.b").is_none());
+        let all_dbs = OplogFilter::for_all_dbs(&filters, source).unwrap();
+        assert!(all_dbs.get_filter("x.a").is_some());
+        assert!(all_dbs.get_filter("y.a").is_some());
+    }
+}
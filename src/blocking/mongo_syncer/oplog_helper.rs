@@ -1,4 +1,8 @@
-use crate::{Result, SyncError, COMMAND_OP, NAMESPACE_KEY, OP_KEY, TIMESTAMP_KEY};
+use super::bson_helper::encoded_size;
+use crate::{
+    BatchLimits, CollSelector, NamespaceMapping, Result, SyncError, COMMAND_OP, NAMESPACE_KEY,
+    OP_KEY, TIMESTAMP_KEY,
+};
 use bson::Document;
 use bson::{doc, Timestamp};
 use mongodb::options::{FindOneOptions, FindOptions};
@@ -61,6 +65,24 @@ pub fn get_next_batch(
     start_point: Timestamp,
     end_point: Option<Timestamp>,
     size: usize,
+) -> Result<Vec<Document>> {
+    get_next_batch_with_limits(
+        oplog_coll,
+        start_point,
+        end_point,
+        &BatchLimits::new(size, usize::MAX)?,
+    )
+}
+
+/// get next oplog batch in given collection, the returned batch will not excess `limits`.
+///
+/// It's the same as [get_next_batch], except that the batch stops when encoded bytes of oplogs reach
+/// `limits.max_bytes`, and the batch contains at least one oplog.
+pub fn get_next_batch_with_limits(
+    oplog_coll: &Collection<Document>,
+    start_point: Timestamp,
+    end_point: Option<Timestamp>,
+    limits: &BatchLimits,
 ) -> Result<Vec<Document>> {
     let mut filter = doc! {"ts": {"$gt": start_point}};
     if let Some(end_ts) = end_point {
@@ -71,15 +93,20 @@ pub fn get_next_batch(
     }
 
     let mut result = vec![];
+    let mut bytes = 0;
     for doc in oplog_coll.find(
         filter,
         FindOptions::builder()
             .sort(doc! {TIMESTAMP_KEY: 1})
-            .limit(size as i64)
+            .limit(limits.max_count as i64)
             .build(),
     )? {
         let d = doc?;
+        bytes += encoded_size(&d);
         result.push(d);
+        if bytes >= limits.max_bytes {
+            break;
+        }
     }
     Ok(result)
 }
@@ -120,25 +147,258 @@ pub fn filter_oplogs(
     oplogs
         .into_iter()
         .filter(|one_log| {
-            let (log_db_name, log_coll_name) = one_log
-                .get_str(NAMESPACE_KEY)
-                .expect("oplog should contains `ns` key")
-                .split_once(".")
-                .expect("`ns` value should be split by '.'");
-            if log_db_name == db_name {
-                match valid_colls {
-                    Some(valid_colls) => {
-                        (one_log
-                            .get_str(OP_KEY)
-                            .expect("oplog should contains `op` field")
-                            == COMMAND_OP)
-                            || valid_colls.contains(log_coll_name)
+            let (log_db_name, _) = split_ns(one_log);
+            log_db_name == db_name && is_valid_coll(one_log, valid_colls)
+        })
+        .collect()
+}
+
+/// filter `oplogs` which database of namespace is inside `valid_dbs`, and collection of namespace is selected
+/// by `selector`.
+///
+/// Unlike [filter_oplogs], command oplogs are filtered by the collection they work on too: `create` of
+/// views and commands on unselected collections are removed, renaming a selected collection to an unselected
+/// one becomes a `drop`, renaming an unselected collection to a selected one is removed, which should be
+/// handled by copying the renamed collection, see [renamed_into_selected].
+///
+/// # Example
+/// ```rust
+/// use bson::doc;
+/// use std::collections::HashSet;
+/// use mongo_sync::CollSelector;
+/// use mongo_sync::blocking::mongo_syncer::oplog_helper;
+///
+/// let valid_dbs: HashSet<String> = vec!["a".to_string()].into_iter().collect();
+/// let selector = CollSelector::new().with_exclude("tmp_*");
+/// let oplogs = vec![
+///     doc!{"ns": "a.b", "op": "i"},
+///     doc!{"ns": "a.tmp_b", "op": "i"},
+///     doc!{"ns": "c.b", "op": "i"},
+///     doc!{"ns": "a.$cmd", "op": "c", "o": {"create": "tmp_c"}},
+///     doc!{"ns": "a.$cmd", "op": "c", "o": {"renameCollection": "a.b", "to": "a.tmp_b"}},
+/// ];
+/// let oplogs = oplog_helper::filter_oplogs_in_dbs(oplogs, &valid_dbs, &selector);
+/// assert_eq!(oplogs, vec![doc!{"ns": "a.b", "op": "i"}, doc!{"ns": "a.$cmd", "op": "c", "o": {"drop": "b"}}]);
+/// ```
+pub fn filter_oplogs_in_dbs(
+    oplogs: Vec<Document>,
+    valid_dbs: &HashSet<String>,
+    selector: &CollSelector,
+) -> Vec<Document> {
+    let is_selected =
+        |db_name: &str, coll_name: &str| valid_dbs.contains(db_name) && selector.matches(coll_name);
+    oplogs
+        .into_iter()
+        .filter_map(|mut one_log| {
+            let (log_db_name, log_coll_name) = split_ns(&one_log);
+            if !valid_dbs.contains(log_db_name) {
+                return None;
+            }
+            if one_log.get_str(OP_KEY) != Ok(COMMAND_OP) {
+                return if selector.matches(log_coll_name) {
+                    Some(one_log)
+                } else {
+                    None
+                };
+            }
+
+            let obj = match one_log.get_document("o") {
+                Ok(obj) => obj,
+                Err(_) => return Some(one_log),
+            };
+            if let (Ok(from), Ok(to)) = (obj.get_str("renameCollection"), obj.get_str("to")) {
+                let from = from.split_once('.').unwrap_or((log_db_name, from));
+                let to = to.split_once('.').unwrap_or((log_db_name, to));
+                return match (is_selected(from.0, from.1), is_selected(to.0, to.1)) {
+                    (true, true) => Some(one_log),
+                    // the collection leaves sync set, so it's dropped from target.
+                    (true, false) => {
+                        let (from_db, from_coll) = (from.0.to_string(), from.1.to_string());
+                        one_log.insert(NAMESPACE_KEY, format!("{}.$cmd", from_db));
+                        one_log.insert("o", doc! {"drop": from_coll});
+                        Some(one_log)
                     }
-                    None => true,
-                }
+                    _ => None,
+                };
+            }
+            if obj.contains_key("create") && obj.contains_key("viewOn") {
+                return None;
+            }
+            match command_ns(&one_log) {
+                Some((db_name, coll_name)) if !is_selected(db_name, coll_name) => None,
+                _ => Some(one_log),
+            }
+        })
+        .collect()
+}
+
+/// get database and collection name of the collection which is renamed into selected collections by
+/// `one_log`, from an unselected collection.
+///
+/// Documents of such collection are not synced before, so it needs to be copied from source.
+///
+/// # Example
+/// ```rust
+/// use bson::doc;
+/// use std::collections::HashSet;
+/// use mongo_sync::CollSelector;
+/// use mongo_sync::blocking::mongo_syncer::oplog_helper;
+///
+/// let valid_dbs: HashSet<String> = vec!["a".to_string()].into_iter().collect();
+/// let selector = CollSelector::new().with_exclude("tmp_*");
+/// let one_log = doc!{"ns": "a.$cmd", "op": "c", "o": {"renameCollection": "a.tmp_b", "to": "a.b"}};
+/// assert_eq!(
+///     oplog_helper::renamed_into_selected(&one_log, &valid_dbs, &selector),
+///     Some(("a".to_string(), "b".to_string()))
+/// );
+/// ```
+pub fn renamed_into_selected(
+    one_log: &Document,
+    valid_dbs: &HashSet<String>,
+    selector: &CollSelector,
+) -> Option<(String, String)> {
+    if one_log.get_str(OP_KEY) != Ok(COMMAND_OP) {
+        return None;
+    }
+    let obj = one_log.get_document("o").ok()?;
+    let (from_db, from_coll) = obj.get_str("renameCollection").ok()?.split_once('.')?;
+    let (to_db, to_coll) = obj.get_str("to").ok()?.split_once('.')?;
+    let from_selected = valid_dbs.contains(from_db) && selector.matches(from_coll);
+    let to_selected = valid_dbs.contains(to_db) && selector.matches(to_coll);
+    if !from_selected && to_selected {
+        Some((to_db.to_string(), to_coll.to_string()))
+    } else {
+        None
+    }
+}
+
+/// split namespace of `one_log` into database name and collection name.
+fn split_ns(one_log: &Document) -> (&str, &str) {
+    one_log
+        .get_str(NAMESPACE_KEY)
+        .expect("oplog should contains `ns` key")
+        .split_once(".")
+        .expect("`ns` value should be split by '.'")
+}
+
+/// check if collection of `one_log` is inside `valid_colls`, command oplogs are always valid.
+fn is_valid_coll(one_log: &Document, valid_colls: &Option<HashSet<String>>) -> bool {
+    match valid_colls {
+        Some(valid_colls) => {
+            (one_log
+                .get_str(OP_KEY)
+                .expect("oplog should contains `op` field")
+                == COMMAND_OP)
+                || valid_colls.contains(split_ns(one_log).1)
+        }
+        None => true,
+    }
+}
+
+/// filter `oplogs` like [filter_oplogs], and also ignore oplogs on collections which are written by `mapping`.
+///
+/// When source and target are the same cluster, writes made by syncer itself are recorded in source oplogs
+/// too, they must not be synced again.
+///
+/// # Example
+/// ```rust
+/// use bson::doc;
+/// use mongo_sync::NamespaceMapping;
+/// use mongo_sync::blocking::mongo_syncer::oplog_helper;
+///
+/// let mapping = NamespaceMapping::new().with_coll("a.b", "a.b_copy");
+/// let oplogs = vec![
+///     doc!{"ns": "a.b", "op": "i"},
+///     doc!{"ns": "a.b_copy", "op": "i"},
+///     doc!{"ns": "a.$cmd", "op": "c", "o": {"create": "b_copy"}},
+/// ];
+/// let oplogs = oplog_helper::filter_oplogs_with_mapping(oplogs, "a", &None, &mapping);
+/// assert_eq!(oplogs, vec![doc!{"ns": "a.b", "op": "i"}]);
+/// ```
+pub fn filter_oplogs_with_mapping(
+    oplogs: Vec<Document>,
+    db_name: &str,
+    valid_colls: &Option<HashSet<String>>,
+    mapping: &NamespaceMapping,
+) -> Vec<Document> {
+    ignore_mapped_targets(filter_oplogs(oplogs, db_name, valid_colls), mapping)
+}
+
+/// ignore `oplogs` on collections which are written by `mapping`.
+pub fn ignore_mapped_targets(oplogs: Vec<Document>, mapping: &NamespaceMapping) -> Vec<Document> {
+    oplogs
+        .into_iter()
+        .filter(|one_log| {
+            let ns = if one_log.get_str(OP_KEY) == Ok(COMMAND_OP) {
+                command_ns(one_log)
             } else {
-                false
+                Some(split_ns(one_log))
+            };
+            match ns {
+                Some((db_name, coll_name)) => !mapping.is_target(db_name, coll_name),
+                None => true,
             }
         })
         .collect()
 }
+
+/// split `oplogs` into oplogs which work on collection namespaces inside `namespaces`, and other oplogs.
+///
+/// A command oplog works on the collection of its command, renaming works on both collections.
+///
+/// # Example
+/// ```rust
+/// use bson::doc;
+/// use std::collections::HashSet;
+/// use mongo_sync::blocking::mongo_syncer::oplog_helper;
+///
+/// let namespaces: HashSet<String> = vec!["a.b".to_string()].into_iter().collect();
+/// let oplogs = vec![
+///     doc!{"ns": "a.b", "op": "i"},
+///     doc!{"ns": "a.c", "op": "i"},
+///     doc!{"ns": "a.$cmd", "op": "c", "o": {"renameCollection": "a.b", "to": "a.d"}},
+/// ];
+/// let (on_namespaces, others) = oplog_helper::partition_by_namespaces(oplogs, &namespaces);
+/// assert_eq!(on_namespaces.len(), 2);
+/// assert_eq!(others, vec![doc!{"ns": "a.c", "op": "i"}]);
+/// ```
+pub fn partition_by_namespaces(
+    oplogs: Vec<Document>,
+    namespaces: &HashSet<String>,
+) -> (Vec<Document>, Vec<Document>) {
+    oplogs.into_iter().partition(|one_log| {
+        if one_log.get_str(OP_KEY) != Ok(COMMAND_OP) {
+            return namespaces.contains(one_log.get_str(NAMESPACE_KEY).unwrap_or_default());
+        }
+        let renamed_from = one_log
+            .get_document("o")
+            .and_then(|obj| obj.get_str("renameCollection"));
+        if let Ok(from) = renamed_from {
+            if namespaces.contains(from) {
+                return true;
+            }
+        }
+        match command_ns(one_log) {
+            Some((db_name, coll_name)) => {
+                namespaces.contains(&format!("{}.{}", db_name, coll_name))
+            }
+            None => false,
+        }
+    })
+}
+
+/// get database and collection name which command oplog `one_log` works on.
+///
+/// The collection name is the value of command name, e.g: `{"drop": "coll"}`, for rename command, it's the
+/// collection which is renamed to.
+fn command_ns(one_log: &Document) -> Option<(&str, &str)> {
+    let obj = one_log.get_document("o").ok()?;
+    if obj.contains_key("renameCollection") {
+        return obj.get_str("to").ok().and_then(|ns| ns.split_once('.'));
+    }
+    let (db_name, _) = split_ns(one_log);
+    obj.iter()
+        .next()
+        .and_then(|(_, v)| v.as_str())
+        .map(|coll| (db_name, coll))
+}
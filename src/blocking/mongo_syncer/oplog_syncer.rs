@@ -1,3 +1,4 @@
+use super::cancel::CancelToken;
 use super::oplog_helper;
 use crate::{
     Result, SyncError, LOG_STORAGE_COLL, LOG_STORAGE_DB, NAMESPACE_KEY, NOOP_OP, OPLOG_COLL,
@@ -16,6 +17,7 @@ use tracing::info;
 pub struct OplogSyncer {
     source_conn: Client,
     storage_conn: Client,
+    cancel: CancelToken,
 }
 
 const TRUNCATE_POINT_COLL: &str = "oplog_truncate_after_point";
@@ -34,9 +36,20 @@ impl OplogSyncer {
         Ok(OplogSyncer {
             source_conn,
             storage_conn,
+            cancel: CancelToken::new(),
         })
     }
 
+    /// stop syncing when `cancel` is cancelled.
+    ///
+    /// Batched oplogs are written with truncate after point, then [sync_forever](OplogSyncer::sync_forever)
+    /// returns [SyncError::Cancelled].  The handle is checked when an oplog is fetched, source cluster writes
+    /// a noop oplog every 10 seconds, so it takes at most about 10 seconds to stop.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
     /// Start the syncer, it will run forever to sync oplogs.
     pub fn sync_forever(self) -> Result<()> {
         let storage_latest_ts_may_exists = self.get_storage_latest_ts()?;
@@ -113,34 +126,53 @@ impl OplogSyncer {
                 oplog_batched.push(doc);
             }
 
+            if self.cancel.is_cancelled() {
+                // batched oplogs are written, so next sync doesn't fetch them again.
+                self.write_batch(&log_storage_coll, &truncate_point_coll, &mut oplog_batched)?;
+                info!("Oplog syncer is cancelled, stop. ");
+                return Err(SyncError::Cancelled);
+            }
             if (oplog_batched.len() > BATCH_SIZE)
                 || (now.elapsed().unwrap().as_secs() >= BATCH_DELAY && !oplog_batched.is_empty())
             {
-                let earliest_ts = oplog_batched[0].get_timestamp(TIMESTAMP_KEY)?;
-                let latest_ts =
-                    oplog_batched[oplog_batched.len() - 1].get_timestamp(TIMESTAMP_KEY)?;
-
-                let mut data_to_write: Vec<Document> = Vec::with_capacity(oplog_batched.len());
-                data_to_write.append(&mut oplog_batched);
-                info!(
-                    "begin to insert oplogs, oplog length: {}",
-                    data_to_write.len()
-                );
-                log_storage_coll.insert_many(data_to_write, None)?;
-
-                info!(?earliest_ts, ?latest_ts, "Sync oplog complete. ");
-                self.save_latest_ts(&truncate_point_coll, latest_ts)?;
-                info!(
-                    ?earliest_ts,
-                    ?latest_ts,
-                    "Write truncate after point complete. "
-                );
+                self.write_batch(&log_storage_coll, &truncate_point_coll, &mut oplog_batched)?;
                 now = SystemTime::now();
             }
         }
         Ok(())
     }
 
+    /// write `oplog_batched` into storage, and save the latest timestamp as truncate after point.
+    fn write_batch(
+        &self,
+        log_storage_coll: &Collection<Document>,
+        truncate_point_coll: &Collection<Document>,
+        oplog_batched: &mut Vec<Document>,
+    ) -> Result<()> {
+        if oplog_batched.is_empty() {
+            return Ok(());
+        }
+        let earliest_ts = oplog_batched[0].get_timestamp(TIMESTAMP_KEY)?;
+        let latest_ts = oplog_batched[oplog_batched.len() - 1].get_timestamp(TIMESTAMP_KEY)?;
+
+        let mut data_to_write: Vec<Document> = Vec::with_capacity(oplog_batched.len());
+        data_to_write.append(oplog_batched);
+        info!(
+            "begin to insert oplogs, oplog length: {}",
+            data_to_write.len()
+        );
+        log_storage_coll.insert_many(data_to_write, None)?;
+
+        info!(?earliest_ts, ?latest_ts, "Sync oplog complete. ");
+        self.save_latest_ts(truncate_point_coll, latest_ts)?;
+        info!(
+            ?earliest_ts,
+            ?latest_ts,
+            "Write truncate after point complete. "
+        );
+        Ok(())
+    }
+
     fn save_latest_ts(
         &self,
         oplog_truncate_after_point: &Collection<Document>,
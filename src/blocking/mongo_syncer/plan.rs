@@ -0,0 +1,285 @@
+//! Provide full sync plan, which is persisted in target database to make full sync resumable.
+//!
+//! The plan is saved in `full_sync_plan` collection, it contains one header document, and one document
+//! for each collection:
+//!
+//! ```text
+//! {"_id": "plan", "oplog_start": Timestamp, "colls": ["a", "b"]}
+//! {"_id": "coll.a", "done": false, "ranges": [{"min": .., "max": .., "last_id": .., "done": false}, ...]}
+//! ```
+//!
+//! `min` is inclusive and `max` is exclusive `_id` bound, null means unbounded.  `last_id` is the last `_id` copied
+//! in the range, documents are copied in `_id` order, so a restart can continue after `last_id`.
+
+use crate::Result;
+use bson::{doc, Bson, Document, Timestamp};
+use mongodb::options::UpdateOptions;
+use mongodb::sync::{Collection, Database};
+
+/// collection name which saves full sync plan.
+pub const PLAN_COLL: &str = "full_sync_plan";
+const HEADER_ID: &str = "plan";
+
+/// Header of full sync plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanHeader {
+    /// oplog start point of the full sync.
+    pub oplog_start: Timestamp,
+    /// collections to sync.
+    pub colls: Vec<String>,
+}
+
+/// Copy state of an `_id` range.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RangeState {
+    /// inclusive lower bound, None means unbounded.
+    pub min: Option<Bson>,
+    /// exclusive upper bound, None means unbounded.
+    pub max: Option<Bson>,
+    /// the last `_id` copied.
+    pub last_id: Option<Bson>,
+    /// is the range copied completely.
+    pub done: bool,
+}
+
+fn opt_bson(v: &Option<Bson>) -> Bson {
+    v.clone().unwrap_or(Bson::Null)
+}
+
+fn from_opt_bson(v: Option<&Bson>) -> Option<Bson> {
+    match v {
+        None | Some(Bson::Null) => None,
+        Some(v) => Some(v.clone()),
+    }
+}
+
+impl RangeState {
+    /// create a range which is not copied yet.
+    pub fn new(min: Option<Bson>, max: Option<Bson>) -> Self {
+        RangeState {
+            min,
+            max,
+            last_id: None,
+            done: false,
+        }
+    }
+
+    /// build `_id` filter for the documents which are not copied yet, returns None if all documents are needed.
+    pub fn remaining_filter(&self) -> Option<Document> {
+        let mut id_filter = Document::new();
+        match &self.last_id {
+            Some(last_id) => {
+                id_filter.insert("$gt", last_id.clone());
+            }
+            None => {
+                if let Some(min) = &self.min {
+                    id_filter.insert("$gte", min.clone());
+                }
+            }
+        }
+        if let Some(max) = &self.max {
+            id_filter.insert("$lt", max.clone());
+        }
+        if id_filter.is_empty() {
+            None
+        } else {
+            Some(doc! {"_id": id_filter})
+        }
+    }
+
+    fn to_document(&self) -> Document {
+        doc! {
+            "min": opt_bson(&self.min),
+            "max": opt_bson(&self.max),
+            "last_id": opt_bson(&self.last_id),
+            "done": self.done,
+        }
+    }
+
+    fn from_document(d: &Document) -> Self {
+        RangeState {
+            min: from_opt_bson(d.get("min")),
+            max: from_opt_bson(d.get("max")),
+            last_id: from_opt_bson(d.get("last_id")),
+            done: d.get_bool("done").unwrap_or(false),
+        }
+    }
+}
+
+/// Copy state of a collection.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CollState {
+    /// ranges of the collection, empty means that ranges are not planned yet.
+    pub ranges: Vec<RangeState>,
+    /// is the collection copied completely.
+    pub done: bool,
+}
+
+/// Full sync plan, which is persisted in target database.
+#[derive(Debug, Clone)]
+pub struct FullSyncPlan {
+    coll: Collection<Document>,
+}
+
+fn coll_key(coll: &str) -> String {
+    format!("coll.{}", coll)
+}
+
+impl FullSyncPlan {
+    /// create plan handler, the plan is saved in `target_db`.
+    pub fn new(target_db: &Database) -> Self {
+        FullSyncPlan {
+            coll: target_db.collection(PLAN_COLL),
+        }
+    }
+
+    /// get plan header, returns None if there is no unfinished full sync.
+    pub fn header(&self) -> Result<Option<PlanHeader>> {
+        match self.coll.find_one(doc! {"_id": HEADER_ID}, None)? {
+            None => Ok(None),
+            Some(d) => Ok(Some(PlanHeader {
+                oplog_start: d.get_timestamp("oplog_start")?,
+                colls: d
+                    .get_array("colls")?
+                    .iter()
+                    .filter_map(|c| c.as_str().map(|s| s.to_string()))
+                    .collect(),
+            })),
+        }
+    }
+
+    /// create a new plan, old plan will be removed.
+    pub fn create(&self, oplog_start: Timestamp, colls: &[String]) -> Result<()> {
+        self.remove()?;
+        self.coll.insert_one(
+            doc! {"_id": HEADER_ID, "oplog_start": oplog_start, "colls": colls},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// add collections `colls` to plan header, which are selected after the plan is created.
+    pub fn add_colls(&self, colls: &[String]) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": HEADER_ID},
+            doc! {"$addToSet": {"colls": {"$each": colls}}},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// get copy state of collection `coll`.
+    pub fn coll_state(&self, coll: &str) -> Result<Option<CollState>> {
+        match self.coll.find_one(doc! {"_id": coll_key(coll)}, None)? {
+            None => Ok(None),
+            Some(d) => Ok(Some(CollState {
+                ranges: d
+                    .get_array("ranges")?
+                    .iter()
+                    .filter_map(|r| r.as_document().map(RangeState::from_document))
+                    .collect(),
+                done: d.get_bool("done").unwrap_or(false),
+            })),
+        }
+    }
+
+    /// save `ranges` for collection `coll`, progress of the collection will be reset.
+    pub fn save_ranges(&self, coll: &str, ranges: &[RangeState]) -> Result<()> {
+        let ranges: Vec<Document> = ranges.iter().map(|r| r.to_document()).collect();
+        self.coll.update_one(
+            doc! {"_id": coll_key(coll)},
+            doc! {"$set": {"ranges": ranges, "done": false}},
+            UpdateOptions::builder().upsert(true).build(),
+        )?;
+        Ok(())
+    }
+
+    /// save `last_id` copied in the `idx`th range of collection `coll`.
+    pub fn save_progress(&self, coll: &str, idx: usize, last_id: &Bson) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": coll_key(coll)},
+            doc! {"$set": {format!("ranges.{}.last_id", idx): last_id.clone()}},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// append a new range to collection `coll`, it's used when a range is split.
+    pub fn add_range(&self, coll: &str, range: &RangeState) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": coll_key(coll)},
+            doc! {"$push": {"ranges": range.to_document()}},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// save new upper bound `max` of the `idx`th range of collection `coll`.
+    pub fn save_range_max(&self, coll: &str, idx: usize, max: &Bson) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": coll_key(coll)},
+            doc! {"$set": {format!("ranges.{}.max", idx): max.clone()}},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// mark the `idx`th range of collection `coll` as done.
+    pub fn finish_range(&self, coll: &str, idx: usize) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": coll_key(coll)},
+            doc! {"$set": {format!("ranges.{}.done", idx): true}},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// mark collection `coll` as done.
+    pub fn finish_coll(&self, coll: &str) -> Result<()> {
+        self.coll.update_one(
+            doc! {"_id": coll_key(coll)},
+            doc! {"$set": {"done": true}},
+            UpdateOptions::builder().upsert(true).build(),
+        )?;
+        Ok(())
+    }
+
+    /// remove the plan, it's invoked after full sync complete.
+    pub fn remove(&self) -> Result<()> {
+        self.coll.drop(None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_range_state_document_round_trip() {
+        let range = RangeState {
+            min: Some(Bson::Int32(1)),
+            max: None,
+            last_id: Some(Bson::Int32(5)),
+            done: false,
+        };
+        assert_eq!(RangeState::from_document(&range.to_document()), range);
+        assert_eq!(
+            range.to_document(),
+            doc! {"min": 1, "max": Bson::Null, "last_id": 5, "done": false}
+        );
+    }
+
+    #[test]
+    fn test_remaining_filter() {
+        assert_eq!(RangeState::new(None, None).remaining_filter(), None);
+        assert_eq!(
+            RangeState::new(Some(Bson::Int32(1)), Some(Bson::Int32(9))).remaining_filter(),
+            Some(doc! {"_id": {"$gte": 1, "$lt": 9}})
+        );
+
+        let mut range = RangeState::new(Some(Bson::Int32(1)), None);
+        range.last_id = Some(Bson::Int32(5));
+        assert_eq!(range.remaining_filter(), Some(doc! {"_id": {"$gt": 5}}));
+    }
+}
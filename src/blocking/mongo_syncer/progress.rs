@@ -0,0 +1,295 @@
+//! Provide full sync progress tracker.
+//!
+//! The tracker is shared by all workers which copy documents, it counts documents and bytes copied for each
+//! collection and range, and can be read by embedding applications through [MongoSyncer::progress](crate::MongoSyncer::progress).
+
+use super::full::CopyStats;
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Copy progress of an `_id` range.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RangeProgress {
+    /// how many data is copied in the range.
+    pub copied: CopyStats,
+    /// is the range copied completely.
+    pub done: bool,
+}
+
+/// Copy progress of a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollProgress {
+    /// collection namespace, `db.coll`.
+    pub name: String,
+    /// estimated document count of source collection when the copy starts.
+    pub estimated_docs: u64,
+    /// how many data is copied in the collection.
+    pub copied: CopyStats,
+    /// progress of each range, indexed by range index in full sync plan.
+    pub ranges: Vec<RangeProgress>,
+    /// is the collection copied completely.
+    pub done: bool,
+}
+
+impl CollProgress {
+    /// estimated documents which are not copied yet.
+    pub fn remaining_docs(&self) -> u64 {
+        if self.done {
+            0
+        } else {
+            self.estimated_docs.saturating_sub(self.copied.docs)
+        }
+    }
+}
+
+/// Point-in-time view of full sync progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressSnapshot {
+    /// progress of each collection, in the order that they are started.
+    pub collections: Vec<CollProgress>,
+    /// time elapsed since the first collection is started.
+    pub elapsed: Duration,
+}
+
+impl ProgressSnapshot {
+    /// return total documents and bytes copied.
+    pub fn total(&self) -> CopyStats {
+        let mut total = CopyStats::default();
+        for c in self.collections.iter() {
+            total += c.copied;
+        }
+        total
+    }
+
+    /// return estimated documents which are not copied yet.
+    pub fn remaining_docs(&self) -> u64 {
+        self.collections.iter().map(|c| c.remaining_docs()).sum()
+    }
+
+    /// average documents copied per second.
+    pub fn docs_per_sec(&self) -> f64 {
+        rate(self.total().docs, self.elapsed)
+    }
+
+    /// average bytes copied per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        rate(self.total().bytes, self.elapsed)
+    }
+
+    /// estimated time to copy remaining documents, returns None if nothing is copied yet.
+    pub fn eta(&self) -> Option<Duration> {
+        let docs_per_sec = self.docs_per_sec();
+        if docs_per_sec <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            self.remaining_docs() as f64 / docs_per_sec,
+        ))
+    }
+}
+
+fn rate(amount: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        0.0
+    } else {
+        amount as f64 / secs
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProgressInner {
+    started: Option<Instant>,
+    collections: Vec<CollProgress>,
+}
+
+impl ProgressInner {
+    fn coll_mut(&mut self, name: &str) -> Option<&mut CollProgress> {
+        self.collections.iter_mut().find(|c| c.name == name)
+    }
+}
+
+/// Full sync progress tracker, cloned trackers share the same progress.
+#[derive(Debug, Clone, Default)]
+pub struct FullSyncProgress {
+    inner: Arc<Mutex<ProgressInner>>,
+}
+
+impl FullSyncProgress {
+    /// create an empty progress tracker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ProgressInner> {
+        self.inner.lock().expect("progress lock poisoned")
+    }
+
+    /// start tracking collection `name`, which contains about `estimated_docs` documents.
+    pub fn start_coll(&self, name: &str, estimated_docs: u64) {
+        let mut inner = self.lock();
+        inner.started.get_or_insert_with(Instant::now);
+        let progress = CollProgress {
+            name: name.to_string(),
+            estimated_docs,
+            copied: CopyStats::default(),
+            ranges: vec![],
+            done: false,
+        };
+        match inner.coll_mut(name) {
+            Some(coll) => *coll = progress,
+            None => inner.collections.push(progress),
+        }
+    }
+
+    /// record `stats` copied in the `range_idx`th range of collection `name`.
+    pub fn add_copied(&self, name: &str, range_idx: usize, stats: CopyStats) {
+        let mut inner = self.lock();
+        if let Some(coll) = inner.coll_mut(name) {
+            coll.copied += stats;
+            if coll.ranges.len() <= range_idx {
+                coll.ranges.resize(range_idx + 1, RangeProgress::default());
+            }
+            coll.ranges[range_idx].copied += stats;
+        }
+    }
+
+    /// mark the `range_idx`th range of collection `name` as done.
+    pub fn finish_range(&self, name: &str, range_idx: usize) {
+        let mut inner = self.lock();
+        if let Some(coll) = inner.coll_mut(name) {
+            if coll.ranges.len() <= range_idx {
+                coll.ranges.resize(range_idx + 1, RangeProgress::default());
+            }
+            coll.ranges[range_idx].done = true;
+        }
+    }
+
+    /// mark collection `name` as done.
+    pub fn finish_coll(&self, name: &str) {
+        if let Some(coll) = self.lock().coll_mut(name) {
+            coll.done = true;
+        }
+    }
+
+    /// get current progress.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let inner = self.lock();
+        ProgressSnapshot {
+            collections: inner.collections.clone(),
+            elapsed: inner.started.map(|s| s.elapsed()).unwrap_or_default(),
+        }
+    }
+
+    /// log progress summary every `interval` in a background thread, until the returned logger is dropped.
+    pub fn log_periodically(&self, interval: Duration) -> ProgressLogger {
+        let (stop_sender, stop_receiver) = channel::bounded::<()>(0);
+        let progress = self.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                progress.log_summary();
+            }
+        });
+        ProgressLogger {
+            stop_sender: Some(stop_sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// log progress summary once.
+    pub fn log_summary(&self) {
+        let snapshot = self.snapshot();
+        let total = snapshot.total();
+        info!(
+            docs = total.docs,
+            bytes = total.bytes,
+            remaining_docs = snapshot.remaining_docs(),
+            docs_per_sec = snapshot.docs_per_sec() as u64,
+            bytes_per_sec = snapshot.bytes_per_sec() as u64,
+            eta_secs = ?snapshot.eta().map(|d| d.as_secs()),
+            "Full state: progress. "
+        );
+        for coll in snapshot.collections.iter().filter(|c| !c.done) {
+            info!(
+                collection_name=%coll.name,
+                docs = coll.copied.docs,
+                estimated_docs = coll.estimated_docs,
+                ranges_done = coll.ranges.iter().filter(|r| r.done).count(),
+                ranges = coll.ranges.len(),
+                "Full state: collection progress. "
+            );
+        }
+    }
+}
+
+/// Background progress logger, which is created by [FullSyncProgress::log_periodically].
+///
+/// The logger stops when it's dropped.
+pub struct ProgressLogger {
+    stop_sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for ProgressLogger {
+    fn drop(&mut self) {
+        // drop sender to wake up the logger thread.
+        self.stop_sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_progress_counts_and_eta() {
+        let progress = FullSyncProgress::new();
+        progress.start_coll("a", 100);
+        progress.start_coll("b", 10);
+        progress.add_copied(
+            "a",
+            1,
+            CopyStats {
+                docs: 40,
+                bytes: 400,
+            },
+        );
+        progress.add_copied(
+            "b",
+            0,
+            CopyStats {
+                docs: 10,
+                bytes: 50,
+            },
+        );
+        progress.finish_range("a", 1);
+        progress.finish_coll("b");
+
+        let mut snapshot = progress.snapshot();
+        assert_eq!(
+            snapshot.total(),
+            CopyStats {
+                docs: 50,
+                bytes: 450
+            }
+        );
+        assert_eq!(snapshot.remaining_docs(), 60);
+        let a = &snapshot.collections[0];
+        assert_eq!(a.ranges.len(), 2);
+        assert!(!a.ranges[0].done);
+        assert!(a.ranges[1].done);
+        assert_eq!(a.ranges[1].copied.docs, 40);
+
+        snapshot.elapsed = Duration::from_secs(5);
+        assert_eq!(snapshot.docs_per_sec(), 10.0);
+        assert_eq!(snapshot.eta(), Some(Duration::from_secs(6)));
+        snapshot.elapsed = Duration::default();
+        assert_eq!(snapshot.eta(), None);
+    }
+}
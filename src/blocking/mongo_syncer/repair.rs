@@ -0,0 +1,214 @@
+//! Provide targeted repair, which copies given documents from source into target again while incremental
+//! sync keeps running.
+//!
+//! A repaired document is read from source after the latest source oplog timestamp is taken, so target has
+//! every change of the document until the timestamp.  Stored oplogs of the document before the timestamp
+//! would overwrite it with an older version, so a fence is saved in `repair_fences` collection:
+//!
+//! ```text
+//! {"ns": "db.coll", "doc_id": {"_id": id}, "ts": Timestamp}
+//! ```
+//!
+//! Oplogs of the document at or before `ts` are skipped, and the fence is released after check point passes
+//! `ts`.
+
+use super::bson_helper::id_key;
+use crate::{Result, SyncError, NAMESPACE_KEY, OP_KEY, TIMESTAMP_KEY};
+use bson::{doc, Bson, Document, Timestamp};
+use mongodb::sync::Collection;
+use std::collections::HashMap;
+
+/// collection name which saves fences of repaired documents.
+pub const REPAIR_FENCE_COLL: &str = "repair_fences";
+
+/// Document to repair, which is identified by source namespace and `_id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepairDoc {
+    /// source namespace, `db.coll`.
+    pub ns: String,
+    /// `_id` of the document.
+    pub id: Bson,
+}
+
+impl RepairDoc {
+    /// create document to repair in namespace `ns` with `_id` value `id`.
+    pub fn new(ns: String, id: Bson) -> Self {
+        RepairDoc { ns, id }
+    }
+
+    /// parse document to repair from `d`, which is `{"ns": "db.coll", "_id": id}`.
+    pub fn from_document(d: &Document) -> Result<Self> {
+        let invalid = |detail: &str| SyncError::InvalidRepairDoc {
+            detail: format!("{}: {}", d, detail),
+        };
+        let ns = d
+            .get_str("ns")
+            .map_err(|_| invalid("`ns` field is required"))?;
+        if !ns.contains('.') {
+            return Err(invalid("`ns` should be `db.coll`"));
+        }
+        let id = d
+            .get("_id")
+            .ok_or_else(|| invalid("`_id` field is required"))?;
+        Ok(RepairDoc::new(ns.to_string(), id.clone()))
+    }
+
+    /// convert to `{"ns": "db.coll", "_id": id}`.
+    pub fn to_document(&self) -> Document {
+        doc! {"ns": &self.ns, "_id": &self.id}
+    }
+
+    /// get database and collection name of the document.
+    pub fn split_ns(&self) -> (&str, &str) {
+        self.ns
+            .split_once('.')
+            .expect("namespace of repaired document should be split by '.'")
+    }
+}
+
+/// Fences of repaired documents, keyed by namespace and `_id`.
+#[derive(Debug, Clone, Default)]
+pub struct FenceSet {
+    fences: HashMap<(String, Vec<u8>), Timestamp>,
+}
+
+impl FenceSet {
+    /// fence document `doc` at `ts`, oplogs of it at or before `ts` are skipped.
+    pub fn insert(&mut self, doc: &RepairDoc, ts: Timestamp) {
+        let fence = self
+            .fences
+            .entry((doc.ns.clone(), id_key(&doc.id)))
+            .or_insert(ts);
+        *fence = (*fence).max(ts);
+    }
+
+    /// remove oplogs which are fenced from `oplogs`.
+    pub fn filter_oplogs(&self, oplogs: Vec<Document>) -> Vec<Document> {
+        if self.fences.is_empty() {
+            return oplogs;
+        }
+        oplogs
+            .into_iter()
+            .filter(|one_log| !self.is_fenced(one_log))
+            .collect()
+    }
+
+    fn is_fenced(&self, one_log: &Document) -> bool {
+        let id = match one_log.get_str(OP_KEY) {
+            Ok("i") | Ok("d") => one_log.get_document("o"),
+            Ok("u") => one_log.get_document("o2"),
+            _ => return false,
+        }
+        .ok()
+        .and_then(|d| d.get("_id"));
+        let (ns, id, ts) = match (
+            one_log.get_str(NAMESPACE_KEY),
+            id,
+            one_log.get_timestamp(TIMESTAMP_KEY),
+        ) {
+            (Ok(ns), Some(id), Ok(ts)) => (ns, id, ts),
+            _ => return false,
+        };
+        let fence = self.fences.get(&(ns.to_string(), id_key(id)));
+        matches!(fence, Some(fence) if ts <= *fence)
+    }
+
+    /// release fences which `checkpoint` passes, returns true if any is released.
+    pub fn release(&mut self, checkpoint: Timestamp) -> bool {
+        let len = self.fences.len();
+        self.fences.retain(|_, ts| *ts > checkpoint);
+        self.fences.len() != len
+    }
+
+    /// get how many documents are fenced.
+    pub fn len(&self) -> usize {
+        self.fences.len()
+    }
+
+    /// return true if no document is fenced.
+    pub fn is_empty(&self) -> bool {
+        self.fences.is_empty()
+    }
+}
+
+/// Fences of repaired documents, which are persisted in target database.
+#[derive(Debug, Clone)]
+pub struct RepairFences {
+    coll: Collection<Document>,
+}
+
+impl RepairFences {
+    /// create fences handler, they are saved in `coll`.
+    pub fn new(coll: Collection<Document>) -> Self {
+        RepairFences { coll }
+    }
+
+    /// get all fences.
+    pub fn list(&self) -> Result<FenceSet> {
+        let mut result = FenceSet::default();
+        for d in self.coll.find(None, None)? {
+            let d = d?;
+            let doc = RepairDoc::new(
+                d.get_str("ns")?.to_string(),
+                d.get_document("doc_id")?
+                    .get("_id")
+                    .cloned()
+                    .unwrap_or(Bson::Null),
+            );
+            result.insert(&doc, d.get_timestamp("ts")?);
+        }
+        Ok(result)
+    }
+
+    /// save fence of document `doc` at `ts`.
+    pub fn save(&self, doc: &RepairDoc, ts: Timestamp) -> Result<()> {
+        self.coll.insert_one(
+            doc! {"ns": &doc.ns, "doc_id": {"_id": &doc.id}, "ts": ts},
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// remove fences which `checkpoint` passes.
+    pub fn release(&self, checkpoint: Timestamp) -> Result<()> {
+        self.coll
+            .delete_many(doc! {"ts": {"$lte": checkpoint}}, None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filter_fenced_oplogs() {
+        let ts = |time| Timestamp { time, increment: 0 };
+        let mut fences = FenceSet::default();
+        fences.insert(&RepairDoc::new("a.b".to_string(), Bson::Int32(1)), ts(5));
+        let oplogs = vec![
+            doc! {"ts": ts(4), "op": "u", "ns": "a.b", "o2": {"_id": 1}, "o": {"$set": {"x": 1}}},
+            doc! {"ts": ts(4), "op": "i", "ns": "a.b", "o": {"_id": 2}},
+            doc! {"ts": ts(4), "op": "i", "ns": "a.c", "o": {"_id": 1}},
+            doc! {"ts": ts(5), "op": "d", "ns": "a.b", "o": {"_id": 1}},
+            doc! {"ts": ts(6), "op": "d", "ns": "a.b", "o": {"_id": 1}},
+        ];
+        let oplogs = fences.filter_oplogs(oplogs);
+        assert_eq!(oplogs.len(), 3);
+        assert_eq!(oplogs[2].get_timestamp("ts").unwrap(), ts(6));
+
+        assert!(!fences.release(ts(4)));
+        assert_eq!(fences.len(), 1);
+        assert!(fences.release(ts(5)));
+        assert!(fences.is_empty());
+    }
+
+    #[test]
+    fn test_parse_repair_doc() {
+        let doc = RepairDoc::from_document(&doc! {"ns": "a.b.c", "_id": 1}).unwrap();
+        assert_eq!(doc.split_ns(), ("a", "b.c"));
+        assert_eq!(doc.to_document(), doc! {"ns": "a.b.c", "_id": 1});
+        assert!(RepairDoc::from_document(&doc! {"ns": "a", "_id": 1}).is_err());
+        assert!(RepairDoc::from_document(&doc! {"ns": "a.b"}).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Provide summary report for one-shot copy.
+
+use super::full::CopyStats;
+use crate::SyncError;
+use std::fmt;
+use std::time::Duration;
+
+/// Sync result of one collection.
+#[derive(Debug)]
+pub struct CollSyncReport {
+    /// collection name.
+    pub name: String,
+    /// how many data is copied.
+    pub stats: CopyStats,
+    /// error occurred during sync the collection, None means the collection is synced successfully.
+    pub error: Option<SyncError>,
+}
+
+impl CollSyncReport {
+    /// create a report for collection `name` which is not synced yet.
+    pub fn new(name: String) -> Self {
+        CollSyncReport {
+            name,
+            stats: CopyStats::default(),
+            error: None,
+        }
+    }
+}
+
+/// Summary report of one-shot copy, which is returned by [MongoSyncer::sync_once](crate::MongoSyncer::sync_once).
+#[derive(Debug)]
+pub struct SyncReport {
+    /// report for each collection.
+    pub collections: Vec<CollSyncReport>,
+    /// how long does the copy take.
+    pub duration: Duration,
+}
+
+impl SyncReport {
+    /// return true if all collections are synced successfully.
+    pub fn is_success(&self) -> bool {
+        self.collections.iter().all(|c| c.error.is_none())
+    }
+
+    /// return total documents and bytes copied.
+    pub fn total(&self) -> CopyStats {
+        let mut total = CopyStats::default();
+        for c in self.collections.iter() {
+            total += c.stats;
+        }
+        total
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>12} {:>16}  STATUS",
+            "COLLECTION", "DOCS", "BYTES"
+        )?;
+        for c in self.collections.iter() {
+            let status = match &c.error {
+                None => "ok".to_string(),
+                Some(e) => match std::error::Error::source(e) {
+                    Some(source) => format!("failed: {}: {}", e, source),
+                    None => format!("failed: {}", e),
+                },
+            };
+            writeln!(
+                f,
+                "{:<40} {:>12} {:>16}  {}",
+                c.name, c.stats.docs, c.stats.bytes, status
+            )?;
+        }
+        let total = self.total();
+        let failed = self
+            .collections
+            .iter()
+            .filter(|c| c.error.is_some())
+            .count();
+        write!(
+            f,
+            "Total: {} collections ({} failed), {} docs, {} bytes, takes {:.2}s",
+            self.collections.len(),
+            failed,
+            total.docs,
+            total.bytes,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report_total_and_success() {
+        let mut a = CollSyncReport::new("a".to_string());
+        a.stats = CopyStats { docs: 3, bytes: 30 };
+        let mut b = CollSyncReport::new("b".to_string());
+        b.stats = CopyStats { docs: 2, bytes: 10 };
+        let mut report = SyncReport {
+            collections: vec![a, b],
+            duration: Duration::from_secs(1),
+        };
+        assert!(report.is_success());
+        assert_eq!(report.total(), CopyStats { docs: 5, bytes: 40 });
+
+        report.collections[1].error = Some(SyncError::EmptyDocError);
+        assert!(!report.is_success());
+    }
+}
@@ -0,0 +1,315 @@
+//! Provide point-in-time restore, which replays stored oplogs onto a base copy until a restore point.
+//!
+//! The base is either target database which is synced before, oplogs are replayed from its check point, or
+//! a mongodump directory which is loaded into target first.  A dump made by `mongodump --oplog` is
+//! consistent after `oplog.bson` is applied, so oplogs are replayed after the last one inside it, otherwise
+//! the timestamp when the dump starts needs to be given.
+
+use crate::{Result, SyncError, OP_KEY, TIMESTAMP_KEY};
+use bson::{Document, Timestamp};
+use chrono::DateTime;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// file name of oplogs which are dumped by `mongodump --oplog`.
+pub const DUMP_OPLOG_FILE: &str = "oplog.bson";
+
+/// Base copy to restore from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreBase {
+    /// target database which is synced before, oplogs are replayed from its check point.
+    Target,
+    /// mongodump directory `dir`, oplogs are replayed after the last oplog of `oplog.bson`, or from `start`
+    /// if the dump has no oplogs.
+    Dump {
+        dir: PathBuf,
+        start: Option<Timestamp>,
+    },
+}
+
+/// Options of point-in-time restore.
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    base: RestoreBase,
+    until: Timestamp,
+    stop_before: Option<Timestamp>,
+}
+
+impl RestoreOptions {
+    /// restore from `base` until restore point `until`, oplog at `until` is applied.
+    pub fn new(base: RestoreBase, until: Timestamp) -> Self {
+        RestoreOptions {
+            base,
+            until,
+            stop_before: None,
+        }
+    }
+
+    /// stop before oplog at `ts`, e.g: a bad operation, the oplog and oplogs after it are not applied.
+    pub fn with_stop_before(mut self, ts: Timestamp) -> Self {
+        self.stop_before = Some(ts);
+        self
+    }
+
+    /// get base copy to restore from.
+    pub fn get_base(&self) -> &RestoreBase {
+        &self.base
+    }
+
+    /// get restore point.
+    pub fn get_until(&self) -> Timestamp {
+        self.until
+    }
+
+    /// get timestamp of oplog which restore stops before.
+    pub fn get_stop_before(&self) -> Option<Timestamp> {
+        self.stop_before
+    }
+}
+
+/// parse restore point `s`, which is an oplog timestamp in `time:increment` format, seconds since epoch, or a
+/// RFC 3339 wall-clock time, e.g: `2021-10-08T10:00:00+08:00`.
+///
+/// Seconds and wall-clock time include all oplogs inside the second.
+pub fn parse_restore_point(s: &str) -> Result<Timestamp> {
+    let invalid = || SyncError::InvalidRestorePoint {
+        detail: format!(
+            "{:?}, expect `time:increment`, seconds since epoch, or RFC 3339 time",
+            s
+        ),
+    };
+    if let Some((time, increment)) = s.split_once(':') {
+        if let (Ok(time), Ok(increment)) = (time.parse(), increment.parse()) {
+            return Ok(Timestamp { time, increment });
+        }
+    }
+    let time = match s.parse::<u32>() {
+        Ok(time) => time,
+        Err(_) => {
+            let secs = DateTime::parse_from_rfc3339(s)
+                .map_err(|_| invalid())?
+                .timestamp();
+            u32::try_from(secs).map_err(|_| invalid())?
+        }
+    };
+    Ok(Timestamp {
+        time,
+        increment: u32::MAX,
+    })
+}
+
+/// truncate `oplogs` before the oplog at `stop_before` or after it, returns the first truncated oplog.
+pub fn truncate_before(
+    oplogs: &mut Vec<Document>,
+    stop_before: Timestamp,
+) -> Result<Option<Document>> {
+    for (pos, one_log) in oplogs.iter().enumerate() {
+        if one_log.get_timestamp(TIMESTAMP_KEY)? >= stop_before {
+            return Ok(oplogs.drain(pos..).next());
+        }
+    }
+    Ok(None)
+}
+
+/// Summary report of point-in-time restore, which is returned by
+/// [MongoSyncer::restore](crate::MongoSyncer::restore).
+#[derive(Debug)]
+pub struct RestoreReport {
+    /// timestamp of the base copy, oplogs after it are replayed.
+    pub start: Timestamp,
+    /// timestamp of the last replayed oplog, target is restored to this point.
+    pub end: Timestamp,
+    /// how many oplogs are applied, keyed by operation.
+    pub ops: BTreeMap<String, u64>,
+    /// the oplog which restore stops before, it's not applied.
+    pub stopped_before: Option<Document>,
+    /// how long does the restore take.
+    pub duration: Duration,
+}
+
+impl RestoreReport {
+    /// create a report for restore from `start`, nothing is applied yet.
+    pub fn new(start: Timestamp) -> Self {
+        RestoreReport {
+            start,
+            end: start,
+            ops: BTreeMap::new(),
+            stopped_before: None,
+            duration: Duration::default(),
+        }
+    }
+
+    /// record `oplogs` which are applied.
+    pub fn record_applied(&mut self, oplogs: &[Document]) -> Result<()> {
+        for one_log in oplogs.iter() {
+            let op = match one_log.get_str(OP_KEY)? {
+                "i" => "insert",
+                "u" => "update",
+                "d" => "delete",
+                "c" => "command",
+                _ => "noop",
+            };
+            *self.ops.entry(op.to_string()).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// return total oplogs which are applied.
+    pub fn applied(&self) -> u64 {
+        self.ops.values().sum()
+    }
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<20} {:>12}", "OPERATION", "OPLOGS")?;
+        for (op, count) in self.ops.iter() {
+            writeln!(f, "{:<20} {:>12}", op, count)?;
+        }
+        if let Some(one_log) = &self.stopped_before {
+            writeln!(f, "Stopped before oplog: {}", one_log)?;
+        }
+        write!(
+            f,
+            "Total: {} oplogs applied, restored from {:?} to {:?}, takes {:.2}s",
+            self.applied(),
+            self.start,
+            self.end,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+/// Reader of bson file which is written by mongodump, it yields documents one by one.
+#[derive(Debug)]
+pub struct BsonFileReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl BsonFileReader {
+    /// open bson file at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(|e| dump_error(path, e))?;
+        Ok(BsonFileReader {
+            path: path.to_path_buf(),
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+impl Iterator for BsonFileReader {
+    type Item = Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let eof = match self.reader.fill_buf() {
+            Ok(buf) => buf.is_empty(),
+            Err(e) => return Some(Err(dump_error(&self.path, e))),
+        };
+        if eof {
+            return None;
+        }
+        Some(Document::from_reader(&mut self.reader).map_err(|e| dump_error(&self.path, e)))
+    }
+}
+
+fn dump_error(path: &Path, e: impl fmt::Display) -> SyncError {
+    SyncError::DumpReadError {
+        path: path.display().to_string(),
+        detail: e.to_string(),
+    }
+}
+
+/// get databases inside mongodump directory `dir`, internal databases are ignored.
+pub fn dump_dbs(dir: &Path) -> Result<Vec<String>> {
+    let mut result = vec![];
+    for entry in dir.read_dir().map_err(|e| dump_error(dir, e))? {
+        let path = entry.map_err(|e| dump_error(dir, e))?.path();
+        if let (true, Some(db)) = (path.is_dir(), path.file_name().and_then(|n| n.to_str())) {
+            if !matches!(db, "admin" | "local" | "config") {
+                result.push(db.to_string());
+            }
+        }
+    }
+    result.sort_unstable();
+    Ok(result)
+}
+
+/// get collections and their bson files of database `db` inside mongodump directory `dir`, system
+/// collections are ignored.
+pub fn dump_colls(dir: &Path, db: &str) -> Result<Vec<(String, PathBuf)>> {
+    let db_dir = dir.join(db);
+    let mut result = vec![];
+    for entry in db_dir.read_dir().map_err(|e| dump_error(&db_dir, e))? {
+        let path = entry.map_err(|e| dump_error(&db_dir, e))?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("bson") {
+            continue;
+        }
+        if let Some(coll) = path.file_stem().and_then(|n| n.to_str()) {
+            if !coll.starts_with("system.") {
+                result.push((coll.to_string(), path.clone()));
+            }
+        }
+    }
+    result.sort_unstable();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bson::doc;
+
+    #[test]
+    fn test_parse_restore_point() {
+        assert_eq!(
+            parse_restore_point("1633660800:3").unwrap(),
+            Timestamp {
+                time: 1633660800,
+                increment: 3
+            }
+        );
+        assert_eq!(
+            parse_restore_point("1633660800").unwrap(),
+            Timestamp {
+                time: 1633660800,
+                increment: u32::MAX
+            }
+        );
+        assert_eq!(
+            parse_restore_point("2021-10-08T10:40:00+08:00").unwrap(),
+            Timestamp {
+                time: 1633660800,
+                increment: u32::MAX
+            }
+        );
+        assert!(parse_restore_point("yesterday").is_err());
+        assert!(parse_restore_point("1:a").is_err());
+    }
+
+    #[test]
+    fn test_truncate_before() {
+        let ts = |time| Timestamp { time, increment: 0 };
+        let mut oplogs = vec![
+            doc! {"ts": ts(1), "op": "i"},
+            doc! {"ts": ts(2), "op": "d"},
+            doc! {"ts": ts(3), "op": "i"},
+        ];
+        assert_eq!(truncate_before(&mut oplogs, ts(5)).unwrap(), None);
+        assert_eq!(oplogs.len(), 3);
+        assert_eq!(
+            truncate_before(&mut oplogs, ts(2)).unwrap(),
+            Some(doc! {"ts": ts(2), "op": "d"})
+        );
+        assert_eq!(oplogs, vec![doc! {"ts": ts(1), "op": "i"}]);
+
+        let mut report = RestoreReport::new(ts(0));
+        report.record_applied(&oplogs).unwrap();
+        assert_eq!(report.applied(), 1);
+        assert_eq!(report.ops.get("insert"), Some(&1));
+    }
+}
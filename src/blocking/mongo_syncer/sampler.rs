@@ -0,0 +1,216 @@
+//! Provide continuous consistency sampling, which compares random documents between source and target while
+//! incremental sync runs.
+//!
+//! Each round samples random `_id`s of every synced collection by `$sample`, and compares hashes of source
+//! and target documents.  Documents which differ may be changed by oplogs which are not applied yet, so they
+//! are compared again after check point passes the latest stored oplog, only documents which still differ are
+//! counted as mismatched.  Drift statistics are saved in `sampling_stats` collection next to check point:
+//!
+//! ```text
+//! {"_id": "drift", "rounds": 10, "sampled": 1000, "mismatched": 1, "alerting": false, ...}
+//! ```
+
+use super::bson_helper::id_key;
+use crate::{CollFilter, Result};
+use bson::{doc, Bson, DateTime, Document};
+use mongodb::options::ReplaceOptions;
+use mongodb::sync::Collection;
+use std::collections::BTreeMap;
+
+/// collection name which saves drift statistics of consistency sampling.
+pub const SAMPLING_STATS_COLL: &str = "sampling_stats";
+/// `_id` of the drift statistics document.
+const STATS_ID: &str = "drift";
+/// how many mismatched `_id`s of a round are saved.
+const SAVED_MISMATCHED_IDS: usize = 20;
+
+/// How many documents are sampled, and how many of them mismatch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriftStats {
+    /// documents which are compared.
+    pub sampled: u64,
+    /// documents which still differ after check point passes them.
+    pub mismatched: u64,
+}
+
+impl DriftStats {
+    /// get mismatched documents divided by sampled documents, 0 if nothing is sampled.
+    pub fn mismatch_rate(&self) -> f64 {
+        if self.sampled == 0 {
+            0.0
+        } else {
+            self.mismatched as f64 / self.sampled as f64
+        }
+    }
+
+    /// add `other` into the statistics.
+    pub fn add(&mut self, other: DriftStats) {
+        self.sampled += other.sampled;
+        self.mismatched += other.mismatched;
+    }
+
+    fn to_document(self) -> Document {
+        doc! {
+            "sampled": self.sampled as i64,
+            "mismatched": self.mismatched as i64,
+            "mismatch_rate": self.mismatch_rate(),
+        }
+    }
+}
+
+/// Result of a sampling round.
+#[derive(Debug, Clone, Default)]
+pub struct SamplingRound {
+    /// statistics of each collection, keyed by source namespace `db.coll`.
+    pub collections: BTreeMap<String, DriftStats>,
+    /// `_id`s of mismatched documents, keyed by source namespace.
+    pub mismatched_ids: BTreeMap<String, Vec<Bson>>,
+}
+
+impl SamplingRound {
+    /// get statistics of all collections in the round.
+    pub fn total(&self) -> DriftStats {
+        let mut total = DriftStats::default();
+        for stats in self.collections.values() {
+            total.add(*stats);
+        }
+        total
+    }
+}
+
+/// Drift statistics of consistency sampling since db_sync starts.
+#[derive(Debug, Clone, Default)]
+pub struct SamplingStats {
+    /// how many rounds are finished.
+    pub rounds: u64,
+    /// statistics of all rounds.
+    pub total: DriftStats,
+    /// statistics of all rounds of each collection, keyed by source namespace.
+    pub collections: BTreeMap<String, DriftStats>,
+    /// the last finished round.
+    pub last_round: SamplingRound,
+    /// mismatch rate of the last round crosses alert rate.
+    pub alerting: bool,
+}
+
+impl SamplingStats {
+    /// record finished `round`, returns true if its mismatch rate crosses `alert_rate`.
+    pub fn record_round(&mut self, round: SamplingRound, alert_rate: f64) -> bool {
+        let round_total = round.total();
+        self.rounds += 1;
+        self.total.add(round_total);
+        for (ns, stats) in round.collections.iter() {
+            self.collections.entry(ns.clone()).or_default().add(*stats);
+        }
+        self.alerting = round_total.mismatched > 0 && round_total.mismatch_rate() >= alert_rate;
+        self.last_round = round;
+        self.alerting
+    }
+
+    /// convert statistics to the document which is saved.
+    pub fn to_document(&self) -> Document {
+        let collections: Document = self
+            .collections
+            .iter()
+            .map(|(ns, stats)| (ns.clone(), Bson::Document(stats.to_document())))
+            .collect();
+        let mismatched_ids: Document = self
+            .last_round
+            .mismatched_ids
+            .iter()
+            .map(|(ns, ids)| {
+                let ids: Vec<Bson> = ids.iter().take(SAVED_MISMATCHED_IDS).cloned().collect();
+                (ns.clone(), Bson::Array(ids))
+            })
+            .collect();
+        let mut result = self.total.to_document();
+        result.insert("_id", STATS_ID);
+        result.insert("rounds", self.rounds as i64);
+        result.insert("alerting", self.alerting);
+        result.insert("collections", collections);
+        result.insert(
+            "last_round",
+            doc! {"stats": self.last_round.total().to_document(), "mismatched_ids": mismatched_ids},
+        );
+        result.insert("updated_at", DateTime::now());
+        result
+    }
+}
+
+/// save drift `stats` into `coll`, the old statistics are replaced.
+pub fn save_stats(coll: &Collection<Document>, stats: &SamplingStats) -> Result<()> {
+    coll.replace_one(
+        doc! {"_id": STATS_ID},
+        stats.to_document(),
+        ReplaceOptions::builder().upsert(true).build(),
+    )?;
+    Ok(())
+}
+
+/// sample at most `size` random `_id`s of `coll`, only documents which match `coll_filter` are sampled.
+pub fn sample_ids(
+    coll: &Collection<Document>,
+    coll_filter: Option<&CollFilter>,
+    size: usize,
+) -> Result<Vec<Bson>> {
+    let mut pipeline = vec![];
+    if let Some(f) = coll_filter {
+        pipeline.push(doc! {"$match": f.filter.clone()});
+    }
+    pipeline.push(doc! {"$sample": {"size": size as i64}});
+    pipeline.push(doc! {"$project": {"_id": 1}});
+    let mut ids = vec![];
+    for d in coll.aggregate(pipeline, None)? {
+        if let Some(id) = d?.remove("_id") {
+            ids.push(id);
+        }
+    }
+    // `$sample` may return the same document more than once.
+    ids.sort_by_key(id_key);
+    ids.dedup();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_sampling_round() {
+        let mut stats = SamplingStats::default();
+        let mut round = SamplingRound::default();
+        round.collections.insert(
+            "a.b".to_string(),
+            DriftStats {
+                sampled: 100,
+                mismatched: 0,
+            },
+        );
+        assert!(!stats.record_round(round.clone(), 0.0));
+
+        round.collections.insert(
+            "a.c".to_string(),
+            DriftStats {
+                sampled: 100,
+                mismatched: 1,
+            },
+        );
+        round
+            .mismatched_ids
+            .insert("a.c".to_string(), vec![Bson::Int32(1)]);
+        assert!(!stats.record_round(round.clone(), 0.01));
+        assert!(stats.record_round(round, 0.005));
+        assert_eq!(stats.rounds, 3);
+        assert_eq!(
+            stats.total,
+            DriftStats {
+                sampled: 500,
+                mismatched: 2
+            }
+        );
+        assert_eq!(stats.collections["a.b"].sampled, 300);
+        let saved = stats.to_document();
+        assert_eq!(saved.get_i64("mismatched").unwrap(), 2);
+        assert!(saved.get_bool("alerting").unwrap());
+    }
+}
@@ -0,0 +1,199 @@
+//! Provide snapshot read support for full sync.
+//!
+//! Since mongodb 5.0, `find` command can read with `readConcern: {level: "snapshot", atClusterTime: <ts>}`
+//! outside of transactions.  So all collections can be read at the same point of time, and the target is
+//! consistent as of that time even before incremental replay.
+
+use super::oplog_helper;
+use crate::{Result, SyncError, OPLOG_COLL, OPLOG_DB};
+use bson::{doc, Bson, Document, Timestamp};
+use mongodb::error::{Error as MongoError, ErrorKind};
+use mongodb::sync::{Client, ClientSession, Collection, Database};
+use std::collections::VecDeque;
+use tracing::{info, warn};
+
+/// mongodb error code: SnapshotTooOld.
+const SNAPSHOT_TOO_OLD: i32 = 239;
+/// mongodb error code: SnapshotUnavailable.
+const SNAPSHOT_UNAVAILABLE: i32 = 246;
+/// the first major version which supports snapshot read outside of transactions.
+const SNAPSHOT_READ_MIN_VERSION: i32 = 5;
+
+/// Read documents at the given cluster time `at` through `client`.
+#[derive(Clone, Debug)]
+pub struct SnapshotRead {
+    /// source mongodb client.
+    pub client: Client,
+    /// cluster time to read.
+    pub at: Timestamp,
+}
+
+/// Check if mongodb server behind `client` supports snapshot read outside of transactions.
+pub fn supports_snapshot_read(client: &Client) -> Result<bool> {
+    let build_info = client
+        .database("admin")
+        .run_command(doc! {"buildInfo": 1}, None)?;
+    let major = match build_info.get_array("versionArray")?.first() {
+        Some(Bson::Int32(v)) => *v,
+        Some(Bson::Int64(v)) => *v as i32,
+        _ => 0,
+    };
+    Ok(major >= SNAPSHOT_READ_MIN_VERSION)
+}
+
+/// Pick a cluster time to make snapshot read on source mongodb.
+///
+/// It's the timestamp of latest oplog in source cluster, so oplogs after the returned timestamp are exactly
+/// the changes which are not visible in the snapshot.  Returns None if source doesn't support snapshot read,
+/// or we can't read source oplog.
+pub fn pick_snapshot_time(client: &Client) -> Result<Option<Timestamp>> {
+    if !supports_snapshot_read(client)? {
+        info!("Source mongodb doesn't support snapshot read, read live data instead.");
+        return Ok(None);
+    }
+    let oplog_coll = client.database(OPLOG_DB).collection::<Document>(OPLOG_COLL);
+    match oplog_helper::get_latest_ts(&oplog_coll) {
+        Ok(ts) => Ok(Some(ts)),
+        Err(e) => {
+            warn!(
+                ?e,
+                "Can't read source oplog to pick snapshot time, read live data instead."
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Convert mongodb error to [SyncError], snapshot window exceeded error will be converted to
+/// [SyncError::SnapshotTooOld].
+fn convert_error(e: MongoError, at: Timestamp) -> SyncError {
+    match e.kind.as_ref() {
+        ErrorKind::Command(err)
+            if err.code == SNAPSHOT_TOO_OLD || err.code == SNAPSHOT_UNAVAILABLE =>
+        {
+            SyncError::SnapshotTooOld {
+                at,
+                detail: err.message.clone(),
+            }
+        }
+        _ => SyncError::from(e),
+    }
+}
+
+/// A cursor which reads documents at a given cluster time.
+///
+/// The mongodb driver doesn't allow us to specify `atClusterTime`, so it runs `find` and `getMore`
+/// command manually.
+pub struct SnapshotCursor {
+    db: Database,
+    coll_name: String,
+    session: ClientSession,
+    cursor_id: i64,
+    batch: VecDeque<Bson>,
+    at: Timestamp,
+}
+
+impl SnapshotCursor {
+    /// Create a cursor to read documents in `coll` which matches `filter` at cluster time `at`, documents are
+    /// sorted by `_id`.  At most `limit` documents are returned if it's given, and documents are projected by
+    /// `projection` if it's given.
+    ///
+    /// `client` must be the client which `coll` belongs to.
+    pub fn new(
+        client: &Client,
+        coll: &Collection<Document>,
+        filter: Document,
+        projection: Option<Document>,
+        at: Timestamp,
+        batch_size: i32,
+        limit: Option<i64>,
+    ) -> Result<SnapshotCursor> {
+        // cursor is bound to the session which creates it, so `find` and `getMore` must be in the same session.
+        let mut session = client.start_session(None)?;
+        let db = client.database(&coll.namespace().db);
+        let mut find_cmd = doc! {
+            "find": coll.name(),
+            "filter": filter,
+            "sort": {"_id": 1},
+            "batchSize": batch_size,
+            "readConcern": {"level": "snapshot", "atClusterTime": at},
+        };
+        if let Some(limit) = limit {
+            find_cmd.insert("limit", limit);
+        }
+        if let Some(projection) = projection {
+            find_cmd.insert("projection", projection);
+        }
+        let result = db
+            .run_command_with_session(find_cmd, None, &mut session)
+            .map_err(|e| convert_error(e, at))?;
+        let mut cursor = SnapshotCursor {
+            db,
+            coll_name: coll.name().to_string(),
+            session,
+            cursor_id: 0,
+            batch: VecDeque::new(),
+            at,
+        };
+        cursor.consume_response(result, "firstBatch")?;
+        Ok(cursor)
+    }
+
+    fn consume_response(&mut self, mut result: Document, batch_key: &str) -> Result<()> {
+        let cursor = result.get_document_mut("cursor")?;
+        self.cursor_id = cursor.get_i64("id")?;
+        if let Some(Bson::Array(docs)) = cursor.remove(batch_key) {
+            self.batch.extend(docs);
+        }
+        Ok(())
+    }
+
+    fn get_more(&mut self) -> Result<()> {
+        let result = self
+            .db
+            .run_command_with_session(
+                doc! {"getMore": self.cursor_id, "collection": &self.coll_name},
+                None,
+                &mut self.session,
+            )
+            .map_err(|e| convert_error(e, self.at))?;
+        self.consume_response(result, "nextBatch")
+    }
+}
+
+impl Iterator for SnapshotCursor {
+    type Item = Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.batch.is_empty() {
+            if self.cursor_id == 0 {
+                return None;
+            }
+            if let Err(e) = self.get_more() {
+                // don't try to fetch again after error.
+                self.cursor_id = 0;
+                return Some(Err(e));
+            }
+        }
+        match self.batch.pop_front() {
+            Some(Bson::Document(d)) => Some(Ok(d)),
+            Some(other) => Some(Err(SyncError::BsonValueError {
+                key: "cursor".to_string(),
+                val: other.to_string(),
+            })),
+            None => None,
+        }
+    }
+}
+
+impl Drop for SnapshotCursor {
+    fn drop(&mut self) {
+        if self.cursor_id != 0 {
+            let _ = self.db.run_command_with_session(
+                doc! {"killCursors": &self.coll_name, "cursors": [self.cursor_id]},
+                None,
+                &mut self.session,
+            );
+        }
+    }
+}
@@ -0,0 +1,417 @@
+//! Provide `_id` range scheduler for concurrent full sync.
+//!
+//! Workers fetch ranges from [RangeScheduler], when there are no pending ranges, the scheduler tries to split
+//! the active range which has the most remaining `_id` space, so a lagging worker can be helped by idle workers.
+//! Only `ObjectId` and numeric `_id` can be split, because we need to compute the middle point.
+
+use super::plan::{FullSyncPlan, RangeState};
+use crate::Result;
+use bson::oid::ObjectId;
+use bson::{doc, Bson, Document};
+use mongodb::options::{CountOptions, FindOneOptions};
+use mongodb::sync::Collection;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::info;
+
+/// a range is split only when the new range contains at least so many documents.
+const SPLIT_MIN_DOCS: u64 = 20000;
+
+/// numeric value of `_id`, which is used to compute middle point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IdValue {
+    ObjectId(u128),
+    Int(i64),
+    Double(f64),
+}
+
+fn id_value(id: &Bson) -> Option<IdValue> {
+    match id {
+        Bson::ObjectId(oid) => {
+            let mut bytes = [0; 16];
+            bytes[4..].copy_from_slice(&oid.bytes());
+            Some(IdValue::ObjectId(u128::from_be_bytes(bytes)))
+        }
+        Bson::Int32(v) => Some(IdValue::Int(*v as i64)),
+        Bson::Int64(v) => Some(IdValue::Int(*v)),
+        Bson::Double(v) if v.is_finite() => Some(IdValue::Double(*v)),
+        _ => None,
+    }
+}
+
+/// Returns true if `a` and `b` are compared by value in mongodb query, which means that a range query with one of
+/// them as bound can match the other one.
+pub fn same_type_class(a: &Bson, b: &Bson) -> bool {
+    let is_number = |v: &Bson| {
+        matches!(
+            v,
+            Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_)
+        )
+    };
+    (is_number(a) && is_number(b)) || a.element_type() == b.element_type()
+}
+
+/// `$type` aliases of each type bracket in mongodb sort order, values in one bracket are compared by value.
+const TYPE_BRACKETS: [&[&str]; 13] = [
+    &["minKey"],
+    &["null", "undefined"],
+    &["int", "long", "double", "decimal"],
+    &["symbol", "string"],
+    &["object"],
+    &["array"],
+    &["binData"],
+    &["objectId"],
+    &["bool"],
+    &["date"],
+    &["timestamp"],
+    &["regex"],
+    &["maxKey"],
+];
+
+/// Get `$type` aliases of the types which sort after the type bracket of `id`, returns None if the type of `id`
+/// is not known.
+pub fn later_id_types(id: &Bson) -> Option<Vec<&'static str>> {
+    let bracket = match id {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::Symbol(_) | Bson::String(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::MaxKey => 12,
+        _ => return None,
+    };
+    Some(TYPE_BRACKETS[bracket + 1..].concat())
+}
+
+/// Compare two `_id` values, returns None if they can't be compared.
+pub fn compare_ids(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (id_value(a)?, id_value(b)?) {
+        (IdValue::ObjectId(a), IdValue::ObjectId(b)) => Some(a.cmp(&b)),
+        (IdValue::Int(a), IdValue::Int(b)) => Some(a.cmp(&b)),
+        (IdValue::Int(a), IdValue::Double(b)) => (a as f64).partial_cmp(&b),
+        (IdValue::Double(a), IdValue::Int(b)) => a.partial_cmp(&(b as f64)),
+        (IdValue::Double(a), IdValue::Double(b)) => a.partial_cmp(&b),
+        _ => None,
+    }
+}
+
+/// Compute middle point of `low` and `high`, returns None if there is no value strictly between them.
+pub fn middle_id(low: &Bson, high: &Bson) -> Option<Bson> {
+    let mid = match (id_value(low)?, id_value(high)?) {
+        (IdValue::ObjectId(a), IdValue::ObjectId(b)) if a < b => {
+            let mid = a + (b - a) / 2;
+            let mut bytes = [0; 12];
+            bytes.copy_from_slice(&mid.to_be_bytes()[4..]);
+            Bson::ObjectId(ObjectId::from_bytes(bytes))
+        }
+        (IdValue::Int(a), IdValue::Int(b)) if a < b => {
+            Bson::Int64(a + ((b as i128 - a as i128) / 2) as i64)
+        }
+        (IdValue::Double(a), IdValue::Double(b)) if a < b => Bson::Double(a + (b - a) / 2.0),
+        (IdValue::Int(a), IdValue::Double(b)) if (a as f64) < b => {
+            Bson::Double(a as f64 + (b - a as f64) / 2.0)
+        }
+        (IdValue::Double(a), IdValue::Int(b)) if a < (b as f64) => {
+            Bson::Double(a + (b as f64 - a) / 2.0)
+        }
+        _ => return None,
+    };
+    let strictly_between = compare_ids(low, &mid) == Some(Ordering::Less)
+        && compare_ids(&mid, high) == Some(Ordering::Less);
+    if strictly_between {
+        Some(mid)
+    } else {
+        None
+    }
+}
+
+/// width of `_id` space between `low` and `high`, it's only used to compare which range is larger.
+fn id_span(low: &Bson, high: &Bson) -> Option<f64> {
+    match (id_value(low)?, id_value(high)?) {
+        (IdValue::ObjectId(a), IdValue::ObjectId(b)) => Some(b.saturating_sub(a) as f64),
+        (IdValue::Int(a), IdValue::Int(b)) => Some((b as f64 - a as f64).max(0.0)),
+        (IdValue::Int(a), IdValue::Double(b)) => Some((b - a as f64).max(0.0)),
+        (IdValue::Double(a), IdValue::Int(b)) => Some((b as f64 - a).max(0.0)),
+        (IdValue::Double(a), IdValue::Double(b)) => Some((b - a).max(0.0)),
+        _ => None,
+    }
+}
+
+/// A range which is copying by a worker.
+#[derive(Debug)]
+pub struct RangeTask {
+    /// index of the range in plan.
+    pub idx: usize,
+    state: Mutex<RangeState>,
+}
+
+impl RangeTask {
+    /// create a task for the `idx`th range.
+    pub fn new(idx: usize, state: RangeState) -> Arc<RangeTask> {
+        Arc::new(RangeTask {
+            idx,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// lock state of the range.
+    ///
+    /// Worker should hold the lock when writing documents and updating `last_id`, so the range will not be
+    /// split during writing.
+    pub fn lock(&self) -> MutexGuard<'_, RangeState> {
+        self.state.lock().expect("range state lock poisoned")
+    }
+}
+
+struct SchedulerInner {
+    pending: VecDeque<Arc<RangeTask>>,
+    active: Vec<Arc<RangeTask>>,
+    range_cnt: usize,
+}
+
+/// Schedule `_id` ranges of a collection to workers.
+pub struct RangeScheduler {
+    coll: Collection<Document>,
+    plan: Option<FullSyncPlan>,
+    /// the minimum and maximum `_id` of collection, which are used to split unbounded range.
+    id_bounds: Option<(Bson, Bson)>,
+    inner: Mutex<SchedulerInner>,
+}
+
+impl RangeScheduler {
+    /// create a scheduler for `ranges` of `coll`, the ranges which are done will be ignored.
+    ///
+    /// If `plan` is given, new ranges created by split will be saved into the plan.
+    pub fn new(
+        coll: Collection<Document>,
+        plan: Option<FullSyncPlan>,
+        ranges: Vec<RangeState>,
+    ) -> Result<RangeScheduler> {
+        let id_bounds = get_id_bounds(&coll)?;
+        let range_cnt = ranges.len();
+        let pending = ranges
+            .into_iter()
+            .enumerate()
+            .filter(|(_, r)| !r.done)
+            .map(|(idx, r)| RangeTask::new(idx, r))
+            .collect();
+        Ok(RangeScheduler {
+            coll,
+            plan,
+            id_bounds,
+            inner: Mutex::new(SchedulerInner {
+                pending,
+                active: vec![],
+                range_cnt,
+            }),
+        })
+    }
+
+    /// get next range to copy, returns None if there is nothing to do.
+    pub fn next_task(&self) -> Result<Option<Arc<RangeTask>>> {
+        let mut inner = self.inner.lock().expect("scheduler lock poisoned");
+        let task = match inner.pending.pop_front() {
+            Some(task) => Some(task),
+            None => self.split_lagging(&mut inner)?,
+        };
+        if let Some(task) = &task {
+            inner.active.push(task.clone());
+        }
+        Ok(task)
+    }
+
+    /// mark `task` as finished, so it will not be split any more.
+    pub fn finish_task(&self, task: &Arc<RangeTask>) {
+        let mut inner = self.inner.lock().expect("scheduler lock poisoned");
+        inner.active.retain(|t| !Arc::ptr_eq(t, task));
+    }
+
+    /// find the active range which has the most remaining `_id` space, and split it into two.
+    fn split_lagging(&self, inner: &mut SchedulerInner) -> Result<Option<Arc<RangeTask>>> {
+        let (coll_min, coll_max) = match &self.id_bounds {
+            Some(bounds) => bounds,
+            None => return Ok(None),
+        };
+
+        // (task, low, high, span)
+        let mut candidate: Option<(Arc<RangeTask>, Bson, Bson, f64)> = None;
+        for task in inner.active.iter() {
+            let state = task.lock();
+            let low = state
+                .last_id
+                .clone()
+                .or_else(|| state.min.clone())
+                .unwrap_or_else(|| coll_min.clone());
+            let high = state.max.clone().unwrap_or_else(|| coll_max.clone());
+            if let Some(span) = id_span(&low, &high) {
+                if candidate.as_ref().map(|c| span > c.3).unwrap_or(true) {
+                    candidate = Some((task.clone(), low, high, span));
+                }
+            }
+        }
+        let (victim, low, high, _) = match candidate {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let mid = match middle_id(&low, &high) {
+            Some(mid) => mid,
+            None => return Ok(None),
+        };
+
+        // make sure that it's worth to split.
+        let mut upper_filter = doc! {"$gte": mid.clone()};
+        if let Some(max) = victim.lock().max.clone() {
+            upper_filter.insert("$lt", max);
+        }
+        let upper_cnt = self.coll.count_documents(
+            doc! {"_id": upper_filter},
+            CountOptions::builder().limit(SPLIT_MIN_DOCS).build(),
+        )?;
+        if upper_cnt < SPLIT_MIN_DOCS {
+            return Ok(None);
+        }
+
+        let mut state = victim.lock();
+        // worker may have copied beyond the middle point during counting.
+        let still_ahead = state
+            .last_id
+            .as_ref()
+            .map(|last_id| compare_ids(last_id, &mid) == Some(Ordering::Less))
+            .unwrap_or(true);
+        if !still_ahead {
+            return Ok(None);
+        }
+        let new_range = RangeState::new(Some(mid.clone()), state.max.clone());
+        let new_idx = inner.range_cnt;
+        // save new range before shrink the old one, so no documents are lost if we crash between them.
+        if let Some(plan) = &self.plan {
+            plan.add_range(self.coll.name(), &new_range)?;
+            plan.save_range_max(self.coll.name(), victim.idx, &mid)?;
+        }
+        state.max = Some(mid);
+        inner.range_cnt += 1;
+        info!(
+            collection_name=%self.coll.name(),
+            from_range=victim.idx,
+            new_range=new_idx,
+            "Full state: split lagging range. "
+        );
+        Ok(Some(RangeTask::new(new_idx, new_range)))
+    }
+}
+
+/// get the minimum and maximum `_id` of `coll`, returns None if they can't be used to split ranges.
+pub fn get_id_bounds(coll: &Collection<Document>) -> Result<Option<(Bson, Bson)>> {
+    let find_id = |direction: i32| -> Result<Option<Bson>> {
+        Ok(coll
+            .find_one(
+                None,
+                FindOneOptions::builder()
+                    .sort(doc! {"_id": direction})
+                    .projection(doc! {"_id": 1})
+                    .build(),
+            )?
+            .and_then(|d| d.get("_id").cloned()))
+    };
+    match (find_id(1)?, find_id(-1)?) {
+        (Some(min), Some(max)) if same_type_class(&min, &max) => Ok(Some((min, max))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_later_id_types() {
+        let types = later_id_types(&Bson::Int32(3)).unwrap();
+        assert!(types.contains(&"string"));
+        assert!(types.contains(&"objectId"));
+        assert!(!types.contains(&"int") && !types.contains(&"double"));
+        assert!(later_id_types(&Bson::MaxKey).unwrap().is_empty());
+        assert!(later_id_types(&Bson::JavaScriptCode("1".to_string())).is_none());
+    }
+
+    #[test]
+    fn test_same_type_class() {
+        assert!(same_type_class(&Bson::Int32(1), &Bson::Double(2.0)));
+        assert!(same_type_class(
+            &Bson::String("a".to_string()),
+            &Bson::String("b".to_string())
+        ));
+        assert!(!same_type_class(
+            &Bson::Int32(1),
+            &Bson::String("b".to_string())
+        ));
+        assert!(!same_type_class(
+            &Bson::ObjectId(ObjectId::new()),
+            &Bson::Int64(3)
+        ));
+    }
+
+    #[test]
+    fn test_compare_ids() {
+        assert_eq!(
+            compare_ids(&Bson::Int32(1), &Bson::Int64(2)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_ids(&Bson::Double(2.5), &Bson::Int32(2)),
+            Some(Ordering::Greater)
+        );
+        let a = ObjectId::from_bytes([0; 12]);
+        let b = ObjectId::from_bytes([1; 12]);
+        assert_eq!(
+            compare_ids(&Bson::ObjectId(a), &Bson::ObjectId(b)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_ids(&Bson::String("a".to_string()), &Bson::Int32(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_middle_id() {
+        assert_eq!(
+            middle_id(&Bson::Int32(0), &Bson::Int64(10)),
+            Some(Bson::Int64(5))
+        );
+        assert_eq!(middle_id(&Bson::Int32(1), &Bson::Int32(2)), None);
+        assert_eq!(middle_id(&Bson::Int32(5), &Bson::Int32(2)), None);
+        assert_eq!(
+            middle_id(&Bson::Double(1.0), &Bson::Double(2.0)),
+            Some(Bson::Double(1.5))
+        );
+
+        let mut low = [0; 12];
+        low[0] = 2;
+        let mut high = [0; 12];
+        high[0] = 4;
+        let mut mid = [0; 12];
+        mid[0] = 3;
+        assert_eq!(
+            middle_id(
+                &Bson::ObjectId(ObjectId::from_bytes(low)),
+                &Bson::ObjectId(ObjectId::from_bytes(high))
+            ),
+            Some(Bson::ObjectId(ObjectId::from_bytes(mid)))
+        );
+        assert_eq!(
+            middle_id(
+                &Bson::String("a".to_string()),
+                &Bson::String("c".to_string())
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Provide supervised run loop, which runs a sync task again after transient errors.
+//!
+//! A failed run is classified by [SyncError::is_transient](crate::SyncError::is_transient).  Transient errors are
+//! retried with exponential backoff and jitter, every run reconnects and resumes from check point.  Fatal
+//! errors, and transient errors after max consecutive retries, are returned.  A run which lasts long enough
+//! resets consecutive failures, so a process which runs for weeks doesn't exit because of rare errors.  A
+//! cancelled run is not retried, and cancellation interrupts backoff.
+
+use super::cancel::CancelToken;
+use crate::{Result, RetryPolicy, SyncError};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Point-in-time view of retries of a supervised run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetryCounts {
+    /// how many times the task is run again since the supervisor starts.
+    pub retries: u64,
+    /// how many runs fail one after another, it's reset after a long enough run.
+    pub consecutive_failures: u32,
+    /// the latest error which is retried.
+    pub last_error: Option<String>,
+}
+
+/// Retry counts of a supervised run, it's shared, so it can be read from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct RetryStats {
+    counts: Arc<Mutex<RetryCounts>>,
+}
+
+impl RetryStats {
+    /// get current retry counts.
+    pub fn snapshot(&self) -> RetryCounts {
+        self.counts.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut RetryCounts)) -> RetryCounts {
+        let mut counts = self.counts.lock().unwrap();
+        f(&mut counts);
+        counts.clone()
+    }
+}
+
+/// Supervisor which runs a task again after transient errors by [RetryPolicy].
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    policy: RetryPolicy,
+    stats: RetryStats,
+    cancel: CancelToken,
+}
+
+impl Supervisor {
+    /// create a supervisor which retries by `policy`.
+    pub fn new(policy: RetryPolicy) -> Self {
+        Supervisor::with_stats(policy, RetryStats::default())
+    }
+
+    /// create a supervisor which retries by `policy`, and counts retries into `stats`.
+    pub fn with_stats(policy: RetryPolicy, stats: RetryStats) -> Self {
+        Supervisor {
+            policy,
+            stats,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// stop retrying when `cancel` is cancelled, the task should hold the same handle.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// get retry counts of the supervisor.
+    pub fn stats(&self) -> RetryStats {
+        self.stats.clone()
+    }
+
+    /// run `task` until it succeeds or fails with a fatal error, `name` is used in logs.
+    pub fn run<T>(&self, name: &str, mut task: impl FnMut() -> Result<T>) -> Result<T> {
+        loop {
+            let start = Instant::now();
+            let e = match task() {
+                Ok(result) => return Ok(result),
+                Err(SyncError::Cancelled) => {
+                    info!(%name, "Supervisor: run is cancelled, stop. ");
+                    return Err(SyncError::Cancelled);
+                }
+                Err(e) => e,
+            };
+            let healthy = start.elapsed() >= self.policy.reset_after;
+            let counts = self.stats.update(|counts| {
+                if healthy {
+                    counts.consecutive_failures = 0;
+                }
+                counts.consecutive_failures += 1;
+            });
+            if !e.is_transient() {
+                error!(%name, ?e, "Supervisor: fatal error, stop retrying. ");
+                return Err(e);
+            }
+            if let Some(max_retries) = self.policy.max_retries {
+                if counts.consecutive_failures > max_retries {
+                    error!(
+                        %name,
+                        ?e,
+                        ?counts,
+                        "Supervisor: transient error is retried too many times. "
+                    );
+                    return Err(e);
+                }
+            }
+            let backoff = jitter(self.policy.backoff_of(counts.consecutive_failures));
+            warn!(
+                %name,
+                ?e,
+                ?backoff,
+                ?counts,
+                "Supervisor: transient error, run again from check point later. "
+            );
+            self.cancel.sleep(backoff)?;
+            let counts = self.stats.update(|counts| {
+                counts.retries += 1;
+                counts.last_error = Some(e.to_string());
+            });
+            info!(%name, ?counts, "Supervisor: run again. ");
+        }
+    }
+}
+
+// keep half of `backoff`, and add a random part of the other half, so processes don't retry at the same time.
+fn jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(random_fraction())
+}
+
+// get a random fraction in [0, 1), keys of std hashers are random.
+fn random_fraction() -> f64 {
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SyncError;
+    use mongodb::error::Error as MongoError;
+    use std::io;
+
+    fn transient_error() -> SyncError {
+        MongoError::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset")).into()
+    }
+
+    #[test]
+    fn test_supervisor_retries_transient_errors() {
+        let policy = RetryPolicy::new(Some(2)).with_backoff(Duration::ZERO, Duration::ZERO);
+        let supervisor = Supervisor::new(policy);
+        let mut runs = 0;
+        let result = supervisor.run("test", || {
+            runs += 1;
+            if runs < 3 {
+                Err(transient_error())
+            } else {
+                Ok(runs)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        let counts = supervisor.stats().snapshot();
+        assert_eq!(counts.retries, 2);
+        assert_eq!(counts.consecutive_failures, 2);
+        assert!(counts.last_error.is_some());
+
+        // fatal errors are not retried, and transient errors are retried at most max retries.
+        let result: Result<()> = supervisor.run("test", || Err(SyncError::EmptyDocError));
+        assert!(matches!(result, Err(SyncError::EmptyDocError)));
+        let supervisor = Supervisor::new(policy);
+        let result: Result<()> = supervisor.run("test", || Err(transient_error()));
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(supervisor.stats().snapshot().retries, 2);
+    }
+
+    #[test]
+    fn test_supervisor_stops_when_cancelled() {
+        let policy =
+            RetryPolicy::new(None).with_backoff(Duration::from_secs(60), Duration::from_secs(60));
+        let cancel = CancelToken::new();
+        let supervisor = Supervisor::new(policy).with_cancel_token(cancel.clone());
+        let result: Result<()> = supervisor.run("test", || Err(SyncError::Cancelled));
+        assert!(matches!(result, Err(SyncError::Cancelled)));
+        assert_eq!(supervisor.stats().snapshot().consecutive_failures, 0);
+
+        // cancellation interrupts backoff of a transient error.
+        let mut runs = 0;
+        let result: Result<()> = supervisor.run("test", || {
+            runs += 1;
+            cancel.cancel();
+            Err(transient_error())
+        });
+        assert!(matches!(result, Err(SyncError::Cancelled)));
+        assert_eq!(runs, 1);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let backoff = Duration::from_secs(10);
+        for _ in 0..100 {
+            let waited = jitter(backoff);
+            assert!(waited >= backoff / 2 && waited <= backoff);
+        }
+    }
+}
@@ -1,16 +1,43 @@
-use super::full::{sync_one_concurrent, sync_one_serial, SyncTableStatus};
+use super::batch::MemoryBudget;
+use super::bson_helper::encoded_size;
+use super::cancel::CancelToken;
+use super::checkpoint::{CheckpointState, CollCheckpoint, CollCheckpoints};
+use super::control::{ControlChannel, ControlCommand, ControlState};
+use super::dead_letter::DeadLetters;
+use super::full::{
+    create_coll_with_options, sync_one_concurrent, sync_one_serial, write_docs, CopyOptions,
+    CopyStats, SyncTableStatus,
+};
 use super::incr::IncrDumper;
+use super::oplog_filter::{validate_coll_filters, OplogFilter};
 use super::oplog_helper;
+use super::plan::FullSyncPlan;
+use super::progress::FullSyncProgress;
+use super::repair::{FenceSet, RepairDoc, RepairFences};
+use super::report::{CollSyncReport, SyncReport};
+use super::restore::{self, BsonFileReader, RestoreBase, RestoreOptions, RestoreReport};
+use super::sampler::{self, DriftStats, SamplingRound, SamplingStats};
+use super::snapshot::{self, SnapshotRead};
+use super::supervisor::{RetryStats, Supervisor};
+use super::verify::{self, CollVerifyReport, VerifyReport};
 use crate::blocking::connection::Connection;
-use crate::error::Result;
-use crate::{DbSyncConf, TIMESTAMP_KEY};
-use bson::{doc, Bson, Document, Timestamp};
-use crossbeam::channel;
-use mongodb::options::{FindOneOptions, UpdateOptions};
+use crate::error::{Result, SyncError};
+use crate::{
+    ApplyFailurePolicy, CollSelector, DbSelector, DbSyncConf, FullSyncWritePolicy, OplogGapPolicy,
+    RemovedCollPolicy, RetryPolicy, SamplingConfig, COMMAND_OP, NAMESPACE_KEY, OPLOG_COLL,
+    OPLOG_DB, OP_KEY, TIMESTAMP_KEY,
+};
+use bson::{doc, Bson, DateTime, Document, Timestamp};
+use crossbeam::channel::{self, Receiver};
+use mongodb::options::{CountOptions, FindOneOptions, ReplaceOptions, UpdateOptions};
+use mongodb::sync::Collection;
 use rayon::{ThreadPool, ThreadPoolBuilder};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tracing::info;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
 
 /// Mongodb syncer to sync from one database to another database.
 ///
@@ -24,43 +51,88 @@ use tracing::info;
 /// ```
 pub struct MongoSyncer<'a> {
     conf: &'a DbSyncConf,
+    progress: FullSyncProgress,
+    retry_stats: RetryStats,
+    cancel: CancelToken,
 }
 
 const LARGE_COLL_SIZE: usize = 10000;
+/// how often to log full sync progress.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+/// how many times differences are checked again in verification.
+const VERIFY_RECHECK_ROUNDS: usize = 3;
+/// how long to wait for incremental sync to pass differences before checking them again.
+const VERIFY_RECHECK_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long to wait for incremental sync to handle repair command.
+const REPAIR_ACK_TIMEOUT: Duration = Duration::from_secs(60);
 
 impl<'a> MongoSyncer<'a> {
     /// create a new Syncer according to given `conf`.
     pub fn new(conf: &DbSyncConf) -> MongoSyncer {
-        MongoSyncer { conf }
+        MongoSyncer {
+            conf,
+            progress: FullSyncProgress::new(),
+            retry_stats: RetryStats::default(),
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// stop syncing when `cancel` is cancelled.
+    ///
+    /// The in-flight batch is finished, copy progress or check point is saved, then the syncer returns
+    /// [SyncError::Cancelled], next sync resumes from there.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// get full sync progress tracker of the syncer.
+    ///
+    /// The tracker is shared with the syncer, so it can be read from another thread while the syncer is running.
+    pub fn progress(&self) -> FullSyncProgress {
+        self.progress.clone()
     }
 
     /// go and sync databse forever.
+    ///
+    /// When the configuration selects multiple databases by [DbSyncConf::with_dbs], all of them are synced.
+    ///
+    /// If check point is older than the earliest stored oplog, it's handled by
+    /// [DbSyncConf::with_oplog_gap_policy], by default it returns [SyncError::OplogWindowExceeded].
     pub fn sync(self) -> Result<()> {
+        if self.conf.get_dbs().is_some() {
+            return self.sync_dbs();
+        }
         // Full sync stage.
         {
             let connection = Connection::new(self.conf)?;
-            let manager = SyncManager::new(connection);
-            // check time record missing.
-            if manager.is_time_record_missing()? {
+            self.conf.validate_write_policy()?;
+            self.conf.validate_buffers()?;
+            self.conf.validate_sampling()?;
+            self.conf.get_namespace_mapping().validate()?;
+            self.conf.get_coll_selector().validate()?;
+            connection.check_source_target_differ()?;
+            validate_coll_filters(self.conf.get_coll_filters())?;
+            let db = self.conf.get_db().to_string();
+            let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone())
+                .with_recorded_coll_selector(&[db.clone()])?;
+            // check time record missing, or oplogs after it are lost.
+            if manager.is_time_record_missing()? || manager.handle_oplog_gap(&[db])? {
                 manager.sync_full()?;
             } else {
                 // Although we are in increment state, but we may need to full sync some collection.
                 // Example:
                 // The first time we only want to sync collection `A`, `B`.
                 // But this time we want to sync collection `A`, `B`, `C` (or full DB).
+                // New collections are copied in background, other collections keep streaming.
                 if let Some(new_colls) = manager.get_new_colls_to_sync()? {
                     info!(
                         ?new_colls,
-                        "Get new collections to sync, apply oplogs until now. "
-                    );
-                    manager.sync_incr_to_now()?;
-                    info!(?new_colls, "Make full sync for new collections. ");
-                    manager.sync_documents_for_collections(&new_colls)?;
-                    info!(
-                        ?new_colls,
-                        "Full sync for new collections complete, goes into incremental node. "
+                        "Get new collections to sync, copy them in incremental mode. "
                     );
+                    manager.add_colls_with_checkpoint(&new_colls)?;
                 }
+                manager.handle_removed_colls()?;
             }
             // record sync collection arguments.
             manager.write_sync_colls_args()?;
@@ -69,99 +141,742 @@ impl<'a> MongoSyncer<'a> {
         // Incremental sync stage
         {
             let connection = Connection::new(self.conf)?;
-            let manager = SyncManager::new(connection);
+            let db = self.conf.get_db().to_string();
+            let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone())
+                .with_recorded_coll_selector(&[db])?;
 
             manager.sync_incr_forever()
         }
     }
+
+    /// go and sync database forever like [sync](MongoSyncer::sync), and sync again after transient errors.
+    ///
+    /// Each run reconnects and resumes from check point, runs are retried by `policy` with exponential backoff
+    /// and jitter.  Fatal errors are returned at once, retries can be read by
+    /// [retry_stats](MongoSyncer::retry_stats).
+    pub fn sync_supervised(self, policy: RetryPolicy) -> Result<()> {
+        let supervisor = Supervisor::with_stats(policy, self.retry_stats.clone())
+            .with_cancel_token(self.cancel.clone());
+        supervisor.run("db_sync", || {
+            MongoSyncer {
+                conf: self.conf,
+                progress: self.progress.clone(),
+                retry_stats: self.retry_stats.clone(),
+                cancel: self.cancel.clone(),
+            }
+            .sync()
+        })
+    }
+
+    /// get retry counts of [sync_supervised](MongoSyncer::sync_supervised).
+    ///
+    /// The counts are shared with the syncer, so they can be read from another thread while the syncer is
+    /// running.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.retry_stats.clone()
+    }
+
+    /// Make a one-shot copy of database, and return a summary report.
+    ///
+    /// Unlike [sync](MongoSyncer::sync), it doesn't need oplog storage, and it returns after documents,
+    /// indexes and collection options are copied.  A collection failed to sync doesn't stop other
+    /// collections, the error is saved in the report.
+    pub fn sync_once(self) -> Result<SyncReport> {
+        let start = Instant::now();
+        let connection = Connection::new(self.conf)?;
+        self.conf.validate_write_policy()?;
+        self.conf.validate_buffers()?;
+        self.conf.get_namespace_mapping().validate()?;
+        self.conf.get_coll_selector().validate()?;
+        let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone());
+
+        let dbs = match self.conf.get_dbs() {
+            Some(selector) => {
+                let dbs = manager.get_dbs_to_sync(selector)?;
+                manager.check_dbs_differ(&dbs)?;
+                Some(dbs)
+            }
+            None => {
+                manager.conn.check_source_target_differ()?;
+                None
+            }
+        };
+
+        let snapshot = manager.pick_snapshot()?;
+        let _progress_logger = self.progress.log_periodically(PROGRESS_LOG_INTERVAL);
+        let reports = match dbs {
+            Some(dbs) => {
+                info!(?dbs, "One-shot: begin to copy databases. ");
+                manager
+                    .for_each_db(&dbs, |db_manager| {
+                        let mut reports = db_manager.copy_db_once(snapshot.clone())?;
+                        // collections of different databases may have the same name.
+                        for report in reports.iter_mut() {
+                            report.name = format!("{}.{}", db_manager.conn.get_db(), report.name);
+                        }
+                        Ok(reports)
+                    })?
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            }
+            None => manager.copy_db_once(snapshot)?,
+        };
+        self.progress.log_summary();
+
+        let report = SyncReport {
+            collections: reports,
+            duration: start.elapsed(),
+        };
+        info!(
+            success = report.is_success(),
+            "One-shot: copy complete.\n{}", report
+        );
+        Ok(report)
+    }
+
+    /// Restore target databases to a point in time, by replaying stored oplogs onto a base copy, and return
+    /// a summary report.
+    ///
+    /// Oplogs are replayed until `options.get_until()`, or before `options.get_stop_before()`, and check point
+    /// is moved to the last replayed oplog.  Syncer must not run against target during restore.
+    pub fn restore(self, options: &RestoreOptions) -> Result<RestoreReport> {
+        let start = Instant::now();
+        let connection = Connection::new(self.conf)?;
+        self.conf.validate_write_policy()?;
+        self.conf.validate_buffers()?;
+        self.conf.get_namespace_mapping().validate()?;
+        self.conf.get_coll_selector().validate()?;
+        let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone());
+        // replayed oplogs must never be written back onto source.
+        match self.conf.get_dbs() {
+            Some(selector) => {
+                let dbs = manager.get_dbs_to_sync(selector)?;
+                manager.check_dbs_differ(&dbs)?;
+            }
+            None => manager.conn.check_source_target_differ()?,
+        }
+
+        let mut report = manager.restore(options)?;
+        report.duration = start.elapsed();
+        info!("Restore: restore complete.\n{}", report);
+        Ok(report)
+    }
+
+    /// Verify target against source, and return a report of differences.
+    ///
+    /// Counts, indexes and collection options are compared, then documents are compared by hashes of `_id`
+    /// ranges, and differing `_id`s are listed.  When oplog storage is configured, differences are checked
+    /// again after check point passes them, so documents changed during verification are not reported.
+    pub fn verify(self) -> Result<VerifyReport> {
+        let start = Instant::now();
+        let connection = Connection::new(self.conf)?;
+        self.conf.get_namespace_mapping().validate()?;
+        self.conf.get_coll_selector().validate()?;
+        let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone());
+
+        let collections = match self.conf.get_dbs() {
+            Some(selector) => {
+                let dbs = manager.get_dbs_to_sync(selector)?;
+                info!(?dbs, "Verify: begin to verify databases. ");
+                manager
+                    .for_each_db(&dbs, |db_manager| {
+                        let mut reports = db_manager.verify_db()?;
+                        for report in reports.iter_mut() {
+                            report.name = report.ns.clone();
+                        }
+                        Ok(reports)
+                    })?
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            }
+            None => manager.verify_db()?,
+        };
+        let report = VerifyReport {
+            collections,
+            duration: start.elapsed(),
+        };
+        info!(
+            consistent = report.is_consistent(),
+            "Verify: verify complete.\n{}", report
+        );
+        Ok(report)
+    }
+
+    /// Repair documents `docs` of target, they are copied from source again, or removed if they don't exist
+    /// in source any more, and return a summary document.
+    ///
+    /// When oplog storage is configured, docs are repaired by incremental sync between oplog batches, so
+    /// stored oplogs before the repair don't overwrite them.  The repair command is queued if incremental
+    /// sync doesn't handle it in time, and it's handled when db_sync runs.
+    pub fn repair(self, docs: &[RepairDoc]) -> Result<Document> {
+        let connection = Connection::new(self.conf)?;
+        self.conf.get_namespace_mapping().validate()?;
+        self.conf.get_coll_selector().validate()?;
+        let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone());
+
+        if self.conf.get_oplog_storage_uri().is_none() {
+            let dbs = match self.conf.get_dbs() {
+                Some(selector) => manager.get_dbs_to_sync(selector)?.into_iter().collect(),
+                None => HashSet::from([manager.conn.get_db().to_string()]),
+            };
+            let result = manager.repair_docs(
+                docs,
+                &dbs,
+                self.conf.get_coll_selector(),
+                &mut FenceSet::default(),
+            )?;
+            info!(?result, "Repair: repair complete. ");
+            return Ok(result);
+        }
+        let docs: Vec<Document> = docs.iter().map(|doc| doc.to_document()).collect();
+        let control = ControlChannel::new(manager.conn.control_coll());
+        let id = control.submit(doc! {"command": "repair", "docs": docs})?;
+        match control.wait_ack(&id, REPAIR_ACK_TIMEOUT)? {
+            Some(acked) if acked.get_str("state")? == "done" => {
+                let result = acked.get_document("result")?.clone();
+                info!(?result, "Repair: repair complete. ");
+                Ok(result)
+            }
+            Some(acked) => Err(SyncError::RepairFailed {
+                detail: acked.get_str("error").unwrap_or_default().to_string(),
+            }),
+            None => {
+                warn!(
+                    ?id,
+                    "Repair: incremental sync doesn't handle repair in time, it's queued. "
+                );
+                Ok(doc! {"queued": id})
+            }
+        }
+    }
+
+    /// sync all selected databases forever, they share one oplog reader and one checkpoint.
+    fn sync_dbs(self) -> Result<()> {
+        let connection = Connection::new(self.conf)?;
+        self.conf.validate_write_policy()?;
+        self.conf.validate_buffers()?;
+        self.conf.validate_sampling()?;
+        self.conf.get_namespace_mapping().validate()?;
+        self.conf.get_coll_selector().validate()?;
+        validate_coll_filters(self.conf.get_coll_filters())?;
+        let manager = SyncManager::new(connection, self.progress.clone(), self.cancel.clone());
+        // unwrap is ok because it's only called when databases are selected.
+        let dbs = manager.get_dbs_to_sync(self.conf.get_dbs().unwrap())?;
+        manager.check_dbs_differ(&dbs)?;
+        let manager = manager.with_recorded_coll_selector(&dbs)?;
+
+        if manager.is_time_record_missing()? || manager.handle_oplog_gap(&dbs)? {
+            manager.sync_full_dbs(&dbs)?;
+        } else {
+            let new_dbs = manager.get_new_dbs_to_sync(&dbs)?.unwrap_or_default();
+            // like a single database, new collections of synced databases are copied in background.
+            for db in dbs.iter().filter(|db| !new_dbs.contains(db)) {
+                let db_manager = manager.for_db(db);
+                if let Some(new_colls) = db_manager.get_new_colls_to_sync()? {
+                    info!(
+                        %db,
+                        ?new_colls,
+                        "Get new collections to sync, copy them in incremental mode. "
+                    );
+                    db_manager.add_colls_with_checkpoint(&new_colls)?;
+                }
+            }
+            if !new_dbs.is_empty() {
+                // like new collections, existing databases which are selected this time need full sync.
+                info!(
+                    ?new_dbs,
+                    "Get new databases to sync, apply oplogs until now. "
+                );
+                manager.sync_incr_to_now(&new_dbs)?;
+                info!(?new_dbs, "Make full sync for new databases. ");
+                manager.sync_documents_for_dbs(&new_dbs)?;
+                info!(
+                    ?new_dbs,
+                    "Full sync for new databases complete, goes into incremental node. "
+                );
+            }
+        }
+        for db in dbs.iter() {
+            let db_manager = manager.for_db(db);
+            db_manager.handle_removed_colls()?;
+            db_manager.write_sync_colls_args()?;
+        }
+        manager.write_sync_dbs_args(&dbs)?;
+
+        manager.sync_incr_forever()
+    }
+}
+
+/// Convert copy `result` of collection `coll` to [SyncTableStatus], and mark the collection as done in `plan`
+/// if it's copied successfully.
+fn finish_coll(
+    result: Result<CopyStats>,
+    plan: &Option<FullSyncPlan>,
+    coll: &str,
+) -> SyncTableStatus {
+    let result = result.and_then(|stats| {
+        if let Some(plan) = plan {
+            plan.finish_coll(coll)?;
+        }
+        Ok(stats)
+    });
+    match result {
+        Ok(stats) => SyncTableStatus::Done(stats),
+        Err(e) => SyncTableStatus::Failed(e),
+    }
 }
 
 struct SyncManager<'a> {
     conn: Connection<'a>,
-    pool: ThreadPool,
+    pool: Arc<ThreadPool>,
     coll_sync_pool: Arc<ThreadPool>,
+    progress: FullSyncProgress,
+    budget: MemoryBudget,
+    cancel: CancelToken,
+    /// collections to sync, it contains collections added or removed at runtime after
+    /// [with_recorded_coll_selector](SyncManager::with_recorded_coll_selector).
+    coll_selector: CollSelector,
 }
 
 impl<'a> SyncManager<'a> {
-    pub fn new(conn: Connection) -> SyncManager {
+    pub fn new(conn: Connection, progress: FullSyncProgress, cancel: CancelToken) -> SyncManager {
         let conf = conn.get_conf();
         let coll_concurrent = conf.get_collection_concurrent();
         let doc_concurrent = conf.get_doc_concurrent();
+        let budget = MemoryBudget::new(conf.get_memory_budget());
+        let coll_selector = conf.get_coll_selector().clone();
         SyncManager {
             conn,
+            progress,
+            budget,
+            cancel,
+            coll_selector,
             coll_sync_pool: Arc::new(
                 ThreadPoolBuilder::new()
                     .num_threads(doc_concurrent)
                     .build()
                     .unwrap(),
             ),
-            pool: ThreadPoolBuilder::new()
-                .num_threads(coll_concurrent)
-                .build()
-                .unwrap(),
+            pool: Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(coll_concurrent)
+                    .build()
+                    .unwrap(),
+            ),
+        }
+    }
+
+    /// create a manager which syncs database `db`, thread pools, progress and memory budget are shared with
+    /// this manager, so all databases are synced under the same concurrency.
+    fn for_db(&self, db: &str) -> SyncManager<'a> {
+        SyncManager {
+            conn: self.conn.with_db(db),
+            pool: self.pool.clone(),
+            coll_sync_pool: self.coll_sync_pool.clone(),
+            progress: self.progress.clone(),
+            budget: self.budget.clone(),
+            cancel: self.cancel.clone(),
+            coll_selector: self.coll_selector.clone(),
         }
     }
 
+    /// keep collections added or removed at runtime, which are recorded in collection sync arguments of
+    /// `dbs`, unless collection names or patterns are changed since then.
+    ///
+    /// Runtime changes are recorded in every synced database, so the first record is used.
+    fn with_recorded_coll_selector(mut self, dbs: &[String]) -> Result<Self> {
+        for db in dbs.iter() {
+            if let Some(recorded) = self.for_db(db).get_sync_coll_args()? {
+                self.coll_selector =
+                    std::mem::take(&mut self.coll_selector).with_runtime_changes(&recorded);
+                break;
+            }
+        }
+        Ok(self)
+    }
+
+    /// run `f` with a manager of each database in `dbs` concurrently, returns results in the order of `dbs`.
+    fn for_each_db<T, F>(&self, dbs: &[String], f: F) -> Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(&SyncManager) -> Result<T> + Sync,
+    {
+        let f = &f;
+        crossbeam::scope(|scope| {
+            let handles: Vec<_> = dbs
+                .iter()
+                .map(|db| {
+                    let manager = self.for_db(db);
+                    scope.spawn(move |_| f(&manager))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("database sync thread panicked"))
+                .collect()
+        })
+        .expect("database sync thread panicked")
+    }
+
     /// make a full sync progress.
+    ///
+    /// If snapshot read is enabled and source supports it, documents are read at the cluster time of
+    /// `oplog_start`, so target database is consistent as of `oplog_start` even before incremental replay.
+    ///
+    /// The progress is saved in a plan in target database, if a previous full sync is interrupted, it resumes
+    /// unfinished ranges with the original oplog start point.
     pub fn sync_full(&self) -> Result<()> {
-        let oplog_start = oplog_helper::get_latest_ts_no_capped(&self.conn.oplog_coll())?;
+        let start = self.pick_full_start()?;
+        let _progress_logger = self.progress.log_periodically(PROGRESS_LOG_INTERVAL);
+        let (oplog_start, plan) = self.copy_db_full(&start)?;
+        self.progress.log_summary();
+        self.finish_full(oplog_start, &[plan])
+    }
+
+    /// make a full sync progress for databases `dbs`, they are copied concurrently.
+    ///
+    /// All databases are read at the same snapshot if snapshot read is enabled and supported, and the
+    /// incremental sync starts from the earliest oplog start point of them.
+    fn sync_full_dbs(&self, dbs: &[String]) -> Result<()> {
+        let start = self.pick_full_start()?;
+        info!(?dbs, "Full state: begin to sync multiple databases. ");
+        let _progress_logger = self.progress.log_periodically(PROGRESS_LOG_INTERVAL);
+        let copied = self.for_each_db(dbs, |db_manager| db_manager.copy_db_full(&start))?;
+        self.progress.log_summary();
+
+        let mut oplog_start = start.0;
+        let mut plans = Vec::with_capacity(copied.len());
+        for (db_oplog_start, plan) in copied {
+            if db_oplog_start < oplog_start {
+                oplog_start = db_oplog_start;
+            }
+            plans.push(plan);
+        }
+        self.finish_full(oplog_start, &plans)
+    }
+
+    /// pick oplog start point of a new full sync, with a snapshot at the point if source mongodb supports
+    /// snapshot read.
+    fn pick_full_start(&self) -> Result<(Timestamp, Option<SnapshotRead>)> {
+        let snapshot = self.pick_snapshot()?;
+        let oplog_start = match &snapshot {
+            Some(snapshot) => snapshot.at,
+            None => oplog_helper::get_latest_ts_no_capped(&self.conn.oplog_coll()?)?,
+        };
+        Ok((oplog_start, snapshot))
+    }
 
-        info!(?oplog_start, "Full state: begin to sync databases. ");
-        self.sync_documents_full()?;
+    /// copy documents and indexes of the database, returns oplog start point and the plan of full sync.
+    ///
+    /// A new full sync starts from `start`.  If a previous full sync is interrupted, it resumes with the
+    /// original oplog start point in the plan.
+    fn copy_db_full(
+        &self,
+        start: &(Timestamp, Option<SnapshotRead>),
+    ) -> Result<(Timestamp, FullSyncPlan)> {
+        let plan = FullSyncPlan::new(&self.conn.get_target_db());
+        let (oplog_start, coll_names, options) = match plan.header()? {
+            Some(header) => {
+                // resumed documents are read from live data, the snapshot may be too old, and later oplog
+                // replay will make target consistent.
+                let mut coll_names = header.colls;
+                let new_colls: Vec<String> = self
+                    .get_colls_to_sync()?
+                    .into_iter()
+                    .filter(|coll| !coll_names.contains(coll))
+                    .collect();
+                // collections selected after the plan is created are not copied yet, so they're written by
+                // write policy before they're upserted, and recorded so they're not new on next resume.
+                if !new_colls.is_empty() {
+                    self.check_write_policy(&new_colls)?;
+                    self.drop_targets(&new_colls)?;
+                    plan.add_colls(&new_colls)?;
+                    coll_names.extend(new_colls);
+                }
+                info!(db=%self.conn.get_db(), oplog_start=?header.oplog_start, ?coll_names, "Full state: resume unfinished full sync. ");
+                let options = CopyOptions {
+                    snapshot: None,
+                    plan: Some(plan.clone()),
+                    upsert: true,
+                    ..Default::default()
+                };
+                (header.oplog_start, coll_names, options)
+            }
+            None => {
+                let (oplog_start, snapshot) = start.clone();
+                let coll_names = self.get_colls_to_sync()?;
+                plan.create(oplog_start, &coll_names)?;
+                info!(
+                    db=%self.conn.get_db(),
+                    ?oplog_start,
+                    snapshot_read = snapshot.is_some(),
+                    "Full state: begin to sync databases. "
+                );
+                let options = CopyOptions {
+                    snapshot,
+                    plan: Some(plan.clone()),
+                    ..Default::default()
+                };
+                (oplog_start, coll_names, options)
+            }
+        };
+
+        if coll_names.is_empty() {
+            info!(db=%self.conn.get_db(), "Full state: no collections in database, done.");
+        } else {
+            self.sync_documents_with_options(&coll_names, options)?;
+        }
+        Ok((oplog_start, plan))
+    }
+
+    /// check oplogs from `oplog_start` are still available, then write `oplog_start` as the start point of
+    /// incremental sync, and remove full sync `plans`.
+    fn finish_full(&self, oplog_start: Timestamp, plans: &[FullSyncPlan]) -> Result<()> {
         info!(
             ?oplog_start,
             "Full state: sync database complete, check oplog and write start point."
         );
 
-        if self.check_log_valid(oplog_start)? {
-            self.write_log_record(oplog_start)?;
-            info!(
-                ?oplog_start,
-                "Full state: write oplog start point complete, goes into incremental mode."
-            )
-        } else {
-            panic!("Full state: oplog is no-longer valid, because they are not exists in database");
+        if !self.check_log_valid(oplog_start)? {
+            // the plan is kept, so the copy isn't made again after oplog storage is fixed.
+            return Err(SyncError::OplogWindowExceeded {
+                start: oplog_start,
+                earliest: oplog_helper::get_earliest_ts_no_capped(&self.conn.oplog_coll()?)?,
+            });
         }
+        self.write_log_record(oplog_start)?;
+        for plan in plans {
+            plan.remove()?;
+        }
+        // all collections are copied, so they follow the global checkpoint.
+        CollCheckpoints::new(self.conn.coll_checkpoint_coll()).remove_all()?;
+        info!(
+            ?oplog_start,
+            "Full state: write oplog start point complete, goes into incremental mode."
+        );
         Ok(())
     }
 
+    /// make a one-shot copy of the database, returns sync report for each collection.
+    fn copy_db_once(&self, snapshot: Option<SnapshotRead>) -> Result<Vec<CollSyncReport>> {
+        let coll_names = self.get_colls_to_sync()?;
+        info!(db=%self.conn.get_db(), ?coll_names, snapshot_at=?snapshot.as_ref().map(|s| s.at), "One-shot: begin to copy collections. ");
+        let options = CopyOptions {
+            snapshot,
+            ..Default::default()
+        };
+        let mut reports = self.copy_collections(&coll_names, options)?;
+        info!(db=%self.conn.get_db(), "One-shot: Begin to re-build index for target collection");
+        for report in reports.iter_mut().filter(|r| r.error.is_none()) {
+            if let Err(e) = self.rebuild_indexes(&report.name) {
+                error!(coll=%report.name, ?e, "One-shot: re-build index failed. ");
+                report.error = Some(e);
+            }
+        }
+        Ok(reports)
+    }
+
+    /// return true if there is no time record, which means databases are never synced.
     pub fn is_time_record_missing(&self) -> Result<bool> {
-        // when the following happened, return true:
-        // 1. can't get time record information, or
-        // 2. the minimum value of oplog timestamp > time record.
-        // condition 2 means that oplog can't be applied between time record and min(oplog timestamp)
-        let coll = self.conn.time_record_coll();
-        let rec = coll.find_one(None, None)?;
-        match rec {
-            Some(doc) => {
-                let ts = doc.get_timestamp(TIMESTAMP_KEY)?;
-                let missing = self
-                    .conn
-                    .oplog_coll()
-                    .find_one(
-                        None,
-                        FindOneOptions::builder().sort(doc! {"$natural": 1}).build(),
-                    )?
-                    .map(|log| {
-                        // unwrap here is ok because oplog always contains `ts` field.
-                        let oldest_ts = log.get_timestamp(TIMESTAMP_KEY).unwrap();
-                        oldest_ts > ts
-                    });
-                match missing {
-                    Some(missed) => Ok(missed),
-                    None => Ok(false), // can't find oplog.
+        Ok(self.conn.time_record_coll().find_one(None, None)?.is_none())
+    }
+
+    /// get time record and the earliest stored oplog, if the earliest oplog is newer than time record, which
+    /// means oplogs between them can't be applied any more.
+    fn find_oplog_gap(&self) -> Result<Option<(Timestamp, Timestamp)>> {
+        let checkpoint = match self.conn.time_record_coll().find_one(None, None)? {
+            Some(doc) => doc.get_timestamp(TIMESTAMP_KEY)?,
+            None => return Ok(None),
+        };
+        let earliest = self
+            .conn
+            .oplog_coll()?
+            .find_one(
+                None,
+                FindOneOptions::builder().sort(doc! {"$natural": 1}).build(),
+            )?
+            .map(|log| log.get_timestamp(TIMESTAMP_KEY))
+            .transpose()?;
+        Ok(earliest
+            .filter(|earliest| *earliest > checkpoint)
+            .map(|earliest| (checkpoint, earliest)))
+    }
+
+    /// handle time record which falls outside the oplog window by [OplogGapPolicy], returns true if
+    /// databases `dbs` need a full sync again.
+    ///
+    /// Resync policies write target collections by [FullSyncWritePolicy]: all target collections are checked
+    /// first, and [SyncError::TargetNotEmpty] names the non-empty ones under `fail-if-non-empty`, `drop`
+    /// drops them, `merge` keeps them to upsert into.  Time record is moved or removed before copy, so a
+    /// resync interrupted later isn't made from scratch again.
+    fn handle_oplog_gap(&self, dbs: &[String]) -> Result<bool> {
+        let (checkpoint, earliest) = match self.find_oplog_gap()? {
+            Some(gap) => gap,
+            None => return Ok(false),
+        };
+        let policy = self.conn.get_conf().get_oplog_gap_policy();
+        let gap = SyncError::OplogWindowExceeded {
+            start: checkpoint,
+            earliest,
+        };
+        match policy {
+            OplogGapPolicy::Fail => {
+                error!(?checkpoint, ?earliest, %gap, "Incr state: check point falls outside oplog window, choose a resync policy to sync again. ");
+                Err(gap)
+            }
+            OplogGapPolicy::Resync => {
+                warn!(?checkpoint, ?earliest, ?dbs, %gap, "Incr state: check point falls outside oplog window, resync databases. ");
+                let mut targets = Vec::with_capacity(dbs.len());
+                for db in dbs {
+                    let db_manager = self.for_db(db);
+                    let colls = db_manager.get_colls_to_sync()?;
+                    db_manager.check_write_policy(&colls)?;
+                    targets.push((db_manager, colls));
+                }
+                for (db_manager, colls) in targets.iter() {
+                    db_manager.drop_targets(colls)?;
+                }
+                CollCheckpoints::new(self.conn.coll_checkpoint_coll()).remove_all()?;
+                self.conn.time_record_coll().delete_many(doc! {}, None)?;
+                Ok(true)
+            }
+            OplogGapPolicy::ResyncAffected => {
+                let checkpoints = CollCheckpoints::new(self.conn.coll_checkpoint_coll());
+                let own: HashMap<String, CollCheckpoint> = checkpoints
+                    .list()?
+                    .into_iter()
+                    .map(|checkpoint| (checkpoint.ns.clone(), checkpoint))
+                    .collect();
+                let mut targets = Vec::with_capacity(dbs.len());
+                for db in dbs {
+                    let db_manager = self.for_db(db);
+                    // collections being copied get new checkpoints, others with own checkpoints in the window
+                    // can still catch up.
+                    let affected: Vec<String> = db_manager
+                        .get_colls_to_sync()?
+                        .into_iter()
+                        .filter(|coll| match own.get(&format!("{}.{}", db, coll)) {
+                            Some(own) => {
+                                own.state == CheckpointState::CatchingUp && own.ts < earliest
+                            }
+                            None => true,
+                        })
+                        .collect();
+                    db_manager.check_write_policy(&affected)?;
+                    targets.push((db_manager, affected));
                 }
+                for (db_manager, affected) in targets.iter() {
+                    warn!(db=%db_manager.conn.get_db(), ?affected, ?checkpoint, ?earliest, "Incr state: check point falls outside oplog window, copy affected collections in background. ");
+                    db_manager.drop_targets(affected)?;
+                    db_manager.add_colls_with_checkpoint(affected)?;
+                }
+                // no collection follows the lost check point now.
+                self.write_log_record(earliest)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// drop target collections of source collections `coll_names` if write policy is
+    /// [FullSyncWritePolicy::Drop], other policies keep them.
+    fn drop_targets(&self, coll_names: &[String]) -> Result<()> {
+        if self.conn.get_conf().get_write_policy() != FullSyncWritePolicy::Drop {
+            return Ok(());
+        }
+        for coll in coll_names.iter() {
+            info!(db=%self.conn.get_db(), %coll, "Drop target collection to sync again. ");
+            self.conn.get_target_coll(coll).drop(None)?;
+        }
+        Ok(())
+    }
+
+    /// apply oplogs of synced databases, if `forever` is false, it returns after latest oplog is applied.
+    ///
+    /// When multiple databases are synced, selected databases which are created later are picked up, except
+    /// `pending_dbs`, which will be full synced after this.
+    ///
+    /// Collections which have own checkpoints are copied in background, and catch up from their checkpoints
+    /// after copy, other collections keep streaming meanwhile.
+    ///
+    /// When syncing forever with sampling configured, documents are sampled and compared in background.
+    fn sync_incr(&self, forever: bool, pending_dbs: &[String]) -> Result<()> {
+        let checkpoints = CollCheckpoints::new(self.conn.coll_checkpoint_coll());
+        let pending_colls: HashMap<String, CollCheckpoint> = checkpoints
+            .list()?
+            .into_iter()
+            .map(|checkpoint| (checkpoint.ns.clone(), checkpoint))
+            .collect();
+        let mut colls_to_copy: HashMap<String, Vec<String>> = HashMap::new();
+        for checkpoint in pending_colls.values() {
+            if checkpoint.state == CheckpointState::Copying {
+                let (db, coll) = checkpoint.split_ns();
+                colls_to_copy
+                    .entry(db.to_string())
+                    .or_default()
+                    .push(coll.to_string());
             }
-            None => Ok(true),
         }
+
+        let stop_sampling = AtomicBool::new(false);
+        crossbeam::scope(|scope| {
+            let (copied_sender, copied_receiver) = channel::unbounded();
+            let copy_in_background = |db: &str, coll_names: Vec<String>| {
+                let manager = self.for_db(db);
+                let checkpoints = checkpoints.clone();
+                let copied_sender = copied_sender.clone();
+                scope.spawn(move |_| {
+                    let copied = manager.copy_with_checkpoint(&coll_names, &checkpoints);
+                    let _ = copied_sender.send(copied);
+                });
+            };
+            for (db, coll_names) in colls_to_copy {
+                copy_in_background(&db, coll_names);
+            }
+            if let (true, Some(sampling)) = (forever, self.conn.get_conf().get_sampling()) {
+                let manager = self.for_db(self.conn.get_db());
+                let stop = &stop_sampling;
+                scope.spawn(move |_| manager.sample_consistency(sampling, stop));
+            }
+            let result = self.stream_oplogs(
+                forever,
+                pending_dbs,
+                &checkpoints,
+                pending_colls,
+                copied_receiver,
+                &copy_in_background,
+            );
+            stop_sampling.store(true, Ordering::Relaxed);
+            result
+        })
+        .expect("collection copy thread panicked")
     }
 
-    fn sync_incr(&self, forever: bool) -> Result<()> {
-        let oplog_coll = self.conn.oplog_coll();
+    /// apply oplogs like [sync_incr](SyncManager::sync_incr), oplogs of `pending_colls` are not applied until
+    /// they are copied, `copied` receives checkpoints of collections which are copied.
+    ///
+    /// Control commands are handled between oplog batches, `copy_in_background` copies collections of a
+    /// database which are resynced or added.
+    fn stream_oplogs(
+        &self,
+        forever: bool,
+        pending_dbs: &[String],
+        checkpoints: &CollCheckpoints,
+        mut pending_colls: HashMap<String, CollCheckpoint>,
+        copied: Receiver<Result<Vec<CollCheckpoint>>>,
+        copy_in_background: &dyn Fn(&str, Vec<String>),
+    ) -> Result<()> {
+        let oplog_coll = self.conn.oplog_coll()?;
         let mut sleep_secs = std::time::Duration::from_secs(3);
 
-        let target_client = self.conn.get_target_client();
-        let mut incr_dumper = IncrDumper::new(target_client);
+        let limits = self.conn.get_conf().get_batch_limits();
+        let conf = self.conn.get_conf();
+        let mapping = conf.get_namespace_mapping();
+        // changes made by syncer itself must be ignored when syncing into the same cluster.
+        let same_cluster = self.conn.is_same_cluster()?;
+        let ignore_mapped_targets = !mapping.is_empty() && same_cluster;
+        let mut incr_dumper = self.new_incr_dumper()?;
 
         // it's only useful when we don't want to sync forever.
         // When we don't want to sync forever, we just want to apply oplog until this end_point.
@@ -181,12 +896,78 @@ impl<'a> SyncManager<'a> {
                 increment: 0,
             }
         };
-        let sync_db_name = self.conn.get_conf().get_db();
-        let colls_to_sync = self.get_sync_coll_args()?;
+        let mut dbs_to_sync = self.get_incr_dbs()?;
+        let mut coll_selector = self.coll_selector.clone();
+        let control = ControlChannel::new(self.conn.control_coll());
+        let repair_fences = RepairFences::new(self.conn.repair_fence_coll());
+        // fast-forward bound is saved next to check point, so it survives a restart.
+        let mut control_state = ControlState {
+            fences: repair_fences.list()?,
+            fast_forward_to: self
+                .conn
+                .time_record_coll()
+                .find_one(None, None)?
+                .and_then(|record| record.get_timestamp("fast_forward_to").ok()),
+            ..ControlState::default()
+        };
+        // applying until now is used to pick up new databases, so the delay only holds when syncing forever.
+        let apply_delay = if forever {
+            conf.get_apply_delay()
+        } else {
+            None
+        };
+        // current source documents are newer than a delayed target, so they're not fetched.
+        if apply_delay.is_none() {
+            incr_dumper = incr_dumper.with_source_fallback(self.conn.get_src_client());
+            if conf.get_full_document_updates() {
+                incr_dumper = incr_dumper.with_full_document_updates(self.conn.get_src_client());
+            }
+        } else if conf.get_full_document_updates() {
+            warn!("Incr state: full document updates are ignored because oplogs are applied with delay. ");
+        }
+        let dead_letters = DeadLetters::new(self.conn.dead_letter_coll());
+        incr_dumper = incr_dumper.with_retry(conf.get_apply_retry());
+        if conf.get_apply_failure_policy() == ApplyFailurePolicy::DeadLetter {
+            incr_dumper = incr_dumper.with_dead_letters(dead_letters.clone());
+        }
 
         loop {
-            if !sleep_secs.is_zero() {
-                std::thread::sleep(sleep_secs);
+            // check point is written after each batch, so it's safe to stop here.
+            self.cancel.sleep(sleep_secs)?;
+            control_state.unmatched = incr_dumper.unmatched_stats();
+            control_state.dead_lettered = incr_dumper.dead_lettered();
+            for command_doc in control.pending()? {
+                let result = ControlCommand::from_document(&command_doc).and_then(|command| {
+                    match command {
+                        // dead-lettered oplogs are applied by the dumper of incremental sync.
+                        ControlCommand::RedriveDeadLetters => {
+                            if !pending_colls.is_empty() {
+                                return Err(SyncError::InvalidControlCommand {
+                                    detail: "collections are being copied".to_string(),
+                                });
+                            }
+                            incr_dumper.redrive_dead_letters(&dead_letters)
+                        }
+                        command => self.apply_control(
+                            &command,
+                            &mut control_state,
+                            &mut coll_selector,
+                            &mut pending_colls,
+                            &dbs_to_sync,
+                            copy_in_background,
+                        ),
+                    }
+                });
+                info!(
+                    ?command_doc,
+                    ?result,
+                    "Incr state: handle control command. "
+                );
+                control.ack(&command_doc, &result)?;
+            }
+            if control_state.paused {
+                sleep_secs = std::time::Duration::from_secs(3);
+                continue;
             }
             let start_point = self
                 .conn
@@ -194,14 +975,53 @@ impl<'a> SyncManager<'a> {
                 .find_one(None, None)?
                 .unwrap()
                 .get_timestamp(TIMESTAMP_KEY)?;
+            // the bound is not needed any more once check point or the delay passes it.
+            if let (Some(delay), Some(to)) = (apply_delay, control_state.fast_forward_to) {
+                if start_point >= to || delayed_point(delay, None) >= to {
+                    control_state.fast_forward_to = None;
+                    self.write_fast_forward(None)?;
+                }
+            }
+            // collections which are copied in background catch up before the next batch.
+            for copied_colls in copied.try_iter() {
+                for checkpoint in copied_colls? {
+                    pending_colls.insert(checkpoint.ns.clone(), checkpoint);
+                }
+            }
+            let caught_up: Vec<CollCheckpoint> = pending_colls
+                .values()
+                .filter(|checkpoint| checkpoint.state == CheckpointState::CatchingUp)
+                .cloned()
+                .collect();
+            for checkpoint in caught_up {
+                let ns = checkpoint.ns.clone();
+                self.catch_up_coll(
+                    checkpoint,
+                    start_point,
+                    &oplog_coll,
+                    &mut incr_dumper,
+                    checkpoints,
+                )?;
+                pending_colls.remove(&ns);
+            }
 
             let mut end_point = None;
             if !forever {
                 end_point = Some(original_end_point);
             }
+            if let Some(delay) = apply_delay {
+                end_point = Some(delayed_point(delay, control_state.fast_forward_to));
+            }
 
             info!(?start_point, ?end_point, "Incr state: Begin fetch oplog. ");
-            let oplogs = oplog_helper::get_next_batch(&oplog_coll, start_point, end_point, 10000)?;
+            // oplogs are buffered until they are applied.
+            let _permit = self.budget.acquire(limits.max_bytes);
+            let mut oplogs = oplog_helper::get_next_batch_with_limits(
+                &oplog_coll,
+                start_point,
+                end_point,
+                &limits,
+            )?;
             if oplogs.is_empty() {
                 info!("Incr state: No new oplogs available here, continue..");
                 if !forever {
@@ -209,14 +1029,57 @@ impl<'a> SyncManager<'a> {
                 }
                 sleep_secs = std::time::Duration::from_secs(3);
                 continue;
-            } else if oplogs.len() < 1000 {
+            } else if oplogs.len() < 1000
+                && oplogs.iter().map(encoded_size).sum::<usize>() < limits.max_bytes
+            {
+                // there are few oplogs, and the batch is not limited by bytes.
                 sleep_secs = std::time::Duration::from_secs(2);
             } else {
                 sleep_secs = std::time::Duration::from_secs(0);
             }
 
-            let latest_oplog_time = oplogs[oplogs.len() - 1].get_timestamp(TIMESTAMP_KEY)?;
-            let oplogs = oplog_helper::filter_oplogs(oplogs, sync_db_name, &colls_to_sync);
+            let mut latest_oplog_time = oplogs[oplogs.len() - 1].get_timestamp(TIMESTAMP_KEY)?;
+            if let Some(selector) = conf.get_dbs() {
+                for db in new_created_dbs(&oplogs, &dbs_to_sync) {
+                    if pending_dbs.contains(&db)
+                        || !self.is_db_selected(selector, &db, same_cluster)
+                    {
+                        continue;
+                    }
+                    info!(%db, "Incr state: pick up new created database. ");
+                    self.add_sync_db(&db)?;
+                    dbs_to_sync.insert(db);
+                }
+            }
+            // documents of a collection which is renamed into sync set are not synced before, so oplogs are
+            // applied until the rename, then the collection is copied from source.
+            let renamed = oplogs.iter().enumerate().find_map(|(pos, one_log)| {
+                oplog_helper::renamed_into_selected(one_log, &dbs_to_sync, &coll_selector)
+                    .map(|ns| (pos, ns))
+            });
+            let renamed_coll = match renamed {
+                Some((pos, ns)) => {
+                    latest_oplog_time = oplogs[pos].get_timestamp(TIMESTAMP_KEY)?;
+                    oplogs.truncate(pos);
+                    sleep_secs = std::time::Duration::from_secs(0);
+                    Some(ns)
+                }
+                None => None,
+            };
+            let oplogs = oplog_helper::filter_oplogs_in_dbs(oplogs, &dbs_to_sync, &coll_selector);
+            let oplogs = if ignore_mapped_targets {
+                oplog_helper::ignore_mapped_targets(oplogs, mapping)
+            } else {
+                oplogs
+            };
+            let oplogs = if pending_colls.is_empty() {
+                oplogs
+            } else {
+                let namespaces: HashSet<String> = pending_colls.keys().cloned().collect();
+                oplog_helper::partition_by_namespaces(oplogs, &namespaces).1
+            };
+            // oplogs of repaired documents before their fences are older than target.
+            let oplogs = control_state.fences.filter_oplogs(oplogs);
             if !oplogs.is_empty() {
                 info!(
                     ?start_point,
@@ -229,191 +1092,1394 @@ impl<'a> SyncManager<'a> {
                     let (need_apply_again, latest_applied_ts) = incr_dumper.apply_oplogs()?;
                     self.write_log_record(latest_applied_ts)?;
                     if !need_apply_again {
-                        break
+                        break;
                     }
                 }
                 info!(?end_point, "Incr state: Write oplog records");
             } else {
                 info!("Incr state: have fetch oplogs, but all of them is meant to be filtered.");
             }
+            // the renamed collection is copied like an added collection, by write policy and with own
+            // checkpoint, its later oplogs are held until it's copied.
+            if let Some((db, coll)) = renamed_coll {
+                info!(%db, %coll, "Incr state: collection is renamed into sync set, copy it from source in background. ");
+                let coll_names = vec![coll];
+                for checkpoint in self.for_db(&db).add_colls_with_checkpoint(&coll_names)? {
+                    pending_colls.insert(checkpoint.ns.clone(), checkpoint);
+                }
+                copy_in_background(&db, coll_names);
+            }
             self.write_log_record(latest_oplog_time)?;
+            if control_state.fences.release(latest_oplog_time) {
+                repair_fences.release(latest_oplog_time)?;
+            }
             if !forever && latest_oplog_time == original_end_point {
                 return Ok(());
             }
         }
     }
 
-    fn check_log_valid(&self, start_point: Timestamp) -> Result<bool> {
-        // just fetch oplog start point, if the start point is less than given oplogs, we can make sure that these oplogs is still valid.
-        let earliest_ts = oplog_helper::get_earliest_ts_no_capped(&self.conn.oplog_coll())?;
-        Ok(earliest_ts < start_point)
-    }
-
-    fn write_log_record(&self, log_ts: Timestamp) -> Result<()> {
-        self.conn.time_record_coll().update_one(
-            doc! {},
-            doc! { "$set": {"ts": log_ts} },
-            UpdateOptions::builder().upsert(true).build(),
-        )?;
-        Ok(())
+    /// create dumper which applies oplogs into target, with collection filters and namespace mapping.
+    fn new_incr_dumper(&self) -> Result<IncrDumper> {
+        let conf = self.conn.get_conf();
+        let oplog_filter = match conf.get_dbs() {
+            Some(_) => {
+                OplogFilter::for_all_dbs(conf.get_coll_filters(), self.conn.get_src_client())?
+            }
+            None => OplogFilter::new(
+                self.conn.get_db(),
+                conf.get_coll_filters(),
+                self.conn.get_src_client(),
+            )?,
+        };
+        let mut incr_dumper = IncrDumper::new(self.conn.get_target_client())
+            .with_batch_limits(conf.get_batch_limits())
+            .with_namespace_mapping(conf.get_namespace_mapping().clone());
+        if !oplog_filter.is_empty() {
+            incr_dumper = incr_dumper.with_oplog_filter(oplog_filter);
+        }
+        Ok(incr_dumper)
     }
 
-    fn sync_documents_full(&self) -> Result<()> {
-        let src_db = self.conn.get_src_db();
-        let coll_names = match self.conn.get_conf().get_colls() {
-            // use unwrap here is ok, because we have check list_collection_names before.
-            None => src_db.list_collection_names(None).unwrap(),
-            Some(colls) => colls.clone(),
+    /// restore target from `options.get_base()` until the restore point, returns what is replayed.
+    fn restore(&self, options: &RestoreOptions) -> Result<RestoreReport> {
+        let oplog_coll = self.conn.oplog_coll()?;
+        let limits = self.conn.get_conf().get_batch_limits();
+        let coll_selector = &self.coll_selector;
+        let mut incr_dumper = self.new_incr_dumper()?;
+        let (dbs, start_point) = match options.get_base() {
+            RestoreBase::Target => {
+                let start_point = self
+                    .conn
+                    .time_record_coll()
+                    .find_one(None, None)?
+                    .ok_or(SyncError::EmptyDocError)?
+                    .get_timestamp(TIMESTAMP_KEY)?;
+                (self.get_incr_dbs()?, start_point)
+            }
+            RestoreBase::Dump { dir, start } => {
+                let dbs = self.get_dump_dbs(dir)?;
+                for db in dbs.iter() {
+                    self.for_db(db).load_dump(dir)?;
+                }
+                let mut start_point = *start;
+                let oplog_path = dir.join(restore::DUMP_OPLOG_FILE);
+                if oplog_path.exists() {
+                    // the dump is consistent after oplogs during dump are applied.
+                    let oplogs: Vec<Document> =
+                        BsonFileReader::open(&oplog_path)?.collect::<Result<_>>()?;
+                    if let Some(one_log) = oplogs.last() {
+                        start_point = Some(one_log.get_timestamp(TIMESTAMP_KEY)?);
+                    }
+                    let dbs: HashSet<String> = dbs.iter().cloned().collect();
+                    incr_dumper.push_oplogs(oplog_helper::filter_oplogs_in_dbs(
+                        oplogs,
+                        &dbs,
+                        coll_selector,
+                    ));
+                    while incr_dumper.apply_oplogs()?.0 {}
+                }
+                let start_point = start_point.ok_or_else(|| SyncError::InvalidRestorePoint {
+                    detail: "the dump has no oplogs, start of the dump is required".to_string(),
+                })?;
+                (dbs.into_iter().collect(), start_point)
+            }
         };
+        let until = options.get_until();
+        if until < start_point {
+            return Err(SyncError::RestorePointBeforeBase {
+                until,
+                base: start_point,
+            });
+        }
+        if !self.check_log_valid(start_point)? {
+            return Err(SyncError::OplogWindowExceeded {
+                start: start_point,
+                earliest: oplog_helper::get_earliest_ts_no_capped(&oplog_coll)?,
+            });
+        }
+        let latest_ts = oplog_helper::get_latest_ts_no_capped(&oplog_coll)?;
+        if latest_ts < until {
+            warn!(
+                ?latest_ts,
+                ?until,
+                "Restore: oplog storage doesn't reach restore point yet. "
+            );
+        }
 
-        if !coll_names.is_empty() {
-            self.sync_documents_for_collections(&coll_names)
-        } else {
-            info!("Full state: no collections in database, done.");
-            Ok(())
+        info!(
+            ?dbs,
+            ?start_point,
+            ?until,
+            "Restore: begin to replay oplogs. "
+        );
+        let mut report = RestoreReport::new(start_point);
+        loop {
+            let _permit = self.budget.acquire(limits.max_bytes);
+            let mut oplogs = oplog_helper::get_next_batch_with_limits(
+                &oplog_coll,
+                report.end,
+                Some(until),
+                &limits,
+            )?;
+            if oplogs.is_empty() {
+                break;
+            }
+            if let Some(stop_before) = options.get_stop_before() {
+                report.stopped_before = restore::truncate_before(&mut oplogs, stop_before)?;
+            }
+            if let Some(one_log) = oplogs.last() {
+                let latest_oplog_time = one_log.get_timestamp(TIMESTAMP_KEY)?;
+                let oplogs = oplog_helper::filter_oplogs_in_dbs(oplogs, &dbs, coll_selector);
+                report.record_applied(&oplogs)?;
+                incr_dumper.push_oplogs(oplogs);
+                while incr_dumper.apply_oplogs()?.0 {}
+                self.write_log_record(latest_oplog_time)?;
+                report.end = latest_oplog_time;
+                info!(end = ?report.end, applied = report.applied(), "Restore: apply oplogs. ");
+            }
+            if report.stopped_before.is_some() {
+                break;
+            }
         }
+        Ok(report)
     }
 
-    pub fn sync_incr_to_now(&self) -> Result<()> {
-        self.sync_incr(false)
+    /// verify selected collections of the database, returns report for each collection.
+    fn verify_db(&self) -> Result<Vec<CollVerifyReport>> {
+        let mut reports = vec![];
+        for coll in self.get_colls_to_sync()? {
+            info!(db=%self.conn.get_db(), %coll, "Verify: begin to verify collection. ");
+            let mut report = CollVerifyReport::new(self.conn.get_db(), &coll);
+            if let Err(e) = self.verify_coll(&mut report) {
+                error!(coll=%report.name, ?e, "Verify: verify collection failed. ");
+                report.error = Some(e);
+            }
+            reports.push(report);
+        }
+        if self.conn.get_conf().get_oplog_storage_uri().is_some() {
+            self.recheck_diffs(&mut reports)?;
+        }
+        Ok(reports)
     }
 
-    pub fn sync_incr_forever(&self) -> Result<()> {
-        self.sync_incr(true)
+    /// compare collection `report.name` between source and target, differences are saved in `report`.
+    fn verify_coll(&self, report: &mut CollVerifyReport) -> Result<()> {
+        let coll = &report.name;
+        let target_ns = self.conn.get_target_coll(coll).namespace();
+        report.meta_diffs = verify::verify_meta(
+            &self.conn.get_src_db(),
+            coll,
+            &self.conn.get_target_client().database(&target_ns.db),
+            &target_ns.coll,
+        )?;
+        let (src_count, target_count, id_diffs) = verify::verify_docs(
+            &self.conn.get_src_db().collection(coll),
+            &self.conn.get_target_coll(coll),
+            self.conn.get_conf().get_coll_filter(coll),
+        )?;
+        report.src_count = src_count;
+        report.target_count = target_count;
+        report.id_diffs = id_diffs;
+        Ok(())
     }
 
-    pub fn sync_documents_for_collections(&self, coll_names: &[String]) -> Result<()> {
+    /// check documents which differ in `reports` again after check point passes the latest stored oplog, so
+    /// documents which are changed during verification are not reported.
+    fn recheck_diffs(&self, reports: &mut [CollVerifyReport]) -> Result<()> {
         let conf = self.conn.get_conf();
-        let coll_concurrent = conf.get_collection_concurrent();
-        let doc_concurrent = conf.get_doc_concurrent();
-        let (sender, receiver) = channel::bounded(coll_concurrent);
-        let (src_db, target_db) = (self.conn.get_src_db(), self.conn.get_target_db());
-
-        let total = coll_names.len();
-        for coll in coll_names.iter() {
-            let sender = sender.clone();
-            let source_coll = src_db.collection(coll);
-            let target_coll = target_db.collection(coll);
-            let doc_count = source_coll.estimated_document_count(None)? as usize;
-            target_coll.drop(None)?;
-
-            if doc_count <= LARGE_COLL_SIZE {
-                self.pool.spawn(move || {
-                    if let Err(e) = sync_one_serial(source_coll, target_coll) {
-                        let _ = sender.send(SyncTableStatus::Failed(e));
-                    }
-                    let _ = sender.send(SyncTableStatus::Done);
-                })
-            } else {
-                let coll_pool = self.coll_sync_pool.clone();
-                self.pool.spawn(move || {
-                    if let Err(e) =
-                        sync_one_concurrent(source_coll, target_coll, doc_concurrent, coll_pool)
-                    {
-                        let _ = sender.send(SyncTableStatus::Failed(e));
-                    }
-                    let _ = sender.send(SyncTableStatus::Done);
-                })
+        for _ in 0..VERIFY_RECHECK_ROUNDS {
+            let mut pending: Vec<&mut CollVerifyReport> = reports
+                .iter_mut()
+                .filter(|r| r.error.is_none() && !r.is_consistent())
+                .collect();
+            if pending.is_empty() {
+                return Ok(());
+            }
+            let until = oplog_helper::get_latest_ts_no_capped(&self.conn.oplog_coll()?)?;
+            if !self.wait_checkpoint(until)? {
+                warn!(
+                    ?until,
+                    "Verify: check point doesn't pass differences, report them. "
+                );
+                return Ok(());
+            }
+            for report in pending.iter_mut() {
+                let coll = report.name.clone();
+                let source_coll = self.conn.get_src_db().collection::<Document>(&coll);
+                let target_coll = self.conn.get_target_coll(&coll);
+                let coll_filter = conf.get_coll_filter(&coll);
+                info!(db=%self.conn.get_db(), %coll, diffs=report.id_diffs.len(), "Verify: check differences again. ");
+                let filter = coll_filter.map(|f| f.filter.clone());
+                report.src_count = source_coll.count_documents(filter, None)?;
+                report.target_count = target_coll.count_documents(None, None)?;
+                let mut id_diffs = verify::verify_ids(
+                    &source_coll,
+                    &target_coll,
+                    coll_filter,
+                    &report.id_diffs.ids(),
+                )?;
+                id_diffs.truncated = report.id_diffs.truncated;
+                report.id_diffs = id_diffs;
             }
         }
+        Ok(())
+    }
 
-        let mut complete_count = 0;
-        while let Ok(event) = receiver.recv() {
-            match event {
-                SyncTableStatus::Done => {
-                    complete_count += 1;
-                    if total == complete_count {
-                        break;
+    /// wait until check point reaches `until`, returns false if it doesn't in time, or there is no check
+    /// point.
+    fn wait_checkpoint(&self, until: Timestamp) -> Result<bool> {
+        let deadline = Instant::now() + VERIFY_RECHECK_TIMEOUT;
+        loop {
+            match self.conn.time_record_coll().find_one(None, None)? {
+                Some(record) if record.get_timestamp(TIMESTAMP_KEY)? >= until => return Ok(true),
+                Some(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_secs(1)),
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    /// sample documents of synced collections every `sampling.interval` until `stop` is set, drift statistics
+    /// are logged and saved, an error is logged when mismatch rate crosses `sampling.alert_rate`.
+    fn sample_consistency(&self, sampling: SamplingConfig, stop: &AtomicBool) {
+        let mut stats = SamplingStats::default();
+        loop {
+            let deadline = Instant::now() + sampling.interval;
+            while Instant::now() < deadline {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            let round = match self.sample_round(&sampling, stop) {
+                Ok(Some(round)) => round,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(?e, "Sampler: sampling round failed. ");
+                    continue;
+                }
+            };
+            let total = round.total();
+            if stats.record_round(round, sampling.alert_rate) {
+                error!(
+                    sampled = total.sampled,
+                    mismatched = total.mismatched,
+                    rate = total.mismatch_rate(),
+                    mismatched_ids = ?stats.last_round.mismatched_ids,
+                    "Sampler: mismatch rate crosses alert rate, target drifts from source. "
+                );
+            } else {
+                info!(
+                    sampled = total.sampled,
+                    mismatched = total.mismatched,
+                    "Sampler: sampling round complete. "
+                );
+            }
+            if let Err(e) = sampler::save_stats(&self.conn.sampling_stats_coll(), &stats) {
+                warn!(?e, "Sampler: save drift statistics failed. ");
+            }
+        }
+    }
+
+    /// sample documents of each synced collection and compare them, returns None if the round is stopped, or
+    /// check point doesn't pass differences in time.
+    ///
+    /// Collections which are being copied are skipped.
+    fn sample_round(
+        &self,
+        sampling: &SamplingConfig,
+        stop: &AtomicBool,
+    ) -> Result<Option<SamplingRound>> {
+        let conf = self.conn.get_conf();
+        let copying: HashSet<String> = CollCheckpoints::new(self.conn.coll_checkpoint_coll())
+            .list()?
+            .into_iter()
+            .map(|checkpoint| checkpoint.ns)
+            .collect();
+        let mut dbs: Vec<String> = self.get_incr_dbs()?.into_iter().collect();
+        dbs.sort_unstable();
+        let compare = |ns: &str, ids: &[Bson]| -> Result<Vec<Bson>> {
+            let (db, coll) = ns
+                .split_once('.')
+                .expect("namespace should be split by '.'");
+            let manager = self.for_db(db);
+            let diffs = verify::verify_ids(
+                &manager.conn.get_src_db().collection(coll),
+                &manager.conn.get_target_coll(coll),
+                conf.get_coll_filter(coll),
+                ids,
+            )?;
+            Ok(diffs.ids())
+        };
+
+        let mut round = SamplingRound::default();
+        for db in dbs.iter() {
+            let manager = self.for_db(db);
+            for coll in manager.get_colls_to_sync()? {
+                if stop.load(Ordering::Relaxed) {
+                    return Ok(None);
+                }
+                let ns = format!("{}.{}", db, coll);
+                if copying.contains(&ns) {
+                    continue;
+                }
+                let ids = sampler::sample_ids(
+                    &manager.conn.get_src_db().collection(&coll),
+                    conf.get_coll_filter(&coll),
+                    sampling.docs_per_coll,
+                )?;
+                if ids.is_empty() {
+                    continue;
+                }
+                let differing = compare(&ns, &ids)?;
+                let stats = DriftStats {
+                    sampled: ids.len() as u64,
+                    mismatched: 0,
+                };
+                round.collections.insert(ns.clone(), stats);
+                if !differing.is_empty() {
+                    round.mismatched_ids.insert(ns, differing);
+                }
+            }
+        }
+        // documents may differ because their oplogs are not applied yet.
+        for _ in 0..VERIFY_RECHECK_ROUNDS {
+            if round.mismatched_ids.is_empty() {
+                break;
+            }
+            let until = oplog_helper::get_latest_ts_no_capped(&self.conn.oplog_coll()?)?;
+            if !self.wait_checkpoint(until)? {
+                info!(
+                    ?until,
+                    "Sampler: check point doesn't pass differences, skip the round. "
+                );
+                return Ok(None);
+            }
+            let mut mismatched_ids = BTreeMap::new();
+            for (ns, ids) in round.mismatched_ids.iter() {
+                let differing = compare(ns, ids)?;
+                if !differing.is_empty() {
+                    mismatched_ids.insert(ns.clone(), differing);
+                }
+            }
+            round.mismatched_ids = mismatched_ids;
+        }
+        for (ns, ids) in round.mismatched_ids.iter() {
+            if let Some(stats) = round.collections.get_mut(ns) {
+                stats.mismatched = ids.len() as u64;
+            }
+        }
+        Ok(Some(round))
+    }
+
+    /// get databases to restore from mongodump directory `dir`.
+    fn get_dump_dbs(&self, dir: &Path) -> Result<Vec<String>> {
+        match self.conn.get_conf().get_dbs() {
+            Some(selector) => {
+                let same_cluster = self.conn.is_same_cluster()?;
+                Ok(restore::dump_dbs(dir)?
+                    .into_iter()
+                    .filter(|db| self.is_db_selected(selector, db, same_cluster))
+                    .collect())
+            }
+            None => Ok(vec![self.conn.get_db().to_string()]),
+        }
+    }
+
+    /// load selected collections of the database from mongodump directory `dir` into target, target
+    /// collections are written by write policy.
+    fn load_dump(&self, dir: &Path) -> Result<()> {
+        let conf = self.conn.get_conf();
+        let db = self.conn.get_db();
+        let colls: Vec<(String, PathBuf)> = restore::dump_colls(dir, db)?
+            .into_iter()
+            .filter(|(coll, _)| self.coll_selector.matches(coll))
+            .collect();
+        let coll_names: Vec<String> = colls.iter().map(|(coll, _)| coll.clone()).collect();
+        self.check_write_policy(&coll_names)?;
+        let upsert = conf.get_write_policy() == FullSyncWritePolicy::Merge;
+        let limits = conf.get_batch_limits();
+        for (coll, path) in colls {
+            info!(%db, %coll, ?path, "Restore: load collection from dump. ");
+            let target_coll = self.conn.get_target_coll(&coll);
+            if conf.get_write_policy() == FullSyncWritePolicy::Drop {
+                target_coll.drop(None)?;
+            }
+            let mut docs = vec![];
+            let mut bytes = 0;
+            for d in BsonFileReader::open(&path)? {
+                let d = d?;
+                bytes += encoded_size(&d);
+                docs.push(d);
+                if docs.len() >= limits.max_count || bytes >= limits.max_bytes {
+                    write_docs(&target_coll, std::mem::take(&mut docs), upsert)?;
+                    bytes = 0;
+                }
+            }
+            if !docs.is_empty() {
+                write_docs(&target_coll, docs, upsert)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// apply oplogs of the collection which has own `checkpoint` until `end_point`, then the checkpoint is
+    /// removed, and the collection follows the global checkpoint.
+    fn catch_up_coll(
+        &self,
+        mut checkpoint: CollCheckpoint,
+        end_point: Timestamp,
+        oplog_coll: &Collection<Document>,
+        incr_dumper: &mut IncrDumper,
+        checkpoints: &CollCheckpoints,
+    ) -> Result<()> {
+        info!(ns=%checkpoint.ns, ts=?checkpoint.ts, ?end_point, "Incr state: collection begins to catch up. ");
+        let limits = self.conn.get_conf().get_batch_limits();
+        let namespaces: HashSet<String> = vec![checkpoint.ns.clone()].into_iter().collect();
+        while checkpoint.ts < end_point {
+            let _permit = self.budget.acquire(limits.max_bytes);
+            let oplogs = oplog_helper::get_next_batch_with_limits(
+                oplog_coll,
+                checkpoint.ts,
+                Some(end_point),
+                &limits,
+            )?;
+            if oplogs.is_empty() {
+                break;
+            }
+            checkpoint.ts = oplogs[oplogs.len() - 1].get_timestamp(TIMESTAMP_KEY)?;
+            let (oplogs, _) = oplog_helper::partition_by_namespaces(oplogs, &namespaces);
+            if !oplogs.is_empty() {
+                incr_dumper.push_oplogs(oplogs);
+                while incr_dumper.apply_oplogs()?.0 {}
+            }
+            checkpoints.save(&checkpoint)?;
+        }
+        checkpoints.remove(&checkpoint.ns)?;
+        info!(ns=%checkpoint.ns, "Incr state: collection catches up, it follows global checkpoint now. ");
+        Ok(())
+    }
+
+    /// handle control `command` in incremental sync, returns result to acknowledge.
+    ///
+    /// Collections which are added or removed are recorded in collection sync arguments, they last until
+    /// db_sync restarts with a different collection selector.
+    fn apply_control(
+        &self,
+        command: &ControlCommand,
+        state: &mut ControlState,
+        coll_selector: &mut CollSelector,
+        pending_colls: &mut HashMap<String, CollCheckpoint>,
+        dbs_to_sync: &HashSet<String>,
+        copy_in_background: &dyn Fn(&str, Vec<String>),
+    ) -> Result<Document> {
+        let invalid = |detail: String| SyncError::InvalidControlCommand { detail };
+        let mut dbs: Vec<String> = dbs_to_sync.iter().cloned().collect();
+        dbs.sort_unstable();
+        let check_not_copying = |coll: &str, dbs: &[String]| {
+            for db in dbs.iter() {
+                let ns = format!("{}.{}", db, coll);
+                if pending_colls.contains_key(&ns) {
+                    return Err(invalid(format!("collection {} is being copied", ns)));
+                }
+            }
+            Ok(())
+        };
+        match command {
+            ControlCommand::Pause => {
+                state.paused = true;
+                Ok(doc! {"paused": true})
+            }
+            ControlCommand::Resume => {
+                state.paused = false;
+                Ok(doc! {"paused": false})
+            }
+            ControlCommand::FastForward { to } => {
+                if self.conn.get_conf().get_apply_delay().is_none() {
+                    return Err(invalid("apply delay is not configured".to_string()));
+                }
+                let to = to.unwrap_or_else(|| delayed_point(Duration::ZERO, None));
+                self.write_fast_forward(Some(to))?;
+                state.fast_forward_to = Some(to);
+                Ok(doc! {"fast_forward_to": to})
+            }
+            ControlCommand::Status => {
+                let checkpoint = self
+                    .conn
+                    .time_record_coll()
+                    .find_one(None, None)?
+                    .map(|d| d.get_timestamp(TIMESTAMP_KEY))
+                    .transpose()?;
+                let colls_in_progress: Vec<Document> = pending_colls
+                    .values()
+                    .map(|c| doc! {"ns": &c.ns, "state": c.state.as_str(), "ts": c.ts})
+                    .collect();
+                let apply_delay = self.conn.get_conf().get_apply_delay();
+                let lag_secs = checkpoint.map(|ts| {
+                    i64::from(delayed_point(Duration::ZERO, None).time) - i64::from(ts.time)
+                });
+                Ok(doc! {
+                    "paused": state.paused,
+                    "checkpoint": checkpoint,
+                    "lag_secs": lag_secs,
+                    "apply_delay_secs": apply_delay.map(|delay| delay.as_secs() as i64),
+                    "fast_forward_to": state.fast_forward_to,
+                    "repair_fences": state.fences.len() as i64,
+                    "unmatched_updates": state.unmatched.unmatched as i64,
+                    "refetched_docs": state.unmatched.refetched as i64,
+                    "dead_letters": DeadLetters::new(self.conn.dead_letter_coll()).count()? as i64,
+                    "dead_lettered_oplogs": state.dead_lettered as i64,
+                    "drift": self.conn.sampling_stats_coll().find_one(None, None)?,
+                    "dbs": dbs,
+                    "colls_in_progress": colls_in_progress,
+                    "added_colls": coll_selector.added(),
+                    "removed_colls": coll_selector.removed(),
+                })
+            }
+            ControlCommand::Resync { db, coll, confirm } => {
+                if let Some(db) = db {
+                    if !dbs_to_sync.contains(db) {
+                        return Err(invalid(format!("database {:?} is not synced", db)));
                     }
+                    dbs = vec![db.clone()];
                 }
-                SyncTableStatus::Failed(e) => {
-                    return Err(e);
+                if !coll_selector.matches(coll) {
+                    return Err(invalid(format!("collection {:?} is not synced", coll)));
+                }
+                check_not_copying(coll, &dbs)?;
+                // target collection is copied again by write policy, `fail-if-non-empty` can't copy into a synced
+                // collection, so dropping it must be confirmed.
+                let write_policy = self.conn.get_conf().get_write_policy();
+                if write_policy == FullSyncWritePolicy::FailIfNonEmpty && !confirm {
+                    return Err(invalid(format!(
+                        "write policy is fail-if-non-empty, add `\"confirm\": true` to drop target collection {:?}",
+                        coll
+                    )));
+                }
+                let coll_names = vec![coll.clone()];
+                for db in dbs.iter() {
+                    let manager = self.for_db(db);
+                    if write_policy != FullSyncWritePolicy::Merge {
+                        manager.conn.get_target_coll(coll).drop(None)?;
+                    }
+                    for checkpoint in manager.add_colls_with_checkpoint(&coll_names)? {
+                        pending_colls.insert(checkpoint.ns.clone(), checkpoint);
+                    }
+                    copy_in_background(db, coll_names.clone());
+                }
+                Ok(doc! {"dbs": dbs})
+            }
+            ControlCommand::AddColl { coll } => {
+                if coll_selector.matches(coll) {
+                    return Err(invalid(format!("collection {:?} is already synced", coll)));
+                }
+                // collections which don't exist yet are created by oplogs.
+                let coll_names = vec![coll.clone()];
+                let mut copy_dbs = vec![];
+                for db in dbs.iter() {
+                    let manager = self.for_db(db);
+                    let exists = !manager
+                        .conn
+                        .get_src_db()
+                        .list_collection_names(doc! {"name": coll, "type": {"$ne": "view"}})?
+                        .is_empty();
+                    if exists {
+                        manager.check_write_policy(&coll_names)?;
+                        copy_dbs.push(db.clone());
+                    }
+                }
+                for db in copy_dbs.iter() {
+                    let manager = self.for_db(db);
+                    for checkpoint in manager.add_colls_with_checkpoint(&coll_names)? {
+                        pending_colls.insert(checkpoint.ns.clone(), checkpoint);
+                    }
+                    copy_in_background(db, coll_names.clone());
+                }
+                *coll_selector = std::mem::take(coll_selector).with_added(coll);
+                for db in dbs.iter() {
+                    self.for_db(db).write_coll_selector(coll_selector)?;
+                }
+                Ok(doc! {"copied_dbs": copy_dbs})
+            }
+            ControlCommand::Repair { docs } => {
+                for doc in docs.iter() {
+                    let (db, coll) = doc.split_ns();
+                    check_not_copying(coll, &[db.to_string()])?;
+                }
+                self.repair_docs(docs, dbs_to_sync, coll_selector, &mut state.fences)
+            }
+            ControlCommand::RedriveDeadLetters => Err(invalid(
+                "dead letters are only redriven by incremental sync".to_string(),
+            )),
+            ControlCommand::RemoveColl { coll } => {
+                if !coll_selector.matches(coll) {
+                    return Err(invalid(format!("collection {:?} is not synced", coll)));
+                }
+                check_not_copying(coll, &dbs)?;
+                for db in dbs.iter() {
+                    self.for_db(db).remove_colls(vec![coll.clone()])?;
+                }
+                *coll_selector = std::mem::take(coll_selector).with_removed(coll);
+                for db in dbs.iter() {
+                    self.for_db(db).write_coll_selector(coll_selector)?;
+                }
+                let policy = self.conn.get_conf().get_removed_coll_policy();
+                Ok(doc! {"policy": policy.to_string()})
+            }
+        }
+    }
+
+    /// copy documents `docs` from source into target again, or remove them from target if they don't exist
+    /// in source, returns how many are repaired.
+    ///
+    /// Documents are fenced in `fences` at the latest source oplog before they are read, so older oplogs of
+    /// them are skipped afterwards.  Fences are persisted only when oplog storage is configured.
+    fn repair_docs(
+        &self,
+        docs: &[RepairDoc],
+        dbs: &HashSet<String>,
+        coll_selector: &CollSelector,
+        fences: &mut FenceSet,
+    ) -> Result<Document> {
+        for doc in docs.iter() {
+            let (db, coll) = doc.split_ns();
+            if !dbs.contains(db) || !coll_selector.matches(coll) {
+                return Err(SyncError::InvalidRepairDoc {
+                    detail: format!("{}: namespace {} is not synced", doc.to_document(), doc.ns),
+                });
+            }
+        }
+        let conf = self.conn.get_conf();
+        let fence = match conf.get_oplog_storage_uri() {
+            Some(_) => {
+                let src_oplog_coll = self
+                    .conn
+                    .get_src_client()
+                    .database(OPLOG_DB)
+                    .collection(OPLOG_COLL);
+                let fence = match oplog_helper::get_latest_ts(&src_oplog_coll) {
+                    Ok(ts) => ts,
+                    // fence at stored oplogs if source oplogs can't be read, later oplogs are replayed safely.
+                    Err(_) => oplog_helper::get_latest_ts_no_capped(&self.conn.oplog_coll()?)?,
+                };
+                let repair_fences = RepairFences::new(self.conn.repair_fence_coll());
+                for doc in docs.iter() {
+                    repair_fences.save(doc, fence)?;
+                    fences.insert(doc, fence);
+                }
+                Some(fence)
+            }
+            None => None,
+        };
+
+        let (mut upserted, mut deleted) = (0_i64, 0_i64);
+        for doc in docs.iter() {
+            let (db, coll) = doc.split_ns();
+            let manager = self.for_db(db);
+            let id_filter = doc! {"_id": &doc.id};
+            let (filter, projection) =
+                verify::source_query(conf.get_coll_filter(coll), Some(id_filter.clone()));
+            let source_coll = manager.conn.get_src_db().collection::<Document>(coll);
+            let source_doc = source_coll.find_one(
+                filter,
+                FindOneOptions::builder().projection(projection).build(),
+            )?;
+            let target_coll = manager.conn.get_target_coll(coll);
+            match source_doc {
+                Some(source_doc) => {
+                    target_coll.replace_one(
+                        id_filter,
+                        source_doc,
+                        ReplaceOptions::builder().upsert(true).build(),
+                    )?;
+                    upserted += 1;
+                }
+                None => {
+                    target_coll.delete_one(id_filter, None)?;
+                    deleted += 1;
                 }
             }
         }
+        Ok(doc! {"upserted": upserted, "deleted": deleted, "fence": fence})
+    }
+
+    /// pick a snapshot to make full sync, returns None if snapshot read is disabled, or source doesn't support it.
+    fn pick_snapshot(&self) -> Result<Option<SnapshotRead>> {
+        if !self.conn.get_conf().get_snapshot_read() {
+            return Ok(None);
+        }
+        let client = self.conn.get_src_client();
+        Ok(snapshot::pick_snapshot_time(&client)?.map(|at| SnapshotRead { client, at }))
+    }
+
+    fn check_log_valid(&self, start_point: Timestamp) -> Result<bool> {
+        // just fetch oplog start point, if the start point is less than given oplogs, we can make sure that these oplogs is still valid.
+        let earliest_ts = oplog_helper::get_earliest_ts_no_capped(&self.conn.oplog_coll()?)?;
+        Ok(earliest_ts < start_point)
+    }
+
+    fn write_log_record(&self, log_ts: Timestamp) -> Result<()> {
+        self.conn.time_record_coll().update_one(
+            doc! {},
+            doc! { "$set": {"ts": log_ts} },
+            UpdateOptions::builder().upsert(true).build(),
+        )?;
+        Ok(())
+    }
+
+    /// record fast-forward bound `to` next to check point in `oplog_records`, the bound is removed if `to` is
+    /// None.
+    fn write_fast_forward(&self, to: Option<Timestamp>) -> Result<()> {
+        let update = match to {
+            Some(to) => doc! { "$set": {"fast_forward_to": to} },
+            None => doc! { "$unset": {"fast_forward_to": ""} },
+        };
+        self.conn
+            .time_record_coll()
+            .update_one(doc! {}, update, None)?;
+        Ok(())
+    }
+
+    /// get collections which are selected by collection selector in source database, views are never synced.
+    fn get_colls_to_sync(&self) -> Result<Vec<String>> {
+        let selector = &self.coll_selector;
+        Ok(self
+            .conn
+            .get_src_db()
+            .list_collection_names(doc! {"type": {"$ne": "view"}})?
+            .into_iter()
+            .filter(|coll| selector.matches(coll))
+            .collect())
+    }
+
+    pub fn sync_incr_to_now(&self, pending_dbs: &[String]) -> Result<()> {
+        self.sync_incr(false, pending_dbs)
+    }
+
+    pub fn sync_incr_forever(&self) -> Result<()> {
+        self.sync_incr(true, &[])
+    }
+
+    /// add collections `coll_names` into sync set with own checkpoints, they are copied in background by
+    /// incremental sync.
+    pub fn add_colls_with_checkpoint(&self, coll_names: &[String]) -> Result<Vec<CollCheckpoint>> {
+        self.check_write_policy(coll_names)?;
+        let ts = oplog_helper::get_latest_ts_no_capped(&self.conn.oplog_coll()?)?;
+        let checkpoints = CollCheckpoints::new(self.conn.coll_checkpoint_coll());
+        let mut result = Vec::with_capacity(coll_names.len());
+        for coll in coll_names.iter() {
+            let checkpoint =
+                CollCheckpoint::new(self.conn.get_db(), coll, CheckpointState::Copying, ts);
+            checkpoints.save(&checkpoint)?;
+            result.push(checkpoint);
+        }
+        Ok(result)
+    }
+
+    /// check that target collections `coll_names` can be copied into by write policy.
+    fn check_write_policy(&self, coll_names: &[String]) -> Result<()> {
+        if self.conn.get_conf().get_write_policy() == FullSyncWritePolicy::FailIfNonEmpty {
+            self.check_targets_empty(coll_names, &CopyOptions::default())?;
+        }
+        Ok(())
+    }
+
+    /// copy collections `coll_names` which have own checkpoints, returns checkpoints which they catch up from.
+    ///
+    /// Target collections are checked by write policy when they are added, so documents are upserted, and a
+    /// copy interrupted before can be made again.
+    fn copy_with_checkpoint(
+        &self,
+        coll_names: &[String],
+        checkpoints: &CollCheckpoints,
+    ) -> Result<Vec<CollCheckpoint>> {
+        let (ts, snapshot) = self.pick_full_start()?;
+        let db = self.conn.get_db();
+        info!(%db, ?coll_names, ?ts, "Full state: copy collections in background. ");
+        let _progress_logger = self.progress.log_periodically(PROGRESS_LOG_INTERVAL);
+        let options = CopyOptions {
+            snapshot,
+            upsert: true,
+            ..CopyOptions::default()
+        };
+        self.sync_documents_with_options(coll_names, options)?;
+
+        let mut result = Vec::with_capacity(coll_names.len());
+        for coll in coll_names.iter() {
+            let checkpoint = CollCheckpoint::new(db, coll, CheckpointState::CatchingUp, ts);
+            checkpoints.save(&checkpoint)?;
+            result.push(checkpoint);
+        }
+        info!(%db, ?coll_names, "Full state: copy collections in background complete. ");
+        Ok(result)
+    }
+
+    /// make full sync for all collections of databases `dbs`, they are copied concurrently.
+    fn sync_documents_for_dbs(&self, dbs: &[String]) -> Result<()> {
+        let _progress_logger = self.progress.log_periodically(PROGRESS_LOG_INTERVAL);
+        self.for_each_db(dbs, |db_manager| {
+            let coll_names = db_manager.get_colls_to_sync()?;
+            db_manager.sync_documents_with_options(&coll_names, CopyOptions::default())
+        })?;
+        self.progress.log_summary();
+        Ok(())
+    }
+
+    fn sync_documents_with_options(
+        &self,
+        coll_names: &[String],
+        options: CopyOptions,
+    ) -> Result<()> {
+        for report in self.copy_collections(coll_names, options)? {
+            if let Some(e) = report.error {
+                return Err(e);
+            }
+        }
 
         // We rebuild index in this main thread, because build index operation seems like to lock the whole database
         // and build index in the sub-thread is meanless.
         info!("Full state: Begin to re-build index for target collection");
         for coll in coll_names.iter() {
-            let indexes = src_db.run_command(doc! { "listIndexes": coll }, None)?;
-            let indexes = indexes.get_document("cursor")?.get_array("firstBatch")?;
-            // TODO: will have problem when we have many indexes, using firstBatch is not enough, refer to mongodb document:
-            // https://docs.mongodb.com/manual/reference/command/listIndexes/
-            // A document that contains information with which to create a cursor to index information. The cursor information includes the cursor id, the
-            // full namespace for the command, as well as the first batch of results. Index information includes the keys and options used to create the index.
-            // and we have no way to fix it for now, because mongodb-driver doesn't provide something like `command_cursor`.
-            target_db.run_command(
-                doc! {
-                    "createIndexes": coll,
-                    "indexes": indexes,
-                },
-                None,
-            )?;
+            self.rebuild_indexes(coll)?;
         }
         Ok(())
     }
 
-    pub fn write_sync_colls_args(&self) -> Result<()> {
-        let target_db = self.conn.get_target_db();
-        let colls_to_sync = target_db.collection("colls_to_sync");
-        match self.conn.get_conf().get_colls() {
-            None => {
-                colls_to_sync.delete_many(doc! {}, None)?;
+    /// copy documents for given collections, returns sync report for each collection.
+    ///
+    /// A failed collection doesn't stop copying other collections.  If `options` contains a plan, collections
+    /// which are done in the plan are skipped, and collections which are partly copied are resumed.  Returns
+    /// [SyncError::Cancelled] after copying tasks are stopped if the sync is cancelled.
+    fn copy_collections(
+        &self,
+        coll_names: &[String],
+        mut options: CopyOptions,
+    ) -> Result<Vec<CollSyncReport>> {
+        match self.conn.get_conf().get_write_policy() {
+            FullSyncWritePolicy::Merge => options.upsert = true,
+            // targets are checked before if upsert is required, e.g: a copy interrupted before is made again.
+            FullSyncWritePolicy::FailIfNonEmpty if !options.upsert => {
+                self.check_targets_empty(coll_names, &options)?
+            }
+            FullSyncWritePolicy::FailIfNonEmpty | FullSyncWritePolicy::Drop => {}
+        }
+        options.progress = Some(self.progress.clone());
+        options.limits = self.conn.get_conf().get_batch_limits();
+        options.budget = Some(self.budget.clone());
+        options.cancel = Some(self.cancel.clone());
+        let conf = self.conn.get_conf();
+        let coll_concurrent = conf.get_collection_concurrent();
+        let doc_concurrent = conf.get_doc_concurrent();
+        let (sender, receiver) = channel::bounded(coll_concurrent);
+        let src_db = self.conn.get_src_db();
+
+        let mut reports: Vec<CollSyncReport> = coll_names
+            .iter()
+            .map(|c| CollSyncReport::new(c.clone()))
+            .collect();
+        let mut total = 0;
+        for (idx, coll) in coll_names.iter().enumerate() {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let sender = sender.clone();
+            let source_coll = src_db.collection(coll);
+            let target_coll = self.conn.get_target_coll(coll);
+            let prepared = self.prepare_target_coll(coll, &options);
+            let doc_count = match prepared {
+                Ok(None) => {
+                    info!(%coll, "Full state: collection is already synced in plan, skip. ");
+                    continue;
+                }
+                Ok(Some(cnt)) => {
+                    self.progress
+                        .start_coll(&self.progress_name(coll), cnt as u64);
+                    cnt
+                }
+                Err(e) => {
+                    error!(%coll, ?e, "Full state: prepare target collection failed. ");
+                    reports[idx].error = Some(e);
+                    continue;
+                }
+            };
+            total += 1;
+
+            let mut options = options.clone();
+            options.coll_filter = conf.get_coll_filter(coll).cloned();
+            let coll_name = coll.clone();
+            if doc_count <= LARGE_COLL_SIZE {
+                self.pool.spawn(move || {
+                    let plan = options.plan.clone();
+                    let result = sync_one_serial(source_coll, target_coll, options);
+                    let _ = sender.send((idx, finish_coll(result, &plan, &coll_name)));
+                })
+            } else {
+                let coll_pool = self.coll_sync_pool.clone();
+                self.pool.spawn(move || {
+                    let plan = options.plan.clone();
+                    let result = sync_one_concurrent(
+                        source_coll,
+                        target_coll,
+                        doc_concurrent,
+                        coll_pool,
+                        options,
+                    );
+                    let _ = sender.send((idx, finish_coll(result, &plan, &coll_name)));
+                })
             }
-            Some(coll_names) => {
-                colls_to_sync.delete_many(doc! {}, None)?;
-                colls_to_sync.insert_one(doc! {"names": coll_names}, None)?;
+        }
+
+        for _ in 0..total {
+            let (idx, event) = receiver.recv()?;
+            match event {
+                SyncTableStatus::Done(stats) => {
+                    self.progress
+                        .finish_coll(&self.progress_name(&reports[idx].name));
+                    reports[idx].stats = stats;
+                }
+                SyncTableStatus::Failed(e) => {
+                    error!(coll=%reports[idx].name, ?e, "Full state: sync collection failed. ");
+                    reports[idx].error = Some(e);
+                }
             }
         }
+        // copying collections are stopped after their in-flight batch, others are not started.
+        self.cancel.check()?;
+        Ok(reports)
+    }
+
+    /// get name of collection `coll` in full sync progress, which is its namespace.
+    fn progress_name(&self, coll: &str) -> String {
+        format!("{}.{}", self.conn.get_db(), coll)
+    }
+
+    /// make sure that target collections are empty, collections which are started in plan are not checked.
+    ///
+    /// Returns [SyncError::TargetNotEmpty] with all non-empty collections.
+    fn check_targets_empty(&self, coll_names: &[String], options: &CopyOptions) -> Result<()> {
+        let mut non_empty = vec![];
+        for coll in coll_names.iter() {
+            if let Some(plan) = &options.plan {
+                if let Some(state) = plan.coll_state(coll)? {
+                    if state.done || !state.ranges.is_empty() {
+                        continue;
+                    }
+                }
+            }
+            let cnt = self
+                .conn
+                .get_target_coll(coll)
+                .count_documents(None, CountOptions::builder().limit(1).build())?;
+            if cnt > 0 {
+                non_empty.push(coll.clone());
+            }
+        }
+        if non_empty.is_empty() {
+            Ok(())
+        } else {
+            Err(SyncError::TargetNotEmpty { colls: non_empty })
+        }
+    }
+
+    /// prepare target collection `coll` before copy.
+    ///
+    /// Returns None if the collection is already done in plan, or else returns estimated document count
+    /// of source collection.  Target collection is dropped if write policy is [FullSyncWritePolicy::Drop],
+    /// unless it's partly copied in plan.
+    fn prepare_target_coll(&self, coll: &str, options: &CopyOptions) -> Result<Option<usize>> {
+        let state = match &options.plan {
+            Some(plan) => plan.coll_state(coll)?,
+            None => None,
+        };
+        let resuming = match &state {
+            Some(state) if state.done => return Ok(None),
+            Some(state) => !state.ranges.is_empty(),
+            None => false,
+        };
+
+        let src_db = self.conn.get_src_db();
+        if !resuming {
+            let target_coll = self.conn.get_target_coll(coll);
+            if self.conn.get_conf().get_write_policy() == FullSyncWritePolicy::Drop {
+                target_coll.drop(None)?;
+            }
+            let target_ns = target_coll.namespace();
+            let target_db = self.conn.get_target_client().database(&target_ns.db);
+            create_coll_with_options(&src_db, coll, &target_db, &target_ns.coll)?;
+        }
+        let doc_count = src_db
+            .collection::<Document>(coll)
+            .estimated_document_count(None)?;
+        Ok(Some(doc_count as usize))
+    }
+
+    /// re-build indexes of collection `coll` in target database, the indexes definition comes from source database.
+    fn rebuild_indexes(&self, coll: &str) -> Result<()> {
+        let src_db = self.conn.get_src_db();
+        let indexes = src_db.run_command(doc! { "listIndexes": coll }, None)?;
+        // index spec contains source namespace before mongodb 4.4, which can't be used in mapped target.
+        let indexes: Vec<Bson> = indexes
+            .get_document("cursor")?
+            .get_array("firstBatch")?
+            .iter()
+            .map(|index| match index {
+                Bson::Document(spec) => {
+                    let mut spec = spec.clone();
+                    spec.remove("ns");
+                    Bson::Document(spec)
+                }
+                other => other.clone(),
+            })
+            .collect();
+        // TODO: will have problem when we have many indexes, using firstBatch is not enough, refer to mongodb document:
+        // https://docs.mongodb.com/manual/reference/command/listIndexes/
+        // A document that contains information with which to create a cursor to index information. The cursor information includes the cursor id, the
+        // full namespace for the command, as well as the first batch of results. Index information includes the keys and options used to create the index.
+        // and we have no way to fix it for now, because mongodb-driver doesn't provide something like `command_cursor`.
+        let target_ns = self.conn.get_target_coll(coll).namespace();
+        let target_db = self.conn.get_target_client().database(&target_ns.db);
+        target_db.run_command(
+            doc! {
+                "createIndexes": &target_ns.coll,
+                "indexes": indexes,
+            },
+            None,
+        )?;
         Ok(())
     }
 
-    fn get_sync_coll_args(&self) -> Result<Option<HashSet<String>>> {
+    /// record collection selector, so we can find collections which are selected only by new selector later.
+    pub fn write_sync_colls_args(&self) -> Result<()> {
+        self.write_coll_selector(&self.coll_selector)
+    }
+
+    /// record collection `selector`, collections added or removed at runtime are recorded too.
+    fn write_coll_selector(&self, selector: &CollSelector) -> Result<()> {
+        let target_db = self.conn.get_target_db();
+        let colls_to_sync = target_db.collection("colls_to_sync");
+        colls_to_sync.delete_many(doc! {}, None)?;
+        colls_to_sync.insert_one(
+            doc! {
+                "names": selector.names(),
+                "include": selector.includes(),
+                "exclude": selector.excludes(),
+                "added": selector.added(),
+                "removed": selector.removed(),
+            },
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn get_sync_coll_args(&self) -> Result<Option<CollSelector>> {
         let target_db = self.conn.get_target_db();
         let colls_to_sync = target_db.collection::<Document>("colls_to_sync");
         let item = colls_to_sync.find_one(doc! {}, None)?;
+        let as_names = |d: &Document, key: &str| -> Vec<String> {
+            d.get_array(key)
+                .map(|names| {
+                    names
+                        .iter()
+                        .map(|x| match x {
+                            Bson::String(s) => s.clone(),
+                            _ => panic!(r#"The elements in `{}` fields should be string, data corrupted!!"
+"Try drop `colls_to_sync`, `oplog_records` collection in target_database, and make a full sync again."#, key),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
         match item {
+            None => Ok(None),
+            // older version only saves collection `names`, other fields are empty then.
+            Some(d) => {
+                let mut selector = CollSelector::from_names(&as_names(&d, "names"));
+                for pattern in as_names(&d, "include") {
+                    selector = selector.with_include(&pattern);
+                }
+                for pattern in as_names(&d, "exclude") {
+                    selector = selector.with_exclude(&pattern);
+                }
+                for coll in as_names(&d, "added") {
+                    selector = selector.with_added(&coll);
+                }
+                for coll in as_names(&d, "removed") {
+                    selector = selector.with_removed(&coll);
+                }
+                Ok(Some(selector))
+            }
+        }
+    }
+
+    /// get existing collections which are selected by current collection selector, but not by the recorded
+    /// one.
+    pub fn get_new_colls_to_sync(&self) -> Result<Option<Vec<String>>> {
+        let origin_selector = self.get_sync_coll_args()?;
+        match origin_selector {
+            None => Ok(None),
+            Some(origin_selector) => {
+                let new_colls: Vec<String> = self
+                    .get_colls_to_sync()?
+                    .into_iter()
+                    .filter(|coll| !origin_selector.matches(coll))
+                    .collect();
+                if new_colls.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_colls))
+                }
+            }
+        }
+    }
+
+    /// get existing collections which are selected by the recorded collection selector, but not by current
+    /// one.
+    fn get_removed_colls(&self) -> Result<Vec<String>> {
+        let origin_selector = match self.get_sync_coll_args()? {
+            None => return Ok(vec![]),
+            Some(origin_selector) => origin_selector,
+        };
+        let selector = &self.coll_selector;
+        Ok(self
+            .conn
+            .get_src_db()
+            .list_collection_names(doc! {"type": {"$ne": "view"}})?
+            .into_iter()
+            .filter(|coll| origin_selector.matches(coll) && !selector.matches(coll))
+            .collect())
+    }
+
+    /// apply removed collection policy to collections which are not selected any more, the decision is
+    /// recorded in `removed_colls` collection.
+    pub fn handle_removed_colls(&self) -> Result<()> {
+        let removed_colls = self.get_removed_colls()?;
+        if removed_colls.is_empty() {
+            return Ok(());
+        }
+        self.remove_colls(removed_colls)
+    }
+
+    /// apply removed collection policy to collections `removed_colls`, the decision is recorded in
+    /// `removed_colls` collection.
+    ///
+    /// Under [RemovedCollPolicy::Refuse] nothing is changed, and [SyncError::CollsRemovedFromSyncSet] is
+    /// returned.
+    fn remove_colls(&self, removed_colls: Vec<String>) -> Result<()> {
+        let policy = self.conn.get_conf().get_removed_coll_policy();
+        let db = self.conn.get_db();
+        warn!(%db, ?removed_colls, %policy, "Collections are removed from sync set. ");
+        if policy == RemovedCollPolicy::Refuse {
+            return Err(SyncError::CollsRemovedFromSyncSet {
+                db: db.to_string(),
+                colls: removed_colls,
+            });
+        }
+        let removed_record = self
+            .conn
+            .get_target_db()
+            .collection::<Document>("removed_colls");
+        let checkpoints = CollCheckpoints::new(self.conn.coll_checkpoint_coll());
+        for coll in removed_colls.iter() {
+            // a collection which is not copied yet shouldn't be copied any more.
+            checkpoints.remove(&self.progress_name(coll))?;
+            if policy == RemovedCollPolicy::Drop {
+                self.conn.get_target_coll(coll).drop(None)?;
+            }
+            removed_record.update_one(
+                doc! {"name": coll},
+                doc! {"$set": {"policy": policy.to_string(), "removed_at": DateTime::now()}},
+                UpdateOptions::builder().upsert(true).build(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// get databases selected by `selector` in source.
+    fn get_dbs_to_sync(&self, selector: &DbSelector) -> Result<Vec<String>> {
+        let same_cluster = self.conn.is_same_cluster()?;
+        Ok(self
+            .conn
+            .get_src_client()
+            .list_database_names(None, None)?
+            .into_iter()
+            .filter(|db| self.is_db_selected(selector, db, same_cluster))
+            .collect())
+    }
+
+    /// check if database `db` is selected by `selector`, databases written by namespace mapping are not
+    /// selected when source and target are the same cluster.
+    fn is_db_selected(&self, selector: &DbSelector, db: &str, same_cluster: bool) -> bool {
+        let mapping = self.conn.get_conf().get_namespace_mapping();
+        selector.matches(db) && !(same_cluster && mapping.is_target_db(db))
+    }
+
+    /// check that we never sync any database in `dbs` onto itself.
+    fn check_dbs_differ(&self, dbs: &[String]) -> Result<()> {
+        if !self.conn.is_same_cluster()? {
+            return Ok(());
+        }
+        for db in dbs.iter() {
+            self.conn.with_db(db).check_source_target_differ()?;
+        }
+        Ok(())
+    }
+
+    /// get databases to sync in incremental sync.
+    fn get_incr_dbs(&self) -> Result<HashSet<String>> {
+        match self.conn.get_conf().get_dbs() {
+            Some(_) => Ok(self.get_sync_dbs_args()?.unwrap_or_default()),
+            None => Ok(vec![self.conn.get_db().to_string()].into_iter().collect()),
+        }
+    }
+
+    /// record databases `dbs` which are synced.
+    fn write_sync_dbs_args(&self, dbs: &[String]) -> Result<()> {
+        let dbs_to_sync = self.conn.get_sync_meta_db().collection("dbs_to_sync");
+        dbs_to_sync.delete_many(doc! {}, None)?;
+        dbs_to_sync.insert_one(doc! {"names": dbs}, None)?;
+        Ok(())
+    }
+
+    /// record that database `db` is synced too.
+    fn add_sync_db(&self, db: &str) -> Result<()> {
+        self.conn
+            .get_sync_meta_db()
+            .collection::<Document>("dbs_to_sync")
+            .update_one(
+                doc! {},
+                doc! {"$addToSet": {"names": db}},
+                UpdateOptions::builder().upsert(true).build(),
+            )?;
+        Ok(())
+    }
+
+    fn get_sync_dbs_args(&self) -> Result<Option<HashSet<String>>> {
+        let dbs_to_sync = self
+            .conn
+            .get_sync_meta_db()
+            .collection::<Document>("dbs_to_sync");
+        match dbs_to_sync.find_one(doc! {}, None)? {
             None => Ok(None),
             Some(d) => Ok(Some(
-                d.get_array("names")
-                    .unwrap()
+                d.get_array("names")?
                     .iter()
-                    .map(|x| match x {
-                        Bson::String(s) => s.clone(),
-                        _ => panic!(r#"The elements in `names` fields should be string, data corrupted!!"
-"Try drop `colls_to_sync`, `oplog_records` collection in target_database, and make a full sync again."#),
-                    })
+                    .filter_map(|x| x.as_str().map(String::from))
                     .collect(),
             )),
         }
     }
 
-    pub fn get_new_colls_to_sync(&self) -> Result<Option<Vec<String>>> {
-        let origin_sync_colls = self.get_sync_coll_args()?;
-        match origin_sync_colls {
+    /// get databases in `dbs` which are not synced before.
+    fn get_new_dbs_to_sync(&self, dbs: &[String]) -> Result<Option<Vec<String>>> {
+        match self.get_sync_dbs_args()? {
             None => Ok(None),
-            Some(origin_colls) => {
-                let src_db = self.conn.get_src_db();
-                let current_sync_colls: HashSet<String> = match self.conn.get_conf().get_colls() {
-                    // use unwrap here is ok, because we have check list_collection_names before.
-                    None => src_db
-                        .list_collection_names(None)
-                        .unwrap()
-                        .into_iter()
-                        .collect(),
-                    Some(colls) => colls.iter().cloned().collect(),
-                };
-                let new_colls: Vec<String> = current_sync_colls
-                    .difference(&origin_colls)
+            Some(origin_dbs) => {
+                let new_dbs: Vec<String> = dbs
+                    .iter()
+                    .filter(|db| !origin_dbs.contains(*db))
                     .cloned()
                     .collect();
-                if new_colls.is_empty() {
+                if new_dbs.is_empty() {
                     Ok(None)
                 } else {
-                    Ok(Some(new_colls))
+                    Ok(Some(new_dbs))
                 }
             }
         }
     }
 }
+
+/// get the latest oplog timestamp which can be applied by a replica `delay` behind source, oplogs until
+/// `fast_forward_to` can be applied too.
+fn delayed_point(delay: Duration, fast_forward_to: Option<Timestamp>) -> Timestamp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let delayed = Timestamp {
+        time: now.saturating_sub(delay).as_secs() as u32,
+        increment: u32::MAX,
+    };
+    match fast_forward_to {
+        Some(to) if to > delayed => to,
+        _ => delayed,
+    }
+}
+
+/// get databases which are created by `oplogs`, and are not inside `dbs_to_sync`.
+///
+/// A database is created by `create` command, or by inserting into a collection implicitly.
+fn new_created_dbs(oplogs: &[Document], dbs_to_sync: &HashSet<String>) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    for one_log in oplogs {
+        let creates = match one_log.get_str(OP_KEY) {
+            Ok("i") => true,
+            Ok(COMMAND_OP) => one_log
+                .get_document("o")
+                .map(|o| o.contains_key("create"))
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !creates {
+            continue;
+        }
+        let db = match one_log
+            .get_str(NAMESPACE_KEY)
+            .ok()
+            .and_then(|ns| ns.split_once('.'))
+        {
+            Some((db, _)) => db,
+            None => continue,
+        };
+        if !dbs_to_sync.contains(db) && !result.iter().any(|d| d == db) {
+            result.push(db.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new_created_dbs() {
+        let dbs_to_sync: HashSet<String> = vec!["a".to_string()].into_iter().collect();
+        let oplogs = vec![
+            doc! {"ns": "a.x", "op": "i", "o": {"_id": 1}},
+            doc! {"ns": "b.x", "op": "u", "o": {"$set": {"n": 1}}},
+            doc! {"ns": "c.$cmd", "op": "c", "o": {"create": "x"}},
+            doc! {"ns": "d.$cmd", "op": "c", "o": {"drop": "x"}},
+            doc! {"ns": "e.x", "op": "i", "o": {"_id": 1}},
+            doc! {"ns": "c.x", "op": "i", "o": {"_id": 1}},
+        ];
+        assert_eq!(
+            new_created_dbs(&oplogs, &dbs_to_sync),
+            vec!["c".to_string(), "e".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_delayed_point() {
+        let now = delayed_point(Duration::ZERO, None);
+        let delayed = delayed_point(Duration::from_secs(3600), None);
+        assert!(delayed.time + 3600 >= now.time && delayed.time + 3600 <= now.time + 1);
+        assert_eq!(delayed.increment, u32::MAX);
+
+        let old = Timestamp {
+            time: 10,
+            increment: 1,
+        };
+        assert_eq!(
+            delayed_point(Duration::from_secs(3600), Some(old)).time,
+            delayed.time
+        );
+        let future = Timestamp {
+            time: now.time + 10,
+            increment: 1,
+        };
+        assert_eq!(
+            delayed_point(Duration::from_secs(3600), Some(future)),
+            future
+        );
+    }
+}
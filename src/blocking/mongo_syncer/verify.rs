@@ -0,0 +1,517 @@
+//! Provide source and target data verification.
+//!
+//! A collection is verified by counts, index definitions and collection options first.  Then documents are
+//! compared by hashes of `_id` ranges, the ranges are split like concurrent full sync, and only ranges
+//! whose hashes differ are drilled down to list missing, extra and differing `_id`s.
+
+use super::bson_helper::id_key;
+use super::full::split_ids;
+use super::repair::RepairDoc;
+use crate::{CollFilter, Result, SyncError};
+use bson::{doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::FindOptions;
+use mongodb::sync::{Collection, Database};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hasher;
+use std::time::Duration;
+
+/// a collection is split into ranges of about so many documents.
+const RANGE_DOCS: u64 = 10000;
+/// max ranges of a collection.
+const MAX_RANGES: usize = 1000;
+/// max `_id`s which are listed for a collection, further differences are only counted.
+pub const MAX_DIFF_IDS: usize = 10000;
+/// max `_id`s in one `$in` query.
+const IDS_PER_QUERY: usize = 1000;
+/// mongodb error code when the namespace doesn't exist.
+const NAMESPACE_NOT_FOUND: i32 = 26;
+/// how many `_id`s of each kind are displayed in report.
+const DISPLAY_IDS: usize = 20;
+
+/// `_id`s of documents which differ between source and target collection.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IdDiffs {
+    /// documents which exist in source, but not in target.
+    pub missing: Vec<Bson>,
+    /// documents which exist in target, but not in source.
+    pub extra: Vec<Bson>,
+    /// documents which exist in both, but contents differ.
+    pub differing: Vec<Bson>,
+    /// more differences are found, but not listed because of [MAX_DIFF_IDS].
+    pub truncated: bool,
+}
+
+impl IdDiffs {
+    /// return true if there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.differing.is_empty()
+    }
+
+    /// return how many `_id`s are listed.
+    pub fn len(&self) -> usize {
+        self.missing.len() + self.extra.len() + self.differing.len()
+    }
+
+    /// get all listed `_id`s.
+    pub fn ids(&self) -> Vec<Bson> {
+        let mut ids = Vec::with_capacity(self.len());
+        ids.extend(self.missing.iter().cloned());
+        ids.extend(self.extra.iter().cloned());
+        ids.extend(self.differing.iter().cloned());
+        ids
+    }
+
+    /// merge differences in `other`, `_id`s are truncated if there are too many.
+    pub fn extend(&mut self, other: IdDiffs) {
+        self.truncated |= other.truncated;
+        for (ids, other_ids) in [
+            (&mut self.missing, other.missing),
+            (&mut self.extra, other.extra),
+            (&mut self.differing, other.differing),
+        ] {
+            for id in other_ids {
+                if ids.len() >= MAX_DIFF_IDS {
+                    self.truncated = true;
+                    break;
+                }
+                ids.push(id);
+            }
+        }
+    }
+}
+
+/// Verify result of one collection.
+#[derive(Debug)]
+pub struct CollVerifyReport {
+    /// collection name.
+    pub name: String,
+    /// source namespace of the collection, `db.coll`.
+    pub ns: String,
+    /// documents in source collection, which match the collection filter.
+    pub src_count: u64,
+    /// documents in target collection.
+    pub target_count: u64,
+    /// differences of indexes and collection options.
+    pub meta_diffs: Vec<String>,
+    /// differences of documents.
+    pub id_diffs: IdDiffs,
+    /// error occurred during verify the collection.
+    pub error: Option<SyncError>,
+}
+
+impl CollVerifyReport {
+    /// create a report for collection `coll` of database `db` which is not verified yet.
+    pub fn new(db: &str, coll: &str) -> Self {
+        CollVerifyReport {
+            name: coll.to_string(),
+            ns: format!("{}.{}", db, coll),
+            src_count: 0,
+            target_count: 0,
+            meta_diffs: vec![],
+            id_diffs: IdDiffs::default(),
+            error: None,
+        }
+    }
+
+    /// return true if target collection matches source collection.
+    pub fn is_consistent(&self) -> bool {
+        self.error.is_none()
+            && self.src_count == self.target_count
+            && self.meta_diffs.is_empty()
+            && self.id_diffs.is_empty()
+    }
+}
+
+/// Summary report of verification, which is returned by [MongoSyncer::verify](crate::MongoSyncer::verify).
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// report for each collection.
+    pub collections: Vec<CollVerifyReport>,
+    /// how long does the verification take.
+    pub duration: Duration,
+}
+
+impl VerifyReport {
+    /// return true if all collections are consistent.
+    pub fn is_consistent(&self) -> bool {
+        self.collections.iter().all(|c| c.is_consistent())
+    }
+
+    /// get documents which differ, they can be repaired by [MongoSyncer::repair](crate::MongoSyncer::repair).
+    pub fn repair_docs(&self) -> Vec<RepairDoc> {
+        self.collections
+            .iter()
+            .flat_map(|c| {
+                c.id_diffs
+                    .ids()
+                    .into_iter()
+                    .map(move |id| RepairDoc::new(c.ns.clone(), id))
+            })
+            .collect()
+    }
+}
+
+fn write_ids(f: &mut fmt::Formatter<'_>, kind: &str, ids: &[Bson]) -> fmt::Result {
+    for id in ids.iter().take(DISPLAY_IDS) {
+        writeln!(f, "    {} _id: {}", kind, id)?;
+    }
+    if ids.len() > DISPLAY_IDS {
+        writeln!(f, "    ... {} more {} _ids", ids.len() - DISPLAY_IDS, kind)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>12} {:>12}  STATUS",
+            "COLLECTION", "SOURCE", "TARGET"
+        )?;
+        for c in self.collections.iter() {
+            let status = match &c.error {
+                Some(e) => format!("failed: {}", e),
+                None if c.is_consistent() => "ok".to_string(),
+                None => format!(
+                    "mismatch: {} missing, {} extra, {} differing{}",
+                    c.id_diffs.missing.len(),
+                    c.id_diffs.extra.len(),
+                    c.id_diffs.differing.len(),
+                    if c.id_diffs.truncated {
+                        ", truncated"
+                    } else {
+                        ""
+                    }
+                ),
+            };
+            writeln!(
+                f,
+                "{:<40} {:>12} {:>12}  {}",
+                c.name, c.src_count, c.target_count, status
+            )?;
+            for diff in c.meta_diffs.iter() {
+                writeln!(f, "    {}", diff)?;
+            }
+            write_ids(f, "missing", &c.id_diffs.missing)?;
+            write_ids(f, "extra", &c.id_diffs.extra)?;
+            write_ids(f, "differing", &c.id_diffs.differing)?;
+        }
+        let inconsistent = self
+            .collections
+            .iter()
+            .filter(|c| !c.is_consistent())
+            .count();
+        write!(
+            f,
+            "Total: {} collections ({} inconsistent), takes {:.2}s",
+            self.collections.len(),
+            inconsistent,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+/// hash of document `d`, documents with the same fields in the same order have the same hash.
+pub fn doc_hash(d: &Document) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_doc(&mut hasher, d);
+    hasher.finish()
+}
+
+fn hash_doc(hasher: &mut DefaultHasher, d: &Document) {
+    let mut bytes = vec![];
+    // serializing a document into memory never fails.
+    d.to_writer(&mut bytes)
+        .expect("document should be serialized");
+    hasher.write(&bytes);
+}
+
+/// get source query of collection filter `coll_filter` and `id_filter`, returns filter and projection.
+pub fn source_query(
+    coll_filter: Option<&CollFilter>,
+    id_filter: Option<Document>,
+) -> (Option<Document>, Option<Document>) {
+    match coll_filter {
+        Some(f) => {
+            let filter = match id_filter {
+                Some(id_filter) => doc! {"$and": [id_filter, f.filter.clone()]},
+                None => f.filter.clone(),
+            };
+            (Some(filter), f.projection.clone())
+        }
+        None => (id_filter, None),
+    }
+}
+
+fn find_sorted(
+    coll: &Collection<Document>,
+    filter: Option<Document>,
+    projection: Option<Document>,
+) -> Result<impl Iterator<Item = Result<Document>>> {
+    let cursor = coll.find(
+        filter,
+        FindOptions::builder()
+            .sort(doc! {"_id": 1})
+            .projection(projection)
+            .build(),
+    )?;
+    Ok(cursor.map(|d| d.map_err(SyncError::from)))
+}
+
+/// get count and hash of documents which match `filter` in `coll`, documents are hashed in `_id` order.
+fn range_digest(
+    coll: &Collection<Document>,
+    filter: Option<Document>,
+    projection: Option<Document>,
+) -> Result<(u64, u64)> {
+    let mut hasher = DefaultHasher::new();
+    let mut count = 0;
+    for d in find_sorted(coll, filter, projection)? {
+        hash_doc(&mut hasher, &d?);
+        count += 1;
+    }
+    Ok((count, hasher.finish()))
+}
+
+/// get `_id` and hash of documents which match `filter` in `coll`.
+fn id_hashes(
+    coll: &Collection<Document>,
+    filter: Option<Document>,
+    projection: Option<Document>,
+) -> Result<Vec<(Bson, u64)>> {
+    let mut result = vec![];
+    for d in find_sorted(coll, filter, projection)? {
+        let d = d?;
+        let id = d.get("_id").cloned().unwrap_or(Bson::Null);
+        result.push((id, doc_hash(&d)));
+    }
+    Ok(result)
+}
+
+/// compare `_id` and hash of documents in source `src` and target `target`.
+pub fn diff_id_hashes(src: Vec<(Bson, u64)>, target: Vec<(Bson, u64)>) -> IdDiffs {
+    let mut target: HashMap<Vec<u8>, (Bson, u64)> = target
+        .into_iter()
+        .map(|(id, hash)| (id_key(&id), (id, hash)))
+        .collect();
+    let mut src_diffs = IdDiffs::default();
+    for (id, hash) in src {
+        match target.remove(&id_key(&id)) {
+            None => src_diffs.missing.push(id),
+            Some((_, target_hash)) if target_hash != hash => src_diffs.differing.push(id),
+            Some(_) => {}
+        }
+    }
+    let mut extra: Vec<(Vec<u8>, (Bson, u64))> = target.into_iter().collect();
+    extra.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    src_diffs.extra = extra.into_iter().map(|(_, (id, _))| id).collect();
+    // truncate `_id`s if there are too many.
+    let mut diffs = IdDiffs::default();
+    diffs.extend(src_diffs);
+    diffs
+}
+
+/// compare documents of `source_coll` and `target_coll`, returns source count, target count and
+/// differences.
+///
+/// Documents are compared by hashes of `_id` ranges, ranges whose hashes differ are drilled down.  Source
+/// documents are selected and projected by `coll_filter`.  Counts are taken from whole collections, so
+/// target documents outside of the ranges are still noticed.
+pub fn verify_docs(
+    source_coll: &Collection<Document>,
+    target_coll: &Collection<Document>,
+    coll_filter: Option<&CollFilter>,
+) -> Result<(u64, u64, IdDiffs)> {
+    let src_count = source_coll.count_documents(source_query(coll_filter, None).0, None)?;
+    let target_count = target_coll.count_documents(None, None)?;
+    let range_count = ((src_count / RANGE_DOCS) as usize).clamp(1, MAX_RANGES);
+    let mut diffs = IdDiffs::default();
+    for range in split_ids(source_coll, range_count)? {
+        let id_filter = range.remaining_filter();
+        let (filter, projection) = source_query(coll_filter, id_filter.clone());
+        let src_digest = range_digest(source_coll, filter.clone(), projection.clone())?;
+        let target_digest = range_digest(target_coll, id_filter.clone(), None)?;
+        if src_digest != target_digest {
+            diffs.extend(diff_id_hashes(
+                id_hashes(source_coll, filter, projection)?,
+                id_hashes(target_coll, id_filter, None)?,
+            ));
+        }
+    }
+    Ok((src_count, target_count, diffs))
+}
+
+/// compare documents `ids` of `source_coll` and `target_coll` again, returns `_id`s which still differ.
+pub fn verify_ids(
+    source_coll: &Collection<Document>,
+    target_coll: &Collection<Document>,
+    coll_filter: Option<&CollFilter>,
+    ids: &[Bson],
+) -> Result<IdDiffs> {
+    let mut diffs = IdDiffs::default();
+    for chunk in ids.chunks(IDS_PER_QUERY) {
+        let id_filter = doc! {"_id": {"$in": chunk}};
+        let (filter, projection) = source_query(coll_filter, Some(id_filter.clone()));
+        diffs.extend(diff_id_hashes(
+            id_hashes(source_coll, filter, projection)?,
+            id_hashes(target_coll, Some(id_filter), None)?,
+        ));
+    }
+    Ok(diffs)
+}
+
+/// get index definitions of collection `coll` in `db`, empty if the collection doesn't exist.
+fn list_indexes(db: &Database, coll: &str) -> Result<Vec<Document>> {
+    let result = match db.run_command(doc! {"listIndexes": coll}, None) {
+        Ok(result) => result,
+        Err(e) => match e.kind.as_ref() {
+            ErrorKind::Command(err) if err.code == NAMESPACE_NOT_FOUND => return Ok(vec![]),
+            _ => return Err(SyncError::from(e)),
+        },
+    };
+    Ok(result
+        .get_document("cursor")?
+        .get_array("firstBatch")?
+        .iter()
+        .filter_map(|index| index.as_document().cloned())
+        .collect())
+}
+
+/// get options of collection `coll` in `db`, None if the collection doesn't exist.
+fn coll_options(db: &Database, coll: &str) -> Result<Option<Document>> {
+    let result = db.run_command(doc! {"listCollections": 1, "filter": {"name": coll}}, None)?;
+    let colls = result.get_document("cursor")?.get_array("firstBatch")?;
+    match colls.first().and_then(|c| c.as_document()) {
+        Some(coll_info) => Ok(Some(coll_info.get_document("options")?.clone())),
+        None => Ok(None),
+    }
+}
+
+/// compare index definitions `src` and `target` by index name, returns differences.
+///
+/// Index version and namespace are ignored, they differ between servers.
+pub fn index_diffs(src: &[Document], target: &[Document]) -> Vec<String> {
+    let normalize = |indexes: &[Document]| -> HashMap<String, Document> {
+        indexes
+            .iter()
+            .map(|index| {
+                let mut index = index.clone();
+                index.remove("v");
+                index.remove("ns");
+                (index.get_str("name").unwrap_or_default().to_string(), index)
+            })
+            .collect()
+    };
+    let src = normalize(src);
+    let mut target = normalize(target);
+    let mut diffs = vec![];
+    let mut names: Vec<&String> = src.keys().collect();
+    names.sort_unstable();
+    for name in names {
+        match target.remove(name) {
+            None => diffs.push(format!("index {:?} is missing in target", name)),
+            Some(index) if index != src[name] => diffs.push(format!(
+                "index {:?} differs, source: {}, target: {}",
+                name, src[name], index
+            )),
+            Some(_) => {}
+        }
+    }
+    let mut extra: Vec<String> = target.into_keys().collect();
+    extra.sort_unstable();
+    for name in extra {
+        diffs.push(format!("index {:?} is extra in target", name));
+    }
+    diffs
+}
+
+/// compare indexes and options of collection `coll` in `src_db` and `target_coll` in `target_db`, returns
+/// differences.
+pub fn verify_meta(
+    src_db: &Database,
+    coll: &str,
+    target_db: &Database,
+    target_coll: &str,
+) -> Result<Vec<String>> {
+    let target_options = match coll_options(target_db, target_coll)? {
+        Some(options) => options,
+        None => return Ok(vec!["collection is missing in target".to_string()]),
+    };
+    let mut diffs = vec![];
+    let src_options = coll_options(src_db, coll)?.unwrap_or_default();
+    if src_options != target_options {
+        diffs.push(format!(
+            "collection options differ, source: {}, target: {}",
+            src_options, target_options
+        ));
+    }
+    diffs.extend(index_diffs(
+        &list_indexes(src_db, coll)?,
+        &list_indexes(target_db, target_coll)?,
+    ));
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff_id_hashes() {
+        let src = vec![
+            (Bson::Int32(1), 10),
+            (Bson::Int32(2), 20),
+            (Bson::Int32(3), 30),
+        ];
+        let target = vec![
+            (Bson::Int32(1), 10),
+            (Bson::Int32(3), 31),
+            (Bson::Int32(4), 40),
+        ];
+        let diffs = diff_id_hashes(src, target);
+        assert_eq!(diffs.missing, vec![Bson::Int32(2)]);
+        assert_eq!(diffs.extra, vec![Bson::Int32(4)]);
+        assert_eq!(diffs.differing, vec![Bson::Int32(3)]);
+        assert_eq!(diffs.len(), 3);
+        assert!(!diffs.truncated);
+
+        // `_id`s of different types are different documents.
+        let diffs = diff_id_hashes(vec![(Bson::Int32(1), 10)], vec![(Bson::Int64(1), 10)]);
+        assert_eq!(diffs.missing, vec![Bson::Int32(1)]);
+        assert_eq!(diffs.extra, vec![Bson::Int64(1)]);
+    }
+
+    #[test]
+    fn test_doc_hash() {
+        assert_eq!(
+            doc_hash(&doc! {"a": 1, "b": 2}),
+            doc_hash(&doc! {"a": 1, "b": 2})
+        );
+        assert_ne!(
+            doc_hash(&doc! {"a": 1, "b": 2}),
+            doc_hash(&doc! {"a": 1, "b": 3})
+        );
+    }
+
+    #[test]
+    fn test_index_diffs() {
+        let src = vec![
+            doc! {"v": 2, "key": {"_id": 1}, "name": "_id_", "ns": "a.b"},
+            doc! {"v": 2, "key": {"x": 1}, "name": "x_1", "unique": true},
+            doc! {"v": 2, "key": {"y": 1}, "name": "y_1"},
+        ];
+        let target = vec![
+            doc! {"v": 1, "key": {"_id": 1}, "name": "_id_"},
+            doc! {"v": 2, "key": {"x": 1}, "name": "x_1"},
+            doc! {"v": 2, "key": {"z": 1}, "name": "z_1"},
+        ];
+        let diffs = index_diffs(&src, &target);
+        assert_eq!(diffs.len(), 3);
+        assert!(diffs[0].starts_with("index \"x_1\" differs"));
+        assert_eq!(diffs[1], "index \"y_1\" is missing in target");
+        assert_eq!(diffs[2], "index \"z_1\" is extra in target");
+    }
+}
@@ -5,7 +5,7 @@ use mongodb::error::{Error as MongoError, ErrorKind, Result as MongoResult};
 use mongodb::sync::Client as MongoClient;
 use tracing::warn;
 
-use crate::{Result, SyncError};
+use crate::{NamespaceMapping, Result, SyncError};
 
 /// connection namespace.
 #[derive(Debug, PartialEq)]
@@ -21,6 +21,11 @@ impl<'a> CollNs<'a> {
     pub fn new(db_name: &'a str, coll_name: &'a str) -> Self {
         CollNs { db_name, coll_name }
     }
+
+    /// get target database and collection name of the namespace according to `mapping`.
+    fn target(&self, mapping: &NamespaceMapping) -> (String, String) {
+        mapping.target_ns(self.db_name, self.coll_name)
+    }
 }
 
 /// Structured command type oplog definition.
@@ -204,17 +209,31 @@ impl<'a> CmdOplog<'a> {
     /// cmd_oplog.apply(&cli).unwrap();
     /// ```
     pub fn apply(self, mongo_conn: &MongoClient) -> Result<()> {
+        self.apply_with_mapping(mongo_conn, &NamespaceMapping::default())
+    }
+
+    /// Apply oplog represent in `self` against `mongo_conn`, namespaces in the oplog are mapped to target
+    /// namespaces by `mapping`.
+    ///
+    /// For rename collection command, both source and target namespace are mapped.
+    pub fn apply_with_mapping(
+        self,
+        mongo_conn: &MongoClient,
+        mapping: &NamespaceMapping,
+    ) -> Result<()> {
         use CmdOplog::*;
         match self {
             DropCollection(ns) => {
+                let (db_name, coll_name) = ns.target(mapping);
                 let coll = mongo_conn
-                    .database(ns.db_name)
-                    .collection::<Document>(ns.coll_name);
+                    .database(&db_name)
+                    .collection::<Document>(&coll_name);
                 coll.drop(None).map_err(SyncError::from)
             }
             CreateCollection(ns) => {
-                let db = mongo_conn.database(ns.db_name);
-                let result = db.create_collection(ns.coll_name, None).map(|_| ());
+                let (db_name, coll_name) = ns.target(mapping);
+                let db = mongo_conn.database(&db_name);
+                let result = db.create_collection(&coll_name, None).map(|_| ());
 
                 if cmd_result_is_ok(&result, "already exist") {
                     Ok(())
@@ -223,14 +242,16 @@ impl<'a> CmdOplog<'a> {
                 }
             }
             RenameCollection { from, to } => {
+                let (from_db, from_coll) = from.target(mapping);
+                let (to_db, to_coll) = to.target(mapping);
                 // no... no rename collection api. so have to go through `db.run_command` api.
                 // rename collection can only runs in admin database.
                 let admin_db = mongo_conn.database("admin");
                 let result = admin_db
                     .run_command(
                         doc! {
-                            "renameCollection": format!("{}.{}", from.db_name, from.coll_name),
-                            "to": format!("{}.{}", to.db_name, to.coll_name),
+                            "renameCollection": format!("{}.{}", from_db, from_coll),
+                            "to": format!("{}.{}", to_db, to_coll),
                         },
                         None,
                     )
@@ -243,11 +264,12 @@ impl<'a> CmdOplog<'a> {
                 }
             }
             DropIndexes { ns, name } => {
-                let db = mongo_conn.database(ns.db_name);
+                let (db_name, coll_name) = ns.target(mapping);
+                let db = mongo_conn.database(&db_name);
                 let result = db
                     .run_command(
                         doc! {
-                            "dropIndexes": ns.coll_name,
+                            "dropIndexes": coll_name,
                             "index": name
                         },
                         None,
@@ -274,9 +296,10 @@ impl<'a> CmdOplog<'a> {
                 if let Some(partial_filter_expression) = partial_filter_expression {
                     index_info.insert("partialFilterExpression", partial_filter_expression.clone());
                 }
-                let db = mongo_conn.database(ns.db_name);
+                let (db_name, coll_name) = ns.target(mapping);
+                let db = mongo_conn.database(&db_name);
                 let indx_doc = doc! {
-                    "createIndexes": ns.coll_name,
+                    "createIndexes": coll_name,
                     "indexes": [index_info]
                 };
 
@@ -319,6 +342,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_coll_ns_target() {
+        let mapping = NamespaceMapping::new()
+            .with_db("a", "a_mirror")
+            .with_coll("a.b", "a_mirror.b_v2");
+        assert_eq!(
+            CollNs::new("a", "b").target(&mapping),
+            ("a_mirror".to_string(), "b_v2".to_string())
+        );
+        assert_eq!(
+            CollNs::new("a", "c").target(&mapping),
+            ("a_mirror".to_string(), "c".to_string())
+        );
+    }
+
     #[test]
     fn test_cmd_oplog_drop_collection() {
         let test_doc = doc! {"ns": "a.$cmd", "o": {"drop": "cc"}};
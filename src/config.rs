@@ -1,3 +1,10 @@
+use crate::{CollSelector, DbSelector, NamespaceMapping, SyncError};
+use bson::Document;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
 /// Global mongo syncer configuration.
 #[derive(Debug)]
 pub struct OplogSyncerConfig {
@@ -30,6 +37,325 @@ pub struct Src {
     uri: String,
 }
 
+/// How full sync writes documents into target collections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullSyncWritePolicy {
+    /// drop target collections before copy, all existing data in target collections will be lost.
+    Drop,
+    /// replace target documents with the same `_id`, and keep other target documents.
+    Merge,
+    /// refuse to sync if any target collection contains documents.
+    #[default]
+    FailIfNonEmpty,
+}
+
+impl FromStr for FullSyncWritePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(FullSyncWritePolicy::Drop),
+            "merge" => Ok(FullSyncWritePolicy::Merge),
+            "fail-if-non-empty" => Ok(FullSyncWritePolicy::FailIfNonEmpty),
+            _ => Err(format!(
+                "invalid write policy {:?}, expect one of: drop, merge, fail-if-non-empty",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with collections which were synced before, but are not selected any more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemovedCollPolicy {
+    /// keep target collections as frozen copies, they are not updated any more.
+    #[default]
+    Keep,
+    /// drop target collections.
+    Drop,
+    /// refuse to start sync.
+    Refuse,
+}
+
+impl FromStr for RemovedCollPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(RemovedCollPolicy::Keep),
+            "drop" => Ok(RemovedCollPolicy::Drop),
+            "refuse" => Ok(RemovedCollPolicy::Refuse),
+            _ => Err(format!(
+                "invalid removed collection policy {:?}, expect one of: keep, drop, refuse",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RemovedCollPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RemovedCollPolicy::Keep => "keep",
+            RemovedCollPolicy::Drop => "drop",
+            RemovedCollPolicy::Refuse => "refuse",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What to do when check point is older than the earliest stored oplog, so oplogs after check point are lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OplogGapPolicy {
+    /// refuse to start sync, and report the gap.
+    #[default]
+    Fail,
+    /// make a full sync of synced collections again, target collections are written by write policy.
+    Resync,
+    /// copy again collections which follow the lost check point by write policy, collections with own
+    /// checkpoints in the oplog window keep streaming.
+    ResyncAffected,
+}
+
+impl FromStr for OplogGapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OplogGapPolicy::Fail),
+            "resync" => Ok(OplogGapPolicy::Resync),
+            "resync-affected" => Ok(OplogGapPolicy::ResyncAffected),
+            _ => Err(format!(
+                "invalid oplog gap policy {:?}, expect one of: fail, resync, resync-affected",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OplogGapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OplogGapPolicy::Fail => "fail",
+            OplogGapPolicy::Resync => "resync",
+            OplogGapPolicy::ResyncAffected => "resync-affected",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What to do with an oplog which still fails to apply after retries in incremental sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApplyFailurePolicy {
+    /// stop incremental sync with the error.
+    #[default]
+    Fail,
+    /// save the oplog with the error into dead-letter collection, and continue with other oplogs.
+    DeadLetter,
+}
+
+impl FromStr for ApplyFailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ApplyFailurePolicy::Fail),
+            "dead-letter" => Ok(ApplyFailurePolicy::DeadLetter),
+            _ => Err(format!(
+                "invalid apply failure policy {:?}, expect one of: fail, dead-letter",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ApplyFailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ApplyFailurePolicy::Fail => "fail",
+            ApplyFailurePolicy::DeadLetter => "dead-letter",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Retries of oplog batches which fail to apply in incremental sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApplyRetry {
+    /// how many times a failed batch is retried.
+    pub retries: u32,
+    /// how long to wait before the first retry, it's doubled after each retry.
+    pub backoff: Duration,
+    /// max wait between retries.
+    pub max_backoff: Duration,
+}
+
+impl ApplyRetry {
+    /// retry a failed batch `retries` times, wait `backoff` before the first retry.
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        ApplyRetry {
+            retries,
+            backoff,
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// get how long to wait before retry `attempt`, which begins from 0.
+    pub fn backoff_of(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ApplyRetry {
+    fn default() -> Self {
+        ApplyRetry::new(3, Duration::from_secs(1))
+    }
+}
+
+/// How a supervised run is retried after transient errors, it's shared by `db_sync` and `oplog_syncer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// max consecutive retries, None means retrying forever.
+    pub max_retries: Option<u32>,
+    /// how long to wait before the first retry, it's doubled after each consecutive failure.
+    pub initial_backoff: Duration,
+    /// max wait between retries, before jitter.
+    pub max_backoff: Duration,
+    /// a run which lasts this long resets consecutive failures.
+    pub reset_after: Duration,
+}
+
+impl RetryPolicy {
+    /// retry at most `max_retries` consecutive failures, None means retrying forever.
+    pub fn new(max_retries: Option<u32>) -> Self {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            reset_after: Duration::from_secs(600),
+        }
+    }
+
+    /// wait `initial` before the first retry, and at most `max` between retries.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// get how long to wait after `failures` consecutive failures, which begins from 1, before jitter.
+    pub fn backoff_of(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(Some(10))
+    }
+}
+
+/// Batch size limits for full sync copy and oplog replay, a batch is flushed when any limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchLimits {
+    /// max documents (or oplogs) in one batch.
+    pub max_count: usize,
+    /// max encoded bson bytes in one batch.
+    pub max_bytes: usize,
+}
+
+impl BatchLimits {
+    /// create limits with `max_count` documents and `max_bytes` encoded bytes.
+    ///
+    /// Returns [SyncError::InvalidBufferLimit] if any limit is 0, because a batch could never contain a document.
+    pub fn new(max_count: usize, max_bytes: usize) -> crate::Result<Self> {
+        let limits = BatchLimits {
+            max_count,
+            max_bytes,
+        };
+        limits.validate()?;
+        Ok(limits)
+    }
+
+    /// check that both limits are larger than 0.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.max_count == 0 {
+            return Err(SyncError::InvalidBufferLimit {
+                detail: "max documents in one batch must be larger than 0".to_string(),
+            });
+        }
+        if self.max_bytes == 0 {
+            return Err(SyncError::InvalidBufferLimit {
+                detail: "max bytes in one batch must be larger than 0".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        BatchLimits {
+            max_count: 10000,
+            max_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Options of continuous consistency sampling in incremental sync.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingConfig {
+    /// how long to wait between sampling rounds.
+    pub interval: Duration,
+    /// how many random documents are sampled from each collection in a round.
+    pub docs_per_coll: usize,
+    /// alert when mismatched documents divided by sampled documents of a round crosses the rate.
+    pub alert_rate: f64,
+}
+
+impl SamplingConfig {
+    /// sample `docs_per_coll` documents of each collection every `interval`.
+    pub fn new(interval: Duration, docs_per_coll: usize) -> Self {
+        SamplingConfig {
+            interval,
+            docs_per_coll,
+            alert_rate: 0.0,
+        }
+    }
+
+    /// alert when mismatch rate of a round crosses `alert_rate`, default is 0, any mismatch is alerted.
+    pub fn with_alert_rate(mut self, alert_rate: f64) -> Self {
+        self.alert_rate = alert_rate;
+        self
+    }
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig::new(Duration::from_secs(300), 100)
+    }
+}
+
+/// Document filter and projection of a collection.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CollFilter {
+    /// only documents which match the filter are synced.
+    pub filter: Document,
+    /// fields to include or exclude, None means all fields are synced.  `_id` is always synced.
+    pub projection: Option<Document>,
+}
+
+/// default memory budget for all buffers of a process.
+const DEFAULT_MEMORY_BUDGET: usize = 512 * 1024 * 1024;
+
 /// Detail sync config, it indicates which database to sync, or which collection to sync.
 #[derive(Debug)]
 pub struct DetailSyncConf {
@@ -37,12 +363,44 @@ pub struct DetailSyncConf {
     dst_uri: String,
     /// database name
     db: String,
+    /// databases to sync, it's None when only `db` is synced.
+    dbs: Option<DbSelector>,
     /// collections to sync, default it None, which means sync all collections.
     colls: Option<Vec<String>>,
+    /// include and exclude patterns of collections to sync, collections in `colls` are included.
+    coll_selector: CollSelector,
     /// how many collections will be sync concurrently.
     collection_concurrent: usize,
     /// how many threads will used to sync one collection concurrently.
     doc_concurrent: usize,
+    /// how to write documents into target collections during full sync.
+    write_policy: FullSyncWritePolicy,
+    /// is it confirmed that target collections can be dropped.
+    confirm_drop: bool,
+    /// what to do with collections removed from sync set.
+    removed_coll_policy: RemovedCollPolicy,
+    /// what to do when check point falls outside the oplog window.
+    oplog_gap_policy: OplogGapPolicy,
+    /// batch limits for full sync copy and oplog replay.
+    batch_limits: BatchLimits,
+    /// max bytes used by all buffers.
+    memory_budget: usize,
+    /// only apply oplogs which are older than the delay in incremental sync.
+    apply_delay: Option<Duration>,
+    /// sample documents and compare them between source and target in incremental sync.
+    sampling: Option<SamplingConfig>,
+    /// read documents at one cluster time in full sync if source supports snapshot read.
+    snapshot_read: bool,
+    /// apply updates with current full documents which are fetched from source in incremental sync.
+    full_document_updates: bool,
+    /// retries of oplog batches which fail to apply in incremental sync.
+    apply_retry: ApplyRetry,
+    /// what to do with an oplog which still fails to apply after retries.
+    apply_failure_policy: ApplyFailurePolicy,
+    /// document filter and projection of collections, keyed by collection name.
+    coll_filters: HashMap<String, CollFilter>,
+    /// how source namespaces are mapped to target namespaces.
+    namespace_mapping: NamespaceMapping,
 }
 
 fn number_of_cpus() -> usize {
@@ -57,7 +415,8 @@ fn half_number_of_cpus() -> usize {
 /// database sync configuration.
 pub struct DbSyncConf {
     src: Src,
-    oplog_storage: OplogStorage,
+    /// oplog storage, it's None when we only want to make a one-shot copy.
+    oplog_storage: Option<OplogStorage>,
     conf: DetailSyncConf,
 }
 
@@ -74,34 +433,227 @@ impl DbSyncConf {
     ) -> Self {
         DbSyncConf {
             src: Src { uri: src_uri },
-            oplog_storage: {
-                OplogStorage {
-                    uri: oplog_storage_uri,
-                }
-            },
+            oplog_storage: Some(OplogStorage {
+                uri: oplog_storage_uri,
+            }),
             conf: DetailSyncConf {
                 dst_uri: target_uri,
                 db,
+                dbs: None,
+                coll_selector: colls
+                    .as_deref()
+                    .map(CollSelector::from_names)
+                    .unwrap_or_default(),
                 colls,
                 collection_concurrent: collection_concurrent.unwrap_or_else(number_of_cpus),
                 doc_concurrent: doc_concurrent.unwrap_or_else(half_number_of_cpus),
+                write_policy: FullSyncWritePolicy::default(),
+                confirm_drop: false,
+                removed_coll_policy: RemovedCollPolicy::default(),
+                oplog_gap_policy: OplogGapPolicy::default(),
+                batch_limits: BatchLimits::default(),
+                memory_budget: DEFAULT_MEMORY_BUDGET,
+                apply_delay: None,
+                sampling: None,
+                snapshot_read: false,
+                full_document_updates: false,
+                apply_retry: ApplyRetry::default(),
+                apply_failure_policy: ApplyFailurePolicy::default(),
+                coll_filters: HashMap::new(),
+                namespace_mapping: NamespaceMapping::default(),
             },
         }
     }
 
+    /// create a new configuration for one-shot copy, which doesn't need oplog storage.
+    ///
+    /// The configuration can only be used by [MongoSyncer::sync_once](crate::MongoSyncer::sync_once).
+    pub fn new_oneshot(
+        src_uri: String,
+        target_uri: String,
+        db: String,
+        colls: Option<Vec<String>>,
+        collection_concurrent: Option<usize>,
+        doc_concurrent: Option<usize>,
+    ) -> Self {
+        let mut conf = DbSyncConf::new(
+            src_uri,
+            target_uri,
+            String::new(),
+            db,
+            colls,
+            collection_concurrent,
+            doc_concurrent,
+        );
+        conf.oplog_storage = None;
+        conf
+    }
+
+    /// set how to write documents into target collections during full sync.
+    ///
+    /// Default is [FullSyncWritePolicy::FailIfNonEmpty], [FullSyncWritePolicy::Drop] must be confirmed by
+    /// [with_confirm_drop](DbSyncConf::with_confirm_drop).
+    pub fn with_write_policy(mut self, write_policy: FullSyncWritePolicy) -> Self {
+        self.conf.write_policy = write_policy;
+        self
+    }
+
+    /// confirm that target collections can be dropped, which is required by [FullSyncWritePolicy::Drop].
+    pub fn with_confirm_drop(mut self, confirmed: bool) -> Self {
+        self.conf.confirm_drop = confirmed;
+        self
+    }
+
+    /// set what to do with collections which were synced before, but are not selected any more.
+    ///
+    /// Default is [RemovedCollPolicy::Keep].
+    pub fn with_removed_coll_policy(mut self, policy: RemovedCollPolicy) -> Self {
+        self.conf.removed_coll_policy = policy;
+        self
+    }
+
+    /// set what to do when check point is older than the earliest stored oplog.
+    ///
+    /// Default is [OplogGapPolicy::Fail], resync policies copy target collections again by
+    /// [FullSyncWritePolicy], so they must be chosen explicitly.
+    pub fn with_oplog_gap_policy(mut self, policy: OplogGapPolicy) -> Self {
+        self.conf.oplog_gap_policy = policy;
+        self
+    }
+
+    /// set batch limits for full sync copy and oplog replay, they're checked by [DbSyncConf::validate_buffers].
+    pub fn with_batch_limits(mut self, batch_limits: BatchLimits) -> Self {
+        self.conf.batch_limits = batch_limits;
+        self
+    }
+
+    /// set max bytes used by all buffers, default is 512MB, it must be larger than 0.
+    pub fn with_memory_budget(mut self, memory_budget: usize) -> Self {
+        self.conf.memory_budget = memory_budget;
+        self
+    }
+
+    /// only apply oplogs which are older than `delay` in incremental sync, so target is `delay` behind source.
+    pub fn with_apply_delay(mut self, delay: Duration) -> Self {
+        self.conf.apply_delay = Some(delay);
+        self
+    }
+
+    /// sample documents continuously in incremental sync, and compare them between source and target after
+    /// check point passes them.  It can't be used with [DbSyncConf::with_apply_delay].
+    pub fn with_sampling(mut self, sampling: SamplingConfig) -> Self {
+        self.conf.sampling = Some(sampling);
+        self
+    }
+
+    /// read documents at the oplog start point with `readConcern: snapshot` in full sync, if source is mongodb
+    /// 5.0+, so target is consistent before incremental replay.  Default is false.
+    ///
+    /// The whole copy must finish within `minSnapshotHistoryWindowInSeconds` of source (300 seconds by
+    /// default), otherwise full sync fails with [SyncError::SnapshotTooOld](crate::SyncError::SnapshotTooOld),
+    /// raise the server parameter before enabling it for large databases.
+    pub fn with_snapshot_read(mut self, enabled: bool) -> Self {
+        self.conf.snapshot_read = enabled;
+        self
+    }
+
+    /// apply update oplogs with current full documents which are fetched from source in batches, instead of
+    /// replaying update operators.  It's ignored when oplogs are applied with delay.
+    pub fn with_full_document_updates(mut self, enabled: bool) -> Self {
+        self.conf.full_document_updates = enabled;
+        self
+    }
+
+    /// retry oplog batches which fail to apply in incremental sync by `retry`, default is 3 times.
+    pub fn with_apply_retry(mut self, retry: ApplyRetry) -> Self {
+        self.conf.apply_retry = retry;
+        self
+    }
+
+    /// set what to do with an oplog which still fails to apply after retries, default is
+    /// [Fail](ApplyFailurePolicy::Fail).
+    pub fn with_apply_failure_policy(mut self, policy: ApplyFailurePolicy) -> Self {
+        self.conf.apply_failure_policy = policy;
+        self
+    }
+
+    /// only sync documents which match `filter` in collection `coll`, and only sync fields selected by
+    /// `projection`.
+    ///
+    /// Full sync passes them to `find` command.  Incremental sync evaluates oplogs with an embedded matcher,
+    /// which only supports comparison, `$in`, `$nin`, `$exists`, `$not` and logical operators.  Documents
+    /// which stop matching are deleted from target.  `_id` is always synced, so it's ignored in `projection`.
+    pub fn with_coll_filter(
+        mut self,
+        coll: &str,
+        filter: Document,
+        projection: Option<Document>,
+    ) -> Self {
+        let projection = projection
+            .map(|mut p| {
+                p.remove("_id");
+                p
+            })
+            .filter(|p| !p.is_empty());
+        self.conf
+            .coll_filters
+            .insert(coll.to_string(), CollFilter { filter, projection });
+        self
+    }
+
+    /// sync collections which match `pattern` too, it's a regex if it's surrounded by `/`, or else a glob.
+    ///
+    /// When there is no include pattern and no collections given to constructor, all collections are synced.
+    pub fn with_coll_include(mut self, pattern: &str) -> Self {
+        self.conf.coll_selector = self.conf.coll_selector.with_include(pattern);
+        self
+    }
+
+    /// don't sync collections which match `pattern`, it's a regex if it's surrounded by `/`, or else a glob.
+    pub fn with_coll_exclude(mut self, pattern: &str) -> Self {
+        self.conf.coll_selector = self.conf.coll_selector.with_exclude(pattern);
+        self
+    }
+
+    /// sync into target databases and collections according to `mapping`, default names are kept.
+    pub fn with_namespace_mapping(mut self, mapping: NamespaceMapping) -> Self {
+        self.conf.namespace_mapping = mapping;
+        self
+    }
+
+    /// sync all databases selected by `selector` instead of one database, the database given to constructor
+    /// is ignored.
+    ///
+    /// All databases share one oplog reader, one checkpoint, and thread pools for full sync.  `colls` and
+    /// collection filters apply to every database.  Databases which are created later and selected by
+    /// `selector` are picked up during incremental sync.
+    pub fn with_dbs(mut self, selector: DbSelector) -> Self {
+        self.conf.dbs = Some(selector);
+        self
+    }
+
     /// get database to sync.
     pub fn get_db(&self) -> &str {
         &self.conf.db
     }
 
+    /// get selector of databases to sync.
+    ///
+    /// When return None, it indicates that only [get_db](DbSyncConf::get_db) is synced.
+    pub fn get_dbs(&self) -> Option<&DbSelector> {
+        self.conf.dbs.as_ref()
+    }
+
     /// get sync record collection name.
     pub fn get_record_collection(&self) -> &str {
         "oplog_records"
     }
 
     /// get oplog storage uri, which will save oplogs from source cluster.
-    pub fn get_oplog_storage_uri(&self) -> &str {
-        &self.oplog_storage.uri
+    ///
+    /// When return None, it indicates that the configuration is for one-shot copy.
+    pub fn get_oplog_storage_uri(&self) -> Option<&str> {
+        self.oplog_storage.as_ref().map(|s| s.uri.as_str())
     }
 
     /// get destination database uri.
@@ -130,4 +682,282 @@ impl DbSyncConf {
     pub fn get_colls(&self) -> &Option<Vec<String>> {
         &self.conf.colls
     }
+
+    /// get selector of collections to sync, which contains collections given to constructor and include and
+    /// exclude patterns.
+    pub fn get_coll_selector(&self) -> &CollSelector {
+        &self.conf.coll_selector
+    }
+
+    /// get how to write documents into target collections during full sync.
+    pub fn get_write_policy(&self) -> FullSyncWritePolicy {
+        self.conf.write_policy
+    }
+
+    /// return true if target collections can be dropped.
+    pub fn get_confirm_drop(&self) -> bool {
+        self.conf.confirm_drop
+    }
+
+    /// check that [FullSyncWritePolicy::Drop] is confirmed, returns [SyncError::DropNotConfirmed] if it isn't.
+    pub fn validate_write_policy(&self) -> crate::Result<()> {
+        if self.conf.write_policy == FullSyncWritePolicy::Drop && !self.conf.confirm_drop {
+            return Err(SyncError::DropNotConfirmed);
+        }
+        Ok(())
+    }
+
+    /// check that batch limits and memory budget are larger than 0, returns [SyncError::InvalidBufferLimit]
+    /// if they aren't.
+    pub fn validate_buffers(&self) -> crate::Result<()> {
+        self.conf.batch_limits.validate()?;
+        if self.conf.memory_budget == 0 {
+            return Err(SyncError::InvalidBufferLimit {
+                detail: "memory budget must be larger than 0".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// check that consistency sampling is not used with apply delay, returns [SyncError::SamplingWithApplyDelay]
+    /// if it is.
+    pub fn validate_sampling(&self) -> crate::Result<()> {
+        if self.conf.sampling.is_some() && self.conf.apply_delay.is_some() {
+            return Err(SyncError::SamplingWithApplyDelay);
+        }
+        Ok(())
+    }
+
+    /// get what to do with collections removed from sync set.
+    pub fn get_removed_coll_policy(&self) -> RemovedCollPolicy {
+        self.conf.removed_coll_policy
+    }
+
+    /// get what to do when check point falls outside the oplog window.
+    pub fn get_oplog_gap_policy(&self) -> OplogGapPolicy {
+        self.conf.oplog_gap_policy
+    }
+
+    /// get batch limits for full sync copy and oplog replay.
+    pub fn get_batch_limits(&self) -> BatchLimits {
+        self.conf.batch_limits
+    }
+
+    /// get max bytes used by all buffers.
+    pub fn get_memory_budget(&self) -> usize {
+        self.conf.memory_budget
+    }
+
+    /// get delay of applying oplogs, None means oplogs are applied as soon as possible.
+    pub fn get_apply_delay(&self) -> Option<Duration> {
+        self.conf.apply_delay
+    }
+
+    /// get options of consistency sampling, None means it's disabled.
+    pub fn get_sampling(&self) -> Option<SamplingConfig> {
+        self.conf.sampling
+    }
+
+    /// return true if full sync reads documents at one cluster time when source supports it.
+    pub fn get_snapshot_read(&self) -> bool {
+        self.conf.snapshot_read
+    }
+
+    /// return true if updates are applied with full documents fetched from source.
+    pub fn get_full_document_updates(&self) -> bool {
+        self.conf.full_document_updates
+    }
+
+    /// get retries of oplog batches which fail to apply.
+    pub fn get_apply_retry(&self) -> ApplyRetry {
+        self.conf.apply_retry
+    }
+
+    /// get what to do with an oplog which still fails to apply after retries.
+    pub fn get_apply_failure_policy(&self) -> ApplyFailurePolicy {
+        self.conf.apply_failure_policy
+    }
+
+    /// get document filter and projection of collection `coll`.
+    pub fn get_coll_filter(&self, coll: &str) -> Option<&CollFilter> {
+        self.conf.coll_filters.get(coll)
+    }
+
+    /// get how source namespaces are mapped to target namespaces.
+    pub fn get_namespace_mapping(&self) -> &NamespaceMapping {
+        &self.conf.namespace_mapping
+    }
+
+    /// get document filter and projection of all collections, keyed by collection name.
+    pub fn get_coll_filters(&self) -> &HashMap<String, CollFilter> {
+        &self.conf.coll_filters
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_write_policy() {
+        assert_eq!("drop".parse(), Ok(FullSyncWritePolicy::Drop));
+        assert_eq!("merge".parse(), Ok(FullSyncWritePolicy::Merge));
+        assert_eq!(
+            "fail-if-non-empty".parse(),
+            Ok(FullSyncWritePolicy::FailIfNonEmpty)
+        );
+        assert!("overwrite".parse::<FullSyncWritePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_validate_write_policy() {
+        let conf = DbSyncConf::new_oneshot(
+            String::new(),
+            String::new(),
+            String::new(),
+            None,
+            None,
+            None,
+        );
+        assert!(conf.validate_write_policy().is_ok());
+        let conf = conf.with_write_policy(FullSyncWritePolicy::Drop);
+        assert!(matches!(
+            conf.validate_write_policy(),
+            Err(SyncError::DropNotConfirmed)
+        ));
+        let conf = conf.with_confirm_drop(true);
+        assert!(conf.validate_write_policy().is_ok());
+    }
+
+    #[test]
+    fn test_validate_buffers() {
+        assert!(BatchLimits::new(1, 1).is_ok());
+        assert!(matches!(
+            BatchLimits::new(0, 1024),
+            Err(SyncError::InvalidBufferLimit { .. })
+        ));
+        assert!(matches!(
+            BatchLimits::new(100, 0),
+            Err(SyncError::InvalidBufferLimit { .. })
+        ));
+
+        let conf = DbSyncConf::new_oneshot(
+            String::new(),
+            String::new(),
+            String::new(),
+            None,
+            None,
+            None,
+        );
+        assert!(conf.validate_buffers().is_ok());
+        let conf = conf.with_batch_limits(BatchLimits {
+            max_count: 0,
+            max_bytes: 1024,
+        });
+        assert!(matches!(
+            conf.validate_buffers(),
+            Err(SyncError::InvalidBufferLimit { .. })
+        ));
+        let conf = conf
+            .with_batch_limits(BatchLimits::default())
+            .with_memory_budget(0);
+        assert!(matches!(
+            conf.validate_buffers(),
+            Err(SyncError::InvalidBufferLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_validate_sampling() {
+        let conf = DbSyncConf::new_oneshot(
+            String::new(),
+            String::new(),
+            String::new(),
+            None,
+            None,
+            None,
+        )
+        .with_sampling(SamplingConfig::default());
+        assert!(conf.validate_sampling().is_ok());
+        let conf = conf.with_apply_delay(Duration::from_secs(3600));
+        assert!(matches!(
+            conf.validate_sampling(),
+            Err(SyncError::SamplingWithApplyDelay)
+        ));
+    }
+
+    #[test]
+    fn test_parse_removed_coll_policy() {
+        for policy in [
+            RemovedCollPolicy::Keep,
+            RemovedCollPolicy::Drop,
+            RemovedCollPolicy::Refuse,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("ignore".parse::<RemovedCollPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_oplog_gap_policy() {
+        for policy in [
+            OplogGapPolicy::Fail,
+            OplogGapPolicy::Resync,
+            OplogGapPolicy::ResyncAffected,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("ignore".parse::<OplogGapPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_apply_failure_policy() {
+        for policy in [ApplyFailurePolicy::Fail, ApplyFailurePolicy::DeadLetter] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("skip".parse::<ApplyFailurePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy =
+            RetryPolicy::new(None).with_backoff(Duration::from_secs(2), Duration::from_secs(30));
+        assert_eq!(policy.backoff_of(1), Duration::from_secs(2));
+        assert_eq!(policy.backoff_of(3), Duration::from_secs(8));
+        assert_eq!(policy.backoff_of(5), Duration::from_secs(30));
+        assert_eq!(policy.backoff_of(100), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_apply_retry_backoff() {
+        let retry = ApplyRetry::new(3, Duration::from_secs(1));
+        assert_eq!(retry.backoff_of(0), Duration::from_secs(1));
+        assert_eq!(retry.backoff_of(2), Duration::from_secs(4));
+        assert_eq!(retry.backoff_of(10), Duration::from_secs(60));
+        assert_eq!(retry.backoff_of(40), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_coll_filter_ignores_id_projection() {
+        let conf = DbSyncConf::new_oneshot(
+            "src".to_string(),
+            "dst".to_string(),
+            "db".to_string(),
+            None,
+            Some(1),
+            Some(1),
+        )
+        .with_coll_filter(
+            "a",
+            bson::doc! {"tenant": "acme"},
+            Some(bson::doc! {"_id": 0, "blob": 0}),
+        )
+        .with_coll_filter("b", bson::doc! {}, Some(bson::doc! {"_id": 1}));
+        assert_eq!(
+            conf.get_coll_filter("a").unwrap().projection,
+            Some(bson::doc! {"blob": 0})
+        );
+        assert_eq!(conf.get_coll_filter("b").unwrap().projection, None);
+        assert!(conf.get_coll_filter("c").is_none());
+    }
 }
@@ -1,9 +1,9 @@
 #![allow(missing_docs)]
 
 use bson::document::ValueAccessError;
-use bson::Document;
+use bson::{Document, Timestamp};
 use crossbeam::channel::RecvError;
-use mongodb::error::Error as MongoError;
+use mongodb::error::{Error as MongoError, ErrorKind as MongoErrorKind};
 use std::backtrace::Backtrace;
 use std::result::Result as StdResult;
 use thiserror::Error;
@@ -40,6 +40,90 @@ pub enum SyncError {
     EmptyDocError,
     #[error("apply oplogs error")]
     ApplyOplogError(Document),
+    #[error("Snapshot read at {at:?} failed, because the snapshot history window is exceeded, consider increasing `minSnapshotHistoryWindowInSeconds` on source, detailed: {detail:?}")]
+    SnapshotTooOld { at: Timestamp, detail: String },
+    #[error("Oplog storage is not configured, which is required by incremental sync")]
+    OplogStorageMissing,
+    #[error("Source and target are the same cluster (common servers: {servers:?}), refuse to sync database {db:?} onto itself")]
+    SameSourceAndTarget { servers: Vec<String>, db: String },
+    #[error("Target collections {colls:?} are not empty, full sync refuses to write into them under the default `--write-policy fail-if-non-empty`, use `--write-policy merge` to keep target documents and replace the ones with the same `_id`, or `--write-policy drop --confirm-drop` to drop them first")]
+    TargetNotEmpty { colls: Vec<String> },
+    #[error("Write policy `drop` removes data in target collections, it must be confirmed")]
+    DropNotConfirmed,
+    #[error("Invalid buffer limit: {detail}")]
+    InvalidBufferLimit { detail: String },
+    #[error("Consistency sampling can't be used with apply delay, a delayed target always differs from current source")]
+    SamplingWithApplyDelay,
+    #[error("Invalid collection filter or projection: {detail}")]
+    InvalidCollFilter { detail: String },
+    #[error("Invalid namespace mapping rule {detail}")]
+    InvalidNamespaceMapping { detail: String },
+    #[error("Invalid collection pattern {pattern:?}: {detail}")]
+    InvalidCollPattern { pattern: String, detail: String },
+    #[error("Collections {colls:?} of database {db:?} are removed from sync set, use `keep` or `drop` removed collection policy to sync without them")]
+    CollsRemovedFromSyncSet { db: String, colls: Vec<String> },
+    #[error("Invalid control command: {detail}")]
+    InvalidControlCommand { detail: String },
+    #[error("Invalid restore point {detail}")]
+    InvalidRestorePoint { detail: String },
+    #[error("Restore point {until:?} is before base {base:?}, which can't be rewound")]
+    RestorePointBeforeBase { until: Timestamp, base: Timestamp },
+    #[error("Oplogs after {start:?} are not in oplog storage any more, the earliest stored oplog is at {earliest:?}, about {} seconds of oplogs are lost", .earliest.time.saturating_sub(.start.time))]
+    OplogWindowExceeded {
+        start: Timestamp,
+        earliest: Timestamp,
+    },
+    #[error("Read dump file {path:?} failed: {detail}")]
+    DumpReadError { path: String, detail: String },
+    #[error("Invalid document to repair {detail}")]
+    InvalidRepairDoc { detail: String },
+    #[error("Repair documents failed: {detail}")]
+    RepairFailed { detail: String },
+    #[error("Sync is cancelled")]
+    Cancelled,
+}
+
+impl SyncError {
+    /// return true if the error may go away when the operation is run again, e.g: network errors, primary
+    /// step down.  Other errors are fatal, running again doesn't help until something is changed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SyncError::MongoError { source, .. } => is_transient_mongo_error(source),
+            SyncError::PermissionError { detail, .. } => is_transient_mongo_error(detail),
+            _ => false,
+        }
+    }
+}
+
+/// server error codes which go away after a while, they are retryable errors of mongodb drivers.
+const TRANSIENT_ERROR_CODES: [i32; 13] = [
+    6,     // HostUnreachable
+    7,     // HostNotFound
+    89,    // NetworkTimeout
+    91,    // ShutdownInProgress
+    189,   // PrimarySteppedDown
+    262,   // ExceededTimeLimit
+    9001,  // SocketException
+    10107, // NotWritablePrimary
+    11600, // InterruptedAtShutdown
+    11602, // InterruptedDueToReplStateChange
+    13435, // NotPrimaryNoSecondaryOk
+    13436, // NotPrimaryOrSecondary
+    134,   // ReadConcernMajorityNotAvailableYet
+];
+
+fn is_transient_mongo_error(e: &MongoError) -> bool {
+    if e.contains_label("RetryableWriteError") || e.contains_label("TransientTransactionError") {
+        return true;
+    }
+    match e.kind.as_ref() {
+        MongoErrorKind::Io(_)
+        | MongoErrorKind::ServerSelection { .. }
+        | MongoErrorKind::ConnectionPoolCleared { .. }
+        | MongoErrorKind::DnsResolve { .. } => true,
+        MongoErrorKind::Command(e) => TRANSIENT_ERROR_CODES.contains(&e.code),
+        _ => false,
+    }
 }
 
 pub type Result<T> = StdResult<T, SyncError>;
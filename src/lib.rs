@@ -21,6 +21,31 @@
 //! syncer.sync();
 //! ```
 //!
+//! # Full sync progress example:
+//! ```no_run
+//! use mongo_sync::{DbSyncConf, MongoSyncer};
+//!
+//! let conf = DbSyncConf::new_oneshot("mongodb://localhost:27017".to_string(), "mongodb://localhost:27018".to_string(), "a".to_string(), None, None, None);
+//! let syncer = MongoSyncer::new(&conf);
+//! let progress = syncer.progress();
+//! std::thread::spawn(move || loop {
+//!     let snapshot = progress.snapshot();
+//!     println!("copied {} docs, eta {:?}", snapshot.total().docs, snapshot.eta());
+//!     std::thread::sleep(std::time::Duration::from_secs(10));
+//! });
+//! syncer.sync_once().unwrap();
+//! ```
+//!
+//! # One-shot copy example:
+//! ```no_run
+//! use mongo_sync::{DbSyncConf, MongoSyncer};
+//!
+//! let conf = DbSyncConf::new_oneshot("mongodb://localhost:27017".to_string(), "mongodb://localhost:27018".to_string(), "a".to_string(), None, None, None);
+//! let syncer = MongoSyncer::new(&conf);
+//! let report = syncer.sync_once().unwrap();
+//! println!("{}", report);
+//! ```
+//!
 //! # OplogCleaner example:
 //! ```no_run
 //! use mongo_sync::OplogCleaner;
@@ -36,11 +61,14 @@ pub mod blocking;
 pub mod cmd_oplog;
 mod config;
 mod error;
+mod namespace;
 
 /// mongodb internal database for admin.
 const ADMIN_DB_NAME: &str = "admin";
 /// mongodb internal database which saves oplogs.
 const OPLOG_DB: &str = "local";
+/// mongodb internal database which saves sharding metadata and sessions.
+const CONFIG_DB_NAME: &str = "config";
 /// mongodb internal collection which saves oplogs.
 const OPLOG_COLL: &str = "oplog.rs";
 
@@ -48,6 +76,8 @@ const OPLOG_COLL: &str = "oplog.rs";
 const LOG_STORAGE_DB: &str = "source_oplog";
 /// local oplog storage collection name.
 const LOG_STORAGE_COLL: &str = "source_oplog";
+/// target database which saves sync state when multiple databases are synced.
+const SYNC_META_DB: &str = "mongo_sync";
 
 /// oplog namespace key name.
 const NAMESPACE_KEY: &str = "ns";
@@ -60,6 +90,15 @@ const NOOP_OP: &str = "n";
 /// command operation.
 const COMMAND_OP: &str = "c";
 
-pub use blocking::{Connection, MongoSyncer, OplogCleaner, OplogSyncer};
-pub use config::{DbSyncConf, OplogSyncerConfig};
+pub use blocking::{
+    cancel_on_signals, CancelToken, CollProgress, CollSyncReport, CollVerifyReport, Connection,
+    FullSyncProgress, MongoSyncer, OplogCleaner, OplogSyncer, ProgressLogger, ProgressSnapshot,
+    RangeProgress, RepairDoc, RestoreBase, RestoreOptions, RestoreReport, RetryCounts, RetryStats,
+    Supervisor, SyncReport, VerifyReport,
+};
+pub use config::{
+    ApplyFailurePolicy, ApplyRetry, BatchLimits, CollFilter, DbSyncConf, FullSyncWritePolicy,
+    OplogGapPolicy, OplogSyncerConfig, RemovedCollPolicy, RetryPolicy, SamplingConfig,
+};
 pub use error::{Result, SyncError};
+pub use namespace::{CollSelector, DbSelector, NamespaceMapping};
@@ -0,0 +1,675 @@
+//! Provide namespace mapping, which syncs source databases and collections into differently named ones, and
+//! database and collection selectors, which select databases and collections to sync.
+
+use regex::Regex;
+
+use crate::{
+    Result, SyncError, ADMIN_DB_NAME, CONFIG_DB_NAME, LOG_STORAGE_DB, OPLOG_DB, SYNC_META_DB,
+};
+
+/// databases which are never synced, they are mongodb internal databases, or are used by syncer itself.
+const RESERVED_DBS: [&str; 5] = [
+    ADMIN_DB_NAME,
+    OPLOG_DB,
+    CONFIG_DB_NAME,
+    LOG_STORAGE_DB,
+    SYNC_META_DB,
+];
+
+/// A rule which maps source name `from` to target name `to`.
+///
+/// `*` in `from` matches any characters, and each `*` in `to` is replaced by the characters matched by the
+/// `*` in `from` at the same position.
+#[derive(Debug, Clone, PartialEq)]
+struct MappingRule {
+    from: String,
+    to: String,
+}
+
+impl MappingRule {
+    fn is_pattern(&self) -> bool {
+        self.from.contains('*')
+    }
+
+    fn apply(&self, name: &str) -> Option<String> {
+        let captures = wildcard_captures(&self.from, name)?;
+        let mut result = String::with_capacity(self.to.len());
+        for (idx, part) in self.to.split('*').enumerate() {
+            if idx > 0 {
+                result.push_str(captures.get(idx - 1).copied().unwrap_or_default());
+            }
+            result.push_str(part);
+        }
+        Some(result)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.from.is_empty() || self.to.is_empty() {
+            return Err(invalid_mapping(self, "names can't be empty"));
+        }
+        if self.to.matches('*').count() > self.from.matches('*').count() {
+            return Err(invalid_mapping(
+                self,
+                "target contains more `*` than source",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn invalid_mapping(rule: &MappingRule, reason: &str) -> SyncError {
+    SyncError::InvalidNamespaceMapping {
+        detail: format!("{} => {}: {}", rule.from, rule.to, reason),
+    }
+}
+
+/// Match `name` against `pattern` which contains `*` wildcards, returns characters matched by each `*`.
+///
+/// Each `*` except the last one matches as few characters as possible.
+fn wildcard_captures<'a>(pattern: &str, name: &'a str) -> Option<Vec<&'a str>> {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return if pattern == name { Some(vec![]) } else { None };
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if name.len() < first.len() + last.len() || !name.starts_with(first) || !name.ends_with(last) {
+        return None;
+    }
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    let mut captures = Vec::with_capacity(parts.len() - 1);
+    for part in &parts[1..parts.len() - 1] {
+        let pos = rest.find(part)?;
+        captures.push(&rest[..pos]);
+        rest = &rest[pos + part.len()..];
+    }
+    captures.push(rest);
+    Some(captures)
+}
+
+/// find the first rule in `rules` which matches `name`, exact rules take precedence over pattern rules.
+fn apply_rules(rules: &[MappingRule], name: &str) -> Option<String> {
+    rules
+        .iter()
+        .filter(|r| !r.is_pattern())
+        .chain(rules.iter().filter(|r| r.is_pattern()))
+        .find_map(|r| r.apply(name))
+}
+
+/// Namespace mapping from source to target.
+///
+/// Collection rules map full namespace `db.coll`, and database rules map database name.  A namespace is mapped
+/// by the first matched collection rule, if no collection rule matches, its database is mapped by the first
+/// matched database rule and collection name is kept.  Exact rules take precedence over pattern rules, and
+/// names which don't match any rule are kept unchanged.
+///
+/// # Example
+/// ```
+/// use mongo_sync::NamespaceMapping;
+///
+/// let mapping = NamespaceMapping::new()
+///     .with_db("prod", "prod_mirror")
+///     .with_coll("prod.orders", "prod_mirror.orders_v2")
+///     .with_coll("prod.log_*", "prod_mirror.archive_log_*");
+/// assert_eq!(mapping.target_db("prod"), "prod_mirror");
+/// assert_eq!(mapping.target_ns("prod", "orders"), ("prod_mirror".to_string(), "orders_v2".to_string()));
+/// assert_eq!(mapping.target_ns("prod", "log_2021"), ("prod_mirror".to_string(), "archive_log_2021".to_string()));
+/// assert_eq!(mapping.target_ns("prod", "users"), ("prod_mirror".to_string(), "users".to_string()));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NamespaceMapping {
+    db_rules: Vec<MappingRule>,
+    coll_rules: Vec<MappingRule>,
+}
+
+impl NamespaceMapping {
+    /// create an empty mapping, which keeps all names unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// map database `from` to `to`, `*` can be used as wildcard, e.g: `*` to `*_mirror`.
+    pub fn with_db(mut self, from: &str, to: &str) -> Self {
+        self.db_rules.push(MappingRule {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        self
+    }
+
+    /// map collection namespace `from` to `to`, both are full namespace `db.coll`.
+    ///
+    /// `*` can be used as wildcard in collection name, e.g: `prod.log_*` to `prod_mirror.archive_log_*`, but
+    /// database names must be exact.
+    pub fn with_coll(mut self, from: &str, to: &str) -> Self {
+        self.coll_rules.push(MappingRule {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        self
+    }
+
+    /// returns true if the mapping doesn't contain any rule.
+    pub fn is_empty(&self) -> bool {
+        self.db_rules.is_empty() && self.coll_rules.is_empty()
+    }
+
+    /// check that all rules are valid, returns [SyncError::InvalidNamespaceMapping] if not.
+    pub fn validate(&self) -> Result<()> {
+        for rule in self.db_rules.iter() {
+            rule.validate()?;
+            if rule.to.contains('.') {
+                return Err(invalid_mapping(rule, "database name can't contain `.`"));
+            }
+        }
+        for rule in self.coll_rules.iter() {
+            rule.validate()?;
+            for ns in [&rule.from, &rule.to] {
+                match ns.split_once('.') {
+                    Some((db, coll)) if !db.is_empty() && !coll.is_empty() => {
+                        if db.contains('*') {
+                            return Err(invalid_mapping(
+                                rule,
+                                "database name of collection rule can't contain `*`",
+                            ));
+                        }
+                    }
+                    _ => {
+                        return Err(invalid_mapping(
+                            rule,
+                            "collection rule should be full namespace `db.coll`",
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// get target database name of source database `db`.
+    pub fn target_db(&self, db: &str) -> String {
+        apply_rules(&self.db_rules, db).unwrap_or_else(|| db.to_string())
+    }
+
+    /// get target database and collection name of source collection `coll` in database `db`.
+    pub fn target_ns(&self, db: &str, coll: &str) -> (String, String) {
+        match apply_rules(&self.coll_rules, &format!("{}.{}", db, coll)) {
+            // rules are validated, so the mapped namespace always contains `.`.
+            Some(ns) => match ns.split_once('.') {
+                Some((db, coll)) => (db.to_string(), coll.to_string()),
+                None => (self.target_db(db), ns),
+            },
+            None => (self.target_db(db), coll.to_string()),
+        }
+    }
+
+    /// returns true if collection `coll` in database `db` may be written by the mapping.
+    ///
+    /// It's used to ignore changes made by syncer itself, when source and target are the same cluster.
+    pub fn is_target(&self, db: &str, coll: &str) -> bool {
+        let ns = format!("{}.{}", db, coll);
+        self.coll_rules
+            .iter()
+            .any(|r| wildcard_captures(&r.to, &ns).is_some())
+            || self.is_target_db(db)
+    }
+
+    /// returns true if database `db` may be written by database rules of the mapping.
+    pub fn is_target_db(&self, db: &str) -> bool {
+        self.db_rules
+            .iter()
+            .any(|r| wildcard_captures(&r.to, db).is_some())
+    }
+}
+
+/// Databases to sync, which are selected by include and exclude patterns.
+///
+/// `*` in patterns matches any characters.  A database is selected if it matches any include pattern, and
+/// doesn't match any exclude pattern.  Internal databases `admin`, `local`, `config`, and databases used by
+/// syncer itself are never selected.
+///
+/// # Example
+/// ```
+/// use mongo_sync::DbSelector;
+///
+/// let selector = DbSelector::new().with_include("app_*").with_exclude("app_test");
+/// assert!(selector.matches("app_orders"));
+/// assert!(!selector.matches("app_test"));
+/// assert!(!selector.matches("billing"));
+///
+/// let selector = DbSelector::all();
+/// assert!(selector.matches("billing"));
+/// assert!(!selector.matches("admin"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DbSelector {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl DbSelector {
+    /// create an empty selector, which selects nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// create a selector which selects all user databases.
+    pub fn all() -> Self {
+        Self::new().with_include("*")
+    }
+
+    /// select databases which match `pattern`, it can be a database name.
+    pub fn with_include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    /// don't select databases which match `pattern`, even if they match include patterns.
+    pub fn with_exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// returns true if database `db` is selected.
+    pub fn matches(&self, db: &str) -> bool {
+        !RESERVED_DBS.contains(&db)
+            && self
+                .include
+                .iter()
+                .any(|p| wildcard_captures(p, db).is_some())
+            && !self
+                .exclude
+                .iter()
+                .any(|p| wildcard_captures(p, db).is_some())
+    }
+}
+
+/// A collection name pattern, it's a regex if it's surrounded by `/`, or else a glob which `*` matches any
+/// characters.
+#[derive(Debug, Clone)]
+struct NamePattern {
+    source: String,
+    /// compiled regex, it's None for glob, or for invalid regex which is reported by validation.
+    regex: Option<Regex>,
+}
+
+impl NamePattern {
+    fn new(source: &str) -> Self {
+        NamePattern {
+            source: source.to_string(),
+            regex: regex_source(source).and_then(|r| Regex::new(r).ok()),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match (&self.regex, regex_source(&self.source)) {
+            (Some(regex), _) => regex.is_match(name),
+            (None, Some(_)) => false,
+            (None, None) => wildcard_captures(&self.source, name).is_some(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match regex_source(&self.source) {
+            Some(regex) => {
+                Regex::new(regex)
+                    .map(|_| ())
+                    .map_err(|e| SyncError::InvalidCollPattern {
+                        pattern: self.source.clone(),
+                        detail: e.to_string(),
+                    })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl PartialEq for NamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+/// get regex inside `/.../`, returns None if `pattern` is a glob.
+fn regex_source(pattern: &str) -> Option<&str> {
+    pattern
+        .strip_prefix('/')
+        .and_then(|p| p.strip_suffix('/'))
+        .filter(|p| !p.is_empty())
+}
+
+/// Collections to sync, which are selected by include and exclude patterns.
+///
+/// A pattern surrounded by `/` is a regex, e.g: `/^log_\d+$/`, other patterns are globs which `*` matches any
+/// characters.  A collection is selected if it matches any include pattern (or there is no include pattern),
+/// and doesn't match any exclude pattern.  `system.*` collections are excluded by default, unless they're
+/// included by exact name.  Views are never synced.
+///
+/// Collections given by [from_names](CollSelector::from_names), e.g: `--colls`, are exact names, `*` and `/`
+/// in them are literal.  Collections can also be added or removed by exact name, e.g: at runtime, which takes
+/// precedence over patterns.
+///
+/// # Example
+/// ```
+/// use mongo_sync::CollSelector;
+///
+/// let selector = CollSelector::new()
+///     .with_include("orders")
+///     .with_include("/^log_\\d+$/")
+///     .with_exclude("log_0");
+/// assert!(selector.matches("orders"));
+/// assert!(selector.matches("log_2021"));
+/// assert!(!selector.matches("log_0"));
+/// assert!(!selector.matches("log_tmp"));
+///
+/// let selector = CollSelector::new().with_exclude("tmp_*");
+/// assert!(selector.matches("users"));
+/// assert!(!selector.matches("tmp_users"));
+/// assert!(!selector.matches("system.profile"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CollSelector {
+    names: Vec<String>,
+    include: Vec<NamePattern>,
+    exclude: Vec<NamePattern>,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl CollSelector {
+    /// create a selector which selects all collections except `system.*` collections.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// create a selector which only selects collections `names`, they're exact names rather than patterns.
+    pub fn from_names(names: &[String]) -> Self {
+        names
+            .iter()
+            .fold(Self::new(), |selector, name| selector.with_name(name))
+    }
+
+    /// select collection `coll` by exact name, it's excluded by exclude patterns like include patterns.
+    pub fn with_name(mut self, coll: &str) -> Self {
+        self.names.push(coll.to_string());
+        self
+    }
+
+    /// select collections which match `pattern`.
+    pub fn with_include(mut self, pattern: &str) -> Self {
+        self.include.push(NamePattern::new(pattern));
+        self
+    }
+
+    /// don't select collections which match `pattern`, even if they match include patterns.
+    pub fn with_exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(NamePattern::new(pattern));
+        self
+    }
+
+    /// select collection `coll` whatever patterns are.
+    pub fn with_added(mut self, coll: &str) -> Self {
+        self.removed.retain(|c| c != coll);
+        if !self.added.iter().any(|c| c == coll) {
+            self.added.push(coll.to_string());
+        }
+        self
+    }
+
+    /// don't select collection `coll` whatever patterns are.
+    pub fn with_removed(mut self, coll: &str) -> Self {
+        self.added.retain(|c| c != coll);
+        if !self.removed.iter().any(|c| c == coll) {
+            self.removed.push(coll.to_string());
+        }
+        self
+    }
+
+    /// get collections which are selected by exact name.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// get include patterns.
+    pub fn includes(&self) -> Vec<&str> {
+        self.include.iter().map(|p| p.source.as_str()).collect()
+    }
+
+    /// get exclude patterns.
+    pub fn excludes(&self) -> Vec<&str> {
+        self.exclude.iter().map(|p| p.source.as_str()).collect()
+    }
+
+    /// get collections which are added by exact name.
+    pub fn added(&self) -> &[String] {
+        &self.added
+    }
+
+    /// get collections which are removed by exact name.
+    pub fn removed(&self) -> &[String] {
+        &self.removed
+    }
+
+    /// keep collections added or removed in `recorded`, if it selects by the same names and patterns as this
+    /// selector, so runtime changes survive a restart with the same collection options.
+    pub fn with_runtime_changes(self, recorded: &CollSelector) -> Self {
+        if self.names != recorded.names
+            || self.include != recorded.include
+            || self.exclude != recorded.exclude
+        {
+            return self;
+        }
+        let selector = recorded
+            .added
+            .iter()
+            .fold(self, |selector, coll| selector.with_added(coll));
+        recorded
+            .removed
+            .iter()
+            .fold(selector, |selector, coll| selector.with_removed(coll))
+    }
+
+    /// check that all regex patterns are valid, returns [SyncError::InvalidCollPattern] if not.
+    pub fn validate(&self) -> Result<()> {
+        for pattern in self.include.iter().chain(self.exclude.iter()) {
+            pattern.validate()?;
+        }
+        Ok(())
+    }
+
+    /// returns true if collection `coll` is selected.
+    pub fn matches(&self, coll: &str) -> bool {
+        if self.removed.iter().any(|c| c == coll) {
+            return false;
+        }
+        if self.added.iter().any(|c| c == coll) {
+            return true;
+        }
+        if self.exclude.iter().any(|p| p.matches(coll)) {
+            return false;
+        }
+        if self.names.iter().any(|c| c == coll) {
+            return true;
+        }
+        if coll.starts_with("system.") {
+            return self.include.iter().any(|p| p.source == coll);
+        }
+        (self.include.is_empty() && self.names.is_empty())
+            || self.include.iter().any(|p| p.matches(coll))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_captures() {
+        assert_eq!(wildcard_captures("a", "a"), Some(vec![]));
+        assert_eq!(wildcard_captures("a", "b"), None);
+        assert_eq!(wildcard_captures("*", "abc"), Some(vec!["abc"]));
+        assert_eq!(
+            wildcard_captures("log_*_*", "log_2021_10"),
+            Some(vec!["2021", "10"])
+        );
+        assert_eq!(wildcard_captures("db.*", "db.a.b"), Some(vec!["a.b"]));
+        assert_eq!(wildcard_captures("ab*ba", "aba"), None);
+    }
+
+    #[test]
+    fn test_mapping_precedence() {
+        let mapping = NamespaceMapping::new()
+            .with_db("*", "*_mirror")
+            .with_db("prod", "prod_copy")
+            .with_coll("prod.*", "other.*")
+            .with_coll("prod.users", "prod_copy.people");
+        assert!(mapping.validate().is_ok());
+        assert_eq!(mapping.target_db("prod"), "prod_copy");
+        assert_eq!(mapping.target_db("dev"), "dev_mirror");
+        assert_eq!(
+            mapping.target_ns("prod", "users"),
+            ("prod_copy".to_string(), "people".to_string())
+        );
+        assert_eq!(
+            mapping.target_ns("prod", "orders"),
+            ("other".to_string(), "orders".to_string())
+        );
+        assert_eq!(
+            mapping.target_ns("dev", "orders"),
+            ("dev_mirror".to_string(), "orders".to_string())
+        );
+        assert!(NamespaceMapping::new().target_ns("a", "b") == ("a".to_string(), "b".to_string()));
+    }
+
+    #[test]
+    fn test_mapping_validate_and_is_target() {
+        assert!(NamespaceMapping::new()
+            .with_db("a", "b.c")
+            .validate()
+            .is_err());
+        assert!(NamespaceMapping::new()
+            .with_db("a", "*")
+            .validate()
+            .is_err());
+        assert!(NamespaceMapping::new()
+            .with_coll("a", "b.c")
+            .validate()
+            .is_err());
+        assert!(NamespaceMapping::new()
+            .with_coll("*.a", "b.a")
+            .validate()
+            .is_err());
+
+        let mapping = NamespaceMapping::new()
+            .with_db("prod", "prod_mirror")
+            .with_coll("prod.a", "prod.b");
+        assert!(mapping.is_target("prod", "b"));
+        assert!(mapping.is_target("prod_mirror", "a"));
+        assert!(!mapping.is_target("prod", "a"));
+        assert!(mapping.is_target_db("prod_mirror"));
+        assert!(!mapping.is_target_db("prod"));
+    }
+
+    #[test]
+    fn test_db_selector() {
+        let selector = DbSelector::new()
+            .with_include("shop")
+            .with_include("tenant_*")
+            .with_exclude("*_tmp");
+        assert!(selector.matches("shop"));
+        assert!(selector.matches("tenant_a"));
+        assert!(!selector.matches("tenant_a_tmp"));
+        assert!(!selector.matches("shop2"));
+        assert!(!DbSelector::new().matches("shop"));
+        for db in ["admin", "local", "config", "source_oplog", "mongo_sync"] {
+            assert!(!DbSelector::all().matches(db));
+        }
+    }
+
+    #[test]
+    fn test_coll_selector() {
+        let selector = CollSelector::from_names(&["a".to_string(), "system.js".to_string()])
+            .with_include("log_*")
+            .with_include("/^t[0-9]+$/")
+            .with_exclude("log_tmp");
+        assert!(selector.validate().is_ok());
+        assert!(selector.matches("a"));
+        assert!(selector.matches("log_1"));
+        assert!(selector.matches("t12"));
+        assert!(selector.matches("system.js"));
+        assert!(!selector.matches("log_tmp"));
+        assert!(!selector.matches("t1x"));
+        assert!(!selector.matches("b"));
+
+        let all = CollSelector::new();
+        assert!(all.matches("b"));
+        assert!(!all.matches("system.profile"));
+        assert!(!all.matches("system.js"));
+        assert!(!CollSelector::new()
+            .with_include("system.*")
+            .matches("system.js"));
+
+        let invalid = CollSelector::new().with_exclude("/(/");
+        assert!(invalid.validate().is_err());
+        assert!(invalid.matches("("));
+        // `/` alone or `//` are globs.
+        assert!(CollSelector::new().with_include("//").matches("//"));
+    }
+
+    #[test]
+    fn test_coll_selector_exact_names() {
+        let selector = CollSelector::from_names(&["log_*".to_string(), "/a/".to_string()]);
+        assert!(selector.validate().is_ok());
+        assert!(selector.matches("log_*"));
+        assert!(selector.matches("/a/"));
+        assert!(!selector.matches("log_1"));
+        assert!(!selector.matches("a"));
+        assert!(selector.includes().is_empty());
+        assert_eq!(selector.names(), &["log_*", "/a/"]);
+
+        let selector = selector.with_exclude("log_*");
+        assert!(!selector.matches("log_*"));
+    }
+
+    #[test]
+    fn test_coll_selector_added_and_removed() {
+        let selector = CollSelector::new()
+            .with_include("log_*")
+            .with_exclude("log_tmp")
+            .with_added("log_tmp")
+            .with_added("users")
+            .with_removed("log_1");
+        assert!(selector.matches("log_tmp"));
+        assert!(selector.matches("users"));
+        assert!(!selector.matches("log_1"));
+        assert!(selector.matches("log_2"));
+
+        // the latest change wins.
+        let selector = selector.with_removed("users").with_added("log_1");
+        assert!(!selector.matches("users"));
+        assert!(selector.matches("log_1"));
+        assert_eq!(selector.added(), &["log_tmp", "log_1"]);
+        assert_eq!(selector.removed(), &["users"]);
+    }
+
+    #[test]
+    fn test_coll_selector_with_runtime_changes() {
+        let recorded = CollSelector::new()
+            .with_include("log_*")
+            .with_added("users")
+            .with_removed("log_1");
+        let selector = CollSelector::new()
+            .with_include("log_*")
+            .with_runtime_changes(&recorded);
+        assert_eq!(selector, recorded);
+        assert!(selector.matches("users"));
+        assert!(!selector.matches("log_1"));
+
+        // changes are dropped when patterns are changed.
+        let selector = CollSelector::new()
+            .with_include("log_2*")
+            .with_runtime_changes(&recorded);
+        assert!(selector.added().is_empty());
+        assert!(selector.removed().is_empty());
+        assert!(selector.matches("log_1"));
+    }
+}
@@ -1,5 +1,8 @@
-use bson::{doc, oid::ObjectId, Document};
-use mongo_sync::blocking::mongo_syncer::oplog_bulk::execute_normal_oplogs;
+use bson::{doc, oid::ObjectId, Bson, Document};
+use mongo_sync::blocking::mongo_syncer::oplog_bulk::{
+    execute_normal_oplogs, execute_normal_oplogs_with_limits, execute_normal_oplogs_with_mapping,
+};
+use mongo_sync::{BatchLimits, NamespaceMapping};
 use mongodb::sync::Client;
 
 struct Context {
@@ -223,10 +226,13 @@ fn test_execute_normal_oplogs_update_op_with_no_exists_record() {
     let mut oplogs = vec![
         doc! {"op": "u", "ns": "syncer_test.test_coll", "o2": {"_id": test_id}, "o": {"$v": 1, "$set": {"d": 3}}},
     ];
-    execute_normal_oplogs(&mut oplogs, client).unwrap();
+    let unmatched = execute_normal_oplogs(&mut oplogs, client).unwrap();
     let result = test_coll.find_one(doc! {"_id": test_id}, None).unwrap();
 
     assert!(result.is_none());
+    assert_eq!(unmatched.len(), 1);
+    assert_eq!(unmatched[0].ns, "syncer_test.test_coll");
+    assert_eq!(unmatched[0].id, Bson::ObjectId(test_id));
 }
 
 #[test]
@@ -248,3 +254,57 @@ fn test_execute_normal_oplogs_delete_op_with_no_exists_record() {
 
     assert!(result.is_none());
 }
+
+#[test]
+fn test_execute_normal_oplogs_split_large_command() {
+    let context = Context::new();
+    let client = context.get_internal();
+    let test_coll = client
+        .database("syncer_test")
+        .collection::<Document>("test_coll");
+
+    // 20 documents with 1MB value, they can't be written in one 16MB command.
+    let large_value = "a".repeat(1024 * 1024);
+    let mut oplogs: Vec<Document> = (0..20)
+        .map(|_| doc! {"op": "i", "ns": "syncer_test.test_coll", "o": {"_id": ObjectId::new(), "a": &large_value}})
+        .collect();
+    let limits = BatchLimits::new(10000, usize::MAX).unwrap();
+    execute_normal_oplogs_with_limits(&mut oplogs, client, &limits).unwrap();
+    assert_eq!(test_coll.count_documents(None, None).unwrap(), 20);
+}
+
+#[test]
+fn test_execute_normal_oplogs_with_mapping() {
+    let context = Context::new();
+    let client = context.get_internal();
+    let db = client.database("syncer_test");
+    let mapping = NamespaceMapping::new()
+        .with_coll("syncer_test.test_coll", "syncer_test.mapped_coll")
+        .with_coll("syncer_test.log_*", "syncer_test.archive_log_*");
+
+    let (id1, id2) = (ObjectId::new(), ObjectId::new());
+    let mut oplogs = vec![
+        doc! {"op": "i", "ns": "syncer_test.test_coll", "o": {"_id": id1, "a": 1}},
+        doc! {"op": "i", "ns": "syncer_test.log_2021", "o": {"_id": id2, "a": 2}},
+        doc! {"op": "u", "ns": "syncer_test.test_coll", "o2": {"_id": id1}, "o": {"$v": 1, "$set": {"a": 3}}},
+        doc! {"op": "d", "ns": "syncer_test.log_2021", "o": {"_id": id2}},
+    ];
+    execute_normal_oplogs_with_mapping(&mut oplogs, client, &BatchLimits::default(), &mapping)
+        .unwrap();
+
+    let coll_names = db.list_collection_names(None).unwrap();
+    assert!(!coll_names.contains(&"test_coll".to_string()));
+    assert!(!coll_names.contains(&"log_2021".to_string()));
+    let result = db
+        .collection::<Document>("mapped_coll")
+        .find_one(doc! {"_id": id1}, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(result, doc! {"_id": id1, "a": 3});
+    assert_eq!(
+        db.collection::<Document>("archive_log_2021")
+            .count_documents(None, None)
+            .unwrap(),
+        0
+    );
+}
@@ -1,6 +1,9 @@
-use bson::{doc, Document};
+use bson::oid::ObjectId;
+use bson::{doc, Bson, Document, Timestamp};
 use mongo_sync::blocking::mongo_syncer::full;
-use mongodb::options::FindOneOptions;
+use mongo_sync::blocking::mongo_syncer::plan::{FullSyncPlan, RangeState};
+use mongo_sync::BatchLimits;
+use mongodb::options::CreateCollectionOptions;
 use mongodb::sync::{Client, Database};
 use rayon::ThreadPoolBuilder;
 use std::sync::Arc;
@@ -50,7 +53,14 @@ fn test_sync_one_concurrent() {
     source_coll.insert_many(docs, None).unwrap();
 
     // execute.
-    full::sync_one_concurrent(source_coll, target_coll.clone(), 2, pool).unwrap();
+    full::sync_one_concurrent(
+        source_coll,
+        target_coll.clone(),
+        2,
+        pool,
+        Default::default(),
+    )
+    .unwrap();
     // check result in target collection.
     assert_eq!(target_coll.count_documents(None, None).unwrap(), 20000);
     for d in target_coll.find(None, None).unwrap() {
@@ -76,7 +86,7 @@ fn test_sync_one_serial() {
         .collection::<Document>("syncer_test_target");
     source_coll.insert_many(docs, None).unwrap();
     // execute.
-    full::sync_one_serial(source_coll, target_coll.clone()).unwrap();
+    full::sync_one_serial(source_coll, target_coll.clone(), Default::default()).unwrap();
     // check result in target collection.
     assert_eq!(target_coll.count_documents(None, None).unwrap(), 20000);
     for d in target_coll.find(None, None).unwrap() {
@@ -101,37 +111,256 @@ fn test_split_ids() {
 
     // split_ids.
     let result = full::split_ids(&source_coll, 1).unwrap();
-    assert_eq!(result.len(), 1);
-    let min_id = source_coll
-        .find_one(
-            None,
-            FindOneOptions::builder().sort(doc! {"_id": 1}).build(),
-        )
-        .unwrap()
-        .unwrap()
-        .get_object_id("_id")
-        .unwrap();
-    let max_id = source_coll
-        .find_one(
-            None,
-            FindOneOptions::builder().sort(doc! {"_id": -1}).build(),
-        )
-        .unwrap()
-        .unwrap()
-        .get_object_id("_id")
-        .unwrap();
-    assert_eq!(result[0].0, min_id);
-    assert_eq!(result[0].1, max_id);
+    assert_eq!(result, vec![RangeState::new(None, None)]);
 
     // split by 10 concurrent.
     let result = full::split_ids(&source_coll, 10).unwrap();
+    assert!(result.len() > 1 && result.len() <= 10);
+    assert_eq!(result[0].min, None);
+    assert_eq!(result[result.len() - 1].max, None);
     let global_cnt: u64 = result
         .into_iter()
-        .map(|(min_id, max_id)| {
+        .map(|range| {
             source_coll
-                .count_documents(doc! {"_id": {"$gte": min_id, "$lte": max_id}}, None)
+                .count_documents(range.remaining_filter(), None)
                 .unwrap()
         })
         .sum();
     assert_eq!(global_cnt, 20000);
 }
+
+#[test]
+fn test_create_coll_with_options() {
+    let context = Context::new(
+        option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017"),
+        option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018"),
+    );
+    // setup.
+    context
+        .source_db
+        .create_collection(
+            "capped_coll",
+            CreateCollectionOptions::builder()
+                .capped(true)
+                .size(4096)
+                .build(),
+        )
+        .unwrap();
+    context
+        .source_db
+        .create_collection("normal_coll", None)
+        .unwrap();
+
+    // execute.
+    full::create_coll_with_options(
+        &context.source_db,
+        "capped_coll",
+        &context.target_db,
+        "capped_coll",
+    )
+    .unwrap();
+    full::create_coll_with_options(
+        &context.source_db,
+        "normal_coll",
+        &context.target_db,
+        "normal_coll",
+    )
+    .unwrap();
+    // create again is ok.
+    full::create_coll_with_options(
+        &context.source_db,
+        "capped_coll",
+        &context.target_db,
+        "capped_coll",
+    )
+    .unwrap();
+
+    // check result in target database.
+    let stats = context
+        .target_db
+        .run_command(doc! {"collStats": "capped_coll"}, None)
+        .unwrap();
+    assert!(stats.get_bool("capped").unwrap());
+    // collection without options will be created during insertion.
+    let coll_names = context.target_db.list_collection_names(None).unwrap();
+    assert!(!coll_names.contains(&"normal_coll".to_string()));
+}
+
+#[test]
+fn test_sync_one_serial_resume_from_plan() {
+    let context = Context::new(
+        option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017"),
+        option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018"),
+    );
+    // setup.
+    let docs: Vec<Document> = (0..100).map(|i| doc! {"_id": i, "a": 3}).collect();
+    let source_coll = context
+        .source_db
+        .collection::<Document>("syncer_test_source");
+    let target_coll = context
+        .target_db
+        .collection::<Document>("syncer_test_source");
+    source_coll.insert_many(docs, None).unwrap();
+    // the first 60 documents are copied before interrupted, and document 60 is copied without checkpoint.
+    let copied: Vec<Document> = (0..61).map(|i| doc! {"_id": i, "a": 3}).collect();
+    target_coll.insert_many(copied, None).unwrap();
+    let plan = FullSyncPlan::new(&context.target_db);
+    let mut range = RangeState::new(None, None);
+    range.last_id = Some(Bson::Int32(59));
+    plan.save_ranges("syncer_test_source", &[range]).unwrap();
+
+    // execute.
+    let options = full::CopyOptions {
+        plan: Some(plan.clone()),
+        upsert: true,
+        ..Default::default()
+    };
+    let stats = full::sync_one_serial(source_coll, target_coll.clone(), options).unwrap();
+
+    // check result in target collection.
+    assert_eq!(stats.docs, 40);
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 100);
+    let state = plan.coll_state("syncer_test_source").unwrap().unwrap();
+    assert!(state.ranges[0].done);
+    assert_eq!(state.ranges[0].last_id, Some(Bson::Int32(99)));
+}
+
+#[test]
+fn test_sync_one_concurrent_split_lagging_range() {
+    let context = Context::new(
+        option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017"),
+        option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018"),
+    );
+    // setup.
+    let docs: Vec<Document> = (0..60000).map(|i| doc! {"_id": i, "a": 3}).collect();
+    let source_coll = context
+        .source_db
+        .collection::<Document>("syncer_test_source");
+    let target_coll = context
+        .target_db
+        .collection::<Document>("syncer_test_source");
+    source_coll.insert_many(docs, None).unwrap();
+    // all documents are in one range, so idle workers have to split it.
+    let plan = FullSyncPlan::new(&context.target_db);
+    plan.save_ranges("syncer_test_source", &[RangeState::new(None, None)])
+        .unwrap();
+
+    // execute.
+    let pool = Arc::new(ThreadPoolBuilder::new().num_threads(4).build().unwrap());
+    let options = full::CopyOptions {
+        plan: Some(plan.clone()),
+        ..Default::default()
+    };
+    let stats =
+        full::sync_one_concurrent(source_coll, target_coll.clone(), 4, pool, options).unwrap();
+
+    // check result in target collection.
+    assert_eq!(stats.docs, 60000);
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 60000);
+    let state = plan.coll_state("syncer_test_source").unwrap().unwrap();
+    assert!(state.ranges.len() > 1);
+    assert!(state.ranges.iter().all(|r| r.done));
+}
+
+#[test]
+fn test_plan_add_colls() {
+    let context = Context::new(
+        option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017"),
+        option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018"),
+    );
+    let plan = FullSyncPlan::new(&context.target_db);
+    let oplog_start = Timestamp {
+        time: 10,
+        increment: 0,
+    };
+    plan.create(oplog_start, &["a".to_string()]).unwrap();
+
+    // collections which are in plan already are not added again.
+    plan.add_colls(&["a".to_string(), "b".to_string()]).unwrap();
+    let header = plan.header().unwrap().unwrap();
+    assert_eq!(header.oplog_start, oplog_start);
+    assert_eq!(header.colls, vec!["a".to_string(), "b".to_string()]);
+}
+
+#[test]
+fn test_write_docs_upsert() {
+    let context = Context::new(
+        option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017"),
+        option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018"),
+    );
+    let target_coll = context
+        .target_db
+        .collection::<Document>("syncer_test_source");
+    // setup: documents 0 and 1 exist in target.
+    target_coll
+        .insert_many(vec![doc! {"_id": 0, "a": 1}, doc! {"_id": 1, "a": 1}], None)
+        .unwrap();
+
+    // execute.
+    let docs: Vec<Document> = (0..3).map(|i| doc! {"_id": i, "a": 2}).collect();
+    full::write_docs(&target_coll, docs, true).unwrap();
+
+    // existing documents are replaced, new documents are inserted.
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 3);
+    assert_eq!(target_coll.count_documents(doc! {"a": 2}, None).unwrap(), 3);
+
+    // documents which fail to write are kept in target.
+    let docs = vec![doc! {"_id": 0, "a": 3}, doc! {"_id": 3, "a": 3}];
+    assert!(full::write_docs(&target_coll, docs, false).is_err());
+    let doc = target_coll
+        .find_one(doc! {"_id": 0}, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(doc.get_i32("a").unwrap(), 2);
+}
+
+#[test]
+fn test_sync_one_mixed_id_types() {
+    let context = Context::new(
+        option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017"),
+        option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018"),
+    );
+    // setup: int, string and ObjectId `_id`s, more than one batch of each type.
+    let source_coll = context
+        .source_db
+        .collection::<Document>("syncer_test_source");
+    let target_coll = context
+        .target_db
+        .collection::<Document>("syncer_test_source");
+    let mut docs: Vec<Document> = (0..150).map(|i| doc! {"_id": i, "a": 3}).collect();
+    docs.extend((0..150).map(|i| doc! {"_id": format!("s{}", i), "a": 3}));
+    docs.extend((0..150).map(|_| doc! {"_id": ObjectId::new(), "a": 3}));
+    source_coll.insert_many(docs, None).unwrap();
+
+    // execute.
+    let pool = Arc::new(ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+    let options = full::CopyOptions {
+        limits: BatchLimits::new(100, 16 * 1024 * 1024).unwrap(),
+        ..Default::default()
+    };
+    let stats = full::sync_one_concurrent(
+        source_coll.clone(),
+        target_coll.clone(),
+        2,
+        pool,
+        options.clone(),
+    )
+    .unwrap();
+    assert_eq!(stats.docs, 450);
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 450);
+
+    // a copy interrupted after an int `_id` resumes with documents of later types.
+    target_coll.delete_many(doc! {}, None).unwrap();
+    let plan = FullSyncPlan::new(&context.target_db);
+    let mut range = RangeState::new(None, None);
+    range.last_id = Some(Bson::Int32(99));
+    plan.save_ranges("syncer_test_source", &[range]).unwrap();
+    let options = full::CopyOptions {
+        plan: Some(plan),
+        upsert: true,
+        ..options
+    };
+    let stats = full::sync_one_serial(source_coll, target_coll.clone(), options).unwrap();
+    assert_eq!(stats.docs, 350);
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 350);
+}
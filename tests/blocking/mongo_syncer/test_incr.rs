@@ -1,11 +1,20 @@
 // Only test for IncrDyumper.
 
 use bson::{doc, oid::ObjectId, Document, Timestamp};
+use mongodb::options::IndexOptions;
 use mongodb::sync::Client;
+use mongodb::IndexModel;
+use std::time::Duration;
 use uuid::Uuid;
 
 use mongo_sync::blocking::mongo_syncer::bson_helper::new_bson_binary;
+use mongo_sync::blocking::mongo_syncer::checkpoint::{
+    CheckpointState, CollCheckpoint, CollCheckpoints,
+};
+use mongo_sync::blocking::mongo_syncer::control::ControlChannel;
+use mongo_sync::blocking::mongo_syncer::dead_letter::DeadLetters;
 use mongo_sync::blocking::mongo_syncer::incr::IncrDumper;
+use mongo_sync::{ApplyRetry, NamespaceMapping, SyncError};
 
 struct Context {
     pub client: Client,
@@ -221,3 +230,239 @@ fn test_push_oplogs_already_handle_command() {
     assert_eq!(counts, 1);
     db.drop(None).unwrap();
 }
+
+#[test]
+fn test_coll_checkpoints() {
+    let context = Context::new();
+    let checkpoints = CollCheckpoints::new(
+        context
+            .client
+            .database("syncer_test")
+            .collection("coll_checkpoints"),
+    );
+    let ts = Timestamp {
+        time: 10,
+        increment: 0,
+    };
+    let copying = CollCheckpoint::new("a", "b", CheckpointState::Copying, ts);
+    checkpoints.save(&copying).unwrap();
+    assert_eq!(checkpoints.list().unwrap(), vec![copying]);
+
+    // checkpoint of the same collection is replaced.
+    let catching_up = CollCheckpoint::new("a", "b", CheckpointState::CatchingUp, ts);
+    checkpoints.save(&catching_up).unwrap();
+    assert_eq!(checkpoints.list().unwrap(), vec![catching_up]);
+
+    checkpoints.remove("a.b").unwrap();
+    assert!(checkpoints.list().unwrap().is_empty());
+}
+
+#[test]
+fn test_control_channel() {
+    let context = Context::new();
+    let coll = context
+        .client
+        .database("syncer_test")
+        .collection::<Document>("mongo_sync_control");
+    coll.insert_many(
+        vec![
+            doc! {"_id": 1, "command": "pause"},
+            doc! {"_id": 2, "command": "restart"},
+        ],
+        None,
+    )
+    .unwrap();
+    let control = ControlChannel::new(coll.clone());
+    let pending = control.pending().unwrap();
+    assert_eq!(pending.len(), 2);
+
+    control
+        .ack(&pending[0], &Ok(doc! {"paused": true}))
+        .unwrap();
+    let failed = Err(SyncError::InvalidControlCommand {
+        detail: "unknown command".to_string(),
+    });
+    control.ack(&pending[1], &failed).unwrap();
+    assert!(control.pending().unwrap().is_empty());
+
+    let acked = coll.find_one(doc! {"_id": 1}, None).unwrap().unwrap();
+    assert_eq!(acked.get_str("state").unwrap(), "done");
+    assert_eq!(
+        acked.get_document("result").unwrap(),
+        &doc! {"paused": true}
+    );
+    let acked = coll.find_one(doc! {"_id": 2}, None).unwrap().unwrap();
+    assert_eq!(acked.get_str("state").unwrap(), "failed");
+    assert!(acked.get_str("error").unwrap().contains("unknown command"));
+}
+
+#[test]
+fn test_unmatched_update_fetch_from_source() {
+    let context = Context::new();
+    let mongo_cli = context.client.clone();
+    let db = mongo_cli.database("syncer_test");
+    let id = ObjectId::new();
+    db.collection::<Document>("source_coll")
+        .insert_one(doc! {"_id": id, "a": 1, "b": 2}, None)
+        .unwrap();
+    db.create_collection("target_coll", None).unwrap();
+
+    let mapping =
+        NamespaceMapping::new().with_coll("syncer_test.source_coll", "syncer_test.target_coll");
+    let mut dumper = IncrDumper::new(mongo_cli.clone())
+        .with_namespace_mapping(mapping)
+        .with_source_fallback(mongo_cli.clone());
+    dumper.push_oplogs(vec![doc! {
+        "ts": Timestamp{time: 10, increment: 0},
+        "op": "u",
+        "ns": "syncer_test.source_coll",
+        "o2": {"_id": id},
+        "o": {"$v": 1, "$set": {"b": 2}},
+    }]);
+    dumper.apply_oplogs().unwrap();
+
+    let record = db
+        .collection::<Document>("target_coll")
+        .find_one(doc! {"_id": id}, None)
+        .unwrap();
+    assert_eq!(record, Some(doc! {"_id": id, "a": 1, "b": 2}));
+    let stats = dumper.unmatched_stats();
+    assert_eq!(stats.unmatched, 1);
+    assert_eq!(stats.refetched, 1);
+}
+
+#[test]
+fn test_full_document_updates() {
+    let context = Context::new();
+    let mongo_cli = context.client.clone();
+    let db = mongo_cli.database("syncer_test");
+    let (id, removed_id) = (ObjectId::new(), ObjectId::new());
+    db.collection::<Document>("full_source")
+        .insert_one(doc! {"_id": id, "a": 1, "b": 3}, None)
+        .unwrap();
+    db.collection::<Document>("full_target")
+        .insert_many(
+            vec![doc! {"_id": id, "a": 1, "b": 0}, doc! {"_id": removed_id}],
+            None,
+        )
+        .unwrap();
+
+    let mapping =
+        NamespaceMapping::new().with_coll("syncer_test.full_source", "syncer_test.full_target");
+    let mut dumper = IncrDumper::new(mongo_cli.clone())
+        .with_namespace_mapping(mapping)
+        .with_full_document_updates(mongo_cli.clone());
+    let update = |time, id| {
+        doc! {
+            "ts": Timestamp{time, increment: 0},
+            "op": "u",
+            "ns": "syncer_test.full_source",
+            "o2": {"_id": id},
+            "o": {"$v": 2, "diff": {"u": {"b": time as i32}}},
+        }
+    };
+    dumper.push_oplogs(vec![update(10, id), update(11, id), update(12, removed_id)]);
+    dumper.apply_oplogs().unwrap();
+
+    let records: Vec<Document> = db
+        .collection::<Document>("full_target")
+        .find(None, None)
+        .unwrap()
+        .map(|d| d.unwrap())
+        .collect();
+    assert_eq!(records, vec![doc! {"_id": id, "a": 1, "b": 3}]);
+}
+
+#[test]
+fn test_dead_letter_failed_oplog() {
+    let context = Context::new();
+    let mongo_cli = context.client.clone();
+    let db = mongo_cli.database("syncer_test");
+    let coll = db.collection::<Document>("dead_letter_coll");
+    coll.create_index(
+        IndexModel::builder()
+            .keys(doc! {"name": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None,
+    )
+    .unwrap();
+    let dead_letters = DeadLetters::new(db.collection("dead_letters"));
+
+    let mut dumper = IncrDumper::new(mongo_cli.clone())
+        .with_retry(ApplyRetry::new(1, Duration::from_millis(10)))
+        .with_dead_letters(dead_letters.clone());
+    let ids = [ObjectId::new(), ObjectId::new(), ObjectId::new()];
+    let insert = |time, id, name| {
+        doc! {
+            "ts": Timestamp{time, increment: 0},
+            "op": "i",
+            "ns": "syncer_test.dead_letter_coll",
+            "o": {"_id": id, "name": name},
+        }
+    };
+    dumper.push_oplogs(vec![
+        insert(10, ids[0], "a"),
+        insert(11, ids[1], "a"),
+        insert(12, ids[2], "b"),
+    ]);
+    dumper.apply_oplogs().unwrap();
+
+    assert_eq!(coll.count_documents(None, None).unwrap(), 2);
+    assert_eq!(dumper.dead_lettered(), 1);
+    let entries = dead_letters.list().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0].get_document("oplog").unwrap(),
+        &insert(11, ids[1], "a")
+    );
+    assert!(entries[0].get_array("write_errors").is_ok());
+
+    // it still fails until the cause is fixed.
+    let result = dumper.redrive_dead_letters(&dead_letters).unwrap();
+    assert_eq!(result, doc! {"applied": 0, "failed": 1});
+    let entries = dead_letters.list().unwrap();
+    assert_eq!(entries[0].get_i32("attempts").unwrap(), 2);
+
+    coll.drop_index("name_1", None).unwrap();
+    let result = dumper.redrive_dead_letters(&dead_letters).unwrap();
+    assert_eq!(result, doc! {"applied": 1, "failed": 0});
+    assert_eq!(dead_letters.count().unwrap(), 0);
+    assert_eq!(coll.count_documents(None, None).unwrap(), 3);
+}
+
+#[test]
+fn test_apply_failure_without_dead_letters() {
+    let context = Context::new();
+    let mongo_cli = context.client.clone();
+    let coll = mongo_cli
+        .database("syncer_test")
+        .collection::<Document>("failed_coll");
+    coll.create_index(
+        IndexModel::builder()
+            .keys(doc! {"name": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        None,
+    )
+    .unwrap();
+
+    let mut dumper = IncrDumper::new(mongo_cli.clone())
+        .with_retry(ApplyRetry::new(2, Duration::from_millis(10)));
+    let oplogs = (0..2)
+        .map(|time| {
+            doc! {
+                "ts": Timestamp{time, increment: 0},
+                "op": "i",
+                "ns": "syncer_test.failed_coll",
+                "o": {"_id": ObjectId::new(), "name": "a"},
+            }
+        })
+        .collect();
+    dumper.push_oplogs(oplogs);
+    assert!(matches!(
+        dumper.apply_oplogs(),
+        Err(SyncError::ApplyOplogError(_))
+    ));
+    assert_eq!(dumper.dead_lettered(), 0);
+}
@@ -1,6 +1,9 @@
-use bson::{doc, Document, Timestamp};
-use mongo_sync::OplogCleaner;
-use mongodb::sync::{Client, Collection};
+use bson::{doc, Bson, Document, Timestamp};
+use mongo_sync::{
+    CancelToken, DbSelector, DbSyncConf, FullSyncWritePolicy, MongoSyncer, OplogCleaner,
+    RemovedCollPolicy, RepairDoc, RestoreBase, RestoreOptions, SyncError,
+};
+use mongodb::sync::{Client, Collection, Database};
 
 struct Context {
     mongo_uri: String,
@@ -84,3 +87,507 @@ fn test_oplog_run_clean_all_new() {
 
     assert_eq!(coll.count_documents(None, None).unwrap(), 2);
 }
+
+struct CopyContext {
+    src_uri: String,
+    target_uri: String,
+    source_db: Database,
+    target_db: Database,
+}
+
+impl CopyContext {
+    pub fn new() -> Self {
+        let src_uri = option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017");
+        let target_uri = option_env!("SYNCER_TEST_TARGET").unwrap_or("mongodb://localhost:27018");
+        CopyContext {
+            source_db: Client::with_uri_str(src_uri)
+                .unwrap()
+                .database("syncer_test_copy"),
+            target_db: Client::with_uri_str(target_uri)
+                .unwrap()
+                .database("syncer_test_copy"),
+            src_uri: src_uri.to_string(),
+            target_uri: target_uri.to_string(),
+        }
+    }
+
+    pub fn conf(&self, write_policy: FullSyncWritePolicy) -> DbSyncConf {
+        DbSyncConf::new_oneshot(
+            self.src_uri.clone(),
+            self.target_uri.clone(),
+            "syncer_test_copy".to_string(),
+            Some(vec!["coll".to_string()]),
+            None,
+            None,
+        )
+        .with_write_policy(write_policy)
+    }
+}
+
+impl Drop for CopyContext {
+    fn drop(&mut self) {
+        self.source_db.drop(None).unwrap();
+        self.target_db.drop(None).unwrap();
+    }
+}
+
+#[test]
+fn test_sync_once_refuse_same_cluster() {
+    let context = CopyContext::new();
+    let conf = DbSyncConf::new_oneshot(
+        context.src_uri.clone(),
+        context.src_uri.clone(),
+        "syncer_test_copy".to_string(),
+        None,
+        None,
+        None,
+    );
+
+    let result = MongoSyncer::new(&conf).sync_once();
+    assert!(matches!(result, Err(SyncError::SameSourceAndTarget { .. })));
+}
+
+#[test]
+fn test_sync_once_write_policy() {
+    let context = CopyContext::new();
+    // setup.
+    let source_coll = context.source_db.collection::<Document>("coll");
+    let target_coll = context.target_db.collection::<Document>("coll");
+    source_coll
+        .insert_many(vec![doc! {"_id": 1, "a": 1}, doc! {"_id": 2, "a": 2}], None)
+        .unwrap();
+    target_coll
+        .insert_many(vec![doc! {"_id": 1, "a": 0}, doc! {"_id": 3, "a": 3}], None)
+        .unwrap();
+
+    // fail-if-non-empty doesn't touch target.
+    let conf = context.conf(FullSyncWritePolicy::FailIfNonEmpty);
+    let result = MongoSyncer::new(&conf).sync_once();
+    assert!(matches!(result, Err(SyncError::TargetNotEmpty { .. })));
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 2);
+
+    // merge replaces documents with the same `_id`, and keeps others.
+    let conf = context.conf(FullSyncWritePolicy::Merge);
+    let report = MongoSyncer::new(&conf).sync_once().unwrap();
+    assert!(report.is_success());
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 3);
+    assert_eq!(
+        target_coll
+            .find_one(doc! {"_id": 1}, None)
+            .unwrap()
+            .unwrap()
+            .get_i32("a")
+            .unwrap(),
+        1
+    );
+
+    // drop must be confirmed.
+    let conf = context.conf(FullSyncWritePolicy::Drop);
+    assert!(matches!(
+        MongoSyncer::new(&conf).sync_once(),
+        Err(SyncError::DropNotConfirmed)
+    ));
+
+    // drop removes extra documents.
+    let conf = context
+        .conf(FullSyncWritePolicy::Drop)
+        .with_confirm_drop(true);
+    let report = MongoSyncer::new(&conf).sync_once().unwrap();
+    assert!(report.is_success());
+    assert_eq!(target_coll.count_documents(None, None).unwrap(), 2);
+}
+
+#[test]
+fn test_sync_once_coll_filter() {
+    let context = CopyContext::new();
+    // setup.
+    let source_coll = context.source_db.collection::<Document>("coll");
+    let target_coll = context.target_db.collection::<Document>("coll");
+    source_coll
+        .insert_many(
+            vec![
+                doc! {"_id": 1, "tenant": "acme", "blob": "x"},
+                doc! {"_id": 2, "tenant": "other", "blob": "y"},
+            ],
+            None,
+        )
+        .unwrap();
+
+    let conf = context
+        .conf(FullSyncWritePolicy::FailIfNonEmpty)
+        .with_coll_filter("coll", doc! {"tenant": "acme"}, Some(doc! {"blob": 0}));
+    let report = MongoSyncer::new(&conf).sync_once().unwrap();
+    assert!(report.is_success());
+    let docs: Vec<Document> = target_coll
+        .find(None, None)
+        .unwrap()
+        .map(|d| d.unwrap())
+        .collect();
+    assert_eq!(docs, vec![doc! {"_id": 1, "tenant": "acme"}]);
+}
+
+#[test]
+fn test_sync_once_multiple_dbs() {
+    let context = CopyContext::new();
+    let src_client = Client::with_uri_str(&context.src_uri).unwrap();
+    let target_client = Client::with_uri_str(&context.target_uri).unwrap();
+    let db_names = [
+        "syncer_test_multi_a",
+        "syncer_test_multi_b",
+        "syncer_test_multi_c",
+    ];
+    for (idx, db) in db_names.iter().enumerate() {
+        src_client
+            .database(db)
+            .collection::<Document>("coll")
+            .insert_one(doc! {"_id": idx as i32}, None)
+            .unwrap();
+    }
+
+    let conf = DbSyncConf::new_oneshot(
+        context.src_uri.clone(),
+        context.target_uri.clone(),
+        String::new(),
+        None,
+        None,
+        None,
+    )
+    .with_dbs(
+        DbSelector::new()
+            .with_include("syncer_test_multi_*")
+            .with_exclude("syncer_test_multi_c"),
+    );
+    let report = MongoSyncer::new(&conf).sync_once();
+    let counts: Vec<u64> = db_names
+        .iter()
+        .map(|db| {
+            target_client
+                .database(db)
+                .collection::<Document>("coll")
+                .count_documents(None, None)
+                .unwrap()
+        })
+        .collect();
+    for db in db_names.iter() {
+        src_client.database(db).drop(None).unwrap();
+        target_client.database(db).drop(None).unwrap();
+    }
+
+    let report = report.unwrap();
+    assert!(report.is_success());
+    let mut names: Vec<&str> = report.collections.iter().map(|c| c.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        vec!["syncer_test_multi_a.coll", "syncer_test_multi_b.coll"]
+    );
+    assert_eq!(counts, vec![1, 1, 0]);
+}
+
+#[test]
+fn test_sync_once_coll_patterns() {
+    let context = CopyContext::new();
+    // setup.
+    for coll in ["log_1", "log_2", "log_tmp", "users"] {
+        context
+            .source_db
+            .collection::<Document>(coll)
+            .insert_one(doc! {"_id": 1}, None)
+            .unwrap();
+    }
+
+    let conf = context
+        .conf(FullSyncWritePolicy::FailIfNonEmpty)
+        .with_coll_include(r"/^log_\d+$/")
+        .with_coll_include("users")
+        .with_coll_exclude("log_2");
+    let report = MongoSyncer::new(&conf).sync_once().unwrap();
+    assert!(report.is_success());
+    let mut names: Vec<&str> = report.collections.iter().map(|c| c.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["log_1", "users"]);
+    for (coll, count) in [("log_1", 1), ("log_2", 0), ("log_tmp", 0), ("users", 1)] {
+        let target_coll = context.target_db.collection::<Document>(coll);
+        assert_eq!(target_coll.count_documents(None, None).unwrap(), count);
+    }
+}
+
+#[test]
+fn test_restore_until_point() {
+    let context = Context::new();
+    let src_uri = option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017");
+    let target_db = context.mongo_cli.database("syncer_test_restore");
+    let ts = |time| Timestamp { time, increment: 0 };
+    // setup: target is synced until 10, and oplogs after it are stored.
+    target_db
+        .collection::<Document>("oplog_records")
+        .insert_one(doc! {"ts": ts(10)}, None)
+        .unwrap();
+    let ns = "syncer_test_restore.coll";
+    context
+        .get_coll()
+        .insert_many(
+            vec![
+                doc! {"ts": ts(5), "op": "n", "ns": "", "o": {}},
+                doc! {"ts": ts(11), "op": "i", "ns": ns, "o": {"_id": 1}},
+                doc! {"ts": ts(12), "op": "i", "ns": ns, "o": {"_id": 2}},
+                doc! {"ts": ts(13), "op": "d", "ns": ns, "o": {"_id": 1}},
+                doc! {"ts": ts(14), "op": "i", "ns": ns, "o": {"_id": 3}},
+            ],
+            None,
+        )
+        .unwrap();
+    let conf = DbSyncConf::new(
+        src_uri.to_string(),
+        context.mongo_uri.clone(),
+        context.mongo_uri.clone(),
+        "syncer_test_restore".to_string(),
+        None,
+        None,
+        None,
+    );
+
+    // the bad delete is not applied.
+    let options = RestoreOptions::new(RestoreBase::Target, ts(14)).with_stop_before(ts(13));
+    let report = MongoSyncer::new(&conf).restore(&options).unwrap();
+    assert_eq!(report.applied(), 2);
+    assert_eq!(report.end, ts(12));
+    assert!(report.stopped_before.is_some());
+    let coll = target_db.collection::<Document>("coll");
+    assert_eq!(coll.count_documents(None, None).unwrap(), 2);
+
+    // the base can't be rewound.
+    let options = RestoreOptions::new(RestoreBase::Target, ts(11));
+    let result = MongoSyncer::new(&conf).restore(&options);
+    assert!(matches!(
+        result,
+        Err(SyncError::RestorePointBeforeBase { .. })
+    ));
+
+    let options = RestoreOptions::new(RestoreBase::Target, ts(14));
+    let report = MongoSyncer::new(&conf).restore(&options).unwrap();
+    assert_eq!(report.start, ts(12));
+    assert_eq!(report.applied(), 2);
+    assert_eq!(coll.count_documents(None, None).unwrap(), 2);
+    assert!(coll.find_one(doc! {"_id": 1}, None).unwrap().is_none());
+    target_db.drop(None).unwrap();
+}
+
+#[test]
+fn test_restore_dump_drop_not_confirmed() {
+    let context = Context::new();
+    let src_uri = option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017");
+    let target_db = context.mongo_cli.database("syncer_test_restore_dump");
+    let coll = target_db.collection::<Document>("coll");
+    coll.insert_one(doc! {"_id": 1}, None).unwrap();
+    let conf = DbSyncConf::new(
+        src_uri.to_string(),
+        context.mongo_uri.clone(),
+        context.mongo_uri.clone(),
+        "syncer_test_restore_dump".to_string(),
+        None,
+        None,
+        None,
+    )
+    .with_write_policy(FullSyncWritePolicy::Drop);
+
+    // drop policy of dump restore must be confirmed, and nothing is dropped.
+    let base = RestoreBase::Dump {
+        dir: std::env::temp_dir().join("syncer_test_restore_dump"),
+        start: Some(Timestamp {
+            time: 10,
+            increment: 0,
+        }),
+    };
+    let options = RestoreOptions::new(
+        base,
+        Timestamp {
+            time: 20,
+            increment: 0,
+        },
+    );
+    let result = MongoSyncer::new(&conf).restore(&options);
+    assert!(matches!(result, Err(SyncError::DropNotConfirmed)));
+    assert_eq!(coll.count_documents(None, None).unwrap(), 1);
+    target_db.drop(None).unwrap();
+}
+
+#[test]
+fn test_oplog_gap_policy() {
+    let context = Context::new();
+    let src_uri = option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017");
+    let target_db = context.mongo_cli.database("syncer_test_oplog_gap");
+    let ts = |time| Timestamp { time, increment: 0 };
+    // setup: target is synced until 10, but the earliest stored oplog is at 20.
+    let time_record = target_db.collection::<Document>("oplog_records");
+    time_record.insert_one(doc! {"ts": ts(10)}, None).unwrap();
+    context
+        .get_coll()
+        .insert_one(doc! {"ts": ts(20), "op": "n", "ns": "", "o": {}}, None)
+        .unwrap();
+    let conf = DbSyncConf::new(
+        src_uri.to_string(),
+        context.mongo_uri.clone(),
+        context.mongo_uri.clone(),
+        "syncer_test_oplog_gap".to_string(),
+        None,
+        None,
+        None,
+    );
+
+    // the gap is reported, and nothing is changed.
+    let result = MongoSyncer::new(&conf).sync();
+    assert!(matches!(
+        result,
+        Err(SyncError::OplogWindowExceeded { start, earliest }) if start == ts(10) && earliest == ts(20)
+    ));
+    let record = time_record.find_one(None, None).unwrap().unwrap();
+    assert_eq!(record.get_timestamp("ts").unwrap(), ts(10));
+    target_db.drop(None).unwrap();
+}
+
+#[test]
+fn test_runtime_coll_changes_survive_restart() {
+    let context = Context::new();
+    let src_uri = option_env!("SYNCER_TEST_SOURCE").unwrap_or("mongodb://localhost:27017");
+    let source_db = Client::with_uri_str(src_uri)
+        .unwrap()
+        .database("syncer_test_runtime_colls");
+    let target_db = context.mongo_cli.database("syncer_test_runtime_colls");
+    let ts = |time| Timestamp { time, increment: 0 };
+    // setup: `a` and `b` are selected by `--colls`, `c` is added and `b` is removed at runtime, target is
+    // synced until 30.
+    for coll in ["a", "b", "c"] {
+        for db in [&source_db, &target_db] {
+            db.collection::<Document>(coll)
+                .insert_one(doc! {"_id": 1}, None)
+                .unwrap();
+        }
+    }
+    let colls_to_sync = target_db.collection::<Document>("colls_to_sync");
+    colls_to_sync
+        .insert_one(
+            doc! {"names": ["a", "b"], "include": [], "exclude": [], "added": ["c"], "removed": ["b"]},
+            None,
+        )
+        .unwrap();
+    target_db
+        .collection::<Document>("oplog_records")
+        .insert_one(doc! {"ts": ts(30)}, None)
+        .unwrap();
+    context
+        .get_coll()
+        .insert_one(doc! {"ts": ts(20), "op": "n", "ns": "", "o": {}}, None)
+        .unwrap();
+    let conf = |colls: &[&str], policy| {
+        DbSyncConf::new(
+            src_uri.to_string(),
+            context.mongo_uri.clone(),
+            context.mongo_uri.clone(),
+            "syncer_test_runtime_colls".to_string(),
+            Some(colls.iter().map(|c| c.to_string()).collect()),
+            None,
+            None,
+        )
+        .with_removed_coll_policy(policy)
+    };
+
+    // restart with the same collections, `b` is not copied again, and `c` is not removed, so it stops in
+    // incremental sync.
+    for policy in [RemovedCollPolicy::Keep, RemovedCollPolicy::Drop] {
+        let conf = conf(&["a", "b"], policy);
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let result = MongoSyncer::new(&conf).with_cancel_token(cancel).sync();
+        assert!(matches!(result, Err(SyncError::Cancelled)), "{:?}", result);
+        let record = colls_to_sync.find_one(None, None).unwrap().unwrap();
+        assert_eq!(record.get_array("added").unwrap(), &vec![Bson::from("c")]);
+        assert_eq!(record.get_array("removed").unwrap(), &vec![Bson::from("b")]);
+        for coll in ["b", "c"] {
+            let count = target_db
+                .collection::<Document>(coll)
+                .count_documents(None, None)
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+    }
+
+    // runtime changes are dropped when collection options change.
+    let conf = conf(&["a"], RemovedCollPolicy::Refuse);
+    let result = MongoSyncer::new(&conf).sync();
+    assert!(matches!(
+        result,
+        Err(SyncError::CollsRemovedFromSyncSet { ref colls, .. }) if colls == &vec!["c".to_string()]
+    ));
+    // refused removal changes nothing.
+    let record = colls_to_sync.find_one(None, None).unwrap().unwrap();
+    assert_eq!(record.get_array("added").unwrap(), &vec![Bson::from("c")]);
+    assert!(target_db
+        .collection::<Document>("removed_colls")
+        .find_one(None, None)
+        .unwrap()
+        .is_none());
+    source_db.drop(None).unwrap();
+    target_db.drop(None).unwrap();
+}
+
+#[test]
+fn test_verify() {
+    let context = CopyContext::new();
+    // setup.
+    let source_coll = context.source_db.collection::<Document>("coll");
+    source_coll
+        .insert_many(
+            vec![doc! {"_id": 1}, doc! {"_id": 2, "a": 1}, doc! {"_id": 3}],
+            None,
+        )
+        .unwrap();
+    let conf = context.conf(FullSyncWritePolicy::FailIfNonEmpty);
+    assert!(MongoSyncer::new(&conf).sync_once().unwrap().is_success());
+    let report = MongoSyncer::new(&conf).verify().unwrap();
+    assert!(report.is_consistent());
+
+    let target_coll = context.target_db.collection::<Document>("coll");
+    target_coll.delete_one(doc! {"_id": 1}, None).unwrap();
+    target_coll
+        .update_one(doc! {"_id": 2}, doc! {"$set": {"a": 2}}, None)
+        .unwrap();
+    target_coll.insert_one(doc! {"_id": 4}, None).unwrap();
+    let report = MongoSyncer::new(&conf).verify().unwrap();
+    assert!(!report.is_consistent());
+    let coll_report = &report.collections[0];
+    assert_eq!(coll_report.src_count, 3);
+    assert_eq!(coll_report.target_count, 3);
+    assert_eq!(coll_report.id_diffs.missing, vec![Bson::Int32(1)]);
+    assert_eq!(coll_report.id_diffs.extra, vec![Bson::Int32(4)]);
+    assert_eq!(coll_report.id_diffs.differing, vec![Bson::Int32(2)]);
+}
+
+#[test]
+fn test_repair() {
+    let context = CopyContext::new();
+    // setup.
+    let source_coll = context.source_db.collection::<Document>("coll");
+    source_coll
+        .insert_many(vec![doc! {"_id": 1}, doc! {"_id": 2, "a": 1}], None)
+        .unwrap();
+    let conf = context.conf(FullSyncWritePolicy::FailIfNonEmpty);
+    assert!(MongoSyncer::new(&conf).sync_once().unwrap().is_success());
+
+    let target_coll = context.target_db.collection::<Document>("coll");
+    target_coll.delete_one(doc! {"_id": 1}, None).unwrap();
+    target_coll
+        .update_one(doc! {"_id": 2}, doc! {"$set": {"a": 2}}, None)
+        .unwrap();
+    target_coll.insert_one(doc! {"_id": 3}, None).unwrap();
+    let report = MongoSyncer::new(&conf).verify().unwrap();
+    let docs = report.repair_docs();
+    assert_eq!(docs.len(), 3);
+    let result = MongoSyncer::new(&conf).repair(&docs).unwrap();
+    assert_eq!(result.get_i64("upserted").unwrap(), 2);
+    assert_eq!(result.get_i64("deleted").unwrap(), 1);
+    assert!(MongoSyncer::new(&conf).verify().unwrap().is_consistent());
+
+    let not_synced = RepairDoc::new("syncer_test_copy.other".to_string(), Bson::Int32(1));
+    assert!(MongoSyncer::new(&conf).repair(&[not_synced]).is_err());
+}